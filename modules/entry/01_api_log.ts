@@ -0,0 +1,4 @@
+// entry modules are evaluated after main.ts, this one logs every request to /api
+com.mycompany.MyApp.addEventListener("request:/api", (evt: any) => {
+    console.debug("api request %s", evt.requestId);
+});
@@ -0,0 +1,5 @@
+// the handler of the /hello route, see routes::HANDLERS
+export function helloHandler(evt: any) {
+    const name = evt.query.name || "world";
+    return "hello " + name;
+}
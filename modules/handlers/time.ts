@@ -0,0 +1,4 @@
+// the handler of the /time route, it is the default export so its HANDLERS entry has DEFAULT_EXPORT as export
+export default function (evt: any) {
+    return {time: new Date().toISOString(), path: evt.path};
+}
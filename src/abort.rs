@@ -0,0 +1,182 @@
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::{JsError, Script};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+// the signal is plain script, fetch adds a listener to it which aborts the rust side of the request
+const ABORT_CONTROLLER_CLASSES: &str = r#"
+globalThis.AbortSignal = class AbortSignal {
+    constructor() {
+        this.aborted = false;
+        this.reason = undefined;
+        this.onabort = null;
+        this._listeners = [];
+    }
+    addEventListener(type, listener) {
+        if (type === "abort" && typeof listener === "function") {
+            this._listeners.push(listener);
+        }
+    }
+    removeEventListener(type, listener) {
+        if (type === "abort") {
+            this._listeners = this._listeners.filter((l) => l !== listener);
+        }
+    }
+    throwIfAborted() {
+        if (this.aborted) {
+            throw this.reason;
+        }
+    }
+};
+globalThis.AbortController = class AbortController {
+    constructor() {
+        this.signal = new AbortSignal();
+    }
+    abort(reason) {
+        const signal = this.signal;
+        if (signal.aborted) {
+            return;
+        }
+        signal.aborted = true;
+        signal.reason = reason === undefined ? new Error("the operation was aborted") : reason;
+        const evt = { type: "abort", target: signal };
+        if (typeof signal.onabort === "function") {
+            signal.onabort(evt);
+        }
+        for (const listener of signal._listeners.slice()) {
+            listener(evt);
+        }
+    }
+};
+"#;
+
+lazy_static! {
+    // the abortable jobs which are still running by id
+    static ref PENDING: Mutex<HashMap<u64, Pending>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ABORT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Pending {
+    // the request which started the job, the job is aborted when that request is dropped
+    request_id: Option<String>,
+    sender: oneshot::Sender<()>,
+}
+
+/// install the AbortController and AbortSignal classes as globals
+pub fn init_abort_controller<R: JsRealmAdapter>(realm: &R) -> Result<(), JsError> {
+    realm.js_eval(Script::new(
+        "file://abort_controller.js",
+        ABORT_CONTROLLER_CLASSES,
+    ))?;
+    Ok(())
+}
+
+/// register an abortable job, the receiver completes when abort() is called for the returned id or when the request
+/// with request_id is dropped before it responded, call done() when the job completes
+pub fn register(request_id: Option<String>) -> (u64, oneshot::Receiver<()>) {
+    let id = NEXT_ABORT_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = oneshot::channel();
+    PENDING
+        .lock()
+        .unwrap()
+        .insert(id, Pending { request_id, sender });
+    (id, receiver)
+}
+
+/// abort a job, returns false if it already completed or was aborted
+pub fn abort(id: u64) -> bool {
+    match PENDING.lock().unwrap().remove(&id) {
+        Some(pending) => pending.sender.send(()).is_ok(),
+        None => false,
+    }
+}
+
+/// forget a job which completed
+pub fn done(id: u64) {
+    PENDING.lock().unwrap().remove(&id);
+}
+
+/// aborts the jobs a request started when it is dropped without complete() being called, which is what happens
+/// when the client disconnects before we responded
+/// jobs started while the script streams its response after the handler returned are not aborted
+pub struct RequestGuard {
+    request_id: String,
+    completed: bool,
+}
+
+impl RequestGuard {
+    pub fn new(request_id: &str) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            completed: false,
+        }
+    }
+
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let mut pending = PENDING.lock().unwrap();
+        let ids: Vec<u64> = pending
+            .iter()
+            .filter(|(_, job)| job.request_id.as_deref() == Some(self.request_id.as_str()))
+            .map(|(id, _)| *id)
+            .collect();
+        if !ids.is_empty() {
+            log::debug!(
+                "request {} was dropped, aborting {} pending jobs",
+                self.request_id,
+                ids.len()
+            );
+        }
+        for id in ids {
+            if let Some(job) = pending.remove(&id) {
+                let _ = job.sender.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abort_notifies_the_listeners_once() {
+        let notified = crate::tests::eval(
+            r#"(() => {
+                const controller = new AbortController();
+                const notified = [];
+                controller.signal.onabort = () => notified.push("onabort");
+                controller.signal.addEventListener("abort", () => notified.push("listener"));
+                controller.abort();
+                controller.abort();
+                return [notified.join("+"), controller.signal.reason.message].join();
+            })()"#,
+        );
+        assert_eq!(notified, "onabort+listener,the operation was aborted");
+    }
+
+    #[test]
+    fn the_jobs_of_a_dropped_request_are_aborted() {
+        let (completed_job, mut completed_receiver) = register(Some("abort-completed".to_string()));
+        RequestGuard::new("abort-completed").complete();
+        assert!(completed_receiver.try_recv().is_err());
+        done(completed_job);
+        assert!(!abort(completed_job));
+
+        let (_, mut receiver) = register(Some("abort-dropped".to_string()));
+        drop(RequestGuard::new("abort-dropped"));
+        assert!(receiver.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{client_addr, config};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use std::time::Instant;
+
+/// the format of the access log lines, see SCRIPT_ACCESS_LOG
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessLogFormat {
+    Off,
+    /// the common log format followed by the duration
+    Common,
+    /// like Common with the referer and user agent before the duration
+    Combined,
+}
+
+impl AccessLogFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Self::Off),
+            "common" => Some(Self::Common),
+            "combined" => Some(Self::Combined),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Common => "common",
+            Self::Combined => "combined",
+        }
+    }
+}
+
+/// log a line for a request at info level with the access target once its response was determined, like
+/// 127.0.0.1 - - [14/Oct/2026:13:55:36 +0200] "GET /api?x=1 HTTP/1.1" 200 1234 12.3ms
+///
+/// the size is the body before compression, it is - when the body is empty or streamed as we don't know its size
+/// yet, for those script_response_body_bytes has the size
+pub fn log(req: &HttpRequest, response: &HttpResponse, started: Instant) {
+    let format = config::get().access_log;
+    if format == AccessLogFormat::Off || !log::log_enabled!(target: "access", log::Level::Info) {
+        return;
+    }
+    let size = match response.body().size() {
+        BodySize::Sized(size) if size > 0 => size.to_string(),
+        _ => "-".to_string(),
+    };
+    let mut line = format!(
+        "{} - - [{}] \"{} {} {:?}\" {} {}",
+        client_addr::client_ip(req),
+        chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+        req.method(),
+        req.uri()
+            .path_and_query()
+            .map_or_else(|| req.path(), |path| path.as_str()),
+        req.version(),
+        response.status().as_u16(),
+        size
+    );
+    if format == AccessLogFormat::Combined {
+        line.push_str(
+            format!(
+                " \"{}\" \"{}\"",
+                quoted_header(req, header::REFERER),
+                quoted_header(req, header::USER_AGENT)
+            )
+            .as_str(),
+        );
+    }
+    log::info!(
+        target: "access",
+        "{} {:.1}ms",
+        line,
+        started.elapsed().as_secs_f64() * 1000.0
+    );
+}
+
+// a header value for between quotes, - when the request does not have it
+fn quoted_header(req: &HttpRequest, name: header::HeaderName) -> String {
+    match req.headers().get(name).and_then(|val| val.to_str().ok()) {
+        Some(value) if !value.is_empty() => value.replace('\\', "\\\\").replace('"', "\\\""),
+        _ => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn every_format_is_parsed_from_its_name() {
+        for format in [
+            AccessLogFormat::Off,
+            AccessLogFormat::Common,
+            AccessLogFormat::Combined,
+        ] {
+            assert_eq!(AccessLogFormat::parse(format.name()), Some(format));
+        }
+        assert_eq!(AccessLogFormat::parse("json"), None);
+    }
+
+    #[test]
+    fn quotes_in_headers_are_escaped() {
+        let req = TestRequest::get()
+            .insert_header((header::USER_AGENT, r#"curl "8" \o/"#))
+            .insert_header((header::REFERER, ""))
+            .to_http_request();
+        assert_eq!(
+            quoted_header(&req, header::USER_AGENT),
+            r#"curl \"8\" \\o/"#
+        );
+        assert_eq!(quoted_header(&req, header::REFERER), "-");
+        assert_eq!(quoted_header(&req, header::ACCEPT), "-");
+    }
+}
@@ -0,0 +1,167 @@
+use crate::restart::{self, RestartError};
+use crate::{auth, config, flags, logging, maintenance, proxy_registry};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::LevelFilter;
+use std::str::FromStr;
+
+/// register the /admin endpoints if SCRIPT_ADMIN_TOKEN is set, when it is not set requests to them get a 404
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // the /admin endpoints are only there when the token is set, they always require it as bearer token
+    if config::get().admin_token.is_some() {
+        cfg.service(web::resource("/admin/loglevel").route(web::post().to(log_level)));
+        cfg.service(web::resource("/admin/flags").route(web::get().to(list_flags)));
+        cfg.service(web::resource("/admin/flags/{name}").route(web::post().to(set_flag)));
+        cfg.service(
+            web::resource("/admin/maintenance")
+                .route(web::get().to(get_maintenance))
+                .route(web::post().to(set_maintenance)),
+        );
+        cfg.service(web::resource("/admin/proxies").route(web::get().to(list_proxies)));
+        cfg.service(web::resource("/admin/restart").route(web::post().to(restart_runtimes)));
+    }
+}
+
+fn authorized(req: &HttpRequest) -> bool {
+    match config::get().admin_token.as_ref() {
+        Some(token) => auth::has_bearer_token(req, token),
+        None => false,
+    }
+}
+
+/// the POST /admin/loglevel endpoint, the body is the new level like debug or warn
+/// the level is changed until the next restart, which falls back to SCRIPT_LOG_LEVEL
+async fn log_level(req: HttpRequest, body: Bytes) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let level = match std::str::from_utf8(&body)
+        .ok()
+        .and_then(|level| LevelFilter::from_str(level.trim()).ok())
+    {
+        Some(level) => level,
+        None => {
+            return HttpResponse::BadRequest()
+                .body("expected one of off, error, warn, info, debug or trace")
+        }
+    };
+    let previous = logging::set_level(level);
+    log::warn!("log level changed from {} to {}", previous, level);
+    HttpResponse::Ok().json(serde_json::json!({
+        "previous": previous.to_string().to_lowercase(),
+        "level": level.to_string().to_lowercase(),
+    }))
+}
+
+/// the GET /admin/flags endpoint, returns the feature flags as {name: enabled}
+async fn list_flags(req: HttpRequest) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let flags: serde_json::Map<String, serde_json::Value> = flags::all()
+        .into_iter()
+        .map(|(name, enabled)| (name, serde_json::Value::from(enabled)))
+        .collect();
+    HttpResponse::Ok().json(flags)
+}
+
+/// the POST /admin/flags/{name} endpoint, the body is true or false
+/// like the log level the change is lost on restart, the flags then start from the config again
+async fn set_flag(req: HttpRequest, name: web::Path<String>, body: Bytes) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let enabled = match std::str::from_utf8(&body).map(str::trim) {
+        Ok("true") => true,
+        Ok("false") => false,
+        _ => return HttpResponse::BadRequest().body("expected true or false"),
+    };
+    let previous = flags::set(name.as_str(), enabled);
+    log::warn!("flag {} changed from {:?} to {}", name, previous, enabled);
+    HttpResponse::Ok().json(serde_json::json!({
+        "flag": name.as_str(),
+        "previous": previous,
+        "enabled": enabled,
+    }))
+}
+
+/// the GET /admin/maintenance endpoint, returns {"maintenance": enabled}
+async fn get_maintenance(req: HttpRequest) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "maintenance": maintenance::is_enabled() }))
+}
+
+/// the POST /admin/maintenance endpoint, the body is true to enter maintenance mode or false to leave it
+/// the change is lost on restart, which starts from SCRIPT_MAINTENANCE again
+async fn set_maintenance(req: HttpRequest, body: Bytes) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let enabled = match std::str::from_utf8(&body).map(str::trim) {
+        Ok("true") => true,
+        Ok("false") => false,
+        _ => return HttpResponse::BadRequest().body("expected true or false"),
+    };
+    let previous = maintenance::set(enabled);
+    log::warn!("maintenance mode changed from {} to {}", previous, enabled);
+    HttpResponse::Ok().json(serde_json::json!({
+        "previous": previous,
+        "maintenance": enabled,
+    }))
+}
+
+/// the GET /admin/proxies endpoint, returns the proxies installed in the runtimes with their methods and getters by
+/// realm id like {"__main__": [...], "tenant-a": [...]}, see proxy_registry::ProxyInfo, methods of capabilities which are not granted are not listed
+async fn list_proxies(req: HttpRequest) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok().json(proxy_registry::by_realm())
+}
+
+/// the POST /admin/restart endpoint, replaces the script runtimes with new ones while the server keeps running, see
+/// restart::restart
+/// responds with {"restarted": n} once all runtimes were replaced, a 409 while another restart is going on and a 500
+/// when a new runtime could not be initialized
+async fn restart_runtimes(req: HttpRequest) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    match restart::restart().await {
+        Ok(restarted) => HttpResponse::Ok().json(serde_json::json!({ "restarted": restarted })),
+        Err(RestartError::InProgress) => {
+            HttpResponse::Conflict().body("the runtimes are already being restarted")
+        }
+        Err(RestartError::Failed(pool_idx, err)) => {
+            HttpResponse::InternalServerError().body(format!(
+                "could not restart runtime {}, it and the runtimes after it were not replaced: {}",
+                pool_idx, err
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn without_a_token_there_are_no_admin_endpoints() {
+        let req = test::TestRequest::post()
+            .uri("/admin/loglevel")
+            .insert_header(("authorization", "Bearer "))
+            .set_payload("debug");
+        let (status, _, _) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        // and they would not accept any token
+        let req = test::TestRequest::post()
+            .insert_header(("authorization", "Bearer "))
+            .to_http_request();
+        let res = log_level(req, Bytes::from("debug")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
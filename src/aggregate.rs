@@ -0,0 +1,152 @@
+use crate::event::{RequestInfo, ScriptResponse};
+use crate::routes::Aggregate;
+use crate::timeout::{route_timeout, with_deadline};
+use crate::{
+    backpressure, context, dispatch, errors, event, script_pool, tenants, MY_APP_CLASS,
+    MY_APP_NAMESPACE,
+};
+use actix_web::web::Bytes;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::facades::{JsRuntimeFacade, JsValueType};
+use hirofa_utils::js_utils::JsError;
+use serde_json::{json, Map, Value};
+
+/// dispatch the events for a request concurrently and return their results in the order of the events
+///
+/// every event is a job on the next runtime of the pool, so with more than one runtime they run in parallel, and
+/// gets an event object of its own for the request, the listener sets evt.result to its part of the response
+/// the events are dispatched in the main realm (or the realm of the tenant) as an isolated realm only lives in one
+/// runtime, a listener which throws or exceeds the script timeout only fails its own event
+/// every job takes a permit like a request, an event which gets none when SCRIPT_MAX_PENDING is reached fails too
+pub async fn dispatch_all(
+    info: &RequestInfo,
+    events: &[&'static str],
+) -> Vec<Result<Value, JsError>> {
+    let realm_id = info.tenant.as_deref().map(tenants::realm_id);
+    // js_loop_realm queues the job when it is called, so all jobs are queued before we await the first one
+    let jobs: Vec<_> = events
+        .iter()
+        .map(|event_name| {
+            let event_name = *event_name;
+            let info = info.clone();
+            let permit = backpressure::try_acquire()
+                .map_err(|_| JsError::new_str("too many pending requests"))?;
+            let job = script_pool()
+                .next()
+                .js_loop_realm(realm_id.as_deref(), move |_rt, realm| {
+                    with_deadline(
+                        route_timeout(info.route.as_str(), info.method.as_str()),
+                        || {
+                            // the events may run in other runtimes, they get a fresh context
+                            context::with_request_id(info.request_id.as_str(), || {
+                                context::with_script_context(realm, &info, || {
+                                    dispatch_one(realm, &info, event_name)
+                                })
+                            })
+                        },
+                    )
+                });
+            Ok((permit, job))
+        })
+        .collect::<Vec<Result<_, JsError>>>();
+    let mut results = vec![];
+    for job in jobs {
+        results.push(match job {
+            // the permit is held until the job is done
+            Ok((_permit, job)) => job.await,
+            Err(err) => Err(err),
+        });
+    }
+    results
+}
+
+/// dispatch a single event and read back evt.result, null when the listeners set none
+fn dispatch_one<R: JsRealmAdapter>(
+    realm: &R,
+    info: &RequestInfo,
+    event_name: &str,
+) -> Result<Value, JsError> {
+    let event_obj = event::create_event_obj(realm, info)?;
+    dispatch::dispatch_to(
+        realm,
+        MY_APP_NAMESPACE,
+        MY_APP_CLASS,
+        event_name,
+        &event_obj,
+    )?;
+    let result = realm.js_object_get_property(&event_obj, "result")?;
+    if result.js_get_type() == JsValueType::Undefined {
+        return Ok(Value::Null);
+    }
+    let json = realm.js_json_stringify(&result, None)?;
+    serde_json::from_str(json.as_str()).map_err(|err| {
+        JsError::new_string(format!(
+            "result of {} is not valid json: {}",
+            event_name, err
+        ))
+    })
+}
+
+/// dispatch the events of an aggregate route and respond with their results as json by event name
+/// e.g. {"dashboard:greeting": {"result": "hi"}, "dashboard:stats": {"error": "..."}}, failures are reported per
+/// event so one failing part does not fail the whole response
+pub async fn respond(response: &mut ScriptResponse, info: &RequestInfo, aggregate: &Aggregate) {
+    let results = dispatch_all(info, aggregate.events).await;
+    let mut body = Map::new();
+    for (event_name, result) in aggregate.events.iter().zip(results) {
+        let part = match result {
+            Ok(result) => json!({ "result": result }),
+            Err(err) => {
+                errors::log_script_error(
+                    format!("could not dispatch event {}", event_name).as_str(),
+                    &err,
+                );
+                json!({ "error": err.get_message() })
+            }
+        };
+        body.insert(event_name.to_string(), part);
+    }
+    response.body = Some(Bytes::from(Value::Object(body).to_string()));
+    response.content_type = Some("application/json".to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn every_event_gets_its_own_result_or_error() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("aggregateTest:result", (evt) => {
+                evt.result = {path: evt.path};
+            });
+            com.mycompany.MyApp.addEventListener("aggregateTest:error", (evt) => {
+                throw Error("no stats");
+            });"#,
+        );
+        let req = test::TestRequest::get().uri("/dashboard").to_http_request();
+        let info = RequestInfo::from_http_request(&req, Bytes::new(), "id".to_string(), 0);
+        let results = dispatch_all(
+            &info,
+            &[
+                "aggregateTest:result",
+                "aggregateTest:error",
+                "aggregateTest:none",
+            ],
+        )
+        .await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].as_ref().ok().unwrap(),
+            &json!({"path": "/dashboard"})
+        );
+        assert!(results[1]
+            .as_ref()
+            .err()
+            .unwrap()
+            .get_message()
+            .contains("no stats"));
+        assert_eq!(results[2].as_ref().ok().unwrap(), &Value::Null);
+    }
+}
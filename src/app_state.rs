@@ -0,0 +1,68 @@
+use crate::{config, script_pool};
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // the app state only changes on restart so it is serialized once and parsed for every event
+    static ref APP_JSON: String = {
+        let config = config::get();
+        serde_json::json!({
+            "name": config.app_name,
+            "version": env!("CARGO_PKG_VERSION"),
+            "debug": cfg!(debug_assertions),
+            "poolSize": script_pool().runtimes().len(),
+            "isolateRequests": config.isolate_requests,
+            "tenants": config.tenants,
+            "scriptTimeoutMs": config.script_timeout.as_millis() as u64,
+        })
+        .to_string()
+    };
+}
+
+/// create the event.app object with the app name and a snapshot of the config
+/// every event gets its own copy which is frozen so a script can't change what the next event sees
+pub fn create_app_obj<R: JsRealmAdapter>(realm: &R) -> Result<R::JsValueAdapterType, JsError> {
+    let app = realm.js_json_parse(APP_JSON.as_str())?;
+    freeze(realm, &app)?;
+    Ok(app)
+}
+
+// Object.freeze is shallow so the nested objects and arrays are frozen first, arrays are not js_is_object
+fn freeze<R: JsRealmAdapter>(realm: &R, value: &R::JsValueAdapterType) -> Result<(), JsError> {
+    for name in realm.js_object_get_properties(value)? {
+        let prop = realm.js_object_get_property(value, name.as_str())?;
+        if prop.js_is_object() || prop.js_is_array() {
+            freeze(realm, &prop)?;
+        }
+    }
+    realm.js_function_invoke_by_name(&["Object"], "freeze", std::slice::from_ref(value))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::Script;
+
+    #[test]
+    fn the_app_object_is_frozen() {
+        let checked = crate::tests::with_realm(|realm| {
+            let check = realm.js_eval(Script::new(
+                "file://app_state_test.js",
+                r#"(app) => {
+                    app.name = "changed";
+                    return [app.name, Object.isFrozen(app), Object.isFrozen(app.tenants)].join();
+                }"#,
+            ))?;
+            let app = create_app_obj(realm)?;
+            realm
+                .js_function_invoke(None, &check, &[&app])?
+                .js_to_string()
+        });
+        assert_eq!(
+            checked.ok().unwrap(),
+            format!("{},true,true", config::get().app_name)
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{config, secrets};
+use actix_web::http::header;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+
+// the claims of the verified token of a request, stored in the extensions of the request by check
+struct Claims(Value);
+
+// the HS256 keys from SCRIPT_JWT_SECRETS followed by the RS256 keys from SCRIPT_JWT_PUBLIC_KEYS
+static KEYS: OnceCell<Vec<(Algorithm, DecodingKey)>> = OnceCell::new();
+
+/// load the keys tokens are verified with, called once at the start of main after the secrets were loaded
+/// fails for a secret which does not exist and for a key file which is not a PEM rsa public key
+pub fn init() -> std::io::Result<()> {
+    let config = config::get();
+    let mut keys = vec![];
+    for name in config.jwt_secrets.iter() {
+        let secret = secrets::get(name.as_str()).ok_or_else(|| {
+            invalid_input(format!(
+                "invalid {}: there is no secret named {}",
+                config::JWT_SECRETS_VAR,
+                name
+            ))
+        })?;
+        keys.push((
+            Algorithm::HS256,
+            DecodingKey::from_secret(secret.as_bytes()),
+        ));
+    }
+    for path in config.jwt_public_keys.iter() {
+        let pem = std::fs::read(path).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!(
+                    "could not read {} {}: {}",
+                    config::JWT_PUBLIC_KEYS_VAR,
+                    path,
+                    err
+                ),
+            )
+        })?;
+        let key = DecodingKey::from_rsa_pem(&pem).map_err(|err| {
+            invalid_input(format!(
+                "invalid {} {}: {}",
+                config::JWT_PUBLIC_KEYS_VAR,
+                path,
+                err
+            ))
+        })?;
+        keys.push((Algorithm::RS256, key));
+    }
+    let _ = KEYS.set(keys);
+    Ok(())
+}
+
+fn invalid_input(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+// the reason a token was rejected, sent to the client so it can tell an expired token from a wrong one
+fn describe(kind: &ErrorKind) -> String {
+    match kind {
+        ErrorKind::ExpiredSignature => "the token is expired".to_string(),
+        ErrorKind::ImmatureSignature => "the token is not valid yet".to_string(),
+        ErrorKind::InvalidIssuer => "the issuer of the token is not accepted".to_string(),
+        ErrorKind::InvalidSignature => "the signature of the token is invalid".to_string(),
+        ErrorKind::MissingRequiredClaim(claim) => format!("the token has no {} claim", claim),
+        _ => "the token is invalid".to_string(),
+    }
+}
+
+/// verify the signature, exp, nbf and iss of a token and return its claims
+/// the algorithm in the header of the token only selects which of our keys are tried, a token signed with any other
+/// algorithm (like none) is rejected
+fn verify(token: &str) -> Result<Value, String> {
+    let header =
+        jsonwebtoken::decode_header(token).map_err(|_| "the token is malformed".to_string())?;
+    let config = config::get();
+    let mut validation = Validation::new(header.alg);
+    validation.validate_nbf = true;
+    if !config.jwt_issuers.is_empty() {
+        validation.set_issuer(&config.jwt_issuers);
+    }
+    let mut error = None;
+    for (_alg, key) in KEYS
+        .get()
+        .into_iter()
+        .flatten()
+        .filter(|(alg, _key)| *alg == header.alg)
+    {
+        match jsonwebtoken::decode::<Value>(token, key, &validation) {
+            Ok(data) => return Ok(data.claims),
+            // the claims are only checked after the signature, so an expired token signed with another of our keys
+            // is reported as expired rather than as not signed by this key
+            Err(err) if matches!(err.kind(), ErrorKind::InvalidSignature) => {
+                error.get_or_insert_with(|| describe(err.kind()));
+            }
+            Err(err) => error = Some(describe(err.kind())),
+        }
+    }
+    Err(error.unwrap_or_else(|| format!("tokens signed with {:?} are not accepted", header.alg)))
+}
+
+// the token of an Authorization: Bearer <token> header
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    Some(token.trim()).filter(|token| scheme.eq_ignore_ascii_case("bearer") && !token.is_empty())
+}
+
+// a 401 with the challenge of RFC 6750, a request without a token gets no error code
+fn unauthorized(error: Option<String>) -> HttpResponse {
+    let challenge = match &error {
+        Some(description) => format!(
+            "Bearer error=\"invalid_token\", error_description=\"{}\"",
+            description
+        ),
+        None => "Bearer".to_string(),
+    };
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, challenge))
+        .body(
+            error.unwrap_or_else(|| "this route needs an Authorization: Bearer token".to_string()),
+        )
+}
+
+/// the 401 for requests on a route in SCRIPT_AUTH_ROUTES without a valid bearer token, these are not dispatched
+/// the claims of a valid token are kept with the request for event.auth, see claims
+/// only authentication happens here, what the claims allow is up to the script
+pub fn check(req: &HttpRequest) -> Option<HttpResponse> {
+    let route = req.match_pattern()?;
+    if !config::get().auth_routes.contains(&route) {
+        return None;
+    }
+    let token = match bearer_token(req) {
+        Some(token) => token,
+        None => return Some(unauthorized(None)),
+    };
+    match verify(token) {
+        Ok(claims) => {
+            req.extensions_mut().insert(Claims(claims));
+            None
+        }
+        Err(error) => {
+            log::debug!("rejecting the token of a request on {}: {}", route, error);
+            Some(unauthorized(Some(error)))
+        }
+    }
+}
+
+/// true when the request carries credentials, an Authorization or a Cookie header
+/// the responses to those may differ per client so they are not cached or shared with other clients
+pub fn has_credentials(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    headers.contains_key(header::AUTHORIZATION) || headers.contains_key(header::COOKIE)
+}
+
+/// true when the request has the token as bearer token, for the endpoints with a token of their own like /admin
+/// the token is compared in constant time so the response time does not tell how much of a guess was right
+pub fn has_bearer_token(req: &HttpRequest, token: &str) -> bool {
+    match bearer_token(req) {
+        Some(given) => constant_time_eq(given.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+/// compare two secrets, the time only depends on the length
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// the claims of the token check verified for the request, None on routes which are not protected
+pub fn claims(req: &HttpRequest) -> Option<Value> {
+    req.extensions()
+        .get::<Claims>()
+        .map(|claims| claims.0.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"auth-test-secret";
+
+    fn token(header: &Header, claims: Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn only_unexpired_tokens_signed_with_our_keys_are_verified() {
+        config::init_for_tests();
+        KEYS.get_or_init(|| vec![(Algorithm::HS256, DecodingKey::from_secret(SECRET))]);
+        let hs256 = Header::default();
+        let now = jsonwebtoken::get_current_timestamp();
+        let valid = token(&hs256, json!({"sub": "me", "exp": now + 60}), SECRET);
+        assert_eq!(verify(valid.as_str()).unwrap()["sub"], "me");
+        let expired = token(&hs256, json!({"exp": now - 3600}), SECRET);
+        assert_eq!(
+            verify(expired.as_str()).unwrap_err(),
+            "the token is expired"
+        );
+        let forged = token(&hs256, json!({"exp": now + 60}), b"another secret");
+        assert_eq!(
+            verify(forged.as_str()).unwrap_err(),
+            "the signature of the token is invalid"
+        );
+        let no_exp = token(&hs256, json!({"sub": "me"}), SECRET);
+        assert_eq!(
+            verify(no_exp.as_str()).unwrap_err(),
+            "the token has no exp claim"
+        );
+        let hs384 = token(
+            &Header::new(Algorithm::HS384),
+            json!({"exp": now + 60}),
+            SECRET,
+        );
+        assert_eq!(
+            verify(hs384.as_str()).unwrap_err(),
+            "tokens signed with HS384 are not accepted"
+        );
+        assert_eq!(verify("not a token").unwrap_err(), "the token is malformed");
+    }
+
+    #[test]
+    fn only_the_exact_bearer_token_matches() {
+        let request = |value: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, value))
+                .to_http_request()
+        };
+        assert!(has_bearer_token(&request("Bearer secret"), "secret"));
+        assert!(!has_bearer_token(&request("Bearer secre"), "secret"));
+        assert!(!has_bearer_token(&request("Bearer secrets"), "secret"));
+        assert!(!has_bearer_token(&request("Basic secret"), "secret"));
+        assert!(!has_bearer_token(
+            &TestRequest::default().to_http_request(),
+            "secret"
+        ));
+    }
+}
@@ -0,0 +1,36 @@
+use crate::config;
+use crate::metrics;
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use lazy_static::lazy_static;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// the Retry-After of the 503 when too many requests are pending, the dispatches are short so clients can retry soon
+const RETRY_AFTER_SECS: u64 = 1;
+
+lazy_static! {
+    // a permit for every request which may be dispatched or waiting to be dispatched, None when unlimited
+    static ref PERMITS: Option<Semaphore> = match config::get().max_pending {
+        0 => None,
+        max_pending => Some(Semaphore::new(max_pending)),
+    };
+}
+
+/// take a permit to dispatch a request, the permit is returned when it's dropped
+/// when SCRIPT_MAX_PENDING requests are already pending this returns the 503 to respond with instead of queuing
+/// the request so a burst can't grow the job queues of the runtimes without bounds, None when there is no limit
+pub fn try_acquire() -> Result<Option<SemaphorePermit<'static>>, HttpResponse> {
+    let permits = match PERMITS.as_ref() {
+        Some(permits) => permits,
+        None => return Ok(None),
+    };
+    match permits.try_acquire() {
+        Ok(permit) => Ok(Some(permit)),
+        Err(_) => {
+            metrics::OVERLOADED.inc();
+            Err(HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()))
+                .body("too many pending requests"))
+        }
+    }
+}
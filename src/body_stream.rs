@@ -0,0 +1,421 @@
+use crate::deferred::DeferredJobs;
+use crate::event::{RequestInfo, ScriptResponse};
+use crate::timeout::{route_timeout, with_deadline};
+use crate::{
+    abort, access_log, auth, backpressure, config, content_encoding, context, cors, dispatch,
+    errors, event, maintenance, metrics, rate_limit, routes, script_pool, streaming, tenants,
+    MY_APP_CLASS, MY_APP_NAMESPACE,
+};
+use actix_web::dev::Decompress;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse};
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use hirofa_utils::js_utils::JsError;
+use quickjs_runtime::facades::QuickJsRuntimeFacade;
+use quickjs_runtime::quickjsrealmadapter::QuickJsRealmAdapter;
+use quickjs_runtime::valueref::JSValueRef;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_stream::{Stream, StreamExt};
+
+/// dispatched for every chunk of the body as it is read, with the chunk as evt.chunk (a Uint8Array)
+pub const BODY_CHUNK_EVENT: &str = "body:chunk";
+/// dispatched once the whole body was read, the listeners set the response on the event like for `request`
+pub const BODY_END_EVENT: &str = "body:end";
+
+/// the handler for the routes in routes::STREAMING_ROUTES, the body is not buffered but passed to the script in
+/// chunks as they are read so large bodies can be processed incrementally (e.g. hashed)
+///
+/// all events of a request get the same event object, created like for other requests but without a body, so a
+/// script can keep its state on the event, they are dispatched in order in the main realm (or the realm of the
+/// tenant) of one runtime, requests are never isolated here
+/// the middleware events are dispatched before the first chunk is read, all events get the same getContext()
+/// a listener which vetoes a middleware or body:chunk event aborts the request: the rest of the body is not read,
+/// body:end is not dispatched and the response is what the script set on the event
+pub async fn index(req: HttpRequest, payload: web::Payload) -> HttpResponse {
+    if let Some(response) = cors::preflight_response(&req) {
+        return response;
+    }
+    let started = Instant::now();
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let rejected = maintenance::check()
+        .or_else(|| rate_limit::check(&req))
+        .or_else(|| tenants::check(&req))
+        .or_else(|| auth::check(&req))
+        .or_else(|| content_encoding::check(&req));
+    let mut response = match rejected {
+        Some(response) => response,
+        // the permit is held until the body events were dispatched
+        None => match backpressure::try_acquire() {
+            Ok(_permit) => handle_request(&req, payload, request_id.clone()).await,
+            Err(response) => response,
+        },
+    };
+    cors::apply_headers(&req, &mut response);
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+    access_log::log(&req, &response, started);
+    response
+}
+
+async fn handle_request(
+    req: &HttpRequest,
+    payload: web::Payload,
+    request_id: String,
+) -> HttpResponse {
+    // a body which announces it is too large is rejected before the script sees any of it, a chunked body has no
+    // Content-Length so for those the limit is only enforced while reading, see dispatch_body
+    let max_body = config::get().max_body;
+    if matches!(content_length(req), Some(length) if length > max_body as u64) {
+        return rejection(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("the body is larger than {} bytes", max_body),
+        )
+        .into_http_response(req);
+    }
+    // aborts the fetches the script started for this request when the client disconnects before we respond
+    let guard = abort::RequestGuard::new(request_id.as_str());
+    let (stream_id, receiver) = streaming::open();
+    let info = RequestInfo::from_http_request(req, Bytes::new(), request_id, stream_id);
+    let (method, route) = (info.method.clone(), info.route.clone());
+    let labels = [method.as_str(), route.as_str()];
+    metrics::DISPATCHED.with_label_values(&labels).inc();
+
+    // unlike web::Bytes the payload is not decoded for us, the chunks the script gets are the decoded body
+    let result = dispatch_body(info, Decompress::from_headers(payload, req.headers())).await;
+    guard.complete();
+    let streamed = streaming::detach(stream_id);
+    let response = match result {
+        Ok(response) if streamed => {
+            response.into_streaming_response(receiver, metrics::StreamedSize::new(&method, &route))
+        }
+        Ok(response) => response.into_http_response(req),
+        Err(err) => {
+            if errors::http_error_status(&err).is_none() {
+                errors::log_script_error("could not dispatch the body events", &err);
+                metrics::SCRIPT_ERRORS.with_label_values(&labels).inc();
+            }
+            if streamed {
+                streaming::fail(stream_id, &err);
+                ScriptResponse::default()
+                    .into_streaming_response(receiver, metrics::StreamedSize::new(&method, &route))
+            } else {
+                errors::script_error_response(&err)
+            }
+        }
+    };
+    metrics::observe_response_size(&labels, &response);
+    response
+}
+
+// the Content-Length of the request, None for a chunked body, actix already rejected requests with an invalid one
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// the event and context objects are kept in the object cache of the realm in between the jobs, they are removed
+/// when this drops
+struct CachedEvent {
+    rt: Arc<QuickJsRuntimeFacade>,
+    realm_id: Option<String>,
+    id: i32,
+    context_id: i32,
+    // the timeout of every job, not of the whole request
+    timeout: Duration,
+}
+
+impl Drop for CachedEvent {
+    fn drop(&mut self) {
+        let (id, context_id) = (self.id, self.context_id);
+        self.rt
+            .js_loop_realm_void(self.realm_id.as_deref(), move |_rt, realm| {
+                realm.js_cache_dispose(id);
+                realm.js_cache_dispose(context_id);
+            });
+    }
+}
+
+impl CachedEvent {
+    /// run a job for the request with the event object, in the context of the request
+    async fn with_event<T, F>(&self, request_id: &str, job: F) -> Result<T, JsError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&QuickJsRealmAdapter, &JSValueRef) -> Result<T, JsError> + Send + 'static,
+    {
+        let (id, context_id, timeout) = (self.id, self.context_id, self.timeout);
+        let request_id = request_id.to_string();
+        self.rt
+            .js_loop_realm(self.realm_id.as_deref(), move |_rt, realm| {
+                with_deadline(timeout, || {
+                    context::with_request_id(request_id.as_str(), || {
+                        context::with_cached_context(realm, context_id, || {
+                            realm.js_cache_with(id, |event_obj| job(realm, event_obj))
+                        })
+                    })
+                })
+            })
+            .await
+    }
+}
+
+// dispatch routes::MIDDLEWARE in order, true when one vetoed
+fn dispatch_middleware<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+) -> Result<bool, JsError> {
+    for event_name in routes::MIDDLEWARE {
+        if dispatch::dispatch_to(realm, MY_APP_NAMESPACE, MY_APP_CLASS, event_name, event_obj)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// a response for a body we could not read, the script is not told about these
+fn rejection(status: StatusCode, message: String) -> ScriptResponse {
+    ScriptResponse {
+        handled: true,
+        status: Some(status.as_u16()),
+        body: Some(Bytes::from(message)),
+        content_type: Some("text/plain; charset=utf-8".to_string()),
+        ..ScriptResponse::default()
+    }
+}
+
+async fn dispatch_body<S: Stream<Item = Result<Bytes, PayloadError>> + Unpin>(
+    info: RequestInfo,
+    payload: S,
+) -> Result<ScriptResponse, JsError> {
+    let rt = script_pool().next();
+    let realm_id = info.tenant.as_deref().map(tenants::realm_id);
+    // the callbacks of event.after() run once the response was sent like for other requests, also after a
+    // rejection, when dispatching failed they run when this returns
+    let deferred = DeferredJobs::new(rt.clone(), realm_id.clone(), info.request_id.as_str());
+    #[cfg(feature = "db")]
+    let transactions = crate::proxies::db::TransactionGuard::new(info.request_id.as_str());
+    let mut response = dispatch_events(rt, realm_id, info, payload).await?;
+    response.deferred = Some(deferred);
+    #[cfg(feature = "db")]
+    {
+        response.transactions = Some(transactions);
+    }
+    Ok(response)
+}
+
+// dispatch the middleware and the body events and read back the response
+async fn dispatch_events<S: Stream<Item = Result<Bytes, PayloadError>> + Unpin>(
+    rt: Arc<QuickJsRuntimeFacade>,
+    realm_id: Option<String>,
+    info: RequestInfo,
+    mut payload: S,
+) -> Result<ScriptResponse, JsError> {
+    let request_id = info.request_id.clone();
+    let labels = [info.method.clone(), info.route.clone()];
+    let timeout = route_timeout(info.route.as_str(), info.method.as_str());
+    let (id, context_id) = rt
+        .js_loop_realm(realm_id.as_deref(), move |_rt, realm| {
+            let event_obj = event::create_event_obj(realm, &info)?;
+            event::set_after_function(realm, &event_obj, &info)?;
+            let context_id = context::cache_script_context(realm, &info)?;
+            Ok((realm.js_cache_add(&event_obj), context_id))
+        })
+        .await?;
+    let cached = CachedEvent {
+        rt,
+        realm_id,
+        id,
+        context_id,
+        timeout,
+    };
+    // like for other requests the middleware runs before anything else, a veto means the body is not read at all
+    let vetoed = cached
+        .with_event(request_id.as_str(), |realm, event_obj| {
+            dispatch_middleware(realm, event_obj)
+        })
+        .await?;
+    if vetoed {
+        log::debug!("request {} was handled by the middleware", request_id);
+        return finish(&cached, request_id, None).await;
+    }
+
+    // actix decodes a Transfer-Encoding: chunked body, the chunks we get are what was read from the connection and
+    // don't match the chunks the client sent, the limit applies to their total as the Content-Length may be missing
+    // and, for a compressed body, is the size before decoding
+    let max_body = config::get().max_body;
+    let mut size = 0;
+    let mut chunk_index = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                log::debug!("could not read the body: {}", err);
+                return Ok(rejection(
+                    StatusCode::BAD_REQUEST,
+                    format!("could not read the body: {}", err),
+                ));
+            }
+        };
+        size += chunk.len();
+        if size > max_body {
+            return Ok(rejection(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("the body is larger than {} bytes", max_body),
+            ));
+        }
+        let vetoed = cached
+            .with_event(request_id.as_str(), move |realm, event_obj| {
+                let chunk_obj = realm.js_typed_array_uint8_create(chunk.to_vec())?;
+                realm.js_object_set_property(event_obj, "chunk", &chunk_obj)?;
+                realm.js_object_set_property(
+                    event_obj,
+                    "chunkIndex",
+                    &realm.js_i32_create(chunk_index)?,
+                )?;
+                dispatch::dispatch_to(
+                    realm,
+                    MY_APP_NAMESPACE,
+                    MY_APP_CLASS,
+                    BODY_CHUNK_EVENT,
+                    event_obj,
+                )
+            })
+            .await?;
+        if vetoed {
+            log::debug!(
+                "request {} was aborted after {} chunks",
+                request_id,
+                chunk_index + 1
+            );
+            observe_request_size(&labels, size);
+            return finish(&cached, request_id, None).await;
+        }
+        chunk_index += 1;
+    }
+    observe_request_size(&labels, size);
+    finish(&cached, request_id, Some(size)).await
+}
+
+// for an aborted request this is the part of the body which was read, bodies which were rejected are not recorded
+fn observe_request_size(labels: &[String; 2], size: usize) {
+    metrics::REQUEST_BODY_BYTES
+        .with_label_values(&[labels[0].as_str(), labels[1].as_str()])
+        .observe(size as f64);
+}
+
+/// dispatch body:end with the total size unless the request was aborted and read back the response
+async fn finish(
+    cached: &CachedEvent,
+    request_id: String,
+    size: Option<usize>,
+) -> Result<ScriptResponse, JsError> {
+    cached
+        .with_event(request_id.as_str(), move |realm, event_obj| {
+            let handled = match size {
+                Some(size) => {
+                    realm.js_object_set_property(
+                        event_obj,
+                        "chunk",
+                        &realm.js_undefined_create()?,
+                    )?;
+                    realm.js_object_set_property(
+                        event_obj,
+                        "size",
+                        &realm.js_f64_create(size as f64)?,
+                    )?;
+                    dispatch::dispatch_to(
+                        realm,
+                        MY_APP_NAMESPACE,
+                        MY_APP_CLASS,
+                        BODY_END_EVENT,
+                        event_obj,
+                    )?
+                }
+                None => true,
+            };
+            let mut response = ScriptResponse::read_from_event_obj(realm, event_obj)?;
+            response.handled = handled;
+            Ok(response)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn the_body_is_dispatched_in_chunks_and_then_ends() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("body:chunk", (evt) => {
+                if (evt.headers["x-test"] === "body-stream") {
+                    evt.received = (evt.received || 0) + evt.chunk.byteLength;
+                }
+            });
+            com.mycompany.MyApp.addEventListener("body:end", (evt) => {
+                if (evt.headers["x-test"] === "body-stream") {
+                    evt.responseBody = `${evt.received} of ${evt.size}`;
+                }
+            });"#,
+        );
+        let req = test::TestRequest::post()
+            .uri("/ingest")
+            .insert_header(("x-test", "body-stream"))
+            .set_payload("hello world");
+        let (status, _, body) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "11 of 11");
+    }
+
+    #[actix_web::test]
+    async fn a_chunked_body_is_reassembled_and_limited_in_total() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("body:chunk", (evt) => {
+                if (evt.headers["x-test"] === "chunked") {
+                    evt.bytes = (evt.bytes || []).concat(Array.from(evt.chunk));
+                }
+            });
+            com.mycompany.MyApp.addEventListener("body:end", (evt) => {
+                if (evt.headers["x-test"] === "chunked") {
+                    evt.responseBody = String.fromCharCode(...evt.bytes);
+                }
+            });"#,
+        );
+        let chunked = |payload: Vec<u8>| {
+            test::TestRequest::post()
+                .uri("/ingest")
+                .insert_header(("x-test", "chunked"))
+                .insert_header(("transfer-encoding", "chunked"))
+                .set_payload(payload)
+        };
+        let (status, _, body) = crate::tests::call(chunked(b"first,second".to_vec())).await;
+        assert_eq!(
+            (status, body.as_ref()),
+            (StatusCode::OK, &b"first,second"[..])
+        );
+        // without a Content-Length the limit applies to the bytes which were read
+        let too_large = vec![b'x'; crate::config::get().max_body + 1];
+        let (status, _, _) = crate::tests::call(chunked(too_large)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        // a body which announces it is too large is rejected before it is read
+        let req = test::TestRequest::post()
+            .uri("/ingest")
+            .insert_header(("x-test", "chunked"))
+            .insert_header(("content-length", "100000000000"))
+            .set_payload("small");
+        let (status, _, _) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
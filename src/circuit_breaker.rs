@@ -0,0 +1,227 @@
+use crate::{config, metrics};
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+enum State {
+    Closed,
+    // calls fail fast until then
+    Open(Instant),
+    // the cooldown passed and a trial call is in flight, the other calls still fail fast
+    HalfOpen,
+}
+
+impl State {
+    // the value of the script_circuit_breaker_state gauge
+    fn gauge(&self) -> i64 {
+        match self {
+            State::Closed => 0,
+            State::Open(_) => 1,
+            State::HalfOpen => 2,
+        }
+    }
+}
+
+struct Breaker {
+    state: State,
+    // whether the most recent calls failed, at most SCRIPT_BREAKER_WINDOW
+    outcomes: VecDeque<bool>,
+}
+
+lazy_static! {
+    // by target, shared by all runtimes so every runtime sees the failures of the others
+    static ref BREAKERS: Mutex<HashMap<String, Breaker>> = Mutex::new(HashMap::new());
+}
+
+fn set_state(target: &str, breaker: &mut Breaker, state: State) {
+    metrics::CIRCUIT_BREAKER_STATE
+        .with_label_values(&[target])
+        .set(state.gauge());
+    breaker.state = state;
+}
+
+/// the right to make a call to a target, record the outcome of the call with it
+/// a permit which is dropped without an outcome (e.g. an aborted fetch) does not count, when it was the trial call
+/// the next call is the trial instead
+pub struct Permit {
+    // None when the breakers are disabled
+    target: Option<String>,
+    trial: bool,
+}
+
+/// get a permit for a call to target, a host for fetch or database for query
+///
+/// a target's breaker opens when SCRIPT_BREAKER_FAILURE_RATE percent of its last SCRIPT_BREAKER_WINDOW calls failed,
+/// calls then fail right away for SCRIPT_BREAKER_COOLDOWN_MS instead of piling onto a target which is down, after that
+/// a single trial call is let through which closes the breaker when it succeeds and opens it again when it fails
+pub fn acquire(target: &str) -> Result<Permit, JsError> {
+    let config = config::get();
+    if config.breaker_failure_rate == 0 {
+        return Ok(Permit {
+            target: None,
+            trial: false,
+        });
+    }
+    let mut breakers = BREAKERS.lock().unwrap();
+    let breaker = breakers
+        .entry(target.to_string())
+        .or_insert_with(|| Breaker {
+            state: State::Closed,
+            outcomes: VecDeque::new(),
+        });
+    let trial = match breaker.state {
+        State::Closed => false,
+        State::Open(until) if until <= Instant::now() => {
+            log::info!("circuit breaker of {} is half open, trying a call", target);
+            set_state(target, breaker, State::HalfOpen);
+            true
+        }
+        State::Open(until) => {
+            metrics::CIRCUIT_BREAKER_REJECTIONS
+                .with_label_values(&[target])
+                .inc();
+            return Err(JsError::new_string(format!(
+                "the circuit breaker of {} is open after too many failed calls, retry in {}ms",
+                target,
+                until.saturating_duration_since(Instant::now()).as_millis()
+            )));
+        }
+        State::HalfOpen => {
+            metrics::CIRCUIT_BREAKER_REJECTIONS
+                .with_label_values(&[target])
+                .inc();
+            return Err(JsError::new_string(format!(
+                "the circuit breaker of {} is open, a trial call is in flight",
+                target
+            )));
+        }
+    };
+    Ok(Permit {
+        target: Some(target.to_string()),
+        trial,
+    })
+}
+
+impl Permit {
+    /// record whether the call failed, only failures which say something about the target (like a network error or
+    /// a 5xx) should count
+    pub fn record(mut self, failed: bool) {
+        let target = match self.target.take() {
+            Some(target) => target,
+            None => return,
+        };
+        let config = config::get();
+        let mut breakers = BREAKERS.lock().unwrap();
+        let breaker = match breakers.get_mut(&target) {
+            Some(breaker) => breaker,
+            None => return,
+        };
+        if self.trial {
+            if failed {
+                log::warn!(
+                    "trial call to {} failed, circuit breaker opened again",
+                    target
+                );
+                set_state(
+                    &target,
+                    breaker,
+                    State::Open(Instant::now() + config.breaker_cooldown),
+                );
+            } else {
+                log::info!("trial call to {} succeeded, circuit breaker closed", target);
+                breaker.outcomes.clear();
+                set_state(&target, breaker, State::Closed);
+            }
+            return;
+        }
+        // a call which was made before the breaker opened does not count anymore
+        if !matches!(breaker.state, State::Closed) {
+            return;
+        }
+        breaker.outcomes.push_back(failed);
+        while breaker.outcomes.len() > config.breaker_window {
+            breaker.outcomes.pop_front();
+        }
+        let failures = breaker.outcomes.iter().filter(|failed| **failed).count();
+        if breaker.outcomes.len() == config.breaker_window
+            && failures * 100 >= config.breaker_failure_rate as usize * config.breaker_window
+        {
+            log::warn!(
+                "{} of the last {} calls to {} failed, circuit breaker opened for {}ms",
+                failures,
+                config.breaker_window,
+                target,
+                config.breaker_cooldown.as_millis()
+            );
+            breaker.outcomes.clear();
+            set_state(
+                &target,
+                breaker,
+                State::Open(Instant::now() + config.breaker_cooldown),
+            );
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // only set when the permit was not recorded
+        if let (Some(target), true) = (self.target.as_ref(), self.trial) {
+            if let Some(breaker) = BREAKERS.lock().unwrap().get_mut(target) {
+                set_state(target, breaker, State::Open(Instant::now()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the breaker of a target as if its cooldown just passed
+    fn cooled_down(target: &str) {
+        BREAKERS.lock().unwrap().insert(
+            target.to_string(),
+            Breaker {
+                state: State::Open(Instant::now()),
+                outcomes: VecDeque::new(),
+            },
+        );
+    }
+
+    #[test]
+    fn the_breaker_opens_when_enough_calls_failed() {
+        let config = config::init_for_tests();
+        let target = "opens.example.com";
+        let failures = (config.breaker_window * config.breaker_failure_rate as usize).div_ceil(100);
+        for call in 0..config.breaker_window {
+            acquire(target).ok().unwrap().record(call < failures);
+        }
+        assert!(acquire(target).is_err());
+    }
+
+    #[test]
+    fn a_successful_trial_closes_the_breaker() {
+        config::init_for_tests();
+        let target = "trial.example.com";
+        cooled_down(target);
+        let trial = acquire(target).ok().unwrap();
+        // the other calls fail fast while the trial is in flight
+        assert!(acquire(target).is_err());
+        trial.record(false);
+        assert!(acquire(target).is_ok());
+    }
+
+    #[test]
+    fn an_unrecorded_trial_lets_the_next_call_be_the_trial() {
+        config::init_for_tests();
+        let target = "aborted.example.com";
+        cooled_down(target);
+        drop(acquire(target).ok().unwrap());
+        let trial = acquire(target).ok().unwrap();
+        trial.record(true);
+        assert!(acquire(target).is_err());
+    }
+}
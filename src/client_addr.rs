@@ -0,0 +1,74 @@
+use crate::config;
+use actix_web::HttpRequest;
+use std::net::IpAddr;
+
+/// the ip of the peer which connected to us, when running behind a proxy this is the proxy
+pub fn remote_addr(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// the ip of the client, when SCRIPT_TRUSTED_PROXIES is set this is taken from X-Forwarded-For and the remote_addr
+/// otherwise
+pub fn client_ip(req: &HttpRequest) -> String {
+    let trusted_proxies = config::get().trusted_proxies;
+    let forwarded_for = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|val| val.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    forwarded_ip(forwarded_for.as_str(), trusted_proxies).unwrap_or_else(|| remote_addr(req))
+}
+
+// every proxy appends the address of its peer, so walking from the right the entries up to the one of the outermost
+// trusted proxy were written by proxies we trust, whatever is left of that the client sent itself
+// with fewer entries than trusted proxies the leftmost entry is used, None when that is not an ip
+fn forwarded_ip(forwarded_for: &str, trusted_proxies: usize) -> Option<String> {
+    if trusted_proxies == 0 {
+        return None;
+    }
+    let entries: Vec<&str> = forwarded_for
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    let entry = entries.get(entries.len().saturating_sub(trusted_proxies))?;
+    entry.parse::<IpAddr>().ok().map(|ip| ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_header_is_ignored_without_trusted_proxies() {
+        assert_eq!(forwarded_ip("1.1.1.1", 0), None);
+    }
+
+    #[test]
+    fn entries_are_taken_from_the_right() {
+        // the client sent 6.6.6.6 itself, our proxy appended the address it saw
+        assert_eq!(
+            forwarded_ip("6.6.6.6, 1.1.1.1", 1),
+            Some("1.1.1.1".to_string())
+        );
+        assert_eq!(
+            forwarded_ip("6.6.6.6, 1.1.1.1, 10.0.0.2", 2),
+            Some("1.1.1.1".to_string())
+        );
+    }
+
+    #[test]
+    fn the_leftmost_entry_is_used_with_fewer_entries_than_proxies() {
+        assert_eq!(forwarded_ip("1.1.1.1", 2), Some("1.1.1.1".to_string()));
+        assert_eq!(forwarded_ip("", 1), None);
+    }
+
+    #[test]
+    fn entries_which_are_not_an_ip_are_ignored() {
+        assert_eq!(forwarded_ip("1.1.1.1, not-an-ip", 1), None);
+        assert_eq!(forwarded_ip("::1", 1), Some("::1".to_string()));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::event::ScriptResponse;
+use crate::{auth, config, metrics, tenants};
+use actix_web::http::header::{EntityTag, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+// the tenant (empty without) and the path with query
+type Key = (String, String);
+
+// the response of the first request, None until it was handled
+type Shared = Option<Arc<SharedResponse>>;
+
+/// a GET on a coalesced route which is being dispatched, identical requests wait for its response
+struct InFlight {
+    id: u64,
+    sender: watch::Sender<Shared>,
+    receiver: watch::Receiver<Shared>,
+}
+
+/// the parts of a ScriptResponse we need to respond to the waiting requests
+struct SharedResponse {
+    handled: bool,
+    status: Option<u16>,
+    body: Option<Bytes>,
+    content_type: Option<String>,
+    location: Option<String>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    etag: Option<EntityTag>,
+    last_modified: Option<SystemTime>,
+    cache_control: Option<String>,
+}
+
+lazy_static! {
+    // only the requests which are in flight, an entry is removed as soon as its response was shared
+    static ref IN_FLIGHT: Mutex<HashMap<Key, InFlight>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// held while the first of identical requests is dispatched, dropping it wakes up the requests waiting for it,
+/// without a shared response (the script failed or streamed) the next of those is dispatched instead
+pub struct CoalesceGuard {
+    key: Key,
+    id: u64,
+}
+
+impl Drop for CoalesceGuard {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if matches!(in_flight.get(&self.key), Some(entry) if entry.id == self.id) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+fn in_flight_key(req: &HttpRequest) -> Option<Key> {
+    request_key(req, &config::get().coalesce_routes)
+}
+
+// the key of a GET on one of the coalesced routes, requests with credentials are not coalesced as the response may
+// differ per client
+// requests for an unknown tenant are rejected before we get here
+fn request_key(req: &HttpRequest, coalesce_routes: &[String]) -> Option<Key> {
+    if req.method() != Method::GET || auth::has_credentials(req) {
+        return None;
+    }
+    let route = req.match_pattern()?;
+    if !coalesce_routes.contains(&route) {
+        return None;
+    }
+    let tenant = tenants::resolve(req).ok().flatten().unwrap_or_default();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| req.path(), |path| path.as_str());
+    Some((tenant, path.to_string()))
+}
+
+/// coalesce a GET on a route in SCRIPT_COALESCE_ROUTES with the identical requests (same path and query, and
+/// tenant) which are in flight, requests with an Authorization or Cookie header are always dispatched
+///
+/// the first request gets a guard to hold until it was handled, the requests which arrive while it is dispatched
+/// wait for it and get its response (Err) without dispatching the script again
+/// nothing is kept once the first request was handled, the next identical request is dispatched again, see
+/// response_cache.rs for keeping responses
+/// Ok(None) for other requests
+pub async fn begin(req: &HttpRequest) -> Result<Option<CoalesceGuard>, HttpResponse> {
+    let key = match in_flight_key(req) {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    loop {
+        let mut receiver = {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(entry) => entry.receiver.clone(),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+                    in_flight.insert(
+                        key.clone(),
+                        InFlight {
+                            id,
+                            sender,
+                            receiver,
+                        },
+                    );
+                    return Ok(Some(CoalesceGuard { key, id }));
+                }
+            }
+        };
+        // an Err means the guard was dropped without a response, then we try to be the first again
+        if receiver.changed().await.is_ok() {
+            let shared = receiver.borrow().clone();
+            if let Some(shared) = shared {
+                log::debug!("coalesced {} {} with a request in flight", key.0, key.1);
+                metrics::COALESCED_REQUESTS.inc();
+                return Err(shared.to_http_response(req));
+            }
+        }
+    }
+}
+
+/// share the response of the first of identical requests with the requests waiting for it
+/// streamed responses, failed requests and responses which set cookies are not shared, a cookie is meant for the
+/// client it is sent to
+pub fn share(req: &HttpRequest, response: &ScriptResponse) {
+    let key = match in_flight_key(req) {
+        Some(key) => key,
+        None => return,
+    };
+    if !response.set_cookies.is_empty() {
+        return;
+    }
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    // removed here so a request which arrives after this is dispatched again instead of waiting for nothing
+    if let Some(entry) = in_flight.remove(&key) {
+        let _ = entry.sender.send(Some(Arc::new(SharedResponse {
+            handled: response.handled,
+            status: response.status,
+            body: response.body.clone(),
+            content_type: response.content_type.clone(),
+            location: response.location.clone(),
+            headers: response.headers.clone(),
+            etag: response.etag.clone(),
+            last_modified: response.last_modified,
+            cache_control: response.cache_control.clone(),
+        })));
+    }
+}
+
+impl SharedResponse {
+    // the conditional headers of the waiting request are checked against the shared etag again
+    fn to_http_response(&self, req: &HttpRequest) -> HttpResponse {
+        ScriptResponse {
+            handled: self.handled,
+            status: self.status,
+            body: self.body.clone(),
+            content_type: self.content_type.clone(),
+            location: self.location.clone(),
+            headers: self.headers.clone(),
+            etag: self.etag.clone(),
+            last_modified: self.last_modified,
+            cache_control: self.cache_control.clone(),
+            ..ScriptResponse::default()
+        }
+        .into_http_response(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::{test, web, App};
+
+    // responds with the key of the request on the coalesced route /items/{id}
+    async fn key_of(req: HttpRequest) -> HttpResponse {
+        let routes = vec!["/items/{id}".to_string()];
+        HttpResponse::Ok().body(format!("{:?}", request_key(&req, &routes)))
+    }
+
+    async fn key_for(req: test::TestRequest) -> String {
+        crate::config::init_for_tests();
+        let app = test::init_service(
+            App::new()
+                .route("/items/{id}", web::to(key_of))
+                .route("/other", web::to(key_of)),
+        )
+        .await;
+        let body = test::call_and_read_body(&app, req.to_request()).await;
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn gets_on_coalesced_routes_are_keyed_by_path_and_query() {
+        let key = key_for(test::TestRequest::get().uri("/items/1?full=true")).await;
+        assert_eq!(key, r#"Some(("", "/items/1?full=true"))"#);
+    }
+
+    #[actix_web::test]
+    async fn other_requests_are_not_coalesced() {
+        assert_eq!(
+            key_for(test::TestRequest::get().uri("/other")).await,
+            "None"
+        );
+        assert_eq!(
+            key_for(test::TestRequest::post().uri("/items/1")).await,
+            "None"
+        );
+    }
+
+    #[actix_web::test]
+    async fn requests_with_credentials_are_not_coalesced() {
+        let req = test::TestRequest::get()
+            .uri("/items/1")
+            .insert_header((header::AUTHORIZATION, "Bearer token"));
+        assert_eq!(key_for(req).await, "None");
+        let req = test::TestRequest::get()
+            .uri("/items/1")
+            .insert_header((header::COOKIE, "session=1"));
+        assert_eq!(key_for(req).await, "None");
+    }
+}
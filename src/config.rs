@@ -0,0 +1,1353 @@
+use crate::access_log::AccessLogFormat;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use typescript_utils::TargetVersion;
+
+pub const CONFIG_VAR: &str = "SCRIPT_CONFIG";
+pub const MODULE_DIR_VAR: &str = "SCRIPT_MODULE_DIR";
+pub const ALLOWED_DOMAINS_VAR: &str = "SCRIPT_ALLOWED_DOMAINS";
+pub const TS_CACHE_DIR_VAR: &str = "SCRIPT_TS_CACHE_DIR";
+pub const BIND_ADDR_VAR: &str = "SCRIPT_BIND_ADDR";
+pub const PORT_VAR: &str = "SCRIPT_PORT";
+pub const SCRIPT_TIMEOUT_VAR: &str = "SCRIPT_TIMEOUT_MS";
+pub const TS_TARGET_VAR: &str = "SCRIPT_TS_TARGET";
+pub const TS_MINIFY_VAR: &str = "SCRIPT_TS_MINIFY";
+pub const TS_MANGLE_VAR: &str = "SCRIPT_TS_MANGLE";
+pub const TS_MODULES_VAR: &str = "SCRIPT_TS_MODULES";
+pub const ISOLATE_REQUESTS_VAR: &str = "SCRIPT_ISOLATE_REQUESTS";
+pub const WARMUP_ITERATIONS_VAR: &str = "SCRIPT_WARMUP_ITERATIONS";
+pub const MAX_BODY_VAR: &str = "SCRIPT_MAX_BODY";
+pub const WORKERS_VAR: &str = "SCRIPT_WORKERS";
+pub const KEEPALIVE_SECS_VAR: &str = "SCRIPT_KEEPALIVE_SECS";
+pub const MODULE_RETRIES_VAR: &str = "SCRIPT_MODULE_RETRIES";
+pub const MODULE_RETRY_DELAY_VAR: &str = "SCRIPT_MODULE_RETRY_DELAY_MS";
+pub const TENANTS_VAR: &str = "SCRIPT_TENANTS";
+pub const TENANT_HEADER_VAR: &str = "SCRIPT_TENANT_HEADER";
+pub const CAPABILITIES_VAR: &str = "SCRIPT_CAPABILITIES";
+pub const CSP_VAR: &str = "SCRIPT_CSP";
+pub const FLAGS_VAR: &str = "SCRIPT_FLAGS";
+pub const APP_NAME_VAR: &str = "SCRIPT_APP_NAME";
+pub const SLOW_HANDLER_VAR: &str = "SCRIPT_SLOW_HANDLER_MS";
+pub const STATIC_MAX_AGE_VAR: &str = "SCRIPT_STATIC_MAX_AGE";
+pub const MAX_PENDING_VAR: &str = "SCRIPT_MAX_PENDING";
+pub const HTTP2_VAR: &str = "SCRIPT_HTTP2";
+pub const ACCESS_LOG_VAR: &str = "SCRIPT_ACCESS_LOG";
+pub const OTLP_ENDPOINT_VAR: &str = "SCRIPT_OTLP_ENDPOINT";
+pub const MEMORY_LIMIT_VAR: &str = "SCRIPT_MEMORY_LIMIT";
+pub const MAINTENANCE_VAR: &str = "SCRIPT_MAINTENANCE";
+pub const MAINTENANCE_BODY_VAR: &str = "SCRIPT_MAINTENANCE_BODY";
+pub const IDEMPOTENCY_TTL_VAR: &str = "SCRIPT_IDEMPOTENCY_TTL_SECS";
+pub const RNG_SEED_VAR: &str = "SCRIPT_RNG_SEED";
+pub const AUTH_ROUTES_VAR: &str = "SCRIPT_AUTH_ROUTES";
+pub const JWT_SECRETS_VAR: &str = "SCRIPT_JWT_SECRETS";
+pub const JWT_PUBLIC_KEYS_VAR: &str = "SCRIPT_JWT_PUBLIC_KEYS";
+pub const JWT_ISSUERS_VAR: &str = "SCRIPT_JWT_ISSUERS";
+pub const PRELOAD_MODULES_VAR: &str = "SCRIPT_PRELOAD_MODULES";
+pub const COALESCE_ROUTES_VAR: &str = "SCRIPT_COALESCE_ROUTES";
+pub const BREAKER_FAILURE_RATE_VAR: &str = "SCRIPT_BREAKER_FAILURE_RATE";
+pub const BREAKER_WINDOW_VAR: &str = "SCRIPT_BREAKER_WINDOW";
+pub const BREAKER_COOLDOWN_VAR: &str = "SCRIPT_BREAKER_COOLDOWN_MS";
+pub const STATIC_PREFIX_VAR: &str = "SCRIPT_STATIC_PREFIX";
+pub const STATIC_DIR_VAR: &str = "SCRIPT_STATIC_DIR";
+pub const TRUSTED_PROXIES_VAR: &str = "SCRIPT_TRUSTED_PROXIES";
+pub const TEMPLATES_DIR_VAR: &str = "SCRIPT_TEMPLATES_DIR";
+pub const FETCH_TIMEOUT_VAR: &str = "SCRIPT_FETCH_TIMEOUT_MS";
+pub const FETCH_CONNECT_TIMEOUT_VAR: &str = "SCRIPT_FETCH_CONNECT_TIMEOUT_MS";
+pub const FETCH_MAX_BODY_VAR: &str = "SCRIPT_FETCH_MAX_BODY";
+pub const POOL_SIZE_VAR: &str = "SCRIPT_POOL_SIZE";
+pub const FILES_DIR_VAR: &str = "SCRIPT_FILES_DIR";
+pub const WRITE_DIR_VAR: &str = "SCRIPT_WRITE_DIR";
+pub const WRITE_QUOTA_VAR: &str = "SCRIPT_WRITE_QUOTA";
+pub const UPLOAD_DIR_VAR: &str = "SCRIPT_UPLOAD_DIR";
+pub const ADMIN_TOKEN_VAR: &str = "SCRIPT_ADMIN_TOKEN";
+pub const DEBUG_EVAL_VAR: &str = "SCRIPT_DEBUG_EVAL";
+pub const DEBUG_EVAL_TOKEN_VAR: &str = "SCRIPT_DEBUG_EVAL_TOKEN";
+
+const DEFAULT_MODULE_DIR: &str = "./modules";
+const DEFAULT_ALLOWED_DOMAINS: &str = "https://github.com";
+const DEFAULT_TS_CACHE_DIR: &str = "./.ts_cache";
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 8070;
+const DEFAULT_SCRIPT_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_TS_TARGET: &str = "es2020";
+const DEFAULT_MAX_BODY: usize = 10 * 1024 * 1024;
+const DEFAULT_MODULE_RETRIES: u32 = 3;
+const DEFAULT_MODULE_RETRY_DELAY_MS: u64 = 200;
+const DEFAULT_TENANT_HEADER: &str = "x-tenant";
+const DEFAULT_APP_NAME: &str = env!("CARGO_PKG_NAME");
+const DEFAULT_SLOW_HANDLER_MS: u64 = 500;
+const DEFAULT_MAX_PENDING: usize = 1024;
+const DEFAULT_ACCESS_LOG: &str = "common";
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_MAINTENANCE_BODY: &str = "down for maintenance, please try again later";
+const DEFAULT_BREAKER_FAILURE_RATE: u32 = 50;
+const DEFAULT_BREAKER_WINDOW: usize = 20;
+const DEFAULT_BREAKER_COOLDOWN_MS: u64 = 30_000;
+const DEFAULT_STATIC_DIR: &str = "./public";
+const DEFAULT_TEMPLATES_DIR: &str = "./templates";
+const DEFAULT_FETCH_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_FETCH_CONNECT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_FILES_DIR: &str = "./static";
+const DEFAULT_WRITE_DIR: &str = "./data";
+const DEFAULT_WRITE_QUOTA: u64 = 100 * 1024 * 1024;
+const DEFAULT_UPLOAD_DIR: &str = "./uploads";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// the settings as read from the SCRIPT_CONFIG toml file, everything is optional
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    module_dir: Option<String>,
+    allowed_domains: Option<Vec<String>>,
+    ts_cache_dir: Option<String>,
+    bind_addr: Option<String>,
+    port: Option<u16>,
+    script_timeout_ms: Option<u64>,
+    ts_target: Option<String>,
+    ts_minify: Option<bool>,
+    ts_mangle: Option<bool>,
+    ts_modules: Option<HashMap<String, FileTsOptions>>,
+    isolate_requests: Option<bool>,
+    warmup_iterations: Option<u32>,
+    max_body: Option<usize>,
+    workers: Option<usize>,
+    keepalive_secs: Option<u64>,
+    module_retries: Option<u32>,
+    module_retry_delay_ms: Option<u64>,
+    tenants: Option<Vec<String>>,
+    tenant_header: Option<String>,
+    capabilities: Option<Vec<String>>,
+    csp: Option<String>,
+    flags: Option<HashMap<String, bool>>,
+    app_name: Option<String>,
+    slow_handler_ms: Option<u64>,
+    static_max_age: Option<u64>,
+    max_pending: Option<usize>,
+    http2: Option<bool>,
+    access_log: Option<String>,
+    otlp_endpoint: Option<String>,
+    memory_limit: Option<u64>,
+    maintenance: Option<bool>,
+    maintenance_body: Option<String>,
+    idempotency_ttl_secs: Option<u64>,
+    auth_routes: Option<Vec<String>>,
+    jwt_secrets: Option<Vec<String>>,
+    jwt_public_keys: Option<Vec<String>>,
+    jwt_issuers: Option<Vec<String>>,
+    preload_modules: Option<bool>,
+    coalesce_routes: Option<Vec<String>>,
+    breaker_failure_rate: Option<u32>,
+    breaker_window: Option<usize>,
+    breaker_cooldown_ms: Option<u64>,
+    static_prefix: Option<String>,
+    static_dir: Option<String>,
+    trusted_proxies: Option<usize>,
+    templates_dir: Option<String>,
+    fetch_timeout_ms: Option<u64>,
+    fetch_connect_timeout_ms: Option<u64>,
+    fetch_max_body: Option<usize>,
+    pool_size: Option<usize>,
+    files_dir: Option<String>,
+    write_dir: Option<String>,
+    write_quota: Option<u64>,
+    upload_dir: Option<String>,
+    admin_token: Option<String>,
+    debug_eval: Option<bool>,
+    debug_eval_token: Option<String>,
+}
+
+/// the transpile options of the modules under a path prefix in the [ts_modules] table, unset options are those of
+/// ts_target, ts_minify and ts_mangle
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileTsOptions {
+    target: Option<String>,
+    minify: Option<bool>,
+    mangle: Option<bool>,
+}
+
+/// the effective configuration, every setting is read from its env var, then from the SCRIPT_CONFIG file and
+/// falls back to its default
+pub struct Config {
+    /// the dir the FileSystemModuleLoader loads modules from
+    pub module_dir: String,
+    /// the domains the HttpModuleLoader and fetch are allowed to load from
+    pub allowed_domains: Vec<String>,
+    /// the dir the transpiled typescript is cached in
+    pub ts_cache_dir: String,
+    pub bind_addr: String,
+    pub port: u16,
+    /// the max time a single script job (e.g. dispatching a request event) may run
+    pub script_timeout: Duration,
+    pub ts: TsOptions,
+    /// the options of the modules under a path prefix, longest prefix first, modules under none of them use ts
+    /// see ts_cache::ModulePreProcessor for how a prefix matches
+    pub ts_modules: Vec<(String, TsOptions)>,
+    /// run every request in its own realm, see isolation.rs
+    pub isolate_requests: bool,
+    /// the number of times the warmup event is dispatched in every runtime at startup, 0 skips the warmup
+    pub warmup_iterations: u32,
+    /// the max size in bytes of a request body, larger requests get a 413 without being dispatched
+    pub max_body: usize,
+    /// the number of http workers, None for the actix default of one per cpu
+    pub workers: Option<usize>,
+    /// the keep-alive of idle connections, 0 disables keep-alive, None for the actix default
+    pub keepalive_secs: Option<u64>,
+    /// the number of attempts to load a module over http, see http_modules.rs
+    pub module_retries: u32,
+    /// the delay before the first retry, it doubles for every next retry
+    pub module_retry_delay: Duration,
+    /// the tenants which get a realm of their own in every runtime, see tenants.rs
+    pub tenants: Vec<String>,
+    /// the request header which selects the tenant, lowercase
+    pub tenant_header: String,
+    /// the proxies which are installed for scripts, see Capabilities
+    pub capabilities: Capabilities,
+    /// the Content-Security-Policy added to text/html responses which don't set their own, None adds nothing
+    pub csp: Option<String>,
+    /// the initial state of the feature flags by name, see flags.rs
+    pub flags: Vec<(String, bool)>,
+    /// passed to scripts as event.app.name, see app_state.rs
+    pub app_name: String,
+    /// requests which take longer than this to dispatch are logged as warning, None disables the warning
+    pub slow_handler: Option<Duration>,
+    /// the max-age in seconds of the Cache-Control header of event.responseFile responses, 0 means no-cache
+    pub static_max_age: u64,
+    /// the max number of requests which are dispatched or waiting for a runtime, more get a 503, 0 is unlimited
+    pub max_pending: usize,
+    /// negotiate HTTP/2 with TLS clients, see tls::protocols
+    pub http2: bool,
+    /// the format of the line logged for every request, see access_log.rs
+    pub access_log: AccessLogFormat,
+    /// the OTLP/HTTP collector the spans are exported to like http://localhost:4318, None exports nothing
+    pub otlp_endpoint: Option<String>,
+    /// the max bytes every runtime in the pool may allocate, None is unlimited
+    pub memory_limit: Option<u64>,
+    /// start in maintenance mode, see maintenance.rs
+    pub maintenance: bool,
+    /// the body of the 503 requests get while in maintenance
+    pub maintenance_body: String,
+    /// how long the response of a POST with an Idempotency-Key is replayed, None disables it, see idempotency.rs
+    pub idempotency_ttl: Option<Duration>,
+    /// seeds the generator of uuidV4() and randomBytes() so tests get the same values every run, None uses the os
+    /// rng, only env and only allowed in debug builds so a release can't end up with predictable values
+    pub rng_seed: Option<u64>,
+    /// the route patterns which need a valid bearer token, see auth.rs
+    pub auth_routes: Vec<String>,
+    /// the names of the secrets tokens signed with HS256 are verified with
+    pub jwt_secrets: Vec<String>,
+    /// the paths of the PEM rsa public keys tokens signed with RS256 are verified with
+    pub jwt_public_keys: Vec<String>,
+    /// the accepted iss claims of tokens, empty accepts any issuer
+    pub jwt_issuers: Vec<String>,
+    /// import every module in module_dir at startup instead of on first import, see entry::load
+    pub preload_modules: bool,
+    /// the route patterns whose identical GETs in flight share one dispatch, see coalesce.rs
+    pub coalesce_routes: Vec<String>,
+    /// the percentage of failed calls to a target which opens its circuit breaker, 0 disables the breakers, see
+    /// circuit_breaker.rs
+    pub breaker_failure_rate: u32,
+    /// the number of most recent calls to a target the failure rate is taken over
+    pub breaker_window: usize,
+    /// how long an open breaker fails calls fast before it lets a trial call through
+    pub breaker_cooldown: Duration,
+    /// the path like /static the files in static_dir are served at without dispatching to the scripts, None serves
+    /// no files, see static_files.rs
+    pub static_prefix: Option<String>,
+    /// served to anyone so it can't be SCRIPT_FILES_DIR or share files with SCRIPT_WRITE_DIR
+    pub static_dir: String,
+    /// the number of proxies in front of the server which append the address of their peer to X-Forwarded-For, the
+    /// client ip is the address the outermost of those saw, 0 ignores the header, see client_addr.rs
+    pub trusted_proxies: usize,
+    /// the dir render() loads the <name>.hbs templates from, see proxies/templates.rs
+    pub templates_dir: String,
+    /// the max time a fetch may take until the whole response body was read, see proxies/fetch.rs
+    pub fetch_timeout: Duration,
+    /// the max time to connect to the host of a fetch
+    pub fetch_connect_timeout: Duration,
+    /// the max size in bytes of a fetch response body, fetches of larger responses are rejected
+    pub fetch_max_body: usize,
+    /// the number of runtimes in the pool, defaults to the number of cpus, see pool.rs
+    pub pool_size: usize,
+    /// the dir readFile(), readFileBase64() and event.responseFile read from
+    pub files_dir: String,
+    /// the only dir writeFile() writes to, separate from files_dir so scripts can't replace the assets they serve
+    pub write_dir: String,
+    /// the max total size in bytes of the files in write_dir
+    pub write_quota: u64,
+    /// the dir saveUploadedFile() saves files in
+    pub upload_dir: String,
+    /// the bearer token the /admin endpoints require, None leaves them out, see admin.rs
+    pub admin_token: Option<String>,
+    /// add the /debug/eval endpoint which runs any script it receives, see debug_eval.rs
+    pub debug_eval: bool,
+    /// the bearer token /debug/eval requires, None leaves the endpoint out even when debug_eval is set
+    pub debug_eval_token: Option<String>,
+}
+
+/// the options the TypeScriptPreProcessor is created with
+pub struct TsOptions {
+    pub target: TargetVersion,
+    pub target_name: String,
+    pub minify: bool,
+    pub mangle: bool,
+}
+
+/// the proxies which give scripts access to the outside world, these are only installed when listed in
+/// SCRIPT_CAPABILITIES so a deployment has to opt in to every one of them
+/// methods of a capability which is not granted are simply not there, calling them throws a TypeError
+#[derive(Default)]
+pub struct Capabilities {
+    /// fetch()
+    pub fetch: bool,
+    /// readFile(), readFileBase64() and saveUploadedFile()
+    pub fs: bool,
+    /// query(), only there when compiled with the db feature
+    pub db: bool,
+    /// getEnv()
+    pub env: bool,
+}
+
+impl Capabilities {
+    /// parse a list of capability names, fails for unknown names
+    pub fn parse<'a, I: IntoIterator<Item = &'a str>>(names: I) -> Result<Self, String> {
+        let mut capabilities = Self::default();
+        for name in names {
+            match name.trim() {
+                "fetch" => capabilities.fetch = true,
+                "fs" => capabilities.fs = true,
+                "db" => capabilities.db = true,
+                "env" => capabilities.env = true,
+                "" => {}
+                other => {
+                    return Err(format!(
+                        "unknown capability {}, expected fetch, fs, db or env",
+                        other
+                    ))
+                }
+            }
+        }
+        Ok(capabilities)
+    }
+
+    /// the names of the granted capabilities, for logging
+    pub fn names(&self) -> Vec<&'static str> {
+        let granted = [
+            ("fetch", self.fetch),
+            ("fs", self.fs),
+            ("db", self.db),
+            ("env", self.env),
+        ];
+        granted
+            .iter()
+            .filter(|(_name, granted)| *granted)
+            .map(|(name, _granted)| *name)
+            .collect()
+    }
+}
+
+impl TsOptions {
+    /// the options for a target name like es2020, fails for unknown targets
+    pub fn new(target_name: &str, minify: bool, mangle: bool) -> Result<Self, String> {
+        let target_name = target_name.trim().to_lowercase();
+        let target = parse_ts_target(target_name.as_str()).ok_or_else(|| {
+            format!(
+                "{}, expected one of es3, es5, es2015 .. es2021",
+                target_name
+            )
+        })?;
+        Ok(Self {
+            target,
+            target_name,
+            minify,
+            mangle,
+        })
+    }
+
+    /// a string which identifies these options, used to key the transpile cache
+    pub fn cache_key(&self) -> String {
+        format!(
+            "{}/minify={}/mangle={}",
+            self.target_name, self.minify, self.mangle
+        )
+    }
+}
+
+/// load and validate the config and log the effective settings, called once at the start of main
+pub fn init() -> std::io::Result<&'static Config> {
+    let file_config = match std::env::var(CONFIG_VAR) {
+        Ok(path) => read_file_config(path.as_str())?,
+        Err(_) => FileConfig::default(),
+    };
+    let config = load(file_config)?;
+    log_config(&config);
+    if CONFIG.set(config).is_err() {
+        unreachable!("the config is only initialized once");
+    }
+    Ok(get())
+}
+
+/// the config for tests, the settings are only read from the environment
+#[cfg(test)]
+pub fn init_for_tests() -> &'static Config {
+    CONFIG.get_or_init(|| load(FileConfig::default()).expect("invalid config"))
+}
+
+/// the config, panics when called before init
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config was not initialized")
+}
+
+fn read_file_config(path: &str) -> std::io::Result<FileConfig> {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+        std::io::Error::new(
+            err.kind(),
+            format!("could not read {} {}: {}", CONFIG_VAR, path, err),
+        )
+    })?;
+    toml::from_str(text.as_str()).map_err(|err| invalid_input(format!("invalid {}: {}", path, err)))
+}
+
+fn load(file: FileConfig) -> std::io::Result<Config> {
+    let allowed_domains = match std::env::var(ALLOWED_DOMAINS_VAR) {
+        Ok(list) => parse_domains(list.as_str()),
+        Err(_) => match file.allowed_domains {
+            Some(domains) => parse_domains(domains.join(",").as_str()),
+            None => parse_domains(DEFAULT_ALLOWED_DOMAINS),
+        },
+    };
+
+    let ts = TsOptions::new(
+        string_setting(TS_TARGET_VAR, file.ts_target, DEFAULT_TS_TARGET).as_str(),
+        bool_setting(TS_MINIFY_VAR, file.ts_minify)?,
+        bool_setting(TS_MANGLE_VAR, file.ts_mangle)?,
+    )
+    .map_err(|err| invalid_input(format!("invalid {}: {}", TS_TARGET_VAR, err)))?;
+    let mut ts_modules = vec![];
+    match std::env::var(TS_MODULES_VAR) {
+        Ok(list) => {
+            for (prefix, target) in parse_ts_modules(list.as_str())
+                .map_err(|err| invalid_input(format!("invalid {}: {}", TS_MODULES_VAR, err)))?
+            {
+                let options = TsOptions::new(target.as_str(), ts.minify, ts.mangle)
+                    .map_err(|err| invalid_input(format!("invalid {}: {}", TS_MODULES_VAR, err)))?;
+                ts_modules.push((prefix, options));
+            }
+        }
+        Err(_) => {
+            for (prefix, options) in file.ts_modules.unwrap_or_default() {
+                if prefix.trim().is_empty() {
+                    return Err(invalid_input("ts_modules with an empty prefix".to_string()));
+                }
+                let target = options.target.unwrap_or_else(|| ts.target_name.clone());
+                let options = TsOptions::new(
+                    target.as_str(),
+                    options.minify.unwrap_or(ts.minify),
+                    options.mangle.unwrap_or(ts.mangle),
+                )
+                .map_err(|err| invalid_input(format!("invalid ts_modules.{}: {}", prefix, err)))?;
+                ts_modules.push((prefix.trim().to_string(), options));
+            }
+        }
+    }
+    // the most specific prefix wins, equal lengths are sorted for a stable order
+    ts_modules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+    let script_timeout_ms = parsed_setting(
+        SCRIPT_TIMEOUT_VAR,
+        file.script_timeout_ms,
+        DEFAULT_SCRIPT_TIMEOUT_MS,
+    )?;
+    if script_timeout_ms == 0 {
+        return Err(invalid_input(format!(
+            "{} should be more than 0",
+            SCRIPT_TIMEOUT_VAR
+        )));
+    }
+
+    let max_body = parsed_setting(MAX_BODY_VAR, file.max_body, DEFAULT_MAX_BODY)?;
+    if max_body == 0 {
+        return Err(invalid_input(format!(
+            "{} should be more than 0",
+            MAX_BODY_VAR
+        )));
+    }
+
+    let workers = lenient_setting(WORKERS_VAR, file.workers).filter(|workers| {
+        if *workers == 0 {
+            log::warn!("{} should be more than 0, using the default", WORKERS_VAR);
+        }
+        *workers > 0
+    });
+
+    let module_retries = parsed_setting(
+        MODULE_RETRIES_VAR,
+        file.module_retries,
+        DEFAULT_MODULE_RETRIES,
+    )?;
+    // more would make the backoff overflow
+    if module_retries == 0 || module_retries > 10 {
+        return Err(invalid_input(format!(
+            "{} should be between 1 and 10",
+            MODULE_RETRIES_VAR
+        )));
+    }
+
+    let tenants = match std::env::var(TENANTS_VAR) {
+        Ok(list) => list
+            .split(',')
+            .map(|tenant| tenant.trim().to_string())
+            .filter(|tenant| !tenant.is_empty())
+            .collect(),
+        Err(_) => file.tenants.unwrap_or_default(),
+    };
+    // tenant names end up in realm ids and host names
+    if let Some(tenant) = tenants.iter().find(|tenant| {
+        !tenant
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    }) {
+        return Err(invalid_input(format!(
+            "invalid tenant in {}: {}, only a-z, 0-9 and - are allowed",
+            TENANTS_VAR, tenant
+        )));
+    }
+
+    let capabilities = match std::env::var(CAPABILITIES_VAR) {
+        Ok(list) => Capabilities::parse(list.split(',')),
+        Err(_) => Capabilities::parse(
+            file.capabilities
+                .unwrap_or_default()
+                .iter()
+                .map(String::as_str),
+        ),
+    }
+    .map_err(|err| invalid_input(format!("invalid {}: {}", CAPABILITIES_VAR, err)))?;
+    if capabilities.fetch && !cfg!(feature = "fetch") {
+        log::warn!(
+            "{} grants fetch but we were compiled without the fetch feature",
+            CAPABILITIES_VAR
+        );
+    }
+    if capabilities.db && !cfg!(feature = "db") {
+        log::warn!(
+            "{} grants db but we were compiled without the db feature",
+            CAPABILITIES_VAR
+        );
+    }
+
+    let csp = std::env::var(CSP_VAR)
+        .ok()
+        .or(file.csp)
+        .map(|csp| csp.trim().to_string())
+        .filter(|csp| !csp.is_empty());
+    if let Some(csp) = &csp {
+        // the policy ends up as a header value as is
+        if csp.contains('\r') || csp.contains('\n') || !csp.is_ascii() {
+            return Err(invalid_input(format!(
+                "invalid {}: the policy should be a single line of ascii",
+                CSP_VAR
+            )));
+        }
+    }
+
+    let access_log_name = string_setting(ACCESS_LOG_VAR, file.access_log, DEFAULT_ACCESS_LOG)
+        .trim()
+        .to_lowercase();
+    let access_log = AccessLogFormat::parse(access_log_name.as_str()).ok_or_else(|| {
+        invalid_input(format!(
+            "invalid {}: {}, expected common, combined or off",
+            ACCESS_LOG_VAR, access_log_name
+        ))
+    })?;
+
+    let otlp_endpoint = std::env::var(OTLP_ENDPOINT_VAR)
+        .ok()
+        .or(file.otlp_endpoint)
+        .map(|endpoint| endpoint.trim().to_string())
+        .filter(|endpoint| !endpoint.is_empty());
+    if let Some(endpoint) = &otlp_endpoint {
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            return Err(invalid_input(format!(
+                "invalid {}: {}, expected an http:// or https:// url",
+                OTLP_ENDPOINT_VAR, endpoint
+            )));
+        }
+    }
+
+    let rng_seed = match std::env::var(RNG_SEED_VAR) {
+        Ok(seed) if !seed.trim().is_empty() => {
+            if !cfg!(debug_assertions) {
+                return Err(invalid_input(format!(
+                    "{} is only allowed in debug builds, unset it to use the os rng",
+                    RNG_SEED_VAR
+                )));
+            }
+            Some(seed.trim().parse::<u64>().map_err(|_| {
+                invalid_input(format!(
+                    "invalid {}: {}, expected a number",
+                    RNG_SEED_VAR, seed
+                ))
+            })?)
+        }
+        _ => None,
+    };
+
+    let auth_routes = list_setting(AUTH_ROUTES_VAR, file.auth_routes);
+    let jwt_secrets = list_setting(JWT_SECRETS_VAR, file.jwt_secrets);
+    let jwt_public_keys = list_setting(JWT_PUBLIC_KEYS_VAR, file.jwt_public_keys);
+    if !auth_routes.is_empty() && jwt_secrets.is_empty() && jwt_public_keys.is_empty() {
+        return Err(invalid_input(format!(
+            "{} needs {} or {} to verify the tokens with",
+            AUTH_ROUTES_VAR, JWT_SECRETS_VAR, JWT_PUBLIC_KEYS_VAR
+        )));
+    }
+
+    let breaker_failure_rate = parsed_setting(
+        BREAKER_FAILURE_RATE_VAR,
+        file.breaker_failure_rate,
+        DEFAULT_BREAKER_FAILURE_RATE,
+    )?;
+    if breaker_failure_rate > 100 {
+        return Err(invalid_input(format!(
+            "{} should be a percentage between 0 and 100",
+            BREAKER_FAILURE_RATE_VAR
+        )));
+    }
+    let breaker_window = parsed_setting(
+        BREAKER_WINDOW_VAR,
+        file.breaker_window,
+        DEFAULT_BREAKER_WINDOW,
+    )?;
+    if breaker_window == 0 {
+        return Err(invalid_input(format!(
+            "{} should be at least 1",
+            BREAKER_WINDOW_VAR
+        )));
+    }
+
+    // without the trailing slash, actix matches the prefix per path segment
+    let static_prefix = std::env::var(STATIC_PREFIX_VAR)
+        .ok()
+        .or(file.static_prefix)
+        .map(|prefix| prefix.trim().trim_end_matches('/').to_string())
+        .filter(|prefix| !prefix.is_empty());
+    if let Some(prefix) = &static_prefix {
+        if !prefix.starts_with('/') {
+            return Err(invalid_input(format!(
+                "invalid {}: {}, expected a path like /static",
+                STATIC_PREFIX_VAR, prefix
+            )));
+        }
+    }
+
+    let fetch_timeout_ms = parsed_setting(
+        FETCH_TIMEOUT_VAR,
+        file.fetch_timeout_ms,
+        DEFAULT_FETCH_TIMEOUT_MS,
+    )?;
+    let fetch_connect_timeout_ms = parsed_setting(
+        FETCH_CONNECT_TIMEOUT_VAR,
+        file.fetch_connect_timeout_ms,
+        DEFAULT_FETCH_CONNECT_TIMEOUT_MS,
+    )?;
+    let fetch_max_body = parsed_setting(FETCH_MAX_BODY_VAR, file.fetch_max_body, DEFAULT_MAX_BODY)?;
+    for (var, value) in [
+        (FETCH_TIMEOUT_VAR, fetch_timeout_ms),
+        (FETCH_CONNECT_TIMEOUT_VAR, fetch_connect_timeout_ms),
+        (FETCH_MAX_BODY_VAR, fetch_max_body as u64),
+    ] {
+        if value == 0 {
+            return Err(invalid_input(format!("{} should be more than 0", var)));
+        }
+    }
+
+    let pool_size = parsed_setting(
+        POOL_SIZE_VAR,
+        file.pool_size,
+        std::thread::available_parallelism().map_or(1, |n| n.get()),
+    )?;
+    if pool_size == 0 {
+        return Err(invalid_input(format!(
+            "{} should be more than 0",
+            POOL_SIZE_VAR
+        )));
+    }
+
+    let mut flags: Vec<(String, bool)> = match std::env::var(FLAGS_VAR) {
+        Ok(list) => parse_flags(list.as_str())
+            .map_err(|err| invalid_input(format!("invalid {}: {}", FLAGS_VAR, err)))?,
+        Err(_) => file.flags.unwrap_or_default().into_iter().collect(),
+    };
+    flags.sort();
+
+    Ok(Config {
+        module_dir: string_setting(MODULE_DIR_VAR, file.module_dir, DEFAULT_MODULE_DIR),
+        allowed_domains,
+        ts_cache_dir: string_setting(TS_CACHE_DIR_VAR, file.ts_cache_dir, DEFAULT_TS_CACHE_DIR),
+        bind_addr: string_setting(BIND_ADDR_VAR, file.bind_addr, DEFAULT_BIND_ADDR),
+        port: parsed_setting(PORT_VAR, file.port, DEFAULT_PORT)?,
+        script_timeout: Duration::from_millis(script_timeout_ms),
+        ts,
+        ts_modules,
+        isolate_requests: bool_setting(ISOLATE_REQUESTS_VAR, file.isolate_requests)?,
+        warmup_iterations: parsed_setting(WARMUP_ITERATIONS_VAR, file.warmup_iterations, 0)?,
+        max_body,
+        workers,
+        keepalive_secs: lenient_setting(KEEPALIVE_SECS_VAR, file.keepalive_secs),
+        module_retries,
+        module_retry_delay: Duration::from_millis(parsed_setting(
+            MODULE_RETRY_DELAY_VAR,
+            file.module_retry_delay_ms,
+            DEFAULT_MODULE_RETRY_DELAY_MS,
+        )?),
+        tenants,
+        tenant_header: string_setting(TENANT_HEADER_VAR, file.tenant_header, DEFAULT_TENANT_HEADER)
+            .to_lowercase(),
+        capabilities,
+        csp,
+        flags,
+        app_name: string_setting(APP_NAME_VAR, file.app_name, DEFAULT_APP_NAME),
+        slow_handler: Some(parsed_setting(
+            SLOW_HANDLER_VAR,
+            file.slow_handler_ms,
+            DEFAULT_SLOW_HANDLER_MS,
+        )?)
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis),
+        static_max_age: parsed_setting(STATIC_MAX_AGE_VAR, file.static_max_age, 0)?,
+        max_pending: parsed_setting(MAX_PENDING_VAR, file.max_pending, DEFAULT_MAX_PENDING)?,
+        http2: bool_setting(HTTP2_VAR, Some(file.http2.unwrap_or(true)))?,
+        access_log,
+        otlp_endpoint,
+        memory_limit: Some(parsed_setting(MEMORY_LIMIT_VAR, file.memory_limit, 0)?)
+            .filter(|limit| *limit > 0),
+        maintenance: bool_setting(MAINTENANCE_VAR, file.maintenance)?,
+        maintenance_body: string_setting(
+            MAINTENANCE_BODY_VAR,
+            file.maintenance_body,
+            DEFAULT_MAINTENANCE_BODY,
+        ),
+        idempotency_ttl: Some(parsed_setting(
+            IDEMPOTENCY_TTL_VAR,
+            file.idempotency_ttl_secs,
+            DEFAULT_IDEMPOTENCY_TTL_SECS,
+        )?)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs),
+        rng_seed,
+        auth_routes,
+        jwt_secrets,
+        jwt_public_keys,
+        jwt_issuers: list_setting(JWT_ISSUERS_VAR, file.jwt_issuers),
+        preload_modules: bool_setting(PRELOAD_MODULES_VAR, file.preload_modules)?,
+        coalesce_routes: list_setting(COALESCE_ROUTES_VAR, file.coalesce_routes),
+        breaker_failure_rate,
+        breaker_window,
+        breaker_cooldown: Duration::from_millis(parsed_setting(
+            BREAKER_COOLDOWN_VAR,
+            file.breaker_cooldown_ms,
+            DEFAULT_BREAKER_COOLDOWN_MS,
+        )?),
+        static_prefix,
+        static_dir: string_setting(STATIC_DIR_VAR, file.static_dir, DEFAULT_STATIC_DIR),
+        trusted_proxies: parsed_setting(TRUSTED_PROXIES_VAR, file.trusted_proxies, 0)?,
+        templates_dir: string_setting(TEMPLATES_DIR_VAR, file.templates_dir, DEFAULT_TEMPLATES_DIR),
+        fetch_timeout: Duration::from_millis(fetch_timeout_ms),
+        fetch_connect_timeout: Duration::from_millis(fetch_connect_timeout_ms),
+        fetch_max_body,
+        pool_size,
+        files_dir: string_setting(FILES_DIR_VAR, file.files_dir, DEFAULT_FILES_DIR),
+        write_dir: string_setting(WRITE_DIR_VAR, file.write_dir, DEFAULT_WRITE_DIR),
+        write_quota: parsed_setting(WRITE_QUOTA_VAR, file.write_quota, DEFAULT_WRITE_QUOTA)?,
+        upload_dir: string_setting(UPLOAD_DIR_VAR, file.upload_dir, DEFAULT_UPLOAD_DIR),
+        admin_token: token_setting(ADMIN_TOKEN_VAR, file.admin_token),
+        debug_eval: bool_setting(DEBUG_EVAL_VAR, file.debug_eval)?,
+        debug_eval_token: token_setting(DEBUG_EVAL_TOKEN_VAR, file.debug_eval_token),
+    })
+}
+
+fn log_config(config: &Config) {
+    log::info!("{}: {}", MODULE_DIR_VAR, config.module_dir);
+    log::info!(
+        "{}: {}",
+        ALLOWED_DOMAINS_VAR,
+        config.allowed_domains.join(",")
+    );
+    log::info!("{}: {}", TS_CACHE_DIR_VAR, config.ts_cache_dir);
+    log::info!("{}: {}", BIND_ADDR_VAR, config.bind_addr);
+    log::info!("{}: {}", PORT_VAR, config.port);
+    log::info!(
+        "{}: {}",
+        SCRIPT_TIMEOUT_VAR,
+        config.script_timeout.as_millis()
+    );
+    log::info!("{}: {}", TS_TARGET_VAR, config.ts.target_name);
+    log::info!("{}: {}", TS_MINIFY_VAR, config.ts.minify);
+    log::info!("{}: {}", TS_MANGLE_VAR, config.ts.mangle);
+    let ts_modules: Vec<String> = config
+        .ts_modules
+        .iter()
+        .map(|(prefix, options)| format!("{}={}", prefix, options.cache_key()))
+        .collect();
+    log::info!("{}: {}", TS_MODULES_VAR, ts_modules.join(","));
+    log::info!("{}: {}", ISOLATE_REQUESTS_VAR, config.isolate_requests);
+    log::info!("{}: {}", WARMUP_ITERATIONS_VAR, config.warmup_iterations);
+    log::info!("{}: {}", MAX_BODY_VAR, config.max_body);
+    log_optional(WORKERS_VAR, config.workers);
+    log_optional(KEEPALIVE_SECS_VAR, config.keepalive_secs);
+    log::info!("{}: {}", MODULE_RETRIES_VAR, config.module_retries);
+    log::info!(
+        "{}: {}",
+        MODULE_RETRY_DELAY_VAR,
+        config.module_retry_delay.as_millis()
+    );
+    log::info!("{}: {}", TENANTS_VAR, config.tenants.join(","));
+    log::info!("{}: {}", TENANT_HEADER_VAR, config.tenant_header);
+    log::info!(
+        "{}: {}",
+        CAPABILITIES_VAR,
+        config.capabilities.names().join(",")
+    );
+    log_optional(CSP_VAR, config.csp.as_ref());
+    let flags: Vec<String> = config
+        .flags
+        .iter()
+        .map(|(name, enabled)| format!("{}={}", name, enabled))
+        .collect();
+    log::info!("{}: {}", FLAGS_VAR, flags.join(","));
+    log::info!("{}: {}", APP_NAME_VAR, config.app_name);
+    log::info!(
+        "{}: {}",
+        SLOW_HANDLER_VAR,
+        config.slow_handler.map_or(0, |slow| slow.as_millis())
+    );
+    log::info!("{}: {}", STATIC_MAX_AGE_VAR, config.static_max_age);
+    log::info!("{}: {}", MAX_PENDING_VAR, config.max_pending);
+    log::info!("{}: {}", HTTP2_VAR, config.http2);
+    log::info!("{}: {}", ACCESS_LOG_VAR, config.access_log.name());
+    log_optional(OTLP_ENDPOINT_VAR, config.otlp_endpoint.as_ref());
+    log::info!("{}: {}", MEMORY_LIMIT_VAR, config.memory_limit.unwrap_or(0));
+    log::info!("{}: {}", MAINTENANCE_VAR, config.maintenance);
+    log::info!("{}: {}", MAINTENANCE_BODY_VAR, config.maintenance_body);
+    log::info!(
+        "{}: {}",
+        IDEMPOTENCY_TTL_VAR,
+        config.idempotency_ttl.map_or(0, |ttl| ttl.as_secs())
+    );
+    log::info!("{}: {}", AUTH_ROUTES_VAR, config.auth_routes.join(","));
+    log::info!("{}: {}", JWT_SECRETS_VAR, config.jwt_secrets.join(","));
+    log::info!(
+        "{}: {}",
+        JWT_PUBLIC_KEYS_VAR,
+        config.jwt_public_keys.join(",")
+    );
+    log::info!("{}: {}", JWT_ISSUERS_VAR, config.jwt_issuers.join(","));
+    log::info!("{}: {}", PRELOAD_MODULES_VAR, config.preload_modules);
+    log::info!(
+        "{}: {}",
+        COALESCE_ROUTES_VAR,
+        config.coalesce_routes.join(",")
+    );
+    log::info!(
+        "{}: {}",
+        BREAKER_FAILURE_RATE_VAR,
+        config.breaker_failure_rate
+    );
+    log::info!("{}: {}", BREAKER_WINDOW_VAR, config.breaker_window);
+    log::info!(
+        "{}: {}",
+        BREAKER_COOLDOWN_VAR,
+        config.breaker_cooldown.as_millis()
+    );
+    log_optional(STATIC_PREFIX_VAR, config.static_prefix.as_ref());
+    log::info!("{}: {}", STATIC_DIR_VAR, config.static_dir);
+    log::info!("{}: {}", TRUSTED_PROXIES_VAR, config.trusted_proxies);
+    log::info!("{}: {}", TEMPLATES_DIR_VAR, config.templates_dir);
+    log::info!(
+        "{}: {}",
+        FETCH_TIMEOUT_VAR,
+        config.fetch_timeout.as_millis()
+    );
+    log::info!(
+        "{}: {}",
+        FETCH_CONNECT_TIMEOUT_VAR,
+        config.fetch_connect_timeout.as_millis()
+    );
+    log::info!("{}: {}", FETCH_MAX_BODY_VAR, config.fetch_max_body);
+    log::info!("{}: {}", POOL_SIZE_VAR, config.pool_size);
+    log::info!("{}: {}", FILES_DIR_VAR, config.files_dir);
+    log::info!("{}: {}", WRITE_DIR_VAR, config.write_dir);
+    log::info!("{}: {}", WRITE_QUOTA_VAR, config.write_quota);
+    log::info!("{}: {}", UPLOAD_DIR_VAR, config.upload_dir);
+    // only whether the tokens are set, not the tokens themselves
+    log::info!("{}: {}", ADMIN_TOKEN_VAR, config.admin_token.is_some());
+    log::info!("{}: {}", DEBUG_EVAL_VAR, config.debug_eval);
+    log::info!(
+        "{}: {}",
+        DEBUG_EVAL_TOKEN_VAR,
+        config.debug_eval_token.is_some()
+    );
+    if let Some(seed) = config.rng_seed {
+        log::warn!(
+            "{}: {}, uuidV4() and randomBytes() are predictable, never use this in production",
+            RNG_SEED_VAR,
+            seed
+        );
+    }
+}
+
+fn string_setting(var: &str, file_value: Option<String>, default: &str) -> String {
+    std::env::var(var)
+        .ok()
+        .or(file_value)
+        .unwrap_or_else(|| default.to_string())
+}
+
+// a comma separated env var or a list in the file, empty entries are dropped
+fn list_setting(var: &str, file_value: Option<Vec<String>>) -> Vec<String> {
+    let list = match std::env::var(var) {
+        Ok(list) => list.split(',').map(str::to_string).collect(),
+        Err(_) => file_value.unwrap_or_default(),
+    };
+    list.iter()
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// an empty token means no token, like leaving it out
+fn token_setting(var: &str, file_value: Option<String>) -> Option<String> {
+    std::env::var(var)
+        .ok()
+        .or(file_value)
+        .filter(|token| !token.is_empty())
+}
+
+fn parsed_setting<T: FromStr>(var: &str, file_value: Option<T>, default: T) -> std::io::Result<T> {
+    match std::env::var(var) {
+        Ok(val) => val
+            .trim()
+            .parse::<T>()
+            .map_err(|_| invalid_input(format!("invalid {}: {}", var, val))),
+        Err(_) => Ok(file_value.unwrap_or(default)),
+    }
+}
+
+/// like parsed_setting but an invalid env var is not fatal, we warn and use the value of the file or the default
+fn lenient_setting<T: FromStr>(var: &str, file_value: Option<T>) -> Option<T> {
+    match std::env::var(var) {
+        Ok(val) => match val.trim().parse::<T>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                log::warn!("invalid {}: {}, using the default", var, val);
+                file_value
+            }
+        },
+        Err(_) => file_value,
+    }
+}
+
+fn log_optional<T: std::fmt::Display>(var: &str, value: Option<T>) {
+    match value {
+        Some(value) => log::info!("{}: {}", var, value),
+        None => log::info!("{}: default", var),
+    }
+}
+
+/// an env var of "1" or "true" means true, "0", "false" or empty means false
+fn bool_setting(var: &str, file_value: Option<bool>) -> std::io::Result<bool> {
+    match std::env::var(var) {
+        Ok(val) => match val.trim().to_lowercase().as_str() {
+            "1" | "true" => Ok(true),
+            "0" | "false" | "" => Ok(false),
+            _ => Err(invalid_input(format!("invalid {}: {}", var, val))),
+        },
+        Err(_) => Ok(file_value.unwrap_or(false)),
+    }
+}
+
+fn invalid_input(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+/// parse a list of flags like new-ui,beta=false, a flag without value is enabled
+pub fn parse_flags(list: &str) -> Result<Vec<(String, bool)>, String> {
+    let mut flags = vec![];
+    for flag in list
+        .split(',')
+        .map(str::trim)
+        .filter(|flag| !flag.is_empty())
+    {
+        let (name, enabled) = match flag.split_once('=') {
+            Some((name, value)) => match value.trim() {
+                "true" => (name.trim(), true),
+                "false" => (name.trim(), false),
+                other => {
+                    return Err(format!(
+                        "flag {} should be true or false, got {}",
+                        name, other
+                    ))
+                }
+            },
+            None => (flag, true),
+        };
+        if name.is_empty() {
+            return Err(format!("flag without name: {}", flag));
+        }
+        flags.push((name.to_string(), enabled));
+    }
+    Ok(flags)
+}
+
+/// parse a list of path prefixes and their ts targets like legacy/=es5,vendor/=es2015
+pub fn parse_ts_modules(list: &str) -> Result<Vec<(String, String)>, String> {
+    let mut modules = vec![];
+    for module in list
+        .split(',')
+        .map(str::trim)
+        .filter(|module| !module.is_empty())
+    {
+        match module.split_once('=') {
+            Some((prefix, target)) if !prefix.trim().is_empty() => {
+                modules.push((prefix.trim().to_string(), target.trim().to_string()))
+            }
+            _ => return Err(format!("expected prefix=target, got {}", module)),
+        }
+    }
+    Ok(modules)
+}
+
+/// the address and port the server listens on
+pub fn bind_address() -> (String, u16) {
+    let config = get();
+    (config.bind_addr.clone(), config.port)
+}
+
+/// parse a comma separated list of domains like "https://github.com,https://gitlab.com"
+/// invalid domains are logged and skipped
+pub fn parse_domains(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|domain| domain.trim())
+        .filter(|domain| !domain.is_empty())
+        .filter(|domain| {
+            let valid = is_valid_domain(domain);
+            if !valid {
+                log::warn!(
+                    "ignoring invalid domain in {}: {}",
+                    ALLOWED_DOMAINS_VAR,
+                    domain
+                );
+            }
+            valid
+        })
+        .map(|domain| domain.to_string())
+        .collect()
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    let host = match domain
+        .strip_prefix("https://")
+        .or_else(|| domain.strip_prefix("http://"))
+    {
+        Some(host) => host,
+        None => return false,
+    };
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ':')
+}
+
+/// check if an url is on one of the allowed domains
+pub fn is_allowed_url(url: &str) -> bool {
+    get().allowed_domains.iter().any(|domain| {
+        url == domain.as_str()
+            || url
+                .strip_prefix(domain.as_str())
+                .map(|rest| rest.starts_with('/') || rest.starts_with('?'))
+                .unwrap_or(false)
+    })
+}
+
+/// the redirect policy of the clients which may only load from the allowed domains, every hop is checked like the
+/// url itself so a redirect can't take a request elsewhere
+pub fn allowed_redirects() -> reqwest::redirect::Policy {
+    redirect_policy(is_allowed_url)
+}
+
+// the max number of redirects followed, like the default policy of reqwest
+const MAX_REDIRECTS: usize = 10;
+
+fn redirect_policy<F: Fn(&str) -> bool + Send + Sync + 'static>(
+    allowed: F,
+) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if allowed(attempt.url().as_str()) {
+            attempt.follow()
+        } else {
+            let msg = format!("redirect to {} is not allowed", attempt.url());
+            attempt.error(msg)
+        }
+    })
+}
+
+/// map a target name like "es2020" to a TargetVersion
+pub fn parse_ts_target(name: &str) -> Option<TargetVersion> {
+    match name {
+        "es3" => Some(TargetVersion::Es3),
+        "es5" => Some(TargetVersion::Es5),
+        "es2015" => Some(TargetVersion::Es2015),
+        "es2016" => Some(TargetVersion::Es2016),
+        "es2017" => Some(TargetVersion::Es2017),
+        "es2018" => Some(TargetVersion::Es2018),
+        "es2019" => Some(TargetVersion::Es2019),
+        "es2020" => Some(TargetVersion::Es2020),
+        "es2021" => Some(TargetVersion::Es2021),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // answers /redirect/<path> with a redirect to /<path> and everything else with ok
+    fn mock_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let location = base.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 1024];
+                let len = stream.read(&mut buf).unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]);
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let response = match path.strip_prefix("/redirect") {
+                    Some(to) => format!(
+                        "HTTP/1.1 302 Found\r\nLocation: {}{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        location, to
+                    ),
+                    None => "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+                        .to_string(),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        base
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked_against_the_allowed_domains() {
+        let base = mock_server();
+        let allowed = format!("{}/public", base);
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy(move |url| {
+                url.starts_with(allowed.as_str())
+            }))
+            .build()
+            .unwrap();
+        let followed = client
+            .get(format!("{}/redirect/public", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(followed.status(), 200);
+        let err = client
+            .get(format!("{}/redirect/private", base))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect());
+        assert!(err.to_string().contains("/private is not allowed"));
+    }
+
+    #[test]
+    fn settings_without_an_env_var_are_read_from_the_file() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            allowed_domains = ["https://gitlab.com", "gitlab"]
+            port = 9000
+            script_timeout_ms = 250
+            "#,
+        )
+        .unwrap();
+        let config = load(file).unwrap();
+        assert_eq!(config.allowed_domains, vec!["https://gitlab.com"]);
+        assert_eq!(config.bind_addr, DEFAULT_BIND_ADDR);
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.script_timeout, Duration::from_millis(250));
+
+        assert!(toml::from_str::<FileConfig>("prot = 9000").is_err());
+        let file: FileConfig = toml::from_str("script_timeout_ms = 0").unwrap();
+        assert!(load(file).is_err());
+        assert!(read_file_config("./does-not-exist.toml").is_err());
+    }
+
+    #[test]
+    fn env_vars_take_precedence_over_the_file() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            pool_size = 3
+            files_dir = "./file-static"
+            write_quota = 2048
+            upload_dir = "./file-uploads"
+            admin_token = "file-token"
+            debug_eval = true
+            debug_eval_token = ""
+            "#,
+        )
+        .unwrap();
+        std::env::set_var(UPLOAD_DIR_VAR, "./env-uploads");
+        let config = load(file);
+        std::env::remove_var(UPLOAD_DIR_VAR);
+        let config = config.unwrap();
+
+        assert_eq!(config.pool_size, 3);
+        assert_eq!(config.files_dir, "./file-static");
+        assert_eq!(config.write_dir, DEFAULT_WRITE_DIR);
+        assert_eq!(config.write_quota, 2048);
+        assert_eq!(config.upload_dir, "./env-uploads");
+        assert_eq!(config.admin_token.as_deref(), Some("file-token"));
+        assert!(config.debug_eval);
+        // an empty token is no token
+        assert_eq!(config.debug_eval_token, None);
+    }
+
+    #[test]
+    fn invalid_settings_fail_to_load() {
+        assert!(toml::from_str::<FileConfig>("pool_size = -1").is_err());
+        assert!(toml::from_str::<FileConfig>("pool_dize = 1").is_err());
+        let file: FileConfig = toml::from_str("pool_size = 0").unwrap();
+        assert!(load(file).is_err());
+        let file: FileConfig = toml::from_str("fetch_max_body = 0").unwrap();
+        assert!(load(file).is_err());
+    }
+
+    #[test]
+    fn invalid_domains_are_skipped() {
+        assert_eq!(
+            parse_domains(" https://github.com,,ftp://example.com,http://localhost:8080 ,https://"),
+            vec!["https://github.com", "http://localhost:8080"]
+        );
+    }
+
+    #[test]
+    fn only_urls_on_the_allowed_domains_are_allowed() {
+        init_for_tests();
+        assert!(is_allowed_url("https://github.com"));
+        assert!(is_allowed_url("https://github.com/HiRoFa?tab=repositories"));
+        assert!(!is_allowed_url("https://github.com.example.com/"));
+        assert!(!is_allowed_url("http://github.com/"));
+    }
+
+    #[test]
+    fn the_server_listens_on_all_interfaces_on_8070_by_default() {
+        let config = load(FileConfig::default()).unwrap();
+        assert_eq!((config.bind_addr.as_str(), config.port), ("0.0.0.0", 8070));
+    }
+
+    #[test]
+    fn the_max_body_is_10mb_unless_configured() {
+        assert_eq!(
+            load(FileConfig::default()).unwrap().max_body,
+            10 * 1024 * 1024
+        );
+        let file: FileConfig = toml::from_str("max_body = 1024").unwrap();
+        assert_eq!(load(file).unwrap().max_body, 1024);
+        let file: FileConfig = toml::from_str("max_body = 0").unwrap();
+        assert!(load(file).is_err());
+    }
+
+    #[test]
+    fn zero_workers_is_the_actix_default() {
+        let config = load(FileConfig::default()).unwrap();
+        assert_eq!((config.workers, config.keepalive_secs), (None, None));
+        let file: FileConfig = toml::from_str("workers = 0\nkeepalive_secs = 0").unwrap();
+        let config = load(file).unwrap();
+        // a keep-alive of 0 disables keep-alive so it is kept
+        assert_eq!((config.workers, config.keepalive_secs), (None, Some(0)));
+        let file: FileConfig = toml::from_str("workers = 4").unwrap();
+        assert_eq!(load(file).unwrap().workers, Some(4));
+    }
+
+    #[test]
+    fn only_the_listed_capabilities_are_granted() {
+        let capabilities = Capabilities::parse(vec!["fetch", " env", ""]).unwrap();
+        assert_eq!(capabilities.names(), vec!["fetch", "env"]);
+        assert!(Capabilities::parse(vec!["fetch", "shell"]).is_err());
+        let config = load(FileConfig::default()).unwrap();
+        assert!(config.capabilities.names().is_empty());
+    }
+
+    #[test]
+    fn the_csp_is_a_single_line_of_ascii() {
+        assert_eq!(load(FileConfig::default()).unwrap().csp, None);
+        let file: FileConfig = toml::from_str(r#"csp = " default-src 'self' ""#).unwrap();
+        assert_eq!(
+            load(file).unwrap().csp.as_deref(),
+            Some("default-src 'self'")
+        );
+        let file: FileConfig = toml::from_str(r#"csp = "  ""#).unwrap();
+        assert_eq!(load(file).unwrap().csp, None);
+        let file: FileConfig = toml::from_str(r#"csp = "default-src 'self'\nX-Evil: 1""#).unwrap();
+        assert!(load(file).is_err());
+    }
+
+    #[test]
+    fn a_flag_without_value_is_enabled() {
+        let flags = parse_flags("new-ui, beta=false,,dark = true").ok().unwrap();
+        assert_eq!(
+            flags,
+            vec![
+                ("new-ui".to_string(), true),
+                ("beta".to_string(), false),
+                ("dark".to_string(), true)
+            ]
+        );
+        assert!(parse_flags("beta=maybe").is_err());
+        assert!(parse_flags("=true").is_err());
+    }
+
+    #[test]
+    fn a_slow_handler_threshold_of_zero_disables_the_warning() {
+        assert_eq!(
+            load(FileConfig::default()).unwrap().slow_handler,
+            Some(Duration::from_millis(500))
+        );
+        let file: FileConfig = toml::from_str("slow_handler_ms = 0").unwrap();
+        assert_eq!(load(file).unwrap().slow_handler, None);
+    }
+
+    #[test]
+    fn at_most_1024_requests_are_pending_unless_configured() {
+        assert_eq!(load(FileConfig::default()).unwrap().max_pending, 1024);
+        let file: FileConfig = toml::from_str("max_pending = 0").unwrap();
+        assert_eq!(load(file).unwrap().max_pending, 0);
+    }
+
+    #[test]
+    fn the_ts_options_are_part_of_the_cache_key() {
+        assert!(matches!(
+            parse_ts_target("es2017"),
+            Some(TargetVersion::Es2017)
+        ));
+        assert!(parse_ts_target("es2042").is_none());
+        let options = TsOptions {
+            target: TargetVersion::Es2020,
+            target_name: "es2020".to_string(),
+            minify: true,
+            mangle: false,
+        };
+        assert_eq!(options.cache_key(), "es2020/minify=true/mangle=false");
+    }
+}
@@ -0,0 +1,58 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+
+/// the Content-Encodings of request bodies actix decodes before we read them, see actix_web::dev::Decompress
+/// the decoded body is what counts for SCRIPT_MAX_BODY so a small body which inflates to a huge one gets a 413
+pub const SUPPORTED: &[&str] = &["gzip", "deflate", "br", "zstd", "identity"];
+
+/// the 415 for a body with a Content-Encoding we can't decode, None for bodies we can
+/// actix passes a body with an unknown encoding on as is, the script would get the compressed bytes
+pub fn check(req: &HttpRequest) -> Option<HttpResponse> {
+    let encoding = match req.headers().get(header::CONTENT_ENCODING) {
+        Some(encoding) => encoding.to_str().unwrap_or_default().trim().to_lowercase(),
+        None => return None,
+    };
+    if encoding.is_empty() || SUPPORTED.contains(&encoding.as_str()) {
+        return None;
+    }
+    log::debug!("rejecting a body with Content-Encoding {}", encoding);
+    // a 415 for an encoding lists the ones we do accept, see RFC 7694
+    Some(
+        HttpResponse::UnsupportedMediaType()
+            .insert_header((header::ACCEPT_ENCODING, SUPPORTED.join(", ")))
+            .content_type("text/plain; charset=utf-8")
+            .body(format!(
+                "unsupported Content-Encoding {}, expected one of {}",
+                encoding,
+                SUPPORTED.join(", ")
+            )),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    fn check_encoding(encoding: &str) -> Option<HttpResponse> {
+        let req = TestRequest::post()
+            .insert_header((header::CONTENT_ENCODING, encoding))
+            .to_http_request();
+        check(&req)
+    }
+
+    #[test]
+    fn only_bodies_we_can_decode_are_accepted() {
+        assert!(check(&TestRequest::post().to_http_request()).is_none());
+        assert!(check_encoding("gzip").is_none());
+        assert!(check_encoding(" Zstd ").is_none());
+        let res = check_encoding("compress").unwrap();
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            res.headers().get(header::ACCEPT_ENCODING).unwrap(),
+            "gzip, deflate, br, zstd, identity"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::event::RequestInfo;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::JsError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    // the id of the request the job currently running on this (the runtime's worker) thread is handling
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    // the cache ids of the context objects of the requests which are being dispatched by realm id
+    static SCRIPT_CONTEXTS: RefCell<HashMap<String, i32>> = RefCell::new(HashMap::new());
+}
+
+/// run a job for a request, while the job runs request_id() returns the id of the request
+/// this must be called from the worker thread of the runtime e.g. inside js_loop_realm
+pub fn with_request_id<T, F: FnOnce() -> T>(request_id: &str, job: F) -> T {
+    let previous = REQUEST_ID.with(|id| id.replace(Some(request_id.to_string())));
+    let res = job();
+    REQUEST_ID.with(|id| id.replace(previous));
+    res
+}
+
+/// the id of the request which is currently being handled on this thread, if any
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// dispatch a request with a context object which scripts get from getContext() while the job runs
+///
+/// the context has the id, method, path, route and tenant of the request and a user which is null until a script
+/// sets it, e.g. a pre-request middleware which authenticated the request, it is the same object for all listeners
+/// of the request so libraries can read it without the event being passed to them
+/// the context is realm scoped and removed when the job is done, getContext() in a timer or promise returns undefined
+pub fn with_script_context<R, T, F>(realm: &R, info: &RequestInfo, job: F) -> Result<T, JsError>
+where
+    R: JsRealmAdapter,
+    F: FnOnce() -> Result<T, JsError>,
+{
+    let cache_id = cache_script_context(realm, info)?;
+    let res = with_cached_context(realm, cache_id, job);
+    realm.js_cache_dispose(cache_id);
+    res
+}
+
+/// create the context object of a request for a request which is dispatched in several jobs, like the body events
+/// of a streaming route, it is kept in the object cache of the realm until the returned id is disposed
+pub fn cache_script_context<R: JsRealmAdapter>(
+    realm: &R,
+    info: &RequestInfo,
+) -> Result<i32, JsError> {
+    let context_obj = create_context_obj(realm, info)?;
+    Ok(realm.js_cache_add(&context_obj))
+}
+
+/// like with_script_context with a context object created by cache_script_context, so what a listener set on the
+/// context is still there in the next job
+pub fn with_cached_context<R, T, F>(realm: &R, cache_id: i32, job: F) -> T
+where
+    R: JsRealmAdapter,
+    F: FnOnce() -> T,
+{
+    let realm_id = realm.js_get_realm_id().to_string();
+    let previous =
+        SCRIPT_CONTEXTS.with(|contexts| contexts.borrow_mut().insert(realm_id.clone(), cache_id));
+    let res = job();
+    SCRIPT_CONTEXTS.with(|contexts| {
+        let mut contexts = contexts.borrow_mut();
+        match previous {
+            Some(previous) => contexts.insert(realm_id, previous),
+            None => contexts.remove(&realm_id),
+        }
+    });
+    res
+}
+
+/// the context object of the request which is being dispatched in the realm, undefined outside of a dispatch
+pub fn script_context<R: JsRealmAdapter>(realm: &R) -> Result<R::JsValueAdapterType, JsError> {
+    let cache_id =
+        SCRIPT_CONTEXTS.with(|contexts| contexts.borrow().get(realm.js_get_realm_id()).copied());
+    match cache_id {
+        Some(cache_id) => realm.js_cache_with(cache_id, |context_obj| Ok(context_obj.clone())),
+        None => realm.js_undefined_create(),
+    }
+}
+
+fn create_context_obj<R: JsRealmAdapter>(
+    realm: &R,
+    info: &RequestInfo,
+) -> Result<R::JsValueAdapterType, JsError> {
+    let context_obj = realm.js_object_create()?;
+    realm.js_object_set_property(
+        &context_obj,
+        "id",
+        &realm.js_string_create(info.request_id.as_str())?,
+    )?;
+    realm.js_object_set_property(
+        &context_obj,
+        "method",
+        &realm.js_string_create(info.method.as_str())?,
+    )?;
+    realm.js_object_set_property(
+        &context_obj,
+        "path",
+        &realm.js_string_create(info.path.as_str())?,
+    )?;
+    realm.js_object_set_property(
+        &context_obj,
+        "route",
+        &realm.js_string_create(info.route.as_str())?,
+    )?;
+    let tenant = match &info.tenant {
+        Some(tenant) => realm.js_string_create(tenant.as_str())?,
+        None => realm.js_null_create()?,
+    };
+    realm.js_object_set_property(&context_obj, "tenant", &tenant)?;
+    realm.js_object_set_property(&context_obj, "user", &realm.js_null_create()?)?;
+    Ok(context_obj)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_request_id_is_only_set_while_the_job_runs() {
+        assert_eq!(request_id(), None);
+        let ids = with_request_id("outer", || {
+            let inner = with_request_id("inner", request_id);
+            (inner, request_id())
+        });
+        assert_eq!(ids, (Some("inner".to_string()), Some("outer".to_string())));
+        assert_eq!(request_id(), None);
+    }
+
+    #[actix_web::test]
+    async fn every_listener_of_a_request_gets_the_same_context() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("get", (evt) => {
+                if (evt.headers["x-test"] === "context") {
+                    com.mycompany.MyApp.getContext().user = "jane";
+                }
+            });
+            com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "context") {
+                    const context = com.mycompany.MyApp.getContext();
+                    evt.responseBody = [context.id === evt.requestId, context.method, context.path,
+                        context.route, context.user, context.tenant].join();
+                }
+            });"#,
+        );
+        let req = actix_web::test::TestRequest::get()
+            .uri("/users/7")
+            .insert_header(("x-test", "context"));
+        let (_, _, body) = crate::tests::call(req).await;
+        assert_eq!(body, "true,GET,/users/7,/users/{id},jane,");
+        assert_eq!(
+            crate::tests::eval("typeof com.mycompany.MyApp.getContext()"),
+            "undefined"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+lazy_static! {
+    // set by script with MyApp.setCorsPolicy(), all runtimes evaluate the same main module so the policy is kept
+    // process wide. None means no CORS headers are sent at all
+    static ref POLICY: Mutex<Option<CorsPolicy>> = Mutex::new(None);
+}
+
+#[derive(Clone, Default)]
+pub struct CorsPolicy {
+    // the allowed origins like "https://example.com", "*" allows any origin
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    pub max_age: Option<u32>,
+    pub credentials: bool,
+}
+
+impl CorsPolicy {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+}
+
+/// set the policy, a policy which allows any origin can't allow credentials as that would let every site make
+/// requests with the cookies of the user
+pub fn set_policy(policy: CorsPolicy) -> Result<(), String> {
+    if policy.credentials && policy.origins.iter().any(|allowed| allowed == "*") {
+        return Err(
+            "a cors policy with credentials should list its origins instead of allowing *"
+                .to_string(),
+        );
+    }
+    *POLICY.lock().unwrap() = Some(policy);
+    Ok(())
+}
+
+fn policy() -> Option<CorsPolicy> {
+    POLICY.lock().unwrap().clone()
+}
+
+fn origin(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::ORIGIN)
+        .and_then(|val| val.to_str().ok())
+}
+
+/// answer a preflight request (an OPTIONS request with an Access-Control-Request-Method header)
+/// returns None for other requests, those are dispatched to the script as usual
+/// preflights for disallowed origins or methods get a response without CORS headers so the browser blocks the
+/// actual request
+pub fn preflight_response(req: &HttpRequest) -> Option<HttpResponse> {
+    if req.method() != Method::OPTIONS {
+        return None;
+    }
+    let requested_method = req
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)?
+        .to_str()
+        .ok()?;
+    let mut builder = HttpResponse::build(StatusCode::NO_CONTENT);
+    builder.insert_header((header::VARY, "Origin"));
+    let (policy, origin) = match (policy(), origin(req)) {
+        (Some(policy), Some(origin)) => (policy, origin),
+        _ => return Some(builder.finish()),
+    };
+    if !policy.allows_origin(origin) || !policy.allows_method(requested_method) {
+        return Some(builder.finish());
+    }
+
+    builder.insert_header((
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        allow_origin(&policy, origin),
+    ));
+    builder.insert_header((
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        policy.methods.join(", "),
+    ));
+    if !policy.headers.is_empty() {
+        builder.insert_header((
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            policy.headers.join(", "),
+        ));
+    }
+    if let Some(max_age) = policy.max_age {
+        builder.insert_header((header::ACCESS_CONTROL_MAX_AGE, max_age.to_string()));
+    }
+    if policy.credentials {
+        builder.insert_header((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"));
+    }
+    Some(builder.finish())
+}
+
+/// add the Access-Control-Allow-Origin header to the response of an actual (non preflight) request
+pub fn apply_headers(req: &HttpRequest, response: &mut HttpResponse) {
+    let policy = match policy() {
+        Some(policy) => policy,
+        None => return,
+    };
+    let headers = response.headers_mut();
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    let origin = match origin(req) {
+        Some(origin) if policy.allows_origin(origin) => origin,
+        _ => return,
+    };
+    if let Ok(value) = HeaderValue::from_str(allow_origin(&policy, origin)) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+    }
+    if policy.credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
+
+// a policy with credentials has no * (see set_policy) so only a listed origin is echoed
+fn allow_origin<'a>(policy: &CorsPolicy, origin: &'a str) -> &'a str {
+    if policy.origins.iter().any(|allowed| allowed == "*") {
+        "*"
+    } else {
+        origin
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn policy(origins: &[&str], credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: vec!["GET".to_string()],
+            credentials,
+            ..Default::default()
+        }
+    }
+
+    fn allowed_origin(response: &HttpResponse) -> Option<&str> {
+        response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .and_then(|val| val.to_str().ok())
+    }
+
+    #[test]
+    fn any_origin_with_credentials_is_rejected() {
+        assert!(set_policy(policy(&["*"], true)).is_err());
+        assert_eq!(
+            allow_origin(&policy(&["*"], false), "https://example.com"),
+            "*"
+        );
+    }
+
+    // the only test which sets the policy
+    #[test]
+    fn only_listed_origins_are_allowed() {
+        set_policy(policy(&["https://example.com"], true)).unwrap();
+
+        let req = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://example.com"))
+            .to_http_request();
+        let mut response = HttpResponse::Ok().finish();
+        apply_headers(&req, &mut response);
+        assert_eq!(allowed_origin(&response), Some("https://example.com"));
+        assert!(response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+
+        let req = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://evil.example"))
+            .to_http_request();
+        let mut response = HttpResponse::Ok().finish();
+        apply_headers(&req, &mut response);
+        assert_eq!(allowed_origin(&response), None);
+
+        let preflight = |origin: &str, method: &str| {
+            let req = TestRequest::default()
+                .method(Method::OPTIONS)
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+                .to_http_request();
+            preflight_response(&req).unwrap()
+        };
+        let response = preflight("https://example.com", "GET");
+        assert_eq!(allowed_origin(&response), Some("https://example.com"));
+        assert_eq!(
+            allowed_origin(&preflight("https://evil.example", "GET")),
+            None
+        );
+        assert_eq!(
+            allowed_origin(&preflight("https://example.com", "DELETE")),
+            None
+        );
+    }
+}
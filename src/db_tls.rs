@@ -0,0 +1,144 @@
+use crate::tls;
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::tls::{ChannelBinding, MakeTlsConnect, TlsConnect};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// the TLS of the database connections, used for the sslmode of DATABASE_URL
+///
+/// with the default sslmode=prefer the connection is encrypted when the server supports it, sslmode=require fails
+/// when it does not, the server certificate is checked against the mozilla roots and the ca_file if given (like the
+/// CA of a managed database)
+#[derive(Clone)]
+pub struct MakeRustlsConnect {
+    config: Arc<ClientConfig>,
+}
+
+impl MakeRustlsConnect {
+    pub fn new(ca_file: Option<&str>) -> std::io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|root| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                root.subject,
+                root.spki,
+                root.name_constraints,
+            )
+        }));
+        if let Some(ca_file) = ca_file {
+            for cert in tls::load_certs(ca_file)? {
+                roots.add(&cert).map_err(|err| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid certificate in {}: {}", ca_file, err),
+                    )
+                })?;
+            }
+        }
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl<S> MakeTlsConnect<S> for MakeRustlsConnect
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = RustlsStream<S>;
+    type TlsConnect = RustlsConnect;
+    type Error = Error;
+
+    fn make_tls_connect(&mut self, host: &str) -> Result<RustlsConnect, Error> {
+        let name = ServerName::try_from(host).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a valid server name", host),
+            )
+        })?;
+        Ok(RustlsConnect {
+            name,
+            connector: TlsConnector::from(self.config.clone()),
+        })
+    }
+}
+
+/// the handshake of a single connection
+pub struct RustlsConnect {
+    name: ServerName,
+    connector: TlsConnector,
+}
+
+impl<S> TlsConnect<S> for RustlsConnect
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = RustlsStream<S>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<RustlsStream<S>, Error>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        Box::pin(async move {
+            let stream = self.connector.connect(self.name, stream).await?;
+            Ok(RustlsStream(stream))
+        })
+    }
+}
+
+pub struct RustlsStream<S>(TlsStream<S>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> tokio_postgres::tls::TlsStream for RustlsStream<S> {
+    // scram authenticates without binding to the certificate, like it does against servers without TLS
+    fn channel_binding(&self) -> ChannelBinding {
+        ChannelBinding::none()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for RustlsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for RustlsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_missing_ca_file_fails() {
+        assert!(MakeRustlsConnect::new(None).is_ok());
+        assert!(MakeRustlsConnect::new(Some("./does-not-exist.pem")).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::config::{self, DEBUG_EVAL_TOKEN_VAR, DEBUG_EVAL_VAR};
+use crate::timeout::{script_timeout, with_deadline};
+use crate::{auth, errors, script_pool};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use hirofa_utils::js_utils::{JsError, Script};
+
+/// warn about the endpoint at startup, it should never be enabled in production
+pub fn log_status() {
+    let config = config::get();
+    if config.debug_eval {
+        log::warn!(
+            "{} is set, /debug/eval will evaluate any script it receives",
+            DEBUG_EVAL_VAR
+        );
+        if config.debug_eval_token.is_none() {
+            log::error!(
+                "{} is not set, /debug/eval is not registered",
+                DEBUG_EVAL_TOKEN_VAR
+            );
+        }
+    }
+}
+
+/// register the /debug/eval endpoint if it is enabled and has a token, otherwise requests to it get a 404
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // the endpoint runs any script it receives so it is only there when SCRIPT_DEBUG_EVAL is set and it requires the
+    // token as bearer token
+    let config = config::get();
+    if config.debug_eval && config.debug_eval_token.is_some() {
+        cfg.service(web::resource("/debug/eval").route(web::post().to(debug_eval)));
+    }
+}
+
+fn authorized(req: &HttpRequest) -> bool {
+    match config::get().debug_eval_token.as_ref() {
+        Some(token) => auth::has_bearer_token(req, token),
+        None => false,
+    }
+}
+
+/// evaluate the body in the main realm of one of the runtimes and return the result as json
+/// the body is evaluated as typescript when called with ?lang=ts
+async fn debug_eval(req: HttpRequest, body: Bytes) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let code = match String::from_utf8(body.to_vec()) {
+        Ok(code) => code,
+        Err(_) => return HttpResponse::BadRequest().body("body is not valid utf-8"),
+    };
+    let path = if req
+        .query_string()
+        .split('&')
+        .any(|param| param == "lang=ts")
+    {
+        "file://debug_eval.ts"
+    } else {
+        "file://debug_eval.js"
+    };
+    let res = script_pool()
+        .next()
+        .js_loop_realm(None, move |_rt, realm| {
+            with_deadline(script_timeout(), || {
+                let value = realm.js_eval(Script::new(path, code.as_str()))?;
+                stringify_result(realm, &value)
+            })
+        })
+        .await;
+    match res {
+        Ok(result) => HttpResponse::Ok().json(serde_json::json!({ "result": result })),
+        Err(err) => {
+            errors::log_script_error("debug eval failed", &err);
+            HttpResponse::Ok().json(serde_json::json!({
+                "error": err.get_name(),
+                "message": err.get_message(),
+                "stack": err.get_stack(),
+            }))
+        }
+    }
+}
+
+// values JSON can't represent like undefined or functions are returned as their string value
+fn stringify_result<R: JsRealmAdapter>(
+    realm: &R,
+    value: &R::JsValueAdapterType,
+) -> Result<String, JsError> {
+    if value.js_is_null_or_undefined() || value.js_is_function() {
+        return value.js_to_string();
+    }
+    match realm.js_json_stringify(value, None) {
+        Ok(json) => Ok(json),
+        Err(_) => value.js_to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_json_can_not_represent_are_returned_as_string() {
+        let results = crate::tests::with_realm(|realm| {
+            [
+                "1 + 1",
+                "({a: [1, 'b']})",
+                "undefined",
+                "(function named() {})",
+                "{ const cyclic = {}; cyclic.self = cyclic; cyclic }",
+            ]
+            .iter()
+            .map(|code| {
+                let value = realm
+                    .js_eval(Script::new("file://test.js", code))
+                    .ok()
+                    .unwrap();
+                stringify_result(realm, &value).ok().unwrap()
+            })
+            .collect::<Vec<_>>()
+        });
+        assert_eq!(results[..3], ["2", r#"{"a":[1,"b"]}"#, "undefined"]);
+        assert!(results[3].starts_with("function named()"));
+        assert_eq!(results[4], "[object Object]");
+    }
+}
@@ -0,0 +1,189 @@
+use crate::timeout::{script_timeout, with_deadline};
+use crate::{context, errors};
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use quickjs_runtime::facades::QuickJsRuntimeFacade;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// the callbacks passed to event.after() and the context object of the request, as ids in the object cache of the
+// realm of the request
+#[derive(Default)]
+struct Deferred {
+    callback_ids: Vec<i32>,
+    // the context when the first callback was added, None when there was none
+    context_id: Option<i32>,
+}
+
+lazy_static! {
+    // by request id, a request only has an entry while its DeferredJobs exists
+    static ref DEFERRED: Mutex<HashMap<String, Deferred>> = Mutex::new(HashMap::new());
+}
+
+/// the callbacks a request passed to event.after(), they are run when this is dropped
+///
+/// the ScriptResponse holds it and moves it into the body of the http response, so that is after the body was sent
+/// (for streamed responses after the stream ended), when dispatching the request failed it is dropped with the error
+/// so the callbacks run then
+/// the callbacks run in a job of their own in the realm of the request with the context of the request, so
+/// getContext() returns the same object as in the listeners, the client does not wait for them
+pub struct DeferredJobs {
+    rt: Arc<QuickJsRuntimeFacade>,
+    realm_id: Option<String>,
+    request_id: String,
+}
+
+impl DeferredJobs {
+    /// start collecting the callbacks of a request which is dispatched in realm_id of rt
+    pub fn new(rt: Arc<QuickJsRuntimeFacade>, realm_id: Option<String>, request_id: &str) -> Self {
+        DEFERRED
+            .lock()
+            .unwrap()
+            .insert(request_id.to_string(), Deferred::default());
+        Self {
+            rt,
+            realm_id,
+            request_id: request_id.to_string(),
+        }
+    }
+}
+
+impl Drop for DeferredJobs {
+    fn drop(&mut self) {
+        let deferred = DEFERRED
+            .lock()
+            .unwrap()
+            .remove(&self.request_id)
+            .unwrap_or_default();
+        if deferred.callback_ids.is_empty() {
+            return;
+        }
+        let request_id = self.request_id.clone();
+        self.rt
+            .js_loop_realm_void(self.realm_id.as_deref(), move |_rt, realm| {
+                let Deferred {
+                    callback_ids,
+                    context_id,
+                } = deferred;
+                let run = || {
+                    // a failing callback does not keep the ones after it from running
+                    for callback_id in callback_ids {
+                        let res = with_deadline(script_timeout(), || {
+                            let callback = realm.js_cache_consume(callback_id);
+                            realm.js_function_invoke(None, &callback, &[])
+                        });
+                        if let Err(err) = res {
+                            errors::log_script_error("deferred callback failed", &err);
+                        }
+                    }
+                };
+                context::with_request_id(request_id.as_str(), || match context_id {
+                    Some(context_id) => {
+                        context::with_cached_context(realm, context_id, run);
+                        realm.js_cache_dispose(context_id);
+                    }
+                    None => run(),
+                })
+            });
+    }
+}
+
+/// add a callback to run after the response of a request was sent, see DeferredJobs
+/// fails once the response was created, e.g. when called from a timer or promise which outlived the request
+pub fn add<R: JsRealmAdapter>(
+    realm: &R,
+    request_id: &str,
+    callback: &R::JsValueAdapterType,
+) -> Result<(), JsError> {
+    let mut deferred = DEFERRED.lock().unwrap();
+    let deferred = deferred.get_mut(request_id).ok_or_else(|| {
+        JsError::new_str("after can only be called until the response of the request was created")
+    })?;
+    if deferred.context_id.is_none() {
+        let context_obj = context::script_context(realm)?;
+        if !context_obj.js_is_null_or_undefined() {
+            deferred.context_id = Some(realm.js_cache_add(&context_obj));
+        }
+    }
+    deferred.callback_ids.push(realm.js_cache_add(callback));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{RequestInfo, ScriptResponse};
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+    use quickjs_runtime::quickjsrealmadapter::QuickJsRealmAdapter;
+    use std::sync::mpsc;
+
+    #[actix_web::test]
+    async fn the_callbacks_run_with_the_context_after_the_body_was_sent() {
+        crate::config::init_for_tests();
+        let rt = Arc::new(QuickJsRuntimeBuilder::new().build());
+        let request_id = "deferred-test";
+        let req = TestRequest::get().uri("/deferred").to_http_request();
+        let info = RequestInfo::from_http_request(&req, Bytes::new(), request_id.to_string(), 0);
+        let mut response = ScriptResponse {
+            handled: true,
+            body: Some(Bytes::from_static(b"done")),
+            ..ScriptResponse::default()
+        };
+        response.deferred = Some(DeferredJobs::new(rt.clone(), None, request_id));
+
+        // the callback sends the id of the request in getContext()
+        let (tx, rx) = mpsc::channel();
+        let added = rt.js_loop_realm_sync(None, move |_rt, realm| {
+            context::with_script_context(realm, &info, || {
+                let callback = realm.js_function_create(
+                    "callback",
+                    move |realm: &QuickJsRealmAdapter, _this, _args| {
+                        let context_obj = context::script_context(realm)?;
+                        let id = realm.js_object_get_property(&context_obj, "id")?;
+                        tx.send(id.js_to_string()?).unwrap();
+                        realm.js_undefined_create()
+                    },
+                    0,
+                )?;
+                add(realm, request_id, &callback)
+            })
+        });
+        assert!(added.is_ok());
+
+        let res = response.into_http_response(&req);
+        rt.js_loop_sync(|_rt| ());
+        assert!(rx.try_recv().is_err());
+        let body = actix_web::body::to_bytes(res.into_body())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(body, "done");
+        rt.js_loop_sync(|_rt| ());
+        assert_eq!(rx.try_recv().ok().as_deref(), Some(request_id));
+    }
+
+    #[actix_web::test]
+    async fn the_after_callbacks_run_after_the_response() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "after") {
+                    globalThis.afterRan = false;
+                    evt.after(() => { throw new Error("does not keep the next callback from running"); });
+                    evt.after(() => { globalThis.afterRan = true; });
+                    evt.responseBody = String(globalThis.afterRan);
+                }
+            });"#,
+        );
+        let req = TestRequest::get().insert_header(("x-test", "after"));
+        let (_, headers, body) = crate::tests::call(req).await;
+        assert_eq!(body, "false");
+        assert_eq!(crate::tests::eval("String(globalThis.afterRan)"), "true");
+        // nothing is kept for the request once its callbacks ran
+        let request_id = headers.get("x-request-id").unwrap().to_str().unwrap();
+        assert!(!DEFERRED.lock().unwrap().contains_key(request_id));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::proxy_registry;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::{JsError, Script};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+/// identifies a proxy class which is a static event target e.g. com.mycompany.MyApp
+#[derive(Clone, PartialEq)]
+pub struct ProxyId {
+    pub namespace: &'static [&'static str],
+    pub class_name: &'static str,
+}
+
+lazy_static! {
+    // the proxies which are a static event target, every runtime installs the same proxies so this is shared
+    static ref EVENT_TARGETS: Mutex<Vec<ProxyId>> = Mutex::new(vec![]);
+}
+
+/// register a proxy as static event target so it receives broadcast events
+pub fn register_event_target(namespace: &'static [&'static str], class_name: &'static str) {
+    let id = ProxyId {
+        namespace,
+        class_name,
+    };
+    let mut targets = EVENT_TARGETS.lock().unwrap();
+    if !targets.contains(&id) {
+        targets.push(id);
+    }
+}
+
+/// all registered static event targets
+pub fn event_targets() -> Vec<ProxyId> {
+    EVENT_TARGETS.lock().unwrap().clone()
+}
+
+/// install a proxy which has no methods and is only used as a static event target
+pub fn install_event_target<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    namespace: &'static [&'static str],
+    class_name: &'static str,
+) -> Result<(), JsError> {
+    let proxy = JsProxy::new(namespace, class_name).set_static_event_target(true);
+    proxy_registry::install(realm, proxy)?;
+    track_vetoes(realm, namespace, class_name)?;
+    register_event_target(namespace, class_name);
+    Ok(())
+}
+
+// the non enumerable property a listener which returned false sets on the event, read and reset by dispatch_to
+const VETOED_PROPERTY: &str = "__vetoed";
+
+/// let the listeners of an installed static event target veto an event by returning false
+/// the runtime ignores what a listener returns and always reports an event as not vetoed, so addEventListener is
+/// replaced by a function which adds a wrapper of the listener which marks the event when the listener returned false
+/// the wrapper is kept on the listener so removeEventListener finds it, also after the proxy was reinstalled
+pub fn track_vetoes<R: JsRealmAdapter>(
+    realm: &R,
+    namespace: &[&str],
+    class_name: &str,
+) -> Result<(), JsError> {
+    let script = format!(
+        r#"((target) => {{
+            const add = target.addEventListener;
+            const remove = target.removeEventListener;
+            const wrap = (listener) => {{
+                if (typeof listener !== "function") {{
+                    return listener;
+                }}
+                if (!Object.prototype.hasOwnProperty.call(listener, "__vetoWrapper")) {{
+                    Object.defineProperty(listener, "__vetoWrapper", {{value: function (evt) {{
+                        const result = listener.call(this, evt);
+                        if (result === false && evt !== null && typeof evt === "object") {{
+                            Object.defineProperty(evt, "{}", {{value: true, writable: true, configurable: true}});
+                        }}
+                        return result;
+                    }}}});
+                }}
+                return listener.__vetoWrapper;
+            }};
+            target.addEventListener = (id, listener) => add.call(target, id, wrap(listener));
+            target.removeEventListener = (id, listener) => remove.call(target, id, wrap(listener));
+        }})({}.{});"#,
+        VETOED_PROPERTY,
+        namespace.join("."),
+        class_name
+    );
+    realm.js_eval(Script::new("file://track_vetoes.js", script.as_str()))?;
+    Ok(())
+}
+
+/// create an event object with the given properties
+pub fn build_event<R: JsRealmAdapter>(
+    realm: &R,
+    fields: &[(&str, R::JsValueAdapterType)],
+) -> Result<R::JsValueAdapterType, JsError> {
+    let event_obj = realm.js_object_create()?;
+    for (name, value) in fields {
+        realm.js_object_set_property(&event_obj, name, value)?;
+    }
+    Ok(event_obj)
+}
+
+/// dispatch an event to a single proxy, returns true if a listener vetoed the event
+pub fn dispatch_to<R: JsRealmAdapter>(
+    realm: &R,
+    namespace: &[&str],
+    class_name: &str,
+    event_name: &str,
+    event_obj: &R::JsValueAdapterType,
+) -> Result<bool, JsError> {
+    // what this returns does not depend on the listeners, see track_vetoes
+    realm.js_proxy_dispatch_static_event(namespace, class_name, event_name, event_obj)?;
+    if !event_obj.js_is_object() {
+        return Ok(false);
+    }
+    let vetoed = realm.js_object_get_property(event_obj, VETOED_PROPERTY)?;
+    if vetoed.js_is_bool() && vetoed.js_to_bool() {
+        // the same event object may be dispatched again, e.g. as the error event of the request
+        realm.js_object_set_property(
+            event_obj,
+            VETOED_PROPERTY,
+            &realm.js_boolean_create(false)?,
+        )?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// dispatch an event to all registered proxies, returns true if a listener of any of the proxies vetoed the event
+/// a veto does not stop the event from being dispatched to the other proxies
+pub fn broadcast<R: JsRealmAdapter>(
+    realm: &R,
+    event_name: &str,
+    event_obj: &R::JsValueAdapterType,
+) -> Result<bool, JsError> {
+    let mut vetoed = false;
+    for target in event_targets() {
+        if dispatch_to(
+            realm,
+            target.namespace,
+            target.class_name,
+            event_name,
+            event_obj,
+        )? {
+            vetoed = true;
+        }
+    }
+    Ok(vetoed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    #[test]
+    fn an_event_has_the_given_fields() {
+        let rt = QuickJsRuntimeBuilder::new().build();
+        let json = rt.js_loop_realm_sync(None, |_rt, realm| {
+            let fields = [
+                ("name", realm.js_string_create("cleanup").ok().unwrap()),
+                ("runtime", realm.js_i32_create(2).ok().unwrap()),
+            ];
+            let event_obj = build_event(realm, &fields).ok().unwrap();
+            realm.js_json_stringify(&event_obj, None).ok().unwrap()
+        });
+        assert_eq!(json, r#"{"name":"cleanup","runtime":2}"#);
+    }
+
+    #[test]
+    fn a_listener_vetoes_by_returning_false() {
+        let rt = QuickJsRuntimeBuilder::new().build();
+        let vetoed = rt.js_loop_realm_sync(None, |_rt, realm| {
+            let proxy = JsProxy::new(&["test"], "Vetoes").set_static_event_target(true);
+            proxy_registry::install(realm, proxy).ok().unwrap();
+            track_vetoes(realm, &["test"], "Vetoes").ok().unwrap();
+            let script = r#"
+                const removed = (evt) => false;
+                test.Vetoes.addEventListener("veto", (evt) => false);
+                test.Vetoes.addEventListener("pass", (evt) => { evt.passed = true; });
+                test.Vetoes.addEventListener("removed", removed);
+                test.Vetoes.removeEventListener("removed", removed);
+            "#;
+            assert!(realm
+                .js_eval(Script::new("file://vetoes.js", script))
+                .is_ok());
+            let event_obj = realm.js_object_create().ok().unwrap();
+            ["veto", "pass", "removed"]
+                .iter()
+                .map(|name| {
+                    dispatch_to(realm, &["test"], "Vetoes", name, &event_obj)
+                        .ok()
+                        .unwrap()
+                })
+                .collect::<Vec<bool>>()
+        });
+        assert_eq!(vetoed, vec![true, false, false]);
+    }
+
+    #[test]
+    fn a_broadcast_reaches_every_event_target() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("broadcastTest", (evt) => {
+                evt.reached.push("MyApp");
+            });
+            com.mycompany.Logger.addEventListener("broadcastTest", (evt) => {
+                evt.reached.push("Logger");
+                return false;
+            });"#,
+        );
+        let (vetoed, reached) = crate::tests::with_realm(|realm| {
+            let reached = realm.js_array_create().ok().unwrap();
+            let event_obj = build_event(realm, &[("reached", reached.clone())])
+                .ok()
+                .unwrap();
+            let vetoed = broadcast(realm, "broadcastTest", &event_obj).ok().unwrap();
+            (
+                vetoed,
+                realm.js_json_stringify(&reached, None).ok().unwrap(),
+            )
+        });
+        assert!(vetoed);
+        assert!(reached.contains("MyApp") && reached.contains("Logger"));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::proxy_registry::{self, ProxyInfo};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const DTS_PATH_VAR: &str = "SCRIPT_DTS_PATH";
+const DEFAULT_DTS_PATH: &str = "./types/my_app.d.ts";
+
+/// classes like console which typescript already declares, declaring them again would clash with its lib
+const STANDARD_GLOBALS: &[&str] = &["console", "performance", "Response"];
+
+/// the fields of the event object of a request as set by event::create_event_obj and read back by
+/// event::read_from_event_obj, a name ending in ? is optional
+const EVENT_FIELDS: &[(&str, &str)] = &[
+    // the com.mycompany.RequestEvent of the request, a typed view of this object
+    ("request", "com.mycompany.RequestEvent"),
+    ("requestId", "string"),
+    ("method", "string"),
+    ("path", "string"),
+    ("remoteAddr", "string"),
+    ("realIp", "string"),
+    ("route", "string"),
+    ("tenant", "string | null"),
+    ("auth", "Record<string, any> | null"),
+    ("params", "Record<string, string>"),
+    ("query", "Record<string, string>"),
+    ("queryAll", "Record<string, string[]>"),
+    ("queryString", "string"),
+    ("headers", "Record<string, string>"),
+    ("cookies", "Record<string, string>"),
+    ("app", "{readonly name: string, readonly version: string, readonly debug: boolean, readonly poolSize: number, readonly isolateRequests: boolean, readonly tenants: readonly string[], readonly scriptTimeoutMs: number}"),
+    ("trace", "{traceId: string, spanId: string, parentSpanId: string | null, sampled: boolean, traceparent: string, tracestate: string | null}"),
+    ("body?", "any"),
+    ("rawBody?", "string"),
+    ("bodyBytes?", "Uint8Array"),
+    ("form?", "Record<string, string | string[]>"),
+    ("bodyParseError?", "boolean"),
+    ("files?", "{id: string, field: string, filename: string, contentType: string | null, size: number}[]"),
+    ("fields?", "Record<string, string>"),
+    ("responseStatus?", "number"),
+    ("responseBody?", "string"),
+    ("responseJson?", "any"),
+    ("responseBytes?", "Uint8Array | string"),
+    ("responseFile?", "string"),
+    ("responseContentType?", "string"),
+    ("setCookies?", "{name: string, value: string, path?: string, domain?: string, maxAge?: number, httpOnly?: boolean, secure?: boolean, sameSite?: \"Strict\" | \"Lax\" | \"None\"}[]"),
+    ("responseHeaders?", "Record<string, string>"),
+    ("responseDownload?", "string"),
+    ("etag?", "string"),
+    ("error?", "{name: string, message: string, stack: string}"),
+    ("redirect", "(location: string, status?: number) => void"),
+    ("download", "(filename: string) => void"),
+    ("cacheFor", "(seconds: number, key?: string) => void"),
+    ("after", "(callback: () => any) => void"),
+    ("write", "(chunk: string) => boolean"),
+    ("writeJson", "(value: any) => boolean"),
+    ("end", "() => void"),
+];
+
+const HEADER: &str = "// generated at startup from the installed proxies, changes are overwritten on the next start
+// the typed surface of the native side, the signatures are declared where the proxies add their members
+
+declare const __BUILD__: {readonly profile: \"debug\" | \"release\", readonly version: string, readonly gitHash: string, readonly features: readonly string[], readonly os: string, readonly arch: string};
+// the queries run on the connection of the transaction, after commit() or rollback() they reject
+type Transaction = {query(sql: string, params?: any[]): Promise<Record<string, any>[]>, commit(): Promise<void>, rollback(): Promise<void>};
+// query and hash are without their ? and #, port is null for the default port of the protocol
+type UrlParts = {protocol: string, username?: string, password?: string | null, host?: string | null, port?: number | null, path?: string, query?: string | null, hash?: string | null};
+";
+
+// the members of a class declaration, indented for inside a namespace
+// a member added without a signature (like with add_static_method) is declared as taking and returning any, so the
+// declaration still shows everything that is installed
+fn class_body(proxy: &ProxyInfo, indent: &str) -> String {
+    let mut body = String::new();
+    let method = |prefix: &str, name: &str| {
+        let signature =
+            proxy_registry::signature(proxy.name.as_str(), name).unwrap_or("(...args: any[]): any");
+        format!("{}    {}{}{};\n", indent, prefix, name, signature)
+    };
+    let getter = |prefix: &str, name: &str| {
+        let ts = proxy_registry::signature(proxy.name.as_str(), name).unwrap_or("any");
+        format!("{}    {}readonly {}: {};\n", indent, prefix, name, ts)
+    };
+    for name in proxy.static_getters.iter() {
+        body.push_str(getter("static ", name).as_str());
+    }
+    for name in proxy.static_methods.iter() {
+        body.push_str(method("static ", name).as_str());
+    }
+    if proxy.static_event_target {
+        body.push_str(
+            format!(
+                "{i}    static addEventListener(type: `request${{string}}`, listener: (evt: RequestEventObject) => any): void;\n\
+                 {i}    static addEventListener(type: string, listener: (evt: any) => any): void;\n\
+                 {i}    static removeEventListener(type: string, listener: (evt: any) => any): void;\n",
+                i = indent
+            )
+            .as_str(),
+        );
+    }
+    for name in proxy.getters.iter() {
+        body.push_str(getter("", name).as_str());
+    }
+    for name in proxy.methods.iter() {
+        body.push_str(method("", name).as_str());
+    }
+    body
+}
+
+/// the declaration of the installed proxies and the request event object
+pub fn generate() -> String {
+    let mut dts = HEADER.to_string();
+
+    // not RequestEvent, inside the namespace that would be the com.mycompany.RequestEvent class
+    dts.push_str("\ninterface RequestEventObject {\n");
+    for (name, ts) in EVENT_FIELDS {
+        dts.push_str(format!("    {}: {};\n", name, ts).as_str());
+    }
+    dts.push_str("}\n");
+
+    // the classes of a namespace are declared together
+    let mut namespaces: BTreeMap<String, Vec<ProxyInfo>> = BTreeMap::new();
+    for proxy in proxy_registry::installed(proxy_registry::MAIN_REALM_ID) {
+        let (namespace, _class) = proxy.name.rsplit_once('.').unwrap_or(("", ""));
+        if namespace.is_empty() && STANDARD_GLOBALS.contains(&proxy.name.as_str()) {
+            continue;
+        }
+        namespaces
+            .entry(namespace.to_string())
+            .or_default()
+            .push(proxy);
+    }
+    for (namespace, proxies) in namespaces {
+        dts.push('\n');
+        let indent = if namespace.is_empty() { "" } else { "    " };
+        if !namespace.is_empty() {
+            dts.push_str(format!("declare namespace {} {{\n", namespace).as_str());
+        }
+        for proxy in proxies {
+            let class = proxy.name.rsplit('.').next().unwrap_or_default();
+            let declare = if namespace.is_empty() { "declare " } else { "" };
+            dts.push_str(format!("{}{}class {} {{\n", indent, declare, class).as_str());
+            dts.push_str(class_body(&proxy, indent).as_str());
+            dts.push_str(format!("{}}}\n", indent).as_str());
+        }
+        if !namespace.is_empty() {
+            dts.push_str("}\n");
+        }
+    }
+    dts
+}
+
+/// write the declaration to SCRIPT_DTS_PATH so the scripts can be type checked against what is actually installed,
+/// called once after the runtimes were initialized in debug builds, failing to write it is only logged
+pub fn write() {
+    let path = std::env::var(DTS_PATH_VAR).unwrap_or_else(|_| DEFAULT_DTS_PATH.to_string());
+    let path = Path::new(path.as_str());
+    let res = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+    .and_then(|_| std::fs::write(path, generate()));
+    match res {
+        Ok(()) => log::info!("wrote the script declarations to {}", path.display()),
+        Err(err) => log::warn!("could not write {}: {}", path.display(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxies::SafeStaticMethods;
+    use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+    use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+    use quickjs_runtime::quickjsrealmadapter::QuickJsRealmAdapter;
+
+    #[test]
+    fn the_signatures_come_from_where_the_methods_are_added() {
+        let proxy = JsProxy::<QuickJsRealmAdapter>::new(&["dtsTest"], "Proxy");
+        let _proxy = proxy.add_safe_static_method(
+            "typed",
+            "(n: number): string",
+            |_rt, realm: &QuickJsRealmAdapter, _args| realm.js_undefined_create(),
+        );
+        let info = ProxyInfo {
+            name: "dtsTest.Proxy".to_string(),
+            static_methods: vec!["typed".to_string(), "untyped".to_string()],
+            static_getters: vec![],
+            methods: vec![],
+            getters: vec![],
+            static_event_target: false,
+            event_target: false,
+        };
+        assert_eq!(
+            class_body(&info, ""),
+            "    static typed(n: number): string;\n    static untyped(...args: any[]): any;\n"
+        );
+    }
+
+    #[test]
+    fn the_installed_proxies_are_declared_by_namespace() {
+        crate::tests::pool();
+        let dts = generate();
+        assert!(dts.contains(
+            "\ninterface RequestEventObject {\n    request: com.mycompany.RequestEvent;\n"
+        ));
+        assert!(dts.contains("\ndeclare namespace com.mycompany {\n"));
+        assert!(dts.contains("\n    class MyApp {\n"));
+        assert!(dts.contains("\n        static sleep(ms: number): Promise<number>;\n"));
+        assert!(dts.contains(
+            "\n        static addEventListener(type: string, listener: (evt: any) => any): void;\n"
+        ));
+        // typescript declares console itself
+        assert!(!dts.contains("class console"));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::timeout::{script_timeout, with_deadline};
+use crate::{config, dispatch, MY_APP_CLASS, MY_APP_NAMESPACE};
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::{JsError, Script};
+use lazy_static::lazy_static;
+use std::path::Path;
+use std::sync::Mutex;
+
+pub const ENTRY_DIR_VAR: &str = "SCRIPT_ENTRY_DIR";
+const DEFAULT_ENTRY_DIR: &str = "./modules/entry";
+/// the path of the scripts which import a preloaded module is this followed by the index of the module
+const PRELOAD_PREFIX: &str = "file://preload_";
+
+lazy_static! {
+    /// the dir with the modules which are evaluated after main.ts
+    pub static ref ENTRY_DIR: String =
+        std::env::var(ENTRY_DIR_VAR).unwrap_or_else(|_| DEFAULT_ENTRY_DIR.to_string());
+    // the modules read from ENTRY_DIR by load()
+    static ref ENTRY_MODULES: Mutex<Vec<EntryModule>> = Mutex::new(vec![]);
+    // the modules under the module dir which are imported with SCRIPT_PRELOAD_MODULES, relative to it
+    static ref PRELOAD_MODULES: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+struct EntryModule {
+    path: String,
+    code: String,
+}
+
+/// read the .ts and .js modules in ENTRY_DIR, they are evaluated in order of their file name so a prefix like
+/// 01_ can be used to control the order
+/// a missing ENTRY_DIR just means there are no entry modules besides main.ts
+///
+/// with SCRIPT_PRELOAD_MODULES this also lists the modules under SCRIPT_MODULE_DIR, scripts() imports all of them
+/// so a module which fails is reported at startup instead of on its first import
+/// they are imported in order of their path, the imports of a module are evaluated before it and a module which
+/// was already imported is not evaluated again, so the order only matters for modules which don't import each other
+pub fn load() -> std::io::Result<()> {
+    let dir = Path::new(ENTRY_DIR.as_str());
+    if dir.is_dir() {
+        *ENTRY_MODULES.lock().unwrap() = read_entry_modules(dir)?;
+    }
+    if config::get().preload_modules {
+        let module_dir = Path::new(config::get().module_dir.as_str());
+        let entry_dir = dir.canonicalize().ok();
+        let mut modules = vec![];
+        collect_modules(module_dir, module_dir, entry_dir.as_deref(), &mut modules).map_err(
+            |err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!(
+                        "could not list the modules to preload in {}: {}",
+                        module_dir.display(),
+                        err
+                    ),
+                )
+            },
+        )?;
+        modules.sort();
+        log::info!(
+            "preloading {} modules from {}",
+            modules.len(),
+            module_dir.display()
+        );
+        *PRELOAD_MODULES.lock().unwrap() = modules;
+    }
+    Ok(())
+}
+
+fn read_entry_modules(dir: &Path) -> std::io::Result<Vec<EntryModule>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_module = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("ts") | Some("js")
+        );
+        if path.is_file() && is_module {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut modules = vec![];
+    for path in paths {
+        let code = std::fs::read_to_string(&path).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("could not read entry module {}: {}", path.display(), err),
+            )
+        })?;
+        modules.push(EntryModule {
+            path: format!("file://{}", path.display()),
+            code,
+        });
+    }
+    Ok(modules)
+}
+
+// add the .ts and .js modules under dir to modules, relative to root with / as separator
+// dirs and files starting with a . and the skipped dir (the entry dir, those modules are evaluated as entry modules)
+// are left out, as are .d.ts files which only declare types, symlinks are not followed
+fn collect_modules(
+    root: &Path,
+    dir: &Path,
+    skip: Option<&Path>,
+    modules: &mut Vec<String>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if skip.is_none() || path.canonicalize().ok().as_deref() != skip {
+                collect_modules(root, path.as_path(), skip, modules)?;
+            }
+        } else if file_type.is_file()
+            && (name.ends_with(".js") || (name.ends_with(".ts") && !name.ends_with(".d.ts")))
+        {
+            if let Some(rel_path) = path.strip_prefix(root).ok().and_then(|rel| rel.to_str()) {
+                modules.push(rel_path.replace('\\', "/"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the scripts to evaluate in the realm of an isolated request, main.ts followed by the modules in ENTRY_DIR and the
+/// module which imports the route handlers
+pub fn scripts() -> Vec<Script> {
+    let mut scripts = vec![Script::new("file://main.ts", include_str!("main.ts"))];
+    for module in ENTRY_MODULES.lock().unwrap().iter() {
+        scripts.push(Script::new(module.path.as_str(), module.code.as_str()));
+    }
+    scripts.push(crate::routes::handlers_script());
+    scripts
+}
+
+/// the scripts to evaluate in the main realm of a new runtime and in the realms of the tenants, scripts() with a
+/// script for every preloaded module which imports it
+/// the realms of isolated requests don't preload, the modules were already checked at startup
+pub fn startup_scripts() -> Vec<Script> {
+    let mut scripts = scripts();
+    let handlers = scripts.pop();
+    // imported like the route handlers so a handler module is the same module and is only evaluated once
+    for (idx, module) in PRELOAD_MODULES.lock().unwrap().iter().enumerate() {
+        scripts.push(Script::new(
+            format!("{}{}.js", PRELOAD_PREFIX, idx).as_str(),
+            format!("import {};\n", serde_json::Value::from(module.as_str())).as_str(),
+        ));
+    }
+    scripts.extend(handlers);
+    scripts
+}
+
+/// what a script of scripts() is for error messages, the module a preload script imports or the entry module
+pub fn describe(script_path: &str) -> String {
+    let preloaded = script_path
+        .strip_prefix(PRELOAD_PREFIX)
+        .and_then(|idx| idx.strip_suffix(".js"))
+        .and_then(|idx| idx.parse::<usize>().ok())
+        .and_then(|idx| PRELOAD_MODULES.lock().unwrap().get(idx).cloned());
+    match preloaded {
+        Some(module) => format!(
+            "preloaded module {}",
+            Path::new(config::get().module_dir.as_str())
+                .join(module)
+                .display()
+        ),
+        None => format!("entry module {}", script_path),
+    }
+}
+
+/// dispatch the init event in a realm after the scripts were evaluated, in every runtime and in every isolated realm
+/// before it handles a request, listeners can use the proxies to do their setup
+pub fn dispatch_init<R: JsRealmAdapter>(realm: &R, pool_idx: usize) -> Result<(), JsError> {
+    let event_obj =
+        dispatch::build_event(realm, &[("runtime", realm.js_i32_create(pool_idx as i32)?)])?;
+    with_deadline(script_timeout(), || {
+        dispatch::dispatch_to(realm, MY_APP_NAMESPACE, MY_APP_CLASS, "init", &event_obj)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_entry_modules_are_evaluated_after_main_ts() {
+        config::init_for_tests();
+        load().unwrap();
+        let paths: Vec<String> = scripts()
+            .iter()
+            .map(|script| script.get_path().to_string())
+            .collect();
+        assert_eq!(
+            paths[..2],
+            ["file://main.ts", "file://./modules/entry/01_api_log.ts"]
+        );
+    }
+
+    #[test]
+    fn every_module_but_the_entry_modules_and_declarations_is_preloaded() {
+        let root = std::env::temp_dir().join(format!("preload-test-{}", std::process::id()));
+        for dir in ["entry", "sub", ".hidden"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "a.ts",
+            "b.js",
+            "types.d.ts",
+            "README.md",
+            "entry/01_first.ts",
+            "sub/c.ts",
+            ".hidden/d.ts",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        let entry_dir = root.join("entry").canonicalize().unwrap();
+        let mut modules = vec![];
+        collect_modules(&root, &root, Some(entry_dir.as_path()), &mut modules).unwrap();
+        modules.sort();
+        assert_eq!(modules, ["a.ts", "b.js", "sub/c.ts"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn the_init_event_gets_the_runtime() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("init", (evt) => {
+                globalThis.initRuntime = evt.runtime;
+            });"#,
+        );
+        crate::tests::with_realm(|realm| dispatch_init(realm, 3))
+            .ok()
+            .unwrap();
+        assert_eq!(crate::tests::eval("globalThis.initRuntime"), "3");
+    }
+}
@@ -0,0 +1,230 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::{JsError, Script};
+
+// JsError only has a name, message and stack so the status is passed on in the name e.g. HttpError(404)
+const HTTP_ERROR_CLASS: &str = r#"
+globalThis.HttpError = class HttpError extends Error {
+    constructor(status, message) {
+        if (!Number.isInteger(status) || status < 400 || status > 599) {
+            throw new RangeError("HttpError status should be a 4xx or 5xx status, got " + status);
+        }
+        super(message === undefined ? "" : "" + message);
+        this.status = status;
+        this.name = "HttpError(" + status + ")";
+    }
+};
+"#;
+
+/// install the HttpError class as a global so scripts can throw new HttpError(404, "not found")
+pub fn init_http_error<R: JsRealmAdapter>(realm: &R) -> Result<(), JsError> {
+    realm.js_eval(Script::new("file://http_error.js", HTTP_ERROR_CLASS))?;
+    Ok(())
+}
+
+/// the status of an error thrown as HttpError, None for all other errors
+pub fn http_error_status(err: &JsError) -> Option<StatusCode> {
+    let status = err
+        .get_name()
+        .strip_prefix("HttpError(")?
+        .strip_suffix(')')?
+        .parse::<u16>()
+        .ok()?;
+    match StatusCode::from_u16(status) {
+        Ok(status) if status.is_client_error() || status.is_server_error() => Some(status),
+        _ => None,
+    }
+}
+
+/// true for the error QuickJS throws when a runtime exceeds SCRIPT_MEMORY_LIMIT, those are a 500 like other errors
+pub fn is_out_of_memory(err: &JsError) -> bool {
+    err.get_name() == "InternalError" && err.get_message().contains("out of memory")
+}
+
+/// create the response for a script which failed
+/// a thrown HttpError results in its status with the message as body, all other errors are a 500
+/// the stack is only included in debug builds so we don't leak script internals in production
+pub fn script_error_response(err: &JsError) -> HttpResponse {
+    if let Some(status) = http_error_status(err) {
+        return HttpResponse::build(status)
+            .content_type("text/plain; charset=utf-8")
+            .body(err.get_message().to_string());
+    }
+    #[cfg(debug_assertions)]
+    let body = serde_json::json!({
+        "error": err.get_name(),
+        "message": err.get_message(),
+        "stack": err.get_stack(),
+    });
+    #[cfg(not(debug_assertions))]
+    let body = serde_json::json!({
+        "error": err.get_name(),
+        "message": err.get_message(),
+    });
+    HttpResponse::InternalServerError().json(body)
+}
+
+/// prefix the message of an error with what we were doing, the name and stack are kept
+pub fn with_context(err: JsError, context: &str) -> JsError {
+    JsError::new(
+        err.get_name().to_string(),
+        format!("{}: {}", context, err.get_message()),
+        err.get_stack().to_string(),
+    )
+}
+
+/// log a script error at error level including the raw stack
+/// JsError has no separate filename or line, we take those from the first frame of the stack
+/// note that for typescript the lines are those of the transpiled code
+pub fn log_script_error(context: &str, err: &JsError) {
+    let location = error_location(err.get_stack()).unwrap_or("unknown location");
+    log::error!(
+        "{}: {}: {} at {}\n{}",
+        context,
+        err.get_name(),
+        err.get_message(),
+        location,
+        err.get_stack()
+    );
+}
+
+/// a single line description of a script error with where it was thrown, used for startup errors
+pub fn describe(err: &JsError) -> String {
+    format!(
+        "{}: {} at {}",
+        err.get_name(),
+        err.get_message(),
+        error_location(err.get_stack()).unwrap_or("unknown location")
+    )
+}
+
+// quickjs stack frames look like "    at handler (file://main.ts:65)"
+fn error_location(stack: &str) -> Option<&str> {
+    let frame = stack.lines().find(|line| !line.trim().is_empty())?;
+    let start = frame.rfind('(')?;
+    let end = frame.rfind(')')?;
+    if end > start + 1 {
+        Some(&frame[start + 1..end])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::http::StatusCode;
+    use hirofa_utils::js_utils::adapters::JsValueAdapter;
+    use hirofa_utils::js_utils::facades::{JsRuntimeBuilder, JsRuntimeFacade};
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    #[actix_web::test]
+    async fn a_failed_script_gets_a_json_500() {
+        let err = JsError::new(
+            "TypeError".to_string(),
+            "broken".to_string(),
+            "at file://test.js:1".to_string(),
+        );
+        let res = script_error_response(&err);
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(res.into_body()).await.ok().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "TypeError");
+        assert_eq!(body["message"], "broken");
+    }
+
+    #[actix_web::test]
+    async fn a_thrown_http_error_gets_its_status() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "http-error") {
+                    throw new HttpError(parseInt(evt.query.status), "no such thing");
+                }
+            });"#,
+        );
+        for (status, expected, expected_body) in [
+            (404, StatusCode::NOT_FOUND, Some("no such thing")),
+            (200, StatusCode::INTERNAL_SERVER_ERROR, None),
+        ] {
+            let req = actix_web::test::TestRequest::get()
+                .uri(format!("/?status={}", status).as_str())
+                .insert_header(("x-test", "http-error"));
+            let (status, _, body) = crate::tests::call(req).await;
+            assert_eq!(status, expected);
+            if let Some(expected_body) = expected_body {
+                assert_eq!(body, expected_body);
+            }
+        }
+    }
+
+    #[test]
+    fn the_context_is_prefixed_to_the_message() {
+        let err = JsError::new(
+            "TypeError".to_string(),
+            "broken".to_string(),
+            "at file://test.js:1".to_string(),
+        );
+        let err = with_context(err, "runtime 0: could not install console");
+        assert_eq!(err.get_name(), "TypeError");
+        assert_eq!(
+            err.get_message(),
+            "runtime 0: could not install console: broken"
+        );
+        assert_eq!(err.get_stack(), "at file://test.js:1");
+    }
+
+    #[test]
+    fn the_location_is_taken_from_the_first_frame() {
+        assert_eq!(
+            error_location(
+                "\n    at handler (file://main.ts:65)\n    at <eval> (file://main.ts:80)\n"
+            ),
+            Some("file://main.ts:65")
+        );
+        assert_eq!(error_location("    at <anonymous> ()"), None);
+        assert_eq!(error_location(""), None);
+    }
+
+    #[test]
+    fn a_startup_error_is_described_with_its_location() {
+        let err = JsError::new(
+            "SyntaxError".to_string(),
+            "unexpected token".to_string(),
+            "    at <eval> (file://main.ts:12)\n".to_string(),
+        );
+        assert_eq!(
+            describe(&err),
+            "SyntaxError: unexpected token at file://main.ts:12"
+        );
+        let err = JsError::new_str("no stack");
+        assert!(describe(&err).ends_with(": no stack at unknown location"));
+    }
+
+    #[test]
+    fn a_runtime_which_exceeds_its_memory_limit_fails_the_job() {
+        let rt = QuickJsRuntimeBuilder::new()
+            .memory_limit(8 * 1024 * 1024)
+            .js_build();
+        let eval = |code: &'static str| {
+            rt.js_loop_realm_sync(None, move |_rt, realm| {
+                realm
+                    .js_eval(Script::new("file://memory.js", code))?
+                    .js_to_string()
+            })
+        };
+        let err =
+            eval("const chunks = []; while (true) { chunks.push(new Array(100000).fill(1)); }")
+                .err()
+                .unwrap();
+        assert!(
+            is_out_of_memory(&err),
+            "{}: {}",
+            err.get_name(),
+            err.get_message()
+        );
+        // the memory of the failed job is freed so the runtime can run the next one
+        assert_eq!(eval("1 + 1").ok().unwrap(), "2");
+    }
+}
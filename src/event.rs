@@ -0,0 +1,1797 @@
+use crate::app_state;
+use crate::auth;
+use crate::client_addr;
+use crate::config;
+use crate::deferred::{self, DeferredJobs};
+use crate::dispatch;
+use crate::errors;
+use crate::isolation::IsolatedRealm;
+use crate::metrics;
+use crate::proxies::files;
+use crate::proxies::safe_function_create;
+use crate::response_cache;
+use crate::static_files;
+use crate::streaming;
+use crate::streaming::ResponseBody;
+use crate::tenants;
+use crate::trace::TraceContext;
+use crate::uploads::UploadedFile;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::{ContentEncoding, EntityTag, Header, HeaderName, HeaderValue};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use encoding_rs::Encoding;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::facades::JsValueType;
+use hirofa_utils::js_utils::JsError;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_stream::StreamExt;
+
+/// script bodies smaller than this are not compressed, for those the gzip overhead outweighs the gain
+const COMPRESS_MIN_SIZE: usize = 1024;
+
+/// the parts of an actix HttpRequest we pass on to the script engine
+/// HttpRequest itself can not be moved to the worker thread of the runtime so we copy what we need
+#[derive(Clone)]
+pub struct RequestInfo {
+    // a unique id for every request, also returned in the X-Request-Id response header
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    // the address of the peer and the client ip, which differs when behind a trusted proxy, see client_addr.rs
+    pub remote_addr: String,
+    pub real_ip: String,
+    // the route pattern which matched this request, the path when no route matched
+    pub route: String,
+    // the tenant the request is for, see tenants::resolve
+    pub tenant: Option<String>,
+    // the claims of the bearer token on a route in SCRIPT_AUTH_ROUTES, verified by auth::check
+    pub auth: Option<serde_json::Value>,
+    // true when no route matched and the request is handled by the default service, see routes::NOT_FOUND_EVENT
+    pub not_found: bool,
+    // the path parameters of the route like id for /users/{id}
+    pub params: Vec<(String, String)>,
+    // the query parameters in order, repeated keys like ?a=1&a=2 are kept
+    pub query: Vec<(String, String)>,
+    pub query_string: String,
+    pub headers: Vec<(String, String)>,
+    pub cookies: Vec<(String, String)>,
+    pub content_type: String,
+    // the charset of the Content-Type the body is decoded with, utf-8 when it has none or an unknown one
+    pub charset: &'static Encoding,
+    pub body: Bytes,
+    // the files and fields of a multipart/form-data body, see uploads.rs
+    pub files: Vec<UploadedFile>,
+    pub fields: Vec<(String, String)>,
+    // the id of the stream event.write() writes to, see streaming.rs
+    pub stream_id: u64,
+    // from the traceparent header, see trace.rs
+    pub trace: TraceContext,
+}
+
+impl RequestInfo {
+    pub fn from_http_request(
+        req: &HttpRequest,
+        body: Bytes,
+        request_id: String,
+        stream_id: u64,
+    ) -> Self {
+        let query = serde_urlencoded::from_str::<Vec<(String, String)>>(req.query_string())
+            .unwrap_or_else(|err| {
+                log::debug!("could not parse query string: {}", err);
+                vec![]
+            });
+
+        // multi-valued headers are joined with a comma as described in RFC 7230
+        let mut headers = vec![];
+        for name in req.headers().keys() {
+            let values: Vec<&str> = req
+                .headers()
+                .get_all(name)
+                .filter_map(|val| val.to_str().ok())
+                .collect();
+            headers.push((name.as_str().to_string(), values.join(", ")));
+        }
+
+        // actix already url-decodes the cookie values
+        let cookies = match req.cookies() {
+            Ok(cookies) => cookies
+                .iter()
+                .map(|cookie| (cookie.name().to_string(), cookie.value().to_string()))
+                .collect(),
+            Err(err) => {
+                log::debug!("could not parse cookies: {}", err);
+                vec![]
+            }
+        };
+
+        let charset = req.encoding().unwrap_or_else(|err| {
+            log::debug!("{}, decoding the body as utf-8", err);
+            encoding_rs::UTF_8
+        });
+
+        let params = req
+            .match_info()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        Self {
+            request_id,
+            method: req.method().as_str().to_string(),
+            path: req.path().to_string(),
+            remote_addr: client_addr::remote_addr(req),
+            real_ip: client_addr::client_ip(req),
+            route: req
+                .match_pattern()
+                .unwrap_or_else(|| req.path().to_string()),
+            not_found: req.match_pattern().is_none(),
+            // requests for unknown tenants were already rejected by tenants::check
+            tenant: tenants::resolve(req).ok().flatten(),
+            auth: auth::claims(req),
+            params,
+            query,
+            query_string: req.query_string().to_string(),
+            headers,
+            cookies,
+            content_type: req.content_type().to_string(),
+            charset,
+            body,
+            files: vec![],
+            fields: vec![],
+            stream_id,
+            trace: TraceContext::from_request(req),
+        }
+    }
+}
+
+fn create_string_map<R: JsRealmAdapter>(
+    realm: &R,
+    entries: &[(String, String)],
+) -> Result<R::JsValueAdapterType, JsError> {
+    let obj = realm.js_object_create()?;
+    for (key, value) in entries {
+        realm.js_object_set_property(
+            &obj,
+            key.as_str(),
+            &realm.js_string_create(value.as_str())?,
+        )?;
+    }
+    Ok(obj)
+}
+
+// maps every key to an array of its values
+fn create_multi_map<R: JsRealmAdapter>(
+    realm: &R,
+    entries: &[(String, String)],
+) -> Result<R::JsValueAdapterType, JsError> {
+    let obj = realm.js_object_create()?;
+    for (key, value) in entries {
+        let mut values = realm.js_object_get_property(&obj, key.as_str())?;
+        if !values.js_is_array() {
+            values = realm.js_array_create()?;
+            realm.js_object_set_property(&obj, key.as_str(), &values)?;
+        }
+        let idx = realm.js_array_get_length(&values)?;
+        realm.js_array_set_element(&values, idx, &realm.js_string_create(value.as_str())?)?;
+    }
+    Ok(obj)
+}
+
+// maps every key to its value, or to an array of its values when the key is repeated
+fn create_form_map<R: JsRealmAdapter>(
+    realm: &R,
+    entries: &[(String, String)],
+) -> Result<R::JsValueAdapterType, JsError> {
+    let obj = realm.js_object_create()?;
+    for (key, value) in entries {
+        let value = realm.js_string_create(value.as_str())?;
+        let existing = realm.js_object_get_property(&obj, key.as_str())?;
+        if existing.js_is_array() {
+            let idx = realm.js_array_get_length(&existing)?;
+            realm.js_array_set_element(&existing, idx, &value)?;
+        } else if existing.js_is_string() {
+            let values = realm.js_array_create()?;
+            realm.js_array_set_element(&values, 0, &existing)?;
+            realm.js_array_set_element(&values, 1, &value)?;
+            realm.js_object_set_property(&obj, key.as_str(), &values)?;
+        } else {
+            realm.js_object_set_property(&obj, key.as_str(), &value)?;
+        }
+    }
+    Ok(obj)
+}
+
+/// the trace context as event.trace, traceparent is the header to pass on to outbound calls
+fn create_trace_obj<R: JsRealmAdapter>(
+    realm: &R,
+    trace: &TraceContext,
+) -> Result<R::JsValueAdapterType, JsError> {
+    let optional_string = |value: &Option<String>| match value {
+        Some(value) => realm.js_string_create(value.as_str()),
+        None => realm.js_null_create(),
+    };
+    dispatch::build_event(
+        realm,
+        &[
+            ("traceId", realm.js_string_create(trace.trace_id.as_str())?),
+            ("spanId", realm.js_string_create(trace.span_id.as_str())?),
+            ("parentSpanId", optional_string(&trace.parent_span_id)?),
+            ("sampled", realm.js_boolean_create(trace.sampled)?),
+            (
+                "traceparent",
+                realm.js_string_create(trace.traceparent().as_str())?,
+            ),
+            ("tracestate", optional_string(&trace.tracestate)?),
+        ],
+    )
+}
+
+/// create the event object which is passed to the script's event listeners
+pub fn create_event_obj<R: JsRealmAdapter>(
+    realm: &R,
+    info: &RequestInfo,
+) -> Result<R::JsValueAdapterType, JsError> {
+    let event_obj = dispatch::build_event(
+        realm,
+        &[
+            (
+                "requestId",
+                realm.js_string_create(info.request_id.as_str())?,
+            ),
+            ("method", realm.js_string_create(info.method.as_str())?),
+            ("path", realm.js_string_create(info.path.as_str())?),
+            (
+                "remoteAddr",
+                realm.js_string_create(info.remote_addr.as_str())?,
+            ),
+            ("realIp", realm.js_string_create(info.real_ip.as_str())?),
+            ("route", realm.js_string_create(info.route.as_str())?),
+            (
+                "tenant",
+                match &info.tenant {
+                    Some(tenant) => realm.js_string_create(tenant.as_str())?,
+                    None => realm.js_null_create()?,
+                },
+            ),
+            (
+                "auth",
+                match &info.auth {
+                    Some(claims) => realm.js_json_parse(claims.to_string().as_str())?,
+                    None => realm.js_null_create()?,
+                },
+            ),
+            ("params", create_string_map(realm, &info.params)?),
+            // query has the last value of a repeated key, queryAll all of them
+            ("query", create_string_map(realm, &info.query)?),
+            ("queryAll", create_multi_map(realm, &info.query)?),
+            (
+                "queryString",
+                realm.js_string_create(info.query_string.as_str())?,
+            ),
+            ("headers", create_string_map(realm, &info.headers)?),
+            ("cookies", create_string_map(realm, &info.cookies)?),
+            ("app", app_state::create_app_obj(realm)?),
+            ("trace", create_trace_obj(realm, &info.trace)?),
+        ],
+    )?;
+    set_body(realm, &event_obj, info)?;
+    set_files(realm, &event_obj, info)?;
+    set_stream_functions(realm, &event_obj, info.stream_id)?;
+    set_redirect_function(realm, &event_obj)?;
+    set_download_function(realm, &event_obj)?;
+    set_cache_function(realm, &event_obj, info)?;
+    Ok(event_obj)
+}
+
+/// add the write(chunk), writeJson(value) and end() functions to the event object
+/// once a script calls write() the response is streamed, the script must call end() to complete the response
+/// write() and end() may also be called after the listener returned e.g. from a promise or timer
+/// writeJson() writes the value as a line of json, a response of json lines defaults to application/x-ndjson
+/// both write functions return false when the client is not keeping up, the script should wait before writing more
+fn set_stream_functions<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    stream_id: u64,
+) -> Result<(), JsError> {
+    let write = safe_function_create(
+        realm,
+        "write",
+        move |realm: &R, _this, args| {
+            let below_high_water_mark = match args.first() {
+                Some(chunk) if chunk.js_is_string() => {
+                    streaming::write(stream_id, Bytes::from(chunk.js_to_string()?))?
+                }
+                _ => return Err(JsError::new_str("write expects a string")),
+            };
+            realm.js_boolean_create(below_high_water_mark)
+        },
+        1,
+    )?;
+    realm.js_object_set_property(event_obj, "write", &write)?;
+    let write_json = safe_function_create(
+        realm,
+        "writeJson",
+        move |realm: &R, _this, args| {
+            let json = match args.first() {
+                Some(value) if value.js_get_type() != JsValueType::Undefined => {
+                    realm.js_json_stringify(value, None)?
+                }
+                _ => return Err(JsError::new_str("writeJson expects a value")),
+            };
+            realm.js_boolean_create(streaming::write_json(stream_id, json)?)
+        },
+        1,
+    )?;
+    realm.js_object_set_property(event_obj, "writeJson", &write_json)?;
+    let end = safe_function_create(
+        realm,
+        "end",
+        move |realm: &R, _this, _args| {
+            streaming::end(stream_id);
+            realm.js_undefined_create()
+        },
+        0,
+    )?;
+    realm.js_object_set_property(event_obj, "end", &end)
+}
+
+/// add the redirect(location, status) function to the event object
+/// it sets redirectLocation and responseStatus (302 when no status is given) on the event, status should be a 3xx
+fn set_redirect_function<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+) -> Result<(), JsError> {
+    let redirect = safe_function_create(
+        realm,
+        "redirect",
+        |realm: &R, this, args| {
+            let location = match args.first() {
+                Some(location) if location.js_is_string() => location.js_to_string()?,
+                _ => return Err(JsError::new_str("redirect expects a location")),
+            };
+            let status = match args.get(1) {
+                Some(status) if status.js_is_i32() => status.js_to_i32(),
+                Some(status) if !status.js_is_null_or_undefined() => {
+                    return Err(JsError::new_str("redirect expects a number as status"))
+                }
+                _ => 302,
+            };
+            if !(300..=399).contains(&status) {
+                return Err(JsError::new_string(format!(
+                    "invalid redirect status: {}",
+                    status
+                )));
+            }
+            if !this.js_is_object() {
+                return Err(JsError::new_str("redirect should be called on the event"));
+            }
+            realm.js_object_set_property(
+                this,
+                "redirectLocation",
+                &realm.js_string_create(location.as_str())?,
+            )?;
+            realm.js_object_set_property(this, "responseStatus", &realm.js_i32_create(status)?)?;
+            realm.js_undefined_create()
+        },
+        2,
+    )?;
+    realm.js_object_set_property(event_obj, "redirect", &redirect)
+}
+
+/// add the download(filename) function to the event object
+/// it sets responseDownload on the event, the response then gets a Content-Disposition which makes browsers save the
+/// body as filename instead of showing it, see content_disposition
+fn set_download_function<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+) -> Result<(), JsError> {
+    let download = safe_function_create(
+        realm,
+        "download",
+        |realm: &R, this, args| {
+            let filename = match args.first() {
+                Some(filename) if filename.js_is_string() => filename.js_to_string()?,
+                _ => return Err(JsError::new_str("download expects a filename")),
+            };
+            if !this.js_is_object() {
+                return Err(JsError::new_str("download should be called on the event"));
+            }
+            realm.js_object_set_property(
+                this,
+                "responseDownload",
+                &realm.js_string_create(filename.as_str())?,
+            )?;
+            realm.js_undefined_create()
+        },
+        1,
+    )?;
+    realm.js_object_set_property(event_obj, "download", &download)
+}
+
+/// add the cacheFor(seconds, key) function to the event object
+/// it sets responseCacheSeconds and responseCacheKey (the path when no key is given) on the event, the response of
+/// the GET request is then served from the response cache for that many seconds, see response_cache.rs
+fn set_cache_function<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    info: &RequestInfo,
+) -> Result<(), JsError> {
+    let is_get = info.method == "GET";
+    let path = info.path.clone();
+    let cache_for = safe_function_create(
+        realm,
+        "cacheFor",
+        move |realm: &R, this, args| {
+            if !is_get {
+                return Err(JsError::new_str(
+                    "cacheFor can only be used for GET requests",
+                ));
+            }
+            let seconds = match args.first() {
+                Some(seconds) if seconds.js_is_i32() => seconds.js_to_i32(),
+                _ => return Err(JsError::new_str("cacheFor expects a number of seconds")),
+            };
+            if !(1..=response_cache::MAX_TTL_SECS).contains(&seconds) {
+                return Err(JsError::new_string(format!(
+                    "cacheFor expects 1 to {} seconds, got {}",
+                    response_cache::MAX_TTL_SECS,
+                    seconds
+                )));
+            }
+            let key = match args.get(1) {
+                Some(key) if key.js_is_string() => key.js_to_string()?,
+                Some(key) if !key.js_is_null_or_undefined() => {
+                    return Err(JsError::new_str("cacheFor expects a string as key"))
+                }
+                _ => path.clone(),
+            };
+            if !this.js_is_object() {
+                return Err(JsError::new_str("cacheFor should be called on the event"));
+            }
+            realm.js_object_set_property(
+                this,
+                "responseCacheSeconds",
+                &realm.js_i32_create(seconds)?,
+            )?;
+            realm.js_object_set_property(
+                this,
+                "responseCacheKey",
+                &realm.js_string_create(key.as_str())?,
+            )?;
+            realm.js_undefined_create()
+        },
+        2,
+    )?;
+    realm.js_object_set_property(event_obj, "cacheFor", &cache_for)
+}
+
+/// add the after(callback) function to the event object, the callback runs after the response was sent, see
+/// deferred::DeferredJobs
+/// only the events of the request itself get it (not the events of an aggregate route, which may run in another
+/// runtime) so the callback is kept in the realm the request is dispatched in
+pub fn set_after_function<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    info: &RequestInfo,
+) -> Result<(), JsError> {
+    let request_id = info.request_id.clone();
+    let after = safe_function_create(
+        realm,
+        "after",
+        move |realm: &R, _this, args| {
+            match args.first() {
+                Some(callback) if callback.js_is_function() => {
+                    deferred::add(realm, request_id.as_str(), callback)?
+                }
+                _ => return Err(JsError::new_str("after expects a function")),
+            }
+            realm.js_undefined_create()
+        },
+        1,
+    )?;
+    realm.js_object_set_property(event_obj, "after", &after)
+}
+
+/// add the request body to the event object
+/// json bodies are parsed and added as event.body, all other bodies are added as a string as event.rawBody
+/// the body is decoded with the charset of the Content-Type like iso-8859-1, utf-8 is used when it has none
+/// urlencoded form bodies are also added decoded as event.form, repeated keys get an array of their values
+/// when a json or form body fails to parse we fall back to event.rawBody and set event.bodyParseError to true
+/// bodies which are not text, json or a form (like images) are also added as a Uint8Array as event.bodyBytes
+fn set_body<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    info: &RequestInfo,
+) -> Result<(), JsError> {
+    // multipart bodies are passed as event.files and event.fields
+    if info.body.is_empty() || info.content_type == "multipart/form-data" {
+        return Ok(());
+    }
+    // the body is at most SCRIPT_MAX_BODY bytes, the PayloadConfig of index rejects larger bodies
+    if !is_text_content_type(info.content_type.as_str()) {
+        let bytes = realm.js_typed_array_uint8_create(info.body.to_vec())?;
+        realm.js_object_set_property(event_obj, "bodyBytes", &bytes)?;
+    }
+    // invalid bytes are replaced like String::from_utf8_lossy does, a BOM overrides the charset
+    let (text, _, had_errors) = info.charset.decode(&info.body);
+    if had_errors {
+        log::debug!("the body is not valid {}", info.charset.name());
+    }
+    if info.content_type == "application/json" {
+        match realm.js_json_parse(&text) {
+            Ok(body) => {
+                return realm.js_object_set_property(event_obj, "body", &body);
+            }
+            Err(err) => {
+                log::debug!("could not parse json body: {}", err);
+                realm.js_object_set_property(
+                    event_obj,
+                    "bodyParseError",
+                    &realm.js_boolean_create(true)?,
+                )?;
+            }
+        }
+    }
+    if info.content_type == "application/x-www-form-urlencoded" {
+        // serde_urlencoded decodes the percent-encoding and + as space
+        match serde_urlencoded::from_str::<Vec<(String, String)>>(&text) {
+            Ok(form) => {
+                realm.js_object_set_property(event_obj, "form", &create_form_map(realm, &form)?)?;
+            }
+            Err(err) => {
+                log::debug!("could not parse form body: {}", err);
+                realm.js_object_set_property(
+                    event_obj,
+                    "bodyParseError",
+                    &realm.js_boolean_create(true)?,
+                )?;
+            }
+        }
+    }
+    realm.js_object_set_property(event_obj, "rawBody", &realm.js_string_create(&text)?)
+}
+
+// a request without content type is application/octet-stream
+fn is_text_content_type(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type == "application/json"
+        || content_type.ends_with("+json")
+        || content_type == "application/x-www-form-urlencoded"
+}
+
+/// add the files and fields of a multipart body as event.files and event.fields
+/// the file contents stay on the rust side, scripts save them with MyApp.saveUploadedFile(id, path)
+fn set_files<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    info: &RequestInfo,
+) -> Result<(), JsError> {
+    if info.content_type != "multipart/form-data" {
+        return Ok(());
+    }
+    let files = realm.js_array_create()?;
+    for (idx, file) in info.files.iter().enumerate() {
+        let file_obj = realm.js_object_create()?;
+        realm.js_object_set_property(&file_obj, "id", &realm.js_string_create(&file.id)?)?;
+        realm.js_object_set_property(&file_obj, "field", &realm.js_string_create(&file.field)?)?;
+        realm.js_object_set_property(
+            &file_obj,
+            "filename",
+            &realm.js_string_create(&file.filename)?,
+        )?;
+        let content_type = match &file.content_type {
+            Some(content_type) => realm.js_string_create(content_type)?,
+            None => realm.js_null_create()?,
+        };
+        realm.js_object_set_property(&file_obj, "contentType", &content_type)?;
+        realm.js_object_set_property(&file_obj, "size", &realm.js_i32_create(file.size as i32)?)?;
+        realm.js_array_set_element(&files, idx as u32, &file_obj)?;
+    }
+    realm.js_object_set_property(event_obj, "files", &files)?;
+    realm.js_object_set_property(
+        event_obj,
+        "fields",
+        &create_string_map(realm, &info.fields)?,
+    )
+}
+
+/// the response as set by the script on the event object
+#[derive(Default)]
+pub struct ScriptResponse {
+    // true when a listener vetoed the event, meaning the script fully handled the request
+    pub handled: bool,
+    pub status: Option<u16>,
+    pub body: Option<Bytes>,
+    pub content_type: Option<String>,
+    // set by event.redirect(), redirects have no body
+    pub location: Option<String>,
+    pub set_cookies: Vec<Cookie<'static>>,
+    // from event.responseHeaders, validated by read_headers, these replace headers we set like Content-Type
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    // set by the script as event.etag, when it matches If-None-Match we respond with a 304
+    pub etag: Option<EntityTag>,
+    // the modification time of event.responseFile, when it is not newer than If-Modified-Since we respond with a 304
+    pub last_modified: Option<SystemTime>,
+    // the Cache-Control for event.responseFile when the script did not set one in responseHeaders
+    pub cache_control: Option<String>,
+    // set by event.cacheFor(), the time to live and key of the response in the response cache
+    pub cache: Option<(Duration, String)>,
+    // the transactions of the request, the ones which were not committed are rolled back when the response is dropped
+    #[cfg(feature = "db")]
+    pub transactions: Option<crate::proxies::db::TransactionGuard>,
+    // the callbacks of event.after(), run when the response is dropped, before the isolated realm is removed
+    pub deferred: Option<DeferredJobs>,
+    // the realm the request was handled in when requests are isolated, removed when the response is dropped
+    pub isolated_realm: Option<IsolatedRealm>,
+}
+
+impl ScriptResponse {
+    /// read the responseStatus, the body (see read_body), responseContentType, redirectLocation, setCookies, responseHeaders,
+    /// responseDownload, etag and responseCache fields back from the event object after the listeners ran
+    pub fn read_from_event_obj<R: JsRealmAdapter>(
+        realm: &R,
+        event_obj: &R::JsValueAdapterType,
+    ) -> Result<Self, JsError> {
+        let mut response = Self::default();
+
+        if let Some(status) = get_i64_prop(realm, event_obj, "responseStatus")? {
+            response.status = Some(status_from_i64(status)?);
+        }
+        response.location = get_string_prop(realm, event_obj, "redirectLocation")?;
+        response.body = read_body(realm, event_obj, &mut response.content_type)?;
+        // an explicit content type overrides the default of the body field
+        if let Some(content_type) = get_string_prop(realm, event_obj, "responseContentType")? {
+            if content_type.parse::<mime::Mime>().is_err() {
+                return Err(JsError::new_string(format!(
+                    "invalid responseContentType: {}",
+                    content_type
+                )));
+            }
+            response.content_type = Some(content_type);
+        }
+
+        let headers = realm.js_object_get_property(event_obj, "responseHeaders")?;
+        if headers.js_is_object() {
+            response.headers = read_headers(realm, &headers)?;
+        } else if !headers.js_is_null_or_undefined() {
+            return Err(JsError::new_str("responseHeaders should be an object"));
+        }
+
+        // a Content-Disposition in responseHeaders takes precedence
+        if let Some(filename) = get_string_prop(realm, event_obj, "responseDownload")? {
+            if !response
+                .headers
+                .iter()
+                .any(|(name, _)| name == header::CONTENT_DISPOSITION)
+            {
+                response.headers.push((
+                    header::CONTENT_DISPOSITION,
+                    content_disposition(filename.as_str()),
+                ));
+            }
+        }
+
+        if let Some(path) = get_string_prop(realm, event_obj, "responseFile")? {
+            response.set_file(path.as_str())?;
+        }
+
+        // an explicit etag overrides the one of responseFile
+        if let Some(etag) = get_string_prop(realm, event_obj, "etag")? {
+            response.etag = Some(parse_etag(etag)?);
+        }
+
+        if let Some(seconds) = get_i64_prop(realm, event_obj, "responseCacheSeconds")? {
+            let seconds = seconds.clamp(1, response_cache::MAX_TTL_SECS as i64);
+            let key = get_string_prop(realm, event_obj, "responseCacheKey")?.unwrap_or_default();
+            response.cache = Some((Duration::from_secs(seconds as u64), key));
+        }
+
+        let set_cookies = realm.js_object_get_property(event_obj, "setCookies")?;
+        if set_cookies.js_is_array() {
+            for idx in 0..realm.js_array_get_length(&set_cookies)? {
+                let cookie = realm.js_array_get_element(&set_cookies, idx)?;
+                response.set_cookies.push(read_cookie(realm, &cookie)?);
+            }
+        } else if !set_cookies.js_is_null_or_undefined() {
+            return Err(JsError::new_str("setCookies should be an array"));
+        }
+
+        Ok(response)
+    }
+
+    /// respond with a file from SCRIPT_FILES_DIR, the etag and Last-Modified are derived from its metadata
+    fn set_file(&mut self, path: &str) -> Result<(), JsError> {
+        if self.body.is_some() {
+            return Err(JsError::new_str(
+                "only one of responseBody, responseJson, responseBytes and responseFile can be set",
+            ));
+        }
+        if !config::get().capabilities.fs {
+            return Err(JsError::new_str(
+                "responseFile needs the fs capability, see SCRIPT_CAPABILITIES",
+            ));
+        }
+        let file = files::read_static_file(path)?;
+        // weak as it is derived from the metadata, not the content
+        self.etag = Some(EntityTag::new_weak(format!(
+            "{:x}-{:x}",
+            file.data.len(),
+            unix_secs(file.modified)
+        )));
+        self.last_modified = Some(file.modified);
+        if self.content_type.is_none() {
+            self.content_type = Some(file.content_type);
+        }
+        if !self
+            .headers
+            .iter()
+            .any(|(name, _)| name == header::CACHE_CONTROL)
+        {
+            self.cache_control = Some(static_files::cache_control());
+        }
+        self.body = Some(Bytes::from(file.data));
+        Ok(())
+    }
+
+    /// true when the script did not veto the event and did not set a status, body or redirect
+    pub fn is_untouched(&self) -> bool {
+        !self.handled && self.status.is_none() && self.body.is_none() && self.location.is_none()
+    }
+
+    // html responses get the configured Content-Security-Policy unless the script set one in responseHeaders
+    fn default_csp(&self) -> Option<&'static str> {
+        let csp = config::get().csp.as_deref()?;
+        let is_html = match &self.content_type {
+            Some(content_type) => content_type
+                .parse::<mime::Mime>()
+                .map(|mime| mime.essence_str() == "text/html")
+                .unwrap_or(false),
+            None => false,
+        };
+        let has_csp = self
+            .headers
+            .iter()
+            .any(|(name, _value)| name == header::CONTENT_SECURITY_POLICY);
+        if is_html && !has_csp {
+            Some(csp)
+        } else {
+            None
+        }
+    }
+
+    fn response_builder(&mut self) -> HttpResponseBuilder {
+        let status = StatusCode::from_u16(self.status.unwrap_or(200))
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut builder = HttpResponse::build(status);
+        if let Some(csp) = self.default_csp() {
+            builder.insert_header((header::CONTENT_SECURITY_POLICY, csp));
+        }
+        if let Some(content_type) = self.content_type.take() {
+            builder.content_type(content_type);
+        }
+        for cookie in self.set_cookies.drain(..) {
+            builder.cookie(cookie);
+        }
+        for (name, value) in self.headers.drain(..) {
+            builder.insert_header((name, value));
+        }
+        builder
+    }
+
+    /// create the HttpResponse, when the script did not handle the request the default response is used for
+    /// everything the script did not set
+    /// when the script set an etag which matches the If-None-Match of the request the response is a 304 without body
+    pub fn into_http_response(mut self, req: &HttpRequest) -> HttpResponse {
+        let etag = self.etag.take();
+        let last_modified = self.last_modified.take();
+        let not_modified =
+            self.location.is_none() && self.is_not_modified(req, etag.as_ref(), last_modified);
+        if not_modified {
+            // the 304 keeps the headers of the response it replaces like Set-Cookie, Cache-Control and Vary but
+            // has no body so it has no Content-Type either
+            self.status = Some(StatusCode::NOT_MODIFIED.as_u16());
+            self.content_type = None;
+        }
+        let mut builder = self.response_builder();
+        if let Some(location) = self.location.take() {
+            let body = self.sent_body(Bytes::new());
+            return builder
+                .insert_header((header::LOCATION, location))
+                .body(body);
+        }
+        if let Some(etag) = etag {
+            builder.insert_header(header::ETag(etag));
+        }
+        if let Some(last_modified) = last_modified {
+            builder.insert_header(header::LastModified(last_modified.into()));
+        }
+        if let Some(cache_control) = self.cache_control.take() {
+            builder.insert_header((header::CACHE_CONTROL, cache_control));
+        }
+        if not_modified {
+            let body = self.sent_body(Bytes::new());
+            return builder.body(body);
+        }
+        let body = match self.body.take() {
+            Some(body) => body,
+            None if self.handled => Bytes::new(),
+            None => Bytes::from_static(b"hello there"),
+        };
+        if body.len() < COMPRESS_MIN_SIZE {
+            // the Compress middleware leaves responses which already have a Content-Encoding alone
+            builder.insert_header(ContentEncoding::Identity);
+        }
+        let body = self.sent_body(body);
+        builder.body(body)
+    }
+
+    // the deferred callbacks run and the isolated realm is removed once the body was sent
+    fn sent_body(&mut self, body: Bytes) -> SentBody {
+        SentBody {
+            body,
+            _keep_alive: (self.deferred.take(), self.isolated_realm.take()),
+        }
+    }
+
+    // only successful GET and HEAD responses can be not modified
+    fn is_not_modified(
+        &self,
+        req: &HttpRequest,
+        etag: Option<&EntityTag>,
+        last_modified: Option<SystemTime>,
+    ) -> bool {
+        let status = self.status.unwrap_or(200);
+        if !(200..300).contains(&status) || !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return false;
+        }
+        // If-Modified-Since is ignored when there is an If-None-Match
+        if req.headers().contains_key(header::IF_NONE_MATCH) {
+            let etag = match etag {
+                Some(etag) => etag,
+                None => return false,
+            };
+            return match header::IfNoneMatch::parse(req) {
+                Ok(header::IfNoneMatch::Any) => true,
+                // If-None-Match uses the weak comparison
+                Ok(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+                Err(_) => false,
+            };
+        }
+        // http dates have no sub second precision
+        match (last_modified, header::IfModifiedSince::parse(req)) {
+            (Some(modified), Ok(header::IfModifiedSince(since))) => {
+                unix_secs(modified) <= unix_secs(since.into())
+            }
+            _ => false,
+        }
+    }
+
+    /// create a HttpResponse which streams the chunks the script writes with event.write()
+    /// size counts the bytes which were sent, see metrics::StreamedSize
+    pub fn into_streaming_response(
+        mut self,
+        body: ResponseBody,
+        mut size: metrics::StreamedSize,
+    ) -> HttpResponse {
+        if self.content_type.is_none() && body.is_ndjson() {
+            self.content_type = Some("application/x-ndjson".to_string());
+        }
+        // the script may still write to the stream so the realm has to live as long as the stream, the deferred
+        // callbacks run and the uncommitted transactions are rolled back once it ended
+        let keep_alive = (self.deferred.take(), self.isolated_realm.take());
+        #[cfg(feature = "db")]
+        let transactions = self.transactions.take();
+        let stream = body.into_stream().map(move |chunk| {
+            let _keep_alive = &keep_alive;
+            #[cfg(feature = "db")]
+            let _transactions = &transactions;
+            if let Ok(bytes) = &chunk {
+                size.add(bytes.len());
+            }
+            chunk
+        });
+        // the encoder would buffer the chunks so streaming responses are never compressed
+        self.response_builder()
+            .insert_header(ContentEncoding::Identity)
+            .streaming(stream)
+    }
+}
+
+/// the body of a response which is not streamed, it holds on to what should only be dropped after the response was
+/// sent, which is when actix drops the body after passing its bytes to the connection
+struct SentBody {
+    body: Bytes,
+    _keep_alive: (Option<DeferredJobs>, Option<IsolatedRealm>),
+}
+
+impl MessageBody for SentBody {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        if self.body.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Ready(Some(Ok(std::mem::take(&mut self.body))))
+        }
+    }
+}
+
+/// set the return value of a route handler on the event, a string is the responseBody and other values are sent as
+/// json, when the handler returns nothing the response is whatever it set on the event
+///
+/// the response is read right after the handler returns so what an async handler resolves to can't be the response,
+/// an async handler streams its response instead: it calls event.write() before its first await and event.end()
+/// once done, when its promise rejects after that the stream fails
+/// a handler which returns a promise without having written fails the request
+pub fn set_handler_result<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    result: &R::JsValueAdapterType,
+    stream_id: u64,
+) -> Result<(), JsError> {
+    if result.js_is_null_or_undefined() {
+        Ok(())
+    } else if result.js_is_promise() {
+        if !streaming::is_started(stream_id) {
+            return Err(JsError::new_str(
+                "an async route handler has to call event.write() before it awaits, its response is read when it returns",
+            ));
+        }
+        fail_stream_on_rejection(realm, result, stream_id)
+    } else if result.js_is_string() {
+        realm.js_object_set_property(event_obj, "responseBody", result)
+    } else {
+        realm.js_object_set_property(event_obj, "responseJson", result)
+    }
+}
+
+// the rejection is handled here so it is logged as a failed handler instead of as an unhandled rejection
+fn fail_stream_on_rejection<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    promise: &R::JsValueAdapterType,
+    stream_id: u64,
+) -> Result<(), JsError> {
+    let on_rejected = safe_function_create(
+        realm,
+        "onRejected",
+        move |realm: &R, _this, args| {
+            let err = match args.first() {
+                Some(reason) if reason.js_is_object() => JsError::new(
+                    get_string_prop(realm, reason, "name")?.unwrap_or_else(|| "Error".to_string()),
+                    get_string_prop(realm, reason, "message")?.unwrap_or_default(),
+                    get_string_prop(realm, reason, "stack")?.unwrap_or_default(),
+                ),
+                Some(reason) => JsError::new_string(reason.js_to_string()?),
+                None => JsError::new_str("the route handler was rejected"),
+            };
+            errors::log_script_error("the route handler failed while streaming", &err);
+            streaming::fail(stream_id, &err);
+            realm.js_undefined_create()
+        },
+        1,
+    )?;
+    realm.js_promise_add_reactions(promise, None, Some(on_rejected), None)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// read the body from responseBody (a string), responseJson (any value, stringified) or responseBytes (a Uint8Array
+/// or a base64 string), only one of these may be set
+/// listener return values are not passed back by the EventTarget dispatch so the body is always set on the event
+fn read_body<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    content_type: &mut Option<String>,
+) -> Result<Option<Bytes>, JsError> {
+    let mut body = get_string_prop(realm, event_obj, "responseBody")?.map(Bytes::from);
+    if body.is_some() {
+        *content_type = Some("text/plain; charset=utf-8".to_string());
+    }
+
+    let json = realm.js_object_get_property(event_obj, "responseJson")?;
+    if json.js_get_type() != JsValueType::Undefined {
+        if body.is_some() {
+            return Err(JsError::new_str(
+                "only one of responseBody, responseJson and responseBytes can be set",
+            ));
+        }
+        // values which can't be stringified fail the request
+        body = Some(Bytes::from(realm.js_json_stringify(&json, None)?));
+        *content_type = Some("application/json".to_string());
+    }
+
+    let bytes = realm.js_object_get_property(event_obj, "responseBytes")?;
+    if !bytes.js_is_null_or_undefined() {
+        if body.is_some() {
+            return Err(JsError::new_str(
+                "only one of responseBody, responseJson and responseBytes can be set",
+            ));
+        }
+        let data = if bytes.js_is_typed_array() {
+            realm.js_typed_array_copy_buffer(&bytes)?
+        } else if bytes.js_is_string() {
+            base64::decode(bytes.js_to_string()?).map_err(|err| {
+                JsError::new_string(format!("invalid base64 in responseBytes: {}", err))
+            })?
+        } else {
+            return Err(JsError::new_str(
+                "responseBytes should be a Uint8Array or a base64 string",
+            ));
+        };
+        body = Some(Bytes::from(data));
+        *content_type = Some("application/octet-stream".to_string());
+    }
+    Ok(body)
+}
+
+/// the etag may be given as a plain value like a hash which is sent as a strong etag, or quoted like "abc" or W/"abc"
+fn parse_etag(etag: String) -> Result<EntityTag, JsError> {
+    if etag.ends_with('"') {
+        return etag
+            .parse::<EntityTag>()
+            .map_err(|_| JsError::new_string(format!("invalid etag: {}", etag)));
+    }
+    // the characters allowed in an etag, see RFC 7232
+    let valid = etag
+        .bytes()
+        .all(|c| c == b'\x21' || (b'\x23'..=b'\x7e').contains(&c) || c >= b'\x80');
+    if valid {
+        Ok(EntityTag::new_strong(etag))
+    } else {
+        Err(JsError::new_string(format!("invalid etag: {}", etag)))
+    }
+}
+
+fn get_string_prop<R: JsRealmAdapter>(
+    realm: &R,
+    obj: &R::JsValueAdapterType,
+    name: &str,
+) -> Result<Option<String>, JsError> {
+    let val = realm.js_object_get_property(obj, name)?;
+    if val.js_is_string() {
+        Ok(Some(val.js_to_string()?))
+    } else if val.js_is_null_or_undefined() {
+        Ok(None)
+    } else {
+        Err(JsError::new_string(format!("{} should be a string", name)))
+    }
+}
+
+fn get_i64_prop<R: JsRealmAdapter>(
+    realm: &R,
+    obj: &R::JsValueAdapterType,
+    name: &str,
+) -> Result<Option<i64>, JsError> {
+    let val = realm.js_object_get_property(obj, name)?;
+    if val.js_is_i32() {
+        Ok(Some(val.js_to_i32() as i64))
+    } else if val.js_is_f64() {
+        Ok(Some(val.js_to_f64() as i64))
+    } else if val.js_is_null_or_undefined() {
+        Ok(None)
+    } else {
+        Err(JsError::new_string(format!("{} should be a number", name)))
+    }
+}
+
+fn get_bool_prop<R: JsRealmAdapter>(
+    realm: &R,
+    obj: &R::JsValueAdapterType,
+    name: &str,
+) -> Result<bool, JsError> {
+    let val = realm.js_object_get_property(obj, name)?;
+    Ok(val.js_is_bool() && val.js_to_bool())
+}
+
+/// headers which describe the connection (hop-by-hop) or the framing of the body, actix sets these itself
+const FORBIDDEN_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+/// read the {name: value} responseHeaders object
+/// values containing a newline are rejected so a script passing on user input can't inject headers
+fn read_headers<R: JsRealmAdapter>(
+    realm: &R,
+    obj: &R::JsValueAdapterType,
+) -> Result<Vec<(HeaderName, HeaderValue)>, JsError> {
+    let mut headers = vec![];
+    for name in realm.js_object_get_properties(obj)? {
+        let value = realm
+            .js_object_get_property(obj, name.as_str())?
+            .js_to_string()?;
+        headers.push(validate_header(name.as_str(), value.as_str())?);
+    }
+    Ok(headers)
+}
+
+/// check a response header set by script, it should be valid and not one of the FORBIDDEN_HEADERS
+pub fn validate_header(name: &str, value: &str) -> Result<(HeaderName, HeaderValue), JsError> {
+    let header_name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| JsError::new_string(format!("invalid header name: {}", name)))?;
+    if FORBIDDEN_HEADERS.contains(&header_name.as_str()) {
+        return Err(JsError::new_string(format!(
+            "header {} can not be set from script",
+            name
+        )));
+    }
+    if value.contains('\r') || value.contains('\n') {
+        return Err(JsError::new_string(format!(
+            "value of header {} contains a newline",
+            name
+        )));
+    }
+    let header_value = HeaderValue::from_str(value)
+        .map_err(|_| JsError::new_string(format!("invalid value for header {}", name)))?;
+    Ok((header_name, header_value))
+}
+
+/// the Content-Disposition for event.download(filename), like attachment; filename="report.csv"
+/// the filename comes from the script which may have taken it from user input so only its last path segment is used,
+/// control characters are dropped and quotes are replaced to keep it within the quoted string
+/// a name which is not plain ascii gets an ascii fallback in filename and the full name in filename* (RFC 6266)
+pub fn content_disposition(filename: &str) -> HeaderValue {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    let name = if name.is_empty() || name == "." || name == ".." {
+        "download"
+    } else {
+        name
+    };
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() && c != '"' { c } else { '_' })
+        .collect();
+    let mut value = format!("attachment; filename=\"{}\"", fallback);
+    if fallback != name {
+        value.push_str("; filename*=UTF-8''");
+        for byte in name.bytes() {
+            // the attr-chars of RFC 5987 are passed as is, everything else is percent encoded
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                value.push(byte as char);
+            } else {
+                value.push_str(format!("%{:02X}", byte).as_str());
+            }
+        }
+    }
+    // only visible ascii and spaces are left
+    HeaderValue::from_str(value.as_str()).expect("a sanitized content disposition")
+}
+
+/// read a {name, value, path, domain, maxAge, httpOnly, secure, sameSite} object from setCookies
+fn read_cookie<R: JsRealmAdapter>(
+    realm: &R,
+    obj: &R::JsValueAdapterType,
+) -> Result<Cookie<'static>, JsError> {
+    let name = get_string_prop(realm, obj, "name")?
+        .ok_or_else(|| JsError::new_str("cookie should have a name"))?;
+    let value = get_string_prop(realm, obj, "value")?.unwrap_or_default();
+    let mut cookie = Cookie::new(name, value);
+    if let Some(path) = get_string_prop(realm, obj, "path")? {
+        cookie.set_path(path);
+    }
+    if let Some(domain) = get_string_prop(realm, obj, "domain")? {
+        cookie.set_domain(domain);
+    }
+    if let Some(max_age) = get_i64_prop(realm, obj, "maxAge")? {
+        cookie.set_max_age(actix_web::cookie::time::Duration::seconds(max_age));
+    }
+    cookie.set_http_only(get_bool_prop(realm, obj, "httpOnly")?);
+    cookie.set_secure(get_bool_prop(realm, obj, "secure")?);
+    if let Some(same_site) = get_string_prop(realm, obj, "sameSite")? {
+        cookie.set_same_site(match same_site.to_lowercase().as_str() {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            _ => {
+                return Err(JsError::new_string(format!(
+                    "invalid sameSite: {}",
+                    same_site
+                )))
+            }
+        });
+    }
+    Ok(cookie)
+}
+
+fn status_from_i64(status: i64) -> Result<u16, JsError> {
+    if (100..=999).contains(&status) {
+        Ok(status as u16)
+    } else {
+        Err(JsError::new_string(format!(
+            "invalid responseStatus: {}",
+            status
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use hirofa_utils::js_utils::Script;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    #[test]
+    fn the_query_and_the_headers_are_copied() {
+        config::init_for_tests();
+        let req = TestRequest::get()
+            .uri("/search?q=rust&page=2")
+            .append_header(("accept", "text/html"))
+            .append_header(("accept", "application/json"))
+            .to_http_request();
+        let info = RequestInfo::from_http_request(&req, Bytes::new(), "id".to_string(), 0);
+        assert_eq!(info.method, "GET");
+        assert_eq!(info.path, "/search");
+        assert_eq!(
+            info.query,
+            vec![
+                ("q".to_string(), "rust".to_string()),
+                ("page".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!(
+            info.headers,
+            vec![(
+                "accept".to_string(),
+                "text/html, application/json".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn the_forwarded_for_header_is_not_trusted_by_default() {
+        config::init_for_tests();
+        let req = TestRequest::get()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .append_header(("x-forwarded-for", "6.6.6.6"))
+            .to_http_request();
+        let info = RequestInfo::from_http_request(&req, Bytes::new(), "id".to_string(), 0);
+        assert_eq!(info.remote_addr, "10.0.0.1");
+        assert_eq!(info.real_ip, "10.0.0.1");
+    }
+
+    #[actix_web::test]
+    async fn the_script_sets_the_status_and_body() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "status-and-body") {
+                    evt.responseStatus = 201;
+                    evt.responseBody = "created";
+                }
+            });"#,
+        );
+        let req = TestRequest::get().insert_header(("x-test", "status-and-body"));
+        let (status, _, body) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, "created");
+    }
+
+    #[actix_web::test]
+    async fn json_bodies_are_parsed_and_other_bodies_passed_as_text() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "json-body") {
+                    evt.responseBody = JSON.stringify([evt.body, evt.rawBody, evt.bodyParseError]);
+                }
+            });"#,
+        );
+        for (content_type, payload, expected) in [
+            ("application/json", r#"{"a":1}"#, r#"[{"a":1},null,null]"#),
+            ("application/json", r#"{"a":"#, r#"[null,"{\"a\":",true]"#),
+            ("text/plain", "hi", r#"[null,"hi",null]"#),
+        ] {
+            let req = TestRequest::post()
+                .insert_header(("x-test", "json-body"))
+                .insert_header(("content-type", content_type))
+                .set_payload(payload);
+            let (_, _, body) = crate::tests::call(req).await;
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn cookies_are_read_and_set() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "cookies") {
+                    evt.responseBody = evt.cookies.theme;
+                    evt.setCookies = [{name: "seen", value: "1", httpOnly: true, maxAge: 60}];
+                }
+            });"#,
+        );
+        let req = TestRequest::get()
+            .insert_header(("x-test", "cookies"))
+            .insert_header(("cookie", "session=abc; theme=dark"));
+        let (_, headers, body) = crate::tests::call(req).await;
+        assert_eq!(body, "dark");
+        let set_cookie = headers.get("set-cookie").unwrap().to_str().unwrap();
+        assert!(set_cookie.starts_with("seen=1"));
+        assert!(set_cookie.contains("HttpOnly"));
+        assert!(set_cookie.contains("Max-Age=60"));
+    }
+
+    #[actix_web::test]
+    async fn the_response_is_streamed_until_end_is_called() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "stream") {
+                    evt.write("first ");
+                    setTimeout(() => {
+                        evt.write("second");
+                        evt.end();
+                    }, 10);
+                }
+            });"#,
+        );
+        let req = TestRequest::get().insert_header(("x-test", "stream"));
+        let (status, _, body) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "first second");
+    }
+
+    #[actix_web::test]
+    async fn the_response_json_is_sent_as_json() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "response-json") {
+                    evt.responseJson = {message: "hi", count: 1};
+                    if (evt.query.both) {
+                        evt.responseBody = "hi";
+                    }
+                }
+            });"#,
+        );
+        let req = TestRequest::get().insert_header(("x-test", "response-json"));
+        let (status, headers, body) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get("content-type").unwrap(), "application/json");
+        assert_eq!(body, r#"{"message":"hi","count":1}"#);
+
+        let req = TestRequest::get()
+            .uri("/?both=1")
+            .insert_header(("x-test", "response-json"));
+        let (status, _, _) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn redirect_sets_the_location_and_a_3xx_status() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "redirect") {
+                    evt.responseBody = "ignored";
+                    evt.redirect("/login", evt.query.status === undefined ? undefined : parseInt(evt.query.status));
+                }
+            });"#,
+        );
+        for (uri, expected) in [
+            ("/", StatusCode::FOUND),
+            ("/?status=301", StatusCode::MOVED_PERMANENTLY),
+            ("/?status=200", StatusCode::INTERNAL_SERVER_ERROR),
+        ] {
+            let req = TestRequest::get()
+                .uri(uri)
+                .insert_header(("x-test", "redirect"));
+            let (status, headers, body) = crate::tests::call(req).await;
+            assert_eq!(status, expected);
+            if status.is_redirection() {
+                assert_eq!(headers.get("location").unwrap(), "/login");
+                assert!(body.is_empty());
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn response_bytes_are_sent_as_binary() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "response-bytes") {
+                    if (evt.query.base64) {
+                        evt.responseBytes = "AP8=";
+                        evt.responseContentType = "image/png";
+                    } else {
+                        evt.responseBytes = new Uint8Array([0, 255]);
+                    }
+                }
+            });"#,
+        );
+        for (uri, content_type) in [
+            ("/", "application/octet-stream"),
+            ("/?base64=1", "image/png"),
+        ] {
+            let req = TestRequest::get()
+                .uri(uri)
+                .insert_header(("x-test", "response-bytes"));
+            let (_, headers, body) = crate::tests::call(req).await;
+            assert_eq!(headers.get("content-type").unwrap(), content_type);
+            assert_eq!(body, &[0u8, 255][..]);
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_path_parameters_are_passed_as_params() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request:/users/{id}", (evt) => {
+                if (evt.headers["x-test"] === "params") {
+                    evt.responseBody = evt.route + " " + evt.params.id;
+                }
+            });"#,
+        );
+        let req = TestRequest::get()
+            .uri("/users/42")
+            .insert_header(("x-test", "params"));
+        let (_, _, body) = crate::tests::call(req).await;
+        assert_eq!(body, "/users/{id} 42");
+    }
+
+    #[actix_web::test]
+    async fn repeated_query_keys_are_in_query_all() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "query-all") {
+                    evt.responseBody = [evt.query.a, evt.queryAll.a.join("+"), evt.queryString].join(" ");
+                }
+            });"#,
+        );
+        let req = TestRequest::get()
+            .uri("/?a=1&b=x&a=2")
+            .insert_header(("x-test", "query-all"));
+        let (_, _, body) = crate::tests::call(req).await;
+        assert_eq!(body, "2 1+2 a=1&b=x&a=2");
+    }
+
+    #[actix_web::test]
+    async fn a_response_body_is_plain_text_unless_the_content_type_is_set() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "content-type") {
+                    evt.responseBody = "<p>hi</p>";
+                    if (evt.query.type) {
+                        evt.responseContentType = evt.query.type;
+                    }
+                }
+            });"#,
+        );
+        let content_type = |uri: &str| {
+            let req = TestRequest::get()
+                .uri(uri)
+                .insert_header(("x-test", "content-type"));
+            async {
+                let (status, headers, _) = crate::tests::call(req).await;
+                (status, headers.get(header::CONTENT_TYPE).cloned())
+            }
+        };
+        assert_eq!(
+            content_type("/").await,
+            (
+                StatusCode::OK,
+                Some(HeaderValue::from_static("text/plain; charset=utf-8"))
+            )
+        );
+        assert_eq!(
+            content_type("/?type=text/html").await,
+            (StatusCode::OK, Some(HeaderValue::from_static("text/html")))
+        );
+        let (status, _) = content_type("/?type=not%20a%20type").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn form_bodies_are_decoded_into_form() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "form") {
+                    evt.responseJson = evt.form;
+                }
+            });"#,
+        );
+        let req = TestRequest::post()
+            .insert_header(("x-test", "form"))
+            .insert_header((header::CONTENT_TYPE, "application/x-www-form-urlencoded"))
+            .set_payload("name=J+Doe&tag=a&tag=b%26c");
+        let (_, _, body) = crate::tests::call(req).await;
+        assert_eq!(body, r#"{"name":"J Doe","tag":["a","b&c"]}"#);
+    }
+
+    #[actix_web::test]
+    async fn write_json_streams_json_lines() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "ndjson") {
+                    evt.writeJson({id: 1});
+                    evt.writeJson("two");
+                    evt.end();
+                }
+            });"#,
+        );
+        let req = TestRequest::get().insert_header(("x-test", "ndjson"));
+        let (status, headers, body) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get("content-type").unwrap(), "application/x-ndjson");
+        assert_eq!(body, "{\"id\":1}\n\"two\"\n");
+    }
+
+    #[actix_web::test]
+    async fn binary_bodies_are_passed_as_bytes() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "body-bytes") {
+                    evt.responseBody = evt.bodyBytes ? evt.bodyBytes.join() : "no bytes";
+                }
+            });"#,
+        );
+        for (content_type, expected) in [
+            ("image/png", "0,1,127"),
+            ("application/vnd.api+json", "no bytes"),
+            ("text/plain", "no bytes"),
+        ] {
+            let req = TestRequest::post()
+                .insert_header(("x-test", "body-bytes"))
+                .insert_header(("content-type", content_type))
+                .set_payload(vec![0u8, 1, 127]);
+            let (_, _, body) = crate::tests::call(req).await;
+            assert_eq!(body, expected, "{}", content_type);
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_large_bodies_are_compressed() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "compress") {
+                    evt.responseBody = "x".repeat(parseInt(evt.query.size));
+                }
+            });"#,
+        );
+        for (size, expected) in [(10, "identity"), (10_000, "gzip")] {
+            let req = TestRequest::get()
+                .uri(format!("/?size={}", size).as_str())
+                .insert_header(("x-test", "compress"))
+                .insert_header(("accept-encoding", "gzip"));
+            let (_, headers, body) = crate::tests::call(req).await;
+            assert_eq!(headers.get("content-encoding").unwrap(), expected);
+            assert_eq!(body.len() < size, expected == "gzip");
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_matching_etag_is_not_modified() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "etag") {
+                    evt.etag = "v1";
+                    evt.responseBody = "content";
+                }
+            });"#,
+        );
+        for (if_none_match, expected) in [
+            (None, StatusCode::OK),
+            (Some(r#""v0""#), StatusCode::OK),
+            (Some(r#"W/"v1""#), StatusCode::NOT_MODIFIED),
+            (Some("*"), StatusCode::NOT_MODIFIED),
+        ] {
+            let mut req = TestRequest::get().insert_header(("x-test", "etag"));
+            if let Some(if_none_match) = if_none_match {
+                req = req.insert_header(("if-none-match", if_none_match));
+            }
+            let (status, headers, body) = crate::tests::call(req).await;
+            assert_eq!(status, expected);
+            assert_eq!(headers.get("etag").unwrap(), r#""v1""#);
+            assert_eq!(body.is_empty(), status == StatusCode::NOT_MODIFIED);
+        }
+        assert!(parse_etag("in valid".to_string()).is_err());
+    }
+
+    #[test]
+    fn a_file_which_was_not_modified_since_is_not_modified() {
+        config::init_for_tests();
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        let status = |since_secs: u64| {
+            let since = UNIX_EPOCH + std::time::Duration::from_secs(since_secs);
+            let req = TestRequest::get()
+                .insert_header(header::IfModifiedSince(since.into()))
+                .to_http_request();
+            let response = ScriptResponse {
+                handled: true,
+                body: Some(Bytes::from_static(b"file")),
+                last_modified: Some(modified),
+                ..ScriptResponse::default()
+            };
+            response.into_http_response(&req).status()
+        };
+        assert_eq!(status(1_000_000), StatusCode::NOT_MODIFIED);
+        assert_eq!(status(1_000_001), StatusCode::NOT_MODIFIED);
+        assert_eq!(status(999_999), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn the_response_headers_are_set_unless_they_are_unsafe() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "response-headers") {
+                    const headers = {"x-custom": "1", "content-type": "text/csv"};
+                    if (evt.query.add) {
+                        headers[evt.query.add] = evt.query.value;
+                    }
+                    evt.responseHeaders = headers;
+                }
+            });"#,
+        );
+        let req = TestRequest::get().insert_header(("x-test", "response-headers"));
+        let (status, headers, _) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get("x-custom").unwrap(), "1");
+        assert_eq!(headers.get("content-type").unwrap(), "text/csv");
+
+        for query in [
+            "add=x-injected&value=a%0D%0Aset-cookie:%20b",
+            "add=connection&value=close",
+        ] {
+            let req = TestRequest::get()
+                .uri(format!("/?{}", query).as_str())
+                .insert_header(("x-test", "response-headers"));
+            let (status, _, _) = crate::tests::call(req).await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    #[test]
+    fn a_not_modified_response_keeps_the_headers() {
+        config::init_for_tests();
+        let etag = EntityTag::new_strong("v1".to_string());
+        let response = ScriptResponse {
+            handled: true,
+            body: Some(Bytes::from_static(b"{}")),
+            content_type: Some("application/json".to_string()),
+            set_cookies: vec![Cookie::new("session", "abc")],
+            headers: vec![(header::VARY, HeaderValue::from_static("Accept-Language"))],
+            etag: Some(etag.clone()),
+            cache_control: Some("max-age=60".to_string()),
+            ..Default::default()
+        };
+        let req = TestRequest::get()
+            .insert_header(header::IfNoneMatch::Items(vec![etag]))
+            .to_http_request();
+
+        let res = response.into_http_response(&req);
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        let headers = res.headers();
+        assert!(headers.contains_key(header::SET_COOKIE));
+        assert_eq!(headers.get(header::VARY).unwrap(), "Accept-Language");
+        assert_eq!(headers.get(header::CACHE_CONTROL).unwrap(), "max-age=60");
+        assert_eq!(headers.get(header::ETAG).unwrap(), "\"v1\"");
+        assert!(!headers.contains_key(header::CONTENT_TYPE));
+    }
+
+    // set the promise the script evaluates to as the result of a handler, before and after it wrote to the stream of
+    // the request, and return whether that was accepted and whether the stream is still open once the promise settled
+    fn set_async_result(script: &'static str) -> (bool, bool, bool) {
+        let rt = QuickJsRuntimeBuilder::new().build();
+        let (stream_id, _body) = streaming::open();
+        let (before_write, after_write) = rt.js_loop_realm_sync(None, move |_rt, realm| {
+            let event_obj = realm.js_object_create().ok().unwrap();
+            let promise = realm
+                .js_eval(Script::new("file://handler.js", script))
+                .ok()
+                .unwrap();
+            let before_write = set_handler_result(realm, &event_obj, &promise, stream_id).is_ok();
+            assert!(streaming::write(stream_id, Bytes::from_static(b"first")).is_ok());
+            let after_write = set_handler_result(realm, &event_obj, &promise, stream_id).is_ok();
+            (before_write, after_write)
+        });
+        rt.js_loop_sync(|_rt| ());
+        let open = streaming::write(stream_id, Bytes::from_static(b"second")).is_ok();
+        (before_write, after_write, open)
+    }
+
+    #[test]
+    fn an_async_handler_has_to_stream_its_response() {
+        assert_eq!(
+            set_async_result("Promise.resolve('ignored')"),
+            (false, true, true)
+        );
+    }
+
+    #[test]
+    fn a_rejected_async_handler_fails_its_stream() {
+        assert_eq!(
+            set_async_result("Promise.reject(new Error('failed'))"),
+            (false, true, false)
+        );
+    }
+
+    #[actix_web::test]
+    async fn a_download_gets_a_sanitized_content_disposition() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "download") {
+                    evt.responseBody = "id,name";
+                    evt.download(evt.query.name);
+                }
+            });"#,
+        );
+        for (name, expected) in [
+            ("report.csv", "attachment; filename=\"report.csv\""),
+            ("../../etc/passwd", "attachment; filename=\"passwd\""),
+            (
+                "a\"b.csv",
+                "attachment; filename=\"a_b.csv\"; filename*=UTF-8''a%22b.csv",
+            ),
+            (
+                "r\u{e9}sum\u{e9}.pdf",
+                "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf",
+            ),
+            ("..", "attachment; filename=\"download\""),
+        ] {
+            let query = serde_urlencoded::to_string([("name", name)]).unwrap();
+            let uri = format!("/?{}", query);
+            let req = TestRequest::get()
+                .uri(uri.as_str())
+                .insert_header(("x-test", "download"));
+            let (_, headers, body) = crate::tests::call(req).await;
+            assert_eq!(body, "id,name");
+            assert_eq!(headers.get("content-disposition").unwrap(), expected);
+        }
+        // a header injection only leaves the visible characters
+        assert_eq!(
+            content_disposition("a.csv\r\nx-injected: yes"),
+            "attachment; filename=\"a.csvx-injected: yes\""
+        );
+    }
+
+    #[actix_web::test]
+    async fn a_body_is_decoded_with_the_charset_of_its_content_type() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "charset") {
+                    evt.responseBody = evt.rawBody;
+                }
+            });"#,
+        );
+        for (content_type, expected) in [
+            (
+                "text/plain; charset=iso-8859-1",
+                "caf\u{e9} \u{e0} la cr\u{e8}me",
+            ),
+            // the bytes which are not utf-8 are replaced when there is no or an unknown charset
+            ("text/plain", "caf\u{fffd} \u{fffd} la cr\u{fffd}me"),
+            (
+                "text/plain; charset=no-such-charset",
+                "caf\u{fffd} \u{fffd} la cr\u{fffd}me",
+            ),
+        ] {
+            let req = TestRequest::post()
+                .insert_header(("x-test", "charset"))
+                .insert_header(("content-type", content_type))
+                .set_payload(b"caf\xe9 \xe0 la cr\xe8me".to_vec());
+            let (_, _, body) = crate::tests::call(req).await;
+            assert_eq!(body, expected, "{}", content_type);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    // shared by all runtimes and realms, unknown flags are disabled
+    static ref FLAGS: Mutex<HashMap<String, bool>> = Mutex::new(HashMap::new());
+}
+
+/// set the flags from the config, called once at startup
+pub fn init(flags: &[(String, bool)]) {
+    let mut map = FLAGS.lock().unwrap();
+    for (name, enabled) in flags {
+        map.insert(name.clone(), *enabled);
+    }
+}
+
+/// true when the flag exists and is enabled
+pub fn is_enabled(name: &str) -> bool {
+    FLAGS.lock().unwrap().get(name).copied().unwrap_or(false)
+}
+
+/// enable or disable a flag, flags which did not exist yet are added
+/// returns the previous state, None for a new flag
+pub fn set(name: &str, enabled: bool) -> Option<bool> {
+    FLAGS.lock().unwrap().insert(name.to_string(), enabled)
+}
+
+/// all flags ordered by name
+pub fn all() -> Vec<(String, bool)> {
+    let mut flags: Vec<(String, bool)> = FLAGS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, enabled)| (name.clone(), *enabled))
+        .collect();
+    flags.sort();
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_flags_are_disabled_until_they_are_set() {
+        assert!(!is_enabled("flags-test"));
+        assert_eq!(set("flags-test", true), None);
+        assert!(is_enabled("flags-test"));
+        assert_eq!(set("flags-test", false), Some(true));
+        assert!(!is_enabled("flags-test"));
+        assert!(all().contains(&("flags-test".to_string(), false)));
+    }
+}
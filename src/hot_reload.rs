@@ -0,0 +1,156 @@
+use crate::proxies::panic_message;
+use crate::tasks::TASK_RT;
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use hirofa_utils::js_utils::Script;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::panic::catch_unwind;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+pub const HOT_RELOAD_VAR: &str = "SCRIPT_HOT_RELOAD";
+
+// rapid successive writes (e.g. an editor saving a file) are collapsed into a single event
+const DEBOUNCE: Duration = Duration::from_millis(250);
+// how long to wait before watching again after the watcher failed
+const RETRY: Duration = Duration::from_secs(5);
+
+/// hot reloading is only compiled in debug builds and can be disabled by setting SCRIPT_HOT_RELOAD=0
+pub fn enabled() -> bool {
+    std::env::var(HOT_RELOAD_VAR)
+        .map(|val| val != "0")
+        .unwrap_or(true)
+}
+
+/// the path we evaluate a changed module as
+/// quickjs caches modules by path so we need a new path for every reload, we keep the extension so the typescript
+/// preprocessor still recognizes the module
+fn reload_path(path: &Path, reload: usize) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}.reload-{}.{}", stem, reload, ext.to_string_lossy()),
+        None => format!("{}.reload-{}", stem, reload),
+    };
+    format!("file://{}", path.with_file_name(file_name).display())
+}
+
+/// re-evaluate a changed module in every runtime in the pool, the proxies are reinstalled first so globals the
+/// previous version of the module replaced don't stick around
+/// a module which fails to evaluate is logged and leaves the previous version of the module live
+fn reload(path: &Path, reload: usize) {
+    let code = match std::fs::read_to_string(path) {
+        Ok(code) => code,
+        Err(err) => {
+            log::error!("could not read {}: {}", path.display(), err);
+            return;
+        }
+    };
+    let script_path = reload_path(path, reload);
+    log::info!("reloading {} as {}", path.display(), script_path);
+    for (pool_idx, rt) in crate::script_pool().runtimes().iter().enumerate() {
+        if let Err(err) = rt.js_loop_realm_sync(None, move |_rt, realm| {
+            crate::reinstall_proxies(realm, pool_idx)
+        }) {
+            log::error!("reloading {} failed: {}", path.display(), err);
+            return;
+        }
+        let script = Script::new(script_path.as_str(), code.as_str());
+        if let Err(err) = TASK_RT.block_on(rt.js_eval_module(None, script)) {
+            log::error!("reloading {} failed: {}", path.display(), err);
+            return;
+        }
+    }
+}
+
+// whether a changed file is a module we reload, other files like the .d.ts we write or editor swap files are not
+fn is_module(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    (name.ends_with(".ts") || name.ends_with(".js")) && !name.ends_with(".d.ts")
+}
+
+/// watch a dir for changed .ts and .js modules and reload them in the background
+/// when the watcher fails (e.g. the dir does not exist yet) that is logged and the dir is watched again after a while
+pub fn watch<P: Into<PathBuf>>(dir: P) {
+    let dir = dir.into();
+    std::thread::Builder::new()
+        .name("hot-reload".to_string())
+        .spawn(move || {
+            let mut reloads = 0;
+            loop {
+                if let Err(err) = watch_dir(&dir, &mut reloads) {
+                    log::error!(
+                        "could not watch {}, retrying in {}s: {}",
+                        dir.display(),
+                        RETRY.as_secs(),
+                        err
+                    );
+                }
+                std::thread::sleep(RETRY);
+            }
+        })
+        .expect("could not start hot reload thread");
+}
+
+// reload the changed modules until the watcher fails
+fn watch_dir(dir: &Path, reloads: &mut usize) -> notify::Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, DEBOUNCE)?;
+    watcher.watch(dir, RecursiveMode::Recursive)?;
+    log::info!("watching {} for changes", dir.display());
+    for event in rx {
+        let path = match event {
+            // editors which save by renaming a temp file over the module cause a rename
+            DebouncedEvent::Write(path)
+            | DebouncedEvent::Create(path)
+            | DebouncedEvent::Rename(_, path) => path,
+            DebouncedEvent::Error(err, path) => {
+                log::error!("file watcher error for {:?}: {}", path, err);
+                continue;
+            }
+            _ => continue,
+        };
+        if !is_module(&path) {
+            continue;
+        }
+        *reloads += 1;
+        let reload_nr = *reloads;
+        // a reload which panics should not stop the watching
+        if let Err(panic) = catch_unwind(|| reload(&path, reload_nr)) {
+            log::error!(
+                "reloading {} panicked: {}",
+                path.display(),
+                panic_message(panic.as_ref())
+            );
+        }
+    }
+    Err(notify::Error::Generic("the watcher stopped".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_reload_gets_a_path_of_its_own() {
+        assert_eq!(
+            reload_path(Path::new("./modules/lib/util.ts"), 3),
+            "file://./modules/lib/util.reload-3.ts"
+        );
+        assert_eq!(
+            reload_path(Path::new("./modules/Makefile"), 1),
+            "file://./modules/Makefile.reload-1"
+        );
+    }
+
+    #[test]
+    fn only_modules_are_reloaded() {
+        assert!(is_module(Path::new("./scripts/main.ts")));
+        assert!(is_module(Path::new("./scripts/lib/util.js")));
+        assert!(!is_module(Path::new("./scripts/myapp.d.ts")));
+        assert!(!is_module(Path::new("./scripts/.main.ts.swp")));
+        assert!(!is_module(Path::new("./scripts/data.json")));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::config;
+use crate::tasks::TASK_RT;
+use green_copper_runtime::moduleloaders::HttpModuleLoader;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::modules::ScriptModuleLoader;
+use lazy_static::lazy_static;
+use std::time::Duration;
+
+lazy_static! {
+    // the HttpModuleLoader only checks the url of the import, the redirects of a load are checked by the client
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .https_only(true)
+        .redirect(config::allowed_redirects())
+        .build()
+        .expect("could not create the module client");
+}
+
+/// loads modules over http like the HttpModuleLoader but retries transient failures
+///
+/// the HttpModuleLoader decides which paths are loaded (the allowed domains, https only), we do the loading so we
+/// can tell a 404 from a network error, network errors and 5xx responses are retried with an exponential backoff
+/// starting at SCRIPT_MODULE_RETRY_DELAY_MS, other responses are not retried
+pub struct RetryingHttpModuleLoader {
+    inner: HttpModuleLoader,
+    attempts: u32,
+    base_delay: Duration,
+}
+
+enum LoadError {
+    Transient(String),
+    Permanent(String),
+}
+
+impl RetryingHttpModuleLoader {
+    pub fn new(inner: HttpModuleLoader) -> Self {
+        let config = config::get();
+        Self {
+            inner,
+            attempts: config.module_retries,
+            base_delay: config.module_retry_delay,
+        }
+    }
+
+    fn fetch(&self, url: &str) -> Result<String, LoadError> {
+        // modules are loaded on the worker thread of the runtime which is not a tokio thread
+        TASK_RT.block_on(async {
+            let response = CLIENT
+                .get(url)
+                .send()
+                .await
+                .map_err(|err| LoadError::Transient(err.to_string()))?;
+            let status = response.status();
+            if status.is_server_error() {
+                return Err(LoadError::Transient(format!("status {}", status)));
+            }
+            if !status.is_success() {
+                return Err(LoadError::Permanent(format!("status {}", status)));
+            }
+            response
+                .text()
+                .await
+                .map_err(|err| LoadError::Transient(err.to_string()))
+        })
+    }
+}
+
+impl<R: JsRealmAdapter> ScriptModuleLoader<R> for RetryingHttpModuleLoader {
+    fn normalize_path(&self, realm: &R, ref_path: &str, path: &str) -> Option<String> {
+        self.inner.normalize_path(realm, ref_path, path)
+    }
+
+    fn load_module(&self, _realm: &R, absolute_path: &str) -> String {
+        let mut attempt = 1;
+        let msg = loop {
+            match self.fetch(absolute_path) {
+                Ok(code) => return code,
+                Err(LoadError::Permanent(msg)) => break msg,
+                Err(LoadError::Transient(msg)) if attempt >= self.attempts => break msg,
+                Err(LoadError::Transient(msg)) => {
+                    let delay = self.base_delay * 2u32.pow(attempt - 1);
+                    log::warn!(
+                        "loading module {} failed ({}), attempt {} of {}, retrying in {:?}",
+                        absolute_path,
+                        msg,
+                        attempt,
+                        self.attempts,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        };
+        log::error!("could not load module {}: {}", absolute_path, msg);
+        // the loader can't fail so the module throws, that makes the import fail with this message
+        format!(
+            "throw new Error({});",
+            serde_json::Value::from(format!("could not load module {}: {}", absolute_path, msg))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn a_module_which_can_not_be_loaded_throws_after_the_retries() {
+        let loader = RetryingHttpModuleLoader {
+            inner: HttpModuleLoader::new(),
+            attempts: 3,
+            base_delay: Duration::from_millis(10),
+        };
+        let start = Instant::now();
+        let code = crate::tests::with_realm(move |realm| {
+            loader.load_module(realm, "https://127.0.0.1:1/module.js")
+        });
+        assert!(code.starts_with(
+            r#"throw new Error("could not load module https://127.0.0.1:1/module.js: "#
+        ));
+        // retried after 10 and 20ms
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::event::ScriptResponse;
+use crate::{config, tenants};
+use actix_web::http::header::{self, EntityTag, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use tokio::sync::watch;
+
+/// the request header with the key of a POST which may be retried
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// the max length of a key, a uuid is 36
+const MAX_KEY_LENGTH: usize = 255;
+/// the max number of keys which are remembered, new keys are not remembered when it is full until entries expire
+pub const MAX_KEYS: usize = 10000;
+
+// the tenant (empty without), the path, the key and the credentials of the request, the Authorization and Cookie
+// headers, so a key only matches the requests of the client which used it
+type Key = (String, String, String, String);
+
+enum Entry {
+    // the first request with the key is being handled, the others wait until done is dropped
+    Pending {
+        id: u64,
+        done: watch::Receiver<()>,
+        body_hash: u64,
+    },
+    Done {
+        expires: Instant,
+        response: StoredResponse,
+        body_hash: u64,
+    },
+}
+
+/// the parts of a ScriptResponse we need to respond again
+struct StoredResponse {
+    handled: bool,
+    status: Option<u16>,
+    body: Option<Bytes>,
+    content_type: Option<String>,
+    location: Option<String>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    etag: Option<EntityTag>,
+    last_modified: Option<SystemTime>,
+    cache_control: Option<String>,
+}
+
+lazy_static! {
+    // shared by all workers so a retry which ends up on another connection is still recognized
+    static ref ENTRIES: Mutex<HashMap<Key, Entry>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// held while the first request with a key is handled, dropping it without store lets the next request with the key
+/// be handled again, e.g. after the script failed
+pub struct IdempotencyGuard {
+    key: Key,
+    id: u64,
+    // dropped with the guard which wakes up the requests waiting for the key
+    _done: watch::Sender<()>,
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        let mut entries = ENTRIES.lock().unwrap();
+        if matches!(entries.get(&self.key), Some(Entry::Pending { id, .. }) if *id == self.id) {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+fn entry_key(req: &HttpRequest) -> Result<Option<Key>, HttpResponse> {
+    if req.method() != Method::POST || config::get().idempotency_ttl.is_none() {
+        return Ok(None);
+    }
+    let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    match key.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => {
+            // requests for an unknown tenant are rejected before we get here
+            let tenant = tenants::resolve(req).ok().flatten().unwrap_or_default();
+            Ok(Some((
+                tenant,
+                req.path().to_string(),
+                key.to_string(),
+                credentials(req),
+            )))
+        }
+        _ => Err(HttpResponse::BadRequest().body(format!(
+            "the Idempotency-Key header should be 1 to {} characters",
+            MAX_KEY_LENGTH
+        ))),
+    }
+}
+
+// the Authorization and Cookie headers of the request, empty without those
+fn credentials(req: &HttpRequest) -> String {
+    [header::AUTHORIZATION, header::COOKIE]
+        .iter()
+        .flat_map(|name| {
+            req.headers().get_all(name).map(move |value| {
+                format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()))
+            })
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// only used to tell a retry from another request with the same key, the client could only confuse itself with a
+// collision as the key is scoped to its credentials
+fn hash_body(body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+// the 422 for a request which reuses the key of a request with another body
+fn key_reused(key: &Key) -> HttpResponse {
+    log::debug!("idempotency key {} was reused with another body", key.2);
+    HttpResponse::UnprocessableEntity()
+        .body("the Idempotency-Key was already used for a request with another body")
+}
+
+/// check the Idempotency-Key of a POST request before it is dispatched
+///
+/// the first request with a key gets a guard to hold until its response was stored, a repeat of it within
+/// SCRIPT_IDEMPOTENCY_TTL_SECS gets the stored response (Err) without dispatching the script again, a repeat which
+/// arrives while the first is still being handled waits for it to complete
+/// a request which reuses a key with another body than the first gets a 422
+/// Ok(None) for requests without a key
+pub async fn begin(
+    req: &HttpRequest,
+    body: &[u8],
+) -> Result<Option<IdempotencyGuard>, HttpResponse> {
+    let key = match entry_key(req)? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let body_hash = hash_body(body);
+    loop {
+        let mut done = {
+            let mut entries = ENTRIES.lock().unwrap();
+            match entries.get(&key) {
+                Some(Entry::Done {
+                    expires,
+                    response,
+                    body_hash: stored_hash,
+                }) if *expires > Instant::now() => {
+                    if *stored_hash != body_hash {
+                        return Err(key_reused(&key));
+                    }
+                    log::debug!("replaying the response for idempotency key {}", key.2);
+                    return Err(response.to_http_response(req));
+                }
+                Some(Entry::Pending {
+                    done,
+                    body_hash: pending_hash,
+                    ..
+                }) => {
+                    if *pending_hash != body_hash {
+                        return Err(key_reused(&key));
+                    }
+                    done.clone()
+                }
+                _ => {
+                    let (sender, receiver) = watch::channel(());
+                    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+                    entries.insert(
+                        key.clone(),
+                        Entry::Pending {
+                            id,
+                            done: receiver,
+                            body_hash,
+                        },
+                    );
+                    return Ok(Some(IdempotencyGuard {
+                        key,
+                        id,
+                        _done: sender,
+                    }));
+                }
+            }
+        };
+        // nothing is ever sent, this returns once the guard of the first request is dropped
+        let _ = done.changed().await;
+    }
+}
+
+/// store the response of a request with an Idempotency-Key for the next requests with the key
+/// streamed responses and failed requests are not stored, those are handled again when repeated
+/// the cookies the response sets are not stored, a replayed response sets none
+pub fn store(req: &HttpRequest, response: &ScriptResponse) {
+    let (key, ttl) = match (entry_key(req), config::get().idempotency_ttl) {
+        (Ok(Some(key)), Some(ttl)) => (key, ttl),
+        _ => return,
+    };
+    let now = Instant::now();
+    let mut entries = ENTRIES.lock().unwrap();
+    // the hash of the body begin saw, there is no pending entry when the request did not go through begin
+    let body_hash = match entries.get(&key) {
+        Some(Entry::Pending { body_hash, .. }) => *body_hash,
+        _ => return,
+    };
+    if entries.len() >= MAX_KEYS {
+        entries.retain(|_, entry| !matches!(entry, Entry::Done { expires, .. } if *expires <= now));
+        if entries.len() >= MAX_KEYS {
+            log::warn!(
+                "not storing the response for idempotency key {}, {} keys are stored",
+                key.2,
+                MAX_KEYS
+            );
+            return;
+        }
+    }
+    entries.insert(
+        key,
+        Entry::Done {
+            expires: now + ttl,
+            body_hash,
+            response: StoredResponse {
+                handled: response.handled,
+                status: response.status,
+                body: response.body.clone(),
+                content_type: response.content_type.clone(),
+                location: response.location.clone(),
+                headers: response.headers.clone(),
+                etag: response.etag.clone(),
+                last_modified: response.last_modified,
+                cache_control: response.cache_control.clone(),
+            },
+        },
+    );
+}
+
+impl StoredResponse {
+    fn to_http_response(&self, req: &HttpRequest) -> HttpResponse {
+        let mut response = ScriptResponse {
+            handled: self.handled,
+            status: self.status,
+            body: self.body.clone(),
+            content_type: self.content_type.clone(),
+            location: self.location.clone(),
+            headers: self.headers.clone(),
+            etag: self.etag.clone(),
+            last_modified: self.last_modified,
+            cache_control: self.cache_control.clone(),
+            ..ScriptResponse::default()
+        }
+        .into_http_response(req);
+        response.headers_mut().insert(
+            HeaderName::from_static("idempotent-replayed"),
+            HeaderValue::from_static("true"),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::test::TestRequest;
+
+    fn request(key: &str, authorization: Option<&str>) -> HttpRequest {
+        let mut req = TestRequest::post()
+            .uri("/orders")
+            .insert_header((IDEMPOTENCY_KEY_HEADER, key));
+        if let Some(authorization) = authorization {
+            req = req.insert_header((header::AUTHORIZATION, authorization));
+        }
+        req.to_http_request()
+    }
+
+    fn response() -> ScriptResponse {
+        ScriptResponse {
+            handled: true,
+            body: Some(Bytes::from_static(b"created")),
+            set_cookies: vec![Cookie::new("session", "secret")],
+            ..ScriptResponse::default()
+        }
+    }
+
+    fn rejected(result: Result<Option<IdempotencyGuard>, HttpResponse>) -> HttpResponse {
+        match result {
+            Ok(_) => panic!("the request was not rejected"),
+            Err(response) => response,
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_retry_gets_the_stored_response_without_its_cookies() {
+        config::init_for_tests();
+        let req = request("replay", None);
+        let guard = begin(&req, b"body").await.unwrap();
+        assert!(guard.is_some());
+        store(&req, &response());
+        drop(guard);
+        let replayed = rejected(begin(&req, b"body").await);
+        assert_eq!(
+            replayed.headers().get("idempotent-replayed").unwrap(),
+            "true"
+        );
+        assert!(replayed.headers().get(header::SET_COOKIE).is_none());
+    }
+
+    #[actix_web::test]
+    async fn keys_are_scoped_to_the_credentials_of_the_request() {
+        config::init_for_tests();
+        let req = request("scoped", Some("Bearer alice"));
+        let guard = begin(&req, b"body").await.unwrap();
+        store(&req, &response());
+        drop(guard);
+        let other = request("scoped", Some("Bearer mallory"));
+        assert!(begin(&other, b"body").await.unwrap().is_some());
+        let anonymous = request("scoped", None);
+        assert!(begin(&anonymous, b"body").await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn a_key_reused_with_another_body_is_rejected() {
+        config::init_for_tests();
+        let req = request("reused", None);
+        let guard = begin(&req, b"first").await.unwrap();
+        // also while the first request is still being handled
+        let pending = rejected(begin(&req, b"second").await);
+        assert_eq!(pending.status(), 422);
+        store(&req, &response());
+        drop(guard);
+        let done = rejected(begin(&req, b"second").await);
+        assert_eq!(done.status(), 422);
+    }
+
+    #[actix_web::test]
+    async fn a_repeated_post_is_dispatched_once() {
+        crate::tests::eval(
+            r#"{
+                let runs = 0;
+                com.mycompany.MyApp.addEventListener("request", (evt) => {
+                    if (evt.headers["x-test"] === "idempotency") {
+                        runs += 1;
+                        evt.responseStatus = 201;
+                        evt.responseBody = "run " + runs;
+                    }
+                });
+            }"#,
+        );
+        let post = |key: &'static str| {
+            TestRequest::post()
+                .insert_header(("x-test", "idempotency"))
+                .insert_header((IDEMPOTENCY_KEY_HEADER, key))
+        };
+        // a repeat which arrives while the first is handled waits for it instead of being dispatched as well
+        let (first, second) = tokio::join!(
+            crate::tests::call(post("once")),
+            crate::tests::call(post("once"))
+        );
+        assert_eq!((first.0.as_u16(), second.0.as_u16()), (201, 201));
+        assert_eq!((first.2, second.2), ("run 1".into(), "run 1".into()));
+        assert!(!first.1.contains_key("idempotent-replayed"));
+        assert_eq!(second.1.get("idempotent-replayed").unwrap(), "true");
+        let (_, headers, body) = crate::tests::call(post("once")).await;
+        assert_eq!(body, "run 1");
+        assert!(headers.contains_key("idempotent-replayed"));
+        let (_, headers, body) = crate::tests::call(post("twice")).await;
+        assert_eq!(body, "run 2");
+        assert!(!headers.contains_key("idempotent-replayed"));
+    }
+}
@@ -0,0 +1,217 @@
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::modules::ScriptModuleLoader;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    // the cycles any of the graphs found, the modules are the same in every runtime
+    static ref CYCLES: Mutex<Vec<Vec<String>>> = Mutex::new(vec![]);
+}
+
+/// the imports of the modules of a runtime by the path of the importing module, shared by its loaders
+///
+/// the modules are the same in every realm so the realms share the graph, a module which is loaded again, e.g. in the
+/// realm of an isolated request, replaces the imports it had
+#[derive(Clone, Default)]
+pub struct ImportGraph {
+    imports: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+impl ImportGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // record that from imports to, returns the modules of the cycle when to (indirectly) imports from
+    fn add(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        let mut imports = self.imports.lock().unwrap();
+        imports
+            .entry(from.to_string())
+            .or_default()
+            .insert(to.to_string());
+        // depth first from to, the first path back to from is the cycle
+        let mut path = vec![to.to_string()];
+        let mut visited = HashSet::new();
+        if find_path(&imports, to, from, &mut visited, &mut path) {
+            let mut cycle = vec![from.to_string()];
+            cycle.extend(path);
+            Some(cycle)
+        } else {
+            None
+        }
+    }
+
+    fn clear(&self, module: &str) {
+        self.imports.lock().unwrap().remove(module);
+    }
+}
+
+// extends path with the modules from current to target
+fn find_path(
+    imports: &HashMap<String, HashSet<String>>,
+    current: &str,
+    target: &str,
+    visited: &mut HashSet<String>,
+    path: &mut Vec<String>,
+) -> bool {
+    if current == target {
+        return true;
+    }
+    if !visited.insert(current.to_string()) {
+        return false;
+    }
+    for next in imports.get(current).into_iter().flatten() {
+        path.push(next.clone());
+        if find_path(imports, next, target, visited, path) {
+            return true;
+        }
+        path.pop();
+    }
+    false
+}
+
+// remember a cycle, true when it is new
+fn record(cycle: Vec<String>) -> bool {
+    let mut cycles = CYCLES.lock().unwrap();
+    if cycles.contains(&cycle) {
+        return false;
+    }
+    cycles.push(cycle);
+    true
+}
+
+/// an error of evaluating a module which is thrown in a module of an import cycle becomes an ImportCycleError which
+/// names the cycle next to the original message, other errors are returned as they are
+///
+/// cycles are allowed, but in a cycle one of the modules runs before the module it imports was evaluated, which
+/// fails with a ReferenceError somewhere in the module instead of at the import
+pub fn explain(err: JsError) -> JsError {
+    let cycles = CYCLES.lock().unwrap();
+    let cycle = cycles.iter().find(|cycle| {
+        cycle
+            .iter()
+            .any(|module| err.get_stack().contains(module.as_str()))
+    });
+    match cycle {
+        Some(cycle) => JsError::new(
+            "ImportCycleError".to_string(),
+            format!(
+                "{}: {} (the module is in the import cycle {})",
+                err.get_name(),
+                err.get_message(),
+                cycle.join(" -> ")
+            ),
+            err.get_stack().to_string(),
+        ),
+        None => err,
+    }
+}
+
+/// wraps a module loader to find the cycles of imports like a.ts -> b.ts -> a.ts, those are logged and used by
+/// explain to name the cycle when evaluating a module of it fails
+/// every loader of a runtime is wrapped with the same graph as the importing and imported module may come from
+/// different loaders
+pub struct CycleDetectingLoader<L> {
+    inner: L,
+    graph: ImportGraph,
+}
+
+impl<L> CycleDetectingLoader<L> {
+    pub fn new(inner: L, graph: &ImportGraph) -> Self {
+        Self {
+            inner,
+            graph: graph.clone(),
+        }
+    }
+}
+
+impl<R: JsRealmAdapter, L: ScriptModuleLoader<R>> ScriptModuleLoader<R>
+    for CycleDetectingLoader<L>
+{
+    fn normalize_path(&self, realm: &R, ref_path: &str, path: &str) -> Option<String> {
+        let absolute_path = self.inner.normalize_path(realm, ref_path, path)?;
+        // the runtime also normalizes a path against itself to find the loader which has it, that is no import
+        if absolute_path == ref_path {
+            return Some(absolute_path);
+        }
+        if let Some(cycle) = self.graph.add(ref_path, absolute_path.as_str()) {
+            let description = cycle.join(" -> ");
+            if record(cycle) {
+                log::warn!("import cycle: {}", description);
+            }
+        }
+        Some(absolute_path)
+    }
+
+    fn load_module(&self, realm: &R, absolute_path: &str) -> String {
+        // its imports are recorded again when it is compiled
+        self.graph.clear(absolute_path);
+        self.inner.load_module(realm, absolute_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_import_which_closes_a_cycle_gets_the_modules_of_the_cycle() {
+        let graph = ImportGraph::new();
+        assert!(graph
+            .add("file:///graph-test/a.ts", "file:///graph-test/b.ts")
+            .is_none());
+        assert!(graph
+            .add("file:///graph-test/b.ts", "file:///graph-test/c.ts")
+            .is_none());
+        assert!(graph
+            .add("file:///graph-test/a.ts", "file:///graph-test/c.ts")
+            .is_none());
+        let cycle = graph
+            .add("file:///graph-test/c.ts", "file:///graph-test/a.ts")
+            .unwrap();
+        assert_eq!(cycle.first(), cycle.last());
+        assert!(cycle.len() == 3 || cycle.len() == 4);
+        // a module which is loaded again records its imports again
+        graph.clear("file:///graph-test/c.ts");
+        assert!(graph
+            .add("file:///graph-test/c.ts", "file:///graph-test/d.ts")
+            .is_none());
+    }
+
+    #[test]
+    fn only_errors_in_a_cycle_are_explained() {
+        let graph = ImportGraph::new();
+        assert!(graph
+            .add("file:///cycle-test/a.ts", "file:///cycle-test/b.ts")
+            .is_none());
+        let cycle = graph
+            .add("file:///cycle-test/b.ts", "file:///cycle-test/a.ts")
+            .unwrap();
+        assert_eq!(
+            cycle.join(" -> "),
+            "file:///cycle-test/b.ts -> file:///cycle-test/a.ts -> file:///cycle-test/b.ts"
+        );
+        assert!(record(cycle.clone()));
+        assert!(!record(cycle));
+
+        let err = explain(JsError::new(
+            "ReferenceError".to_string(),
+            "x is not initialized".to_string(),
+            "    at <eval> (file:///cycle-test/a.ts:1)".to_string(),
+        ));
+        assert_eq!(err.get_name(), "ImportCycleError");
+        assert!(err
+            .get_message()
+            .starts_with("ReferenceError: x is not initialized"));
+        assert!(err.get_message().contains("file:///cycle-test/a.ts -> "));
+
+        let err = explain(JsError::new(
+            "ReferenceError".to_string(),
+            "y is not defined".to_string(),
+            "    at <eval> (file:///cycle-test/c.ts:1)".to_string(),
+        ));
+        assert_eq!(err.get_name(), "ReferenceError");
+    }
+}
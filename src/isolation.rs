@@ -0,0 +1,136 @@
+use crate::metrics;
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use hirofa_utils::js_utils::JsError;
+use quickjs_runtime::facades::QuickJsRuntimeFacade;
+use quickjs_runtime::quickjsruntimeadapter::QuickJsRuntimeAdapter;
+use std::sync::Arc;
+
+const REALM_PREFIX: &str = "request-";
+
+/// whether a realm is the realm of an isolated request
+pub fn is_isolated(realm_id: &str) -> bool {
+    realm_id.starts_with(REALM_PREFIX)
+}
+
+/// a realm which is created for a single request when SCRIPT_ISOLATE_REQUESTS is set
+///
+/// globals set by one request are not visible to other requests as every request gets a fresh realm with the
+/// proxies installed and the entry modules evaluated, QuickJS can't clone a realm so this is done for every request
+/// the realm is removed when this is dropped, for streaming responses that is when the stream is done
+pub struct IsolatedRealm {
+    // the runtime the realm is created in, kept so it is also removed from there when the runtime was restarted
+    rt: Arc<QuickJsRuntimeFacade>,
+    id: String,
+}
+
+impl IsolatedRealm {
+    pub async fn create(
+        rt: Arc<QuickJsRuntimeFacade>,
+        pool_idx: usize,
+        request_id: &str,
+    ) -> Result<Self, JsError> {
+        // created first so the realm is also removed when initializing it fails
+        let isolated = Self {
+            rt,
+            id: format!("{}{}", REALM_PREFIX, request_id),
+        };
+        let rt = &isolated.rt;
+        let timer = metrics::REALM_CREATE_DURATION.start_timer();
+        create_realm(rt, isolated.id.as_str(), pool_idx).await?;
+        for script in crate::entry::scripts() {
+            rt.js_eval_module(Some(isolated.id.as_str()), script)
+                .await
+                .map_err(crate::import_cycles::explain)?;
+        }
+        rt.js_loop_realm(Some(isolated.id.as_str()), move |_rt, realm| {
+            crate::entry::dispatch_init(realm, pool_idx)
+        })
+        .await?;
+        timer.observe_duration();
+        Ok(isolated)
+    }
+
+    pub fn id(&self) -> &str {
+        self.id.as_str()
+    }
+}
+
+impl Drop for IsolatedRealm {
+    fn drop(&mut self) {
+        // timers of the request would otherwise fire in a realm which no longer exists
+        crate::timers::clear_realm_timers(self.id.as_str());
+        remove_realm(&self.rt, self.id.as_str());
+    }
+}
+
+// quickjs_runtime 0.7 does not implement js_create_realm and js_remove_realm of the runtime adapter (they panic) so
+// realms are created and removed with the functions of QuickJsRuntimeAdapter, these borrow the runtime themselves
+// and have to run as plain tasks on the worker thread instead of in js_loop
+
+/// create a realm in a runtime and install our proxies and functions in it
+pub async fn create_realm(
+    rt: &QuickJsRuntimeFacade,
+    realm_id: &str,
+    pool_idx: usize,
+) -> Result<(), JsError> {
+    let id = realm_id.to_string();
+    rt.add_task_to_event_loop(move || QuickJsRuntimeAdapter::create_context(id.as_str()))
+        .await?;
+    rt.js_loop_realm(Some(realm_id), move |_rt, realm| {
+        crate::init_realm(realm, pool_idx)
+    })
+    .await
+}
+
+/// remove a realm from a runtime, if it was created
+pub fn remove_realm(rt: &QuickJsRuntimeFacade, realm_id: &str) {
+    let id = realm_id.to_string();
+    rt.add_task_to_event_loop_void(move || {
+        if QuickJsRuntimeAdapter::do_with(|q_js_rt| q_js_rt.has_context(id.as_str())) {
+            QuickJsRuntimeAdapter::remove_context(id.as_str());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsRuntimeAdapter, JsValueAdapter};
+    use hirofa_utils::js_utils::Script;
+
+    // the realm is created like create() does but without evaluating main.ts and the entry modules
+    #[actix_web::test]
+    async fn the_globals_of_an_isolated_realm_stay_in_the_realm() {
+        let rt = crate::tests::pool().get(0);
+        let isolated = IsolatedRealm {
+            rt: rt.clone(),
+            id: format!("{}isolation-test", REALM_PREFIX),
+        };
+        let realm_id = isolated.id().to_string();
+        assert!(is_isolated(realm_id.as_str()));
+        create_realm(&rt, realm_id.as_str(), 0).await.ok().unwrap();
+        let proxy_type = rt
+            .js_loop_realm(Some(realm_id.as_str()), |_rt, realm| {
+                realm
+                    .js_eval(Script::new(
+                        "file://isolated.js",
+                        "globalThis.isolationTest = typeof com.mycompany.MyApp",
+                    ))?
+                    .js_to_string()
+            })
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(proxy_type, "function");
+        assert_eq!(
+            crate::tests::eval("typeof globalThis.isolationTest"),
+            "undefined"
+        );
+
+        drop(isolated);
+        let exists = rt
+            .js_loop(move |q_js_rt| q_js_rt.js_get_realm(realm_id.as_str()).is_some())
+            .await;
+        assert!(!exists);
+    }
+}
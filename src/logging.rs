@@ -0,0 +1,155 @@
+use log::LevelFilter;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const LOG_FILE_VAR: &str = "SCRIPT_LOG_FILE";
+pub const LOG_LEVEL_VAR: &str = "SCRIPT_LOG_LEVEL";
+pub const LOG_MAX_SIZE_VAR: &str = "SCRIPT_LOG_MAX_SIZE";
+
+const DEFAULT_LOG_FILE: &str = "myapp.log";
+// the number of rotated files we keep next to the current one, myapp.log.1 is the most recent
+const ROTATE_KEEP: usize = 3;
+
+#[cfg(debug_assertions)]
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Trace;
+#[cfg(not(debug_assertions))]
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// set up logging from SCRIPT_LOG_FILE, SCRIPT_LOG_LEVEL and SCRIPT_LOG_MAX_SIZE
+/// a log file of - logs to stdout, a max size of 0 (the default) disables rotation
+pub fn init() -> std::io::Result<()> {
+    let level_var = std::env::var(LOG_LEVEL_VAR).ok();
+    let level = level_var
+        .as_deref()
+        .map(|level| LevelFilter::from_str(level.trim()))
+        .unwrap_or(Ok(DEFAULT_LEVEL));
+
+    let path = std::env::var(LOG_FILE_VAR).unwrap_or_else(|_| DEFAULT_LOG_FILE.to_string());
+    let max_size = std::env::var(LOG_MAX_SIZE_VAR)
+        .ok()
+        .and_then(|size| size.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let effective_level = *level.as_ref().unwrap_or(&DEFAULT_LEVEL);
+    if path == "-" {
+        simple_logging::log_to(std::io::stdout(), effective_level);
+    } else if max_size > 0 {
+        simple_logging::log_to(RotatingFile::open(path, max_size)?, effective_level);
+    } else {
+        simple_logging::log_to_file(path, effective_level)?;
+    }
+
+    // only now we can log
+    if level.is_err() {
+        log::warn!(
+            "invalid {} [{}], using default of {}",
+            LOG_LEVEL_VAR,
+            level_var.unwrap_or_default(),
+            DEFAULT_LEVEL
+        );
+    }
+    Ok(())
+}
+
+/// change the level at runtime, simple_logging filters on the max level of the log crate so this takes effect for
+/// the next record, returns the previous level
+pub fn set_level(level: LevelFilter) -> LevelFilter {
+    let previous = log::max_level();
+    log::set_max_level(level);
+    previous
+}
+
+/// a log file which is rotated when it grows over max_size bytes
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: String, max_size: u64) -> std::io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotated_path(&self, idx: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", idx));
+        PathBuf::from(path)
+    }
+
+    /// move myapp.log.1 to myapp.log.2 etc, then the current file to myapp.log.1 and start a new file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        for idx in (1..ROTATE_KEEP).rev() {
+            let from = self.rotated_path(idx);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated_path(idx + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_level_returns_the_previous_level() {
+        let previous = set_level(LevelFilter::Trace);
+        assert_eq!(set_level(previous), LevelFilter::Trace);
+        assert_eq!(log::max_level(), previous);
+    }
+
+    #[test]
+    fn the_file_is_rotated_when_it_grows_over_the_max_size() {
+        let dir = std::env::temp_dir().join(format!("logging-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+        let mut file = RotatingFile::open(path.to_str().unwrap().to_string(), 10).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n", "fifth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+        assert_eq!(read("test.log"), "fifth\n");
+        assert_eq!(read("test.log.1"), "fourth\n");
+        assert_eq!(read("test.log.2"), "third\n");
+        assert_eq!(read("test.log.3"), "second\n");
+        // only ROTATE_KEEP rotated files are kept
+        assert!(!dir.join("test.log.4").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,113 +1,1350 @@
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+mod abort;
+mod access_log;
+mod admin;
+mod aggregate;
+mod app_state;
+mod auth;
+mod backpressure;
+mod body_stream;
+mod circuit_breaker;
+mod client_addr;
+mod coalesce;
+mod config;
+mod content_encoding;
+mod context;
+mod cors;
+#[cfg(feature = "db")]
+mod db_tls;
+mod debug_eval;
+mod deferred;
+mod dispatch;
+#[cfg(debug_assertions)]
+mod dts;
+mod entry;
+mod errors;
+mod event;
+mod flags;
+#[cfg(debug_assertions)]
+mod hot_reload;
+mod http_modules;
+mod idempotency;
+mod import_cycles;
+mod isolation;
+mod logging;
+mod maintenance;
+mod memory_modules;
+mod metrics;
+mod pool;
+mod promises;
+mod proxies;
+mod proxy_registry;
+mod rate_limit;
+mod rejections;
+mod request_event;
+mod resources;
+mod response_cache;
+mod restart;
+mod routes;
+mod rpc;
+mod sandbox;
+mod scheduler;
+mod schema;
+mod script_routes;
+mod secrets;
+mod sse;
+mod static_files;
+mod streaming;
+mod tasks;
+mod tenants;
+mod timeout;
+mod timers;
+mod tls;
+mod trace;
+mod ts_cache;
+mod uploads;
+mod warmup;
+#[cfg(feature = "ws")]
+mod websocket;
+
+use crate::deferred::DeferredJobs;
+use crate::event::{RequestInfo, ScriptResponse};
+use crate::http_modules::RetryingHttpModuleLoader;
+use crate::import_cycles::{CycleDetectingLoader, ImportGraph};
+use crate::isolation::IsolatedRealm;
+use crate::memory_modules::MemoryModuleLoader;
+use crate::pool::ScriptPool;
+use crate::proxies::{SafeMembers, SafeStaticMethods};
+use crate::ts_cache::{CachingTypeScriptPreProcessor, ModulePreProcessor};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::KeepAlive;
+use actix_web::{middleware, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
 use green_copper_runtime::moduleloaders::{FileSystemModuleLoader, HttpModuleLoader};
 use hirofa_utils::js_utils::adapters::proxies::JsProxy;
 use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
 use hirofa_utils::js_utils::facades::{JsRuntimeBuilder, JsRuntimeFacade};
 use hirofa_utils::js_utils::{JsError, Script};
-use lazy_static::lazy_static;
-use log::LevelFilter;
+use once_cell::sync::OnceCell;
 use quickjs_runtime::builder::QuickJsRuntimeBuilder;
 use quickjs_runtime::facades::QuickJsRuntimeFacade;
-use typescript_utils::{TargetVersion, TypeScriptPreProcessor};
+use quickjs_runtime::quickjsrealmadapter::QuickJsRealmAdapter;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use typescript_utils::TypeScriptPreProcessor;
+
+// every runtime in the pool is initialized by init_quickjs so they all have the same proxies and modules
+// the pool is created in main so init errors can be reported instead of panicking in a lazy static
+static SCRIPT_POOL: OnceCell<ScriptPool> = OnceCell::new();
 
-lazy_static! {
-    static ref SCRIPT_RT: QuickJsRuntimeFacade = init_quickjs();
+/// the pool of runtimes, panics when called before main created the pool
+fn script_pool() -> &'static ScriptPool {
+    SCRIPT_POOL.get().expect("script pool was not initialized")
 }
 
-fn init_quickjs() -> QuickJsRuntimeFacade {
-    let tspp = TypeScriptPreProcessor::new(TargetVersion::Es2020, false, false);
-    let fsml = FileSystemModuleLoader::new("./modules");
-    let html = HttpModuleLoader::new()
-        .secure_only()
-        .allow_domain("https://github.com");
+fn init_quickjs(pool_idx: usize) -> Result<QuickJsRuntimeFacade, JsError> {
+    let config = config::get();
+    let caching_tspp = |ts_options: &config::TsOptions| {
+        CachingTypeScriptPreProcessor::new(
+            TypeScriptPreProcessor::new(ts_options.target, ts_options.minify, ts_options.mangle),
+            ts_options.cache_key().as_str(),
+            config.ts_cache_dir.as_str(),
+        )
+    };
+    // the cache key includes the options so the modules of every prefix get their own cache entries
+    let mut tspp = ModulePreProcessor::new(config.module_dir.as_str(), caching_tspp(&config.ts));
+    for (prefix, ts_options) in config.ts_modules.iter() {
+        tspp = tspp.add(prefix.as_str(), caching_tspp(ts_options));
+    }
+    let mml = MemoryModuleLoader::new();
+    let fsml = FileSystemModuleLoader::new(config.module_dir.as_str());
+    let mut html = HttpModuleLoader::new().secure_only();
+    for domain in config.allowed_domains.iter() {
+        html = html.allow_domain(domain.as_str());
+    }
+
+    // the import cycles are logged and named in the error when evaluating one of their modules fails, see
+    // import_cycles.rs
+    let graph = ImportGraph::new();
 
     let mut builder = QuickJsRuntimeBuilder::new()
         .script_pre_processor(tspp)
-        .js_script_module_loader(fsml)
-        .js_script_module_loader(html);
+        // module loaders are tried in the order they are added so embedded modules are preferred over those on
+        // disk which in turn are preferred over those on the allowed domains
+        .js_script_module_loader(CycleDetectingLoader::new(mml, &graph))
+        .js_script_module_loader(CycleDetectingLoader::new(fsml, &graph))
+        .js_script_module_loader(CycleDetectingLoader::new(
+            RetryingHttpModuleLoader::new(html),
+            &graph,
+        ))
+        // the interrupt handler is called periodically while script is running, we use it to abort jobs which
+        // exceed their deadline so a single request can not hang a worker forever
+        .set_interrupt_handler(|_rt| timeout::deadline_passed());
+    // a script which allocates more gets an out of memory InternalError instead of taking the process down, the
+    // memory of the failed job is freed again so the runtime can handle the next requests
+    if let Some(memory_limit) = config.memory_limit {
+        builder = builder.memory_limit(memory_limit);
+    }
 
     builder = green_copper_runtime::init_greco_rt(builder);
     let rt = builder.build();
     // to install out proxy we add a job to the RuntimeFacade
-    // we won't use multiple realms so we pass None as realm_name, this will make the runtime use the main realm (or context)
-    rt.js_loop_realm_sync(None, |_rt, realm| {
-        init_proxy(realm)?;
-        let res: Result<(), JsError> = Ok(());
-        res
+    // we pass None as realm_name, this will make the runtime use the main realm (or context)
+    // other realms are only created for isolated requests, see isolation.rs
+    rt.js_loop_realm_sync(None, move |_rt, realm| init_realm(realm, pool_idx))?;
+    // the tracker is set for the whole runtime so it also covers the realms of isolated requests and tenants
+    rt.js_loop_realm_sync(None, move |_rt, realm: &QuickJsRealmAdapter| {
+        rejections::install(realm.context)
+    });
+    Ok(rt)
+}
+
+/// install our proxies and functions in a realm of the runtime at pool_idx
+/// the errors name the step which failed as they all end up in the same startup error
+fn init_realm<R: JsRealmAdapter + 'static>(realm: &R, pool_idx: usize) -> Result<(), JsError> {
+    let failed = |step: &'static str| {
+        move |err| {
+            let context = format!("runtime {}: could not install {}", pool_idx, step);
+            errors::with_context(err, context.as_str())
+        }
+    };
+    proxies::version::init_build_global(realm).map_err(failed("__BUILD__"))?;
+    init_proxy(realm).map_err(failed("MyApp"))?;
+    dispatch::install_event_target(realm, MY_APP_NAMESPACE, "Logger").map_err(failed("Logger"))?;
+    dispatch::install_event_target(realm, MY_APP_NAMESPACE, "Router").map_err(failed("Router"))?;
+    errors::init_http_error(realm).map_err(failed("HttpError"))?;
+    resources::init_using(realm).map_err(failed("using"))?;
+    abort::init_abort_controller(realm).map_err(failed("AbortController"))?;
+    request_event::init_request_event_proxy(realm).map_err(failed("RequestEvent"))?;
+    proxies::console::init_console_proxy(realm).map_err(failed("console"))?;
+    proxies::performance::init_performance_proxy(realm).map_err(failed("performance"))?;
+    #[cfg(feature = "fetch")]
+    if config::get().capabilities.fetch {
+        proxies::fetch::init_fetch(realm).map_err(failed("fetch"))?;
+    }
+    if config::get().capabilities.fs {
+        proxies::files::init_file_handle(realm).map_err(failed("FileHandle"))?;
+    }
+    timers::init_timers(realm, pool_idx).map_err(failed("timers"))?;
+    Ok(())
+}
+
+/// install the proxies and functions of init_realm again in a realm which was already initialized
+///
+/// used when hot reloading, a module may have replaced globals like fetch or com.mycompany.MyApp.kvGet and those
+/// are restored before it is evaluated again, there is no way to uninstall a proxy and the global of a proxy class
+/// is not writable so installing it again keeps the class, a member a module assigned to the class shadows the
+/// static member of the proxy and is deleted first, the static event listeners are kept by the runtime by class
+/// name so they are neither dropped nor duplicated and this can be called any number of times
+#[cfg(debug_assertions)]
+fn reinstall_proxies<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    pool_idx: usize,
+) -> Result<(), JsError> {
+    // the proxies are recorded by realm id, which is the same for the main realms of all runtimes
+    let proxies: Vec<(String, Vec<String>)> = proxy_registry::installed(realm.js_get_realm_id())
+        .into_iter()
+        .map(|proxy| {
+            let members = proxy.static_methods.into_iter();
+            (proxy.name, members.chain(proxy.static_getters).collect())
+        })
+        .collect();
+    let script = format!(
+        "for (const [name, members] of {}) {{
+            const proxy = name.split('.').reduce((obj, part) => obj && obj[part], globalThis);
+            if (proxy) {{
+                members.forEach((member) => delete proxy[member]);
+            }}
+        }}",
+        serde_json::json!(proxies)
+    );
+    realm
+        .js_eval(Script::new("file://reinstall_proxies.js", script.as_str()))
+        .and_then(|_| init_realm(realm, pool_idx))
+        .map_err(|err| errors::with_context(err, "could not reinstall the proxies"))
+}
+
+const MY_APP_NAMESPACE: &[&str] = &["com", "mycompany"];
+const MY_APP_CLASS: &str = "MyApp";
+
+/// the rust side state of a MyApp instance created from script by calling new com.mycompany.MyApp(name)
+struct MyAppInstance {
+    name: String,
+}
+
+thread_local! {
+    // the MyApp instances in this runtime by realm id and instance id
+    static MY_APP_INSTANCES: RefCell<HashMap<(String, usize), MyAppInstance>> = RefCell::new(HashMap::new());
+}
+
+fn with_my_app_instance<R: JsRealmAdapter, T, C: FnOnce(&MyAppInstance) -> Result<T, JsError>>(
+    realm: &R,
+    instance_id: usize,
+    consumer: C,
+) -> Result<T, JsError> {
+    MY_APP_INSTANCES.with(|instances| {
+        match instances
+            .borrow()
+            .get(&(realm.js_get_realm_id().to_string(), instance_id))
+        {
+            Some(instance) => consumer(instance),
+            None => Err(JsError::new_str("no such MyApp instance")),
+        }
     })
-    .ok()
-    .expect("init proxy failed");
-    return rt;
 }
 
-fn init_proxy<R: JsRealmAdapter>(realm: &R) -> Result<(), JsError> {
-    let proxy = JsProxy::new(&["com", "mycompany"], "MyApp")
-        // out proxy wil have a single static method printSomething
-        .add_static_method("printSomething", |_rt, realm: &R, args| {
-            // if first arg is a string, log that string
-            if args[0].js_is_string() {
-                println!("script printed: {}", args[0].js_to_str()?)
-            }
-            // return undefined
-            realm.js_undefined_create()
+fn init_proxy<R: JsRealmAdapter + 'static>(realm: &R) -> Result<(), JsError> {
+    let proxy = JsProxy::new(MY_APP_NAMESPACE, MY_APP_CLASS)
+        // the constructor is called when script calls new com.mycompany.MyApp(name), every instance gets a unique
+        // instance_id which we use to store the rust side state of the instance
+        .set_safe_constructor(|_rt, realm: &R, instance_id, args| {
+            let name = match args.first() {
+                Some(name) if name.js_is_string() => name.js_to_string()?,
+                _ => format!("instance-{}", instance_id),
+            };
+            MY_APP_INSTANCES.with(|instances| {
+                instances.borrow_mut().insert(
+                    (realm.js_get_realm_id().to_string(), instance_id),
+                    MyAppInstance { name },
+                );
+            });
+            Ok(())
         })
+        // the finalizer is called when the instance is garbage collected
+        .set_safe_finalizer(|_rt, realm: &R, instance_id| {
+            MY_APP_INSTANCES.with(|instances| {
+                instances
+                    .borrow_mut()
+                    .remove(&(realm.js_get_realm_id().to_string(), instance_id));
+            });
+        })
+        .add_safe_method(
+            "getId",
+            "(): number",
+            |_rt, realm: &R, instance_id, _args| realm.js_i32_create(instance_id as i32),
+        )
+        .add_safe_method(
+            "getName",
+            "(): string",
+            |_rt, realm: &R, instance_id, _args| {
+                with_my_app_instance(realm, instance_id, |instance| {
+                    realm.js_string_create(instance.name.as_str())
+                })
+            },
+        )
+        // every instance is an event target of its own, so script can call addEventListener() on instances
+        .set_event_target(true)
+        // out proxy wil have a single static method printSomething
+        .add_safe_static_method(
+            "printSomething",
+            "(thing: string): void",
+            |_rt, realm: &R, args| {
+                // if first arg is a string, log that string
+                if args[0].js_is_string() {
+                    println!("script printed: {}", args[0].js_to_str()?)
+                }
+                // return undefined
+                realm.js_undefined_create()
+            },
+        )
         // setting the static_event_target to true means we can dispatch events and and add listeners from script
         // by calling com.mycompany.MyApp.addEventListener()
         .set_static_event_target(true);
+    // sleep(ms) is an example of an async method, it returns a promise which resolves with the number of ms slept
+    let proxy = promises::add_async_static_method(
+        proxy,
+        "sleep",
+        "(ms: number): Promise<number>",
+        |_realm, args| match args.first() {
+            Some(ms) if ms.js_is_i32() => Ok(ms.js_to_i32().max(0) as u64),
+            _ => Err(JsError::new_str("sleep expects a number of ms")),
+        },
+        |ms| async move {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Ok(ms)
+        },
+        |realm, ms: u64| realm.js_i32_create(ms as i32),
+    );
+    #[cfg(feature = "crypto")]
+    let proxy = proxies::crypto::init_crypto_proxy(proxy);
+    let proxy = proxies::encoding::init_encoding_proxy(proxy);
+    let proxy = proxies::kv::init_kv_proxy(proxy);
+    let proxy = proxies::rate_limit::init_rate_limit_proxy(proxy);
+    let proxy = proxies::cors::init_cors_proxy(proxy);
+    #[cfg(feature = "ws")]
+    let proxy = proxies::websocket::init_websocket_proxy(proxy);
+    let proxy = proxies::sse::init_sse_proxy(proxy);
+    let proxy = proxies::schema::init_schema_proxy(proxy);
+    let proxy = proxies::version::init_version_proxy(proxy);
+    let proxy = proxies::flags::init_flags_proxy(proxy);
+    let proxy = proxies::url::init_url_proxy(proxy);
+    let proxy = proxies::dates::init_dates_proxy(proxy);
+    let proxy = proxies::context::init_context_proxy(proxy);
+    let proxy = proxies::metrics::init_metrics_proxy(proxy);
+    let proxy = proxies::response_cache::init_response_cache_proxy(proxy);
+    let proxy = proxies::routes::init_routes_proxy(proxy);
+    let proxy = proxies::templates::init_templates_proxy(proxy);
+    // the proxies which reach outside of the process are only installed when granted, see config::Capabilities
+    let capabilities = &config::get().capabilities;
+    let proxy = if capabilities.env {
+        proxies::env::init_env_proxy(proxy)
+    } else {
+        proxy
+    };
+    let proxy = if capabilities.fs {
+        proxies::files::init_files_proxy(proxies::uploads::init_uploads_proxy(proxy))
+    } else {
+        proxy
+    };
+    #[cfg(feature = "db")]
+    let proxy = if capabilities.db {
+        proxies::db::init_db_proxy(proxy)
+    } else {
+        proxy
+    };
     // we set add_global_var to true so there will be a com.mycompany.MyApp usable in script
-    realm.js_proxy_install(proxy, true)?;
+    proxy_registry::install(realm, proxy)?;
+    dispatch::track_vetoes(realm, MY_APP_NAMESPACE, MY_APP_CLASS)?;
+    dispatch::register_event_target(MY_APP_NAMESPACE, MY_APP_CLASS);
     Ok(())
 }
 
-async fn do_dispatch() {
-    // for every request we add a job to the script engine and await until it is done
-    SCRIPT_RT
-        .js_loop_realm(None, |_rt, realm| {
-            // dispatch the request event to our proxy class
-            let event_obj = realm
-                .js_null_create()
-                .ok()
-                .expect("could not create event obj");
-            match realm.js_proxy_dispatch_static_event(
-                &["com", "mycompany"],
-                "MyApp",
-                "request",
-                &event_obj,
-            ) {
-                Ok(_vetoed) => {
-                    //
-                }
-                Err(err) => {
-                    log::error!("could not dispatch event: {}", err);
+/// dispatch the events for a request to the script and read back the response the script set on the event object
+///
+/// the events for a route (see routes::event_names) are dispatched in order, the middleware events first
+/// when a listener vetoes an event the script is considered to have fully handled the request: the remaining events
+/// are not dispatched and the response is exactly what the script set on the event
+/// when no listener vetoes, the request falls through to the default handling which fills in whatever the script
+/// did not set with the default response
+///
+/// requests for a tenant are dispatched in the realm of the tenant, see tenants.rs
+/// when SCRIPT_ISOLATE_REQUESTS is set the events are dispatched in a new realm instead of the main realm
+async fn do_dispatch(info: RequestInfo) -> Result<ScriptResponse, JsError> {
+    let pool_idx = script_pool().next_index();
+    // held for the whole request so all its jobs go to the same runtime, even when it is restarted meanwhile
+    let rt = script_pool().get(pool_idx);
+    // the realm of a tenant is already separate from the other tenants so those requests are not isolated
+    let isolated = if config::get().isolate_requests && info.tenant.is_none() {
+        Some(IsolatedRealm::create(rt.clone(), pool_idx, info.request_id.as_str()).await?)
+    } else {
+        None
+    };
+    let realm_id = match (&isolated, &info.tenant) {
+        (Some(isolated), _) => Some(isolated.id().to_string()),
+        (None, Some(tenant)) => Some(tenants::realm_id(tenant)),
+        (None, None) => None,
+    };
+    // declared after the isolated realm so on an error the callbacks are queued before the realm is removed
+    let deferred = DeferredJobs::new(rt.clone(), realm_id.clone(), info.request_id.as_str());
+    #[cfg(feature = "db")]
+    let transactions = proxies::db::TransactionGuard::new(info.request_id.as_str());
+    // the events of an aggregate route are dispatched after the middleware, when no middleware vetoed
+    let aggregate = routes::aggregate(info.route.as_str())
+        .filter(|_| !info.not_found)
+        .map(|aggregate| (aggregate, info.clone()));
+    // for every request we add a job to one of the script engines and await until it is done
+    let mut response = rt
+        .js_loop_realm(realm_id.as_deref(), move |_rt, realm| {
+            // the timeout only applies to this job, not to other jobs in the runtime
+            timeout::with_deadline(
+                timeout::route_timeout(info.route.as_str(), info.method.as_str()),
+                || {
+                    context::with_request_id(info.request_id.as_str(), || {
+                        context::with_script_context(realm, &info, || {
+                            dispatch_request(realm, &info)
+                        })
+                    })
+                },
+            )
+        })
+        .await?;
+    if let Some((aggregate, info)) = aggregate {
+        if !response.handled {
+            aggregate::respond(&mut response, &info, aggregate).await;
+        }
+    }
+    // the response keeps the realm alive until it is sent
+    response.deferred = Some(deferred);
+    #[cfg(feature = "db")]
+    {
+        response.transactions = Some(transactions);
+    }
+    response.isolated_realm = isolated;
+    Ok(response)
+}
+
+/// call the module function which handles a route and use its return value as the response body
+/// when the script registered a response schema for the route the value has to match it, see schema::check_response
+fn invoke_handler<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    handler: &routes::RouteHandler,
+    event_obj: &R::JsValueAdapterType,
+    stream_id: u64,
+) -> Result<(), JsError> {
+    let result = realm.js_function_invoke_by_name(
+        &[routes::HANDLERS_GLOBAL],
+        handler.route,
+        std::slice::from_ref(event_obj),
+    )?;
+    event::set_handler_result(realm, event_obj, &result, stream_id)?;
+    // a streamed response is not what the handler returned, see set_handler_result
+    if result.js_is_null_or_undefined()
+        || result.js_is_promise()
+        || !schema::has_response_schema(handler.route)
+    {
+        return Ok(());
+    }
+    let json = realm.js_json_stringify(&result, None)?;
+    let value: serde_json::Value = serde_json::from_str(json.as_str())
+        .map_err(|err| JsError::new_string(format!("could not read the response: {}", err)))?;
+    schema::check_response(handler.route, &value).map_err(|errors| {
+        // a bug in the handler, not in the request, so this is a 500 like other script errors
+        JsError::new(
+            "ResponseSchemaError".to_string(),
+            format!(
+                "the response of the {} handler does not match its schema: {}",
+                handler.route,
+                errors.join(", ")
+            ),
+            String::new(),
+        )
+    })
+}
+
+/// the part of do_dispatch which runs in the realm
+fn dispatch_request<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    info: &RequestInfo,
+) -> Result<ScriptResponse, JsError> {
+    let event_obj = event::create_event_obj(realm, info)?;
+    event::set_after_function(realm, &event_obj, info)?;
+    request_event::with_request_event(realm, info, &event_obj, || {
+        dispatch_events(realm, info, &event_obj)
+    })
+}
+
+/// dispatch the request events to our proxy class and read the response from the event object
+fn dispatch_events<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    info: &RequestInfo,
+    event_obj: &R::JsValueAdapterType,
+) -> Result<ScriptResponse, JsError> {
+    let mut handled = false;
+    let handler = routes::handler(info.route.as_str());
+    let handler_event = format!("request:{}", info.route);
+    let event_names = if info.not_found {
+        vec![routes::NOT_FOUND_EVENT.to_string()]
+    } else if routes::aggregate(info.route.as_str()).is_some() {
+        // the events of the aggregate are dispatched by do_dispatch
+        routes::MIDDLEWARE
+            .iter()
+            .map(|name| name.to_string())
+            .collect()
+    } else {
+        routes::event_names(info.route.as_str(), info.method.as_str())
+    };
+    for event_name in event_names {
+        let res = match handler {
+            Some(handler) if event_name == handler_event => {
+                invoke_handler(realm, handler, event_obj, info.stream_id).map(|_| false)
+            }
+            _ => dispatch::dispatch_to(
+                realm,
+                MY_APP_NAMESPACE,
+                MY_APP_CLASS,
+                event_name.as_str(),
+                event_obj,
+            ),
+        };
+        match res {
+            Ok(vetoed) => {
+                if vetoed {
+                    handled = true;
+                    break;
                 }
             }
-        })
-        .await;
+            // a thrown HttpError is how a script responds with an error status, not a failure
+            Err(err) if errors::http_error_status(&err).is_some() => {
+                log::debug!(
+                    "event {} threw {}: {}",
+                    event_name,
+                    err.get_name(),
+                    err.get_message()
+                );
+                return Err(err);
+            }
+            Err(err) => {
+                errors::log_script_error(
+                    format!("could not dispatch event {}", event_name).as_str(),
+                    &err,
+                );
+                return recover(realm, event_obj, err);
+            }
+        }
+    }
+    // the listeners may have set responseStatus or responseBody on the event obj
+    let mut response = ScriptResponse::read_from_event_obj(realm, event_obj)?;
+    response.handled = handled;
+    Ok(response)
+}
+
+// the fields of the response which are cleared before the error event, see routes::ERROR_EVENT
+const RESPONSE_FIELDS: &[&str] = &[
+    "responseStatus",
+    "responseBody",
+    "responseJson",
+    "responseBytes",
+    "responseContentType",
+    "redirectLocation",
+];
+
+/// dispatch the error event so the script can respond to an error thrown while handling a request
+/// the error is returned as is when no error listener set a response, an error thrown by an error listener is only
+/// logged so a failing error listener can not recurse
+fn recover<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    err: JsError,
+) -> Result<ScriptResponse, JsError> {
+    let error_obj = dispatch::build_event(
+        realm,
+        &[
+            ("name", realm.js_string_create(err.get_name())?),
+            ("message", realm.js_string_create(err.get_message())?),
+            ("stack", realm.js_string_create(err.get_stack())?),
+        ],
+    )?;
+    realm.js_object_set_property(event_obj, "error", &error_obj)?;
+    for field in RESPONSE_FIELDS {
+        realm.js_object_set_property(event_obj, field, &realm.js_undefined_create()?)?;
+    }
+    if let Err(recover_err) = dispatch::dispatch_to(
+        realm,
+        MY_APP_NAMESPACE,
+        MY_APP_CLASS,
+        routes::ERROR_EVENT,
+        event_obj,
+    ) {
+        errors::log_script_error("the error event failed", &recover_err);
+        return Err(err);
+    }
+    let mut response = ScriptResponse::read_from_event_obj(realm, event_obj)?;
+    if response.is_untouched() {
+        return Err(err);
+    }
+    response.handled = true;
+    Ok(response)
+}
+
+async fn index(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    // preflights are answered from the policy the script set, they never reach the script itself
+    if let Some(response) = cors::preflight_response(&req) {
+        return response;
+    }
+    let started = Instant::now();
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let cors_req = req.clone();
+    // requests during maintenance, over the limit, for an unknown tenant, without a valid token on a protected route
+    // or with a body which is encoded in a way we can't decode or does not match the schema of the route are
+    // rejected without invoking the script
+    // a gzip (or deflate, br, zstd) body was already decoded by the Bytes extractor, within SCRIPT_MAX_BODY
+    let rejected = maintenance::check()
+        .or_else(|| rate_limit::check(&req))
+        .or_else(|| tenants::check(&req))
+        .or_else(|| auth::check(&req))
+        .or_else(|| content_encoding::check(&req))
+        .or_else(|| schema::check(&req, &body));
+    let mut response = match rejected {
+        Some(response) => response,
+        // responses the script cached with event.cacheFor are served without dispatching the request
+        None => match response_cache::lookup(&req) {
+            Some(response) => response,
+            // identical GETs on a coalesced route wait for the one in flight and get its response
+            None => match coalesce::begin(&req).await {
+                // a repeated POST gets the response of the first, the guard is held until that response was stored
+                Ok(_coalesced) => match idempotency::begin(&req, &body).await {
+                    // the permit is held until the request was dispatched
+                    Ok(_guard) => match backpressure::try_acquire() {
+                        Ok(_permit) => handle_request(req, body, request_id.clone()).await,
+                        Err(response) => response,
+                    },
+                    Err(response) => response,
+                },
+                Err(response) => response,
+            },
+        },
+    };
+    cors::apply_headers(&cors_req, &mut response);
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+    access_log::log(&cors_req, &response, started);
+    response
+}
+
+async fn handle_request(req: HttpRequest, body: web::Bytes, request_id: String) -> HttpResponse {
+    // aborts the fetches the script started for this request when the client disconnects before we respond
+    let guard = abort::RequestGuard::new(request_id.as_str());
+    let request_started = SystemTime::now();
+    let request_size = body.len();
+    let multipart = if req.content_type() == "multipart/form-data" {
+        // we need the full header for the boundary, HttpMessage::content_type is just the mime type
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or_default();
+        match uploads::parse_multipart(content_type, body.clone()).await {
+            Ok(multipart) => Some(multipart),
+            Err(err) => {
+                log::debug!("{}", err);
+                return HttpResponse::BadRequest().body(err.get_message().to_string());
+            }
+        }
+    } else {
+        None
+    };
+    // the stream is removed when the receiver drops, also when the client disconnects before we respond
+    let (stream_id, receiver) = streaming::open();
+    let mut info = RequestInfo::from_http_request(&req, body, request_id, stream_id);
+    if let Some(multipart) = multipart {
+        info.files = multipart.files;
+        info.fields = multipart.fields;
+    }
+    // unmatched paths are not used as metric label, scanners would create a label for every path they try
+    let route = if info.not_found {
+        routes::NOT_FOUND_EVENT.to_string()
+    } else {
+        info.route.clone()
+    };
+    let (method, not_found) = (info.method.clone(), info.not_found);
+    let (path, trace) = (info.path.clone(), info.trace.clone());
+    let labels = [method.as_str(), route.as_str()];
+
+    metrics::DISPATCHED.with_label_values(&labels).inc();
+    metrics::REQUEST_BODY_BYTES
+        .with_label_values(&labels)
+        .observe(request_size as f64);
+    metrics::PENDING_JOBS.inc();
+    // the RequestInfo is moved to the runtime so keep the upload ids to discard them afterwards
+    let upload_ids: Vec<String> = info.files.iter().map(|file| file.id.clone()).collect();
+    let started = Instant::now();
+    let dispatch_started = SystemTime::now();
+    let result = do_dispatch(info).await;
+    guard.complete();
+    let mut dispatch_span = trace::Span::internal(&trace, "dispatch", dispatch_started);
+    dispatch_span.error = matches!(&result, Err(err) if errors::http_error_status(err).is_none());
+    trace::record(&trace, dispatch_span);
+    let script_duration = started.elapsed();
+    metrics::observe_dispatch_duration(&labels, script_duration.as_secs_f64(), &trace);
+    if let Some(slow) = config::get().slow_handler {
+        if script_duration > slow {
+            log::warn!(
+                "slow handler: {} {} took {}ms (threshold {}ms)",
+                method,
+                route,
+                script_duration.as_millis(),
+                slow.as_millis()
+            );
+        }
+    }
+    uploads::discard(&upload_ids);
+    metrics::PENDING_JOBS.dec();
+
+    // when the script started writing to the stream there is no way back, errors just terminate the stream
+    let streamed = streaming::detach(stream_id);
+    let mut response = match result {
+        Ok(response) if streamed => {
+            response.into_streaming_response(receiver, metrics::StreamedSize::new(&method, &route))
+        }
+        Ok(response) if not_found && response.is_untouched() => HttpResponse::NotFound().finish(),
+        Ok(response) => {
+            response_cache::store(&req, &response);
+            idempotency::store(&req, &response);
+            coalesce::share(&req, &response);
+            response.into_http_response(&req)
+        }
+        Err(err) => {
+            if errors::http_error_status(&err).is_none() {
+                metrics::SCRIPT_ERRORS.with_label_values(&labels).inc();
+            }
+            if errors::is_out_of_memory(&err) {
+                log::error!(
+                    "{} {} exceeded the memory limit of {} bytes",
+                    method,
+                    route,
+                    config::get().memory_limit.unwrap_or(0)
+                );
+            }
+            if streamed {
+                streaming::fail(stream_id, &err);
+                ScriptResponse::default()
+                    .into_streaming_response(receiver, metrics::StreamedSize::new(&method, &route))
+            } else {
+                errors::script_error_response(&err)
+            }
+        }
+    };
+    let response_size = metrics::observe_response_size(&labels, &response);
+    // the size of a streamed response is only known when the stream ended
+    log::debug!(
+        "{} {}: request body {} bytes, response body {}",
+        method,
+        route,
+        request_size,
+        response_size.map_or_else(|| "streamed".to_string(), |size| format!("{} bytes", size))
+    );
+    // for streamed responses the span ends when the listeners returned, not when the stream ended
+    let mut request_span = trace::Span::server(
+        &trace,
+        format!("{} {}", method, route).as_str(),
+        request_started,
+    );
+    request_span.attributes = vec![
+        ("http.method", method.clone()),
+        ("http.route", route.clone()),
+        ("http.target", path),
+        ("http.status_code", response.status().as_u16().to_string()),
+    ];
+    request_span.error = response.status().is_server_error();
+    trace::record(&trace, request_span);
+    // for streamed responses this is the time until the listeners returned, not until the stream ended
+    let server_timing = format!("script;dur={:.1}", script_duration.as_secs_f64() * 1000.0);
+    if let Ok(value) = HeaderValue::from_str(server_timing.as_str()) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("server-timing"), value);
+    }
+    response
+}
+
+/// the max time a runtime may take to respond to a health check
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// check that every runtime in the pool is responsive by evaluating a trivial script
+/// returns 503 when one of the runtimes errors or does not respond within HEALTH_TIMEOUT, or while the database
+/// can't be reached with the db feature, see proxies::db::start_monitor
+/// in maintenance mode the runtimes are still checked, a healthy server then responds with a 200 and
+/// {"status":"maintenance"} so it is kept in the load balancer and can be switched back without a restart
+async fn health() -> HttpResponse {
+    for rt in script_pool().runtimes() {
+        let job = rt.js_eval(None, Script::new("file://health.js", "1+1"));
+        match actix_web::rt::time::timeout(HEALTH_TIMEOUT, job).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
+                log::error!("health check failed: {}", err);
+                return HttpResponse::ServiceUnavailable()
+                    .content_type("application/json")
+                    .body(r#"{"status":"error"}"#);
+            }
+            Err(_) => {
+                log::error!("health check timed out");
+                return HttpResponse::ServiceUnavailable()
+                    .content_type("application/json")
+                    .body(r#"{"status":"timeout"}"#);
+            }
+        }
+    }
+    // not ready until the database was reached, also when it was lost later on
+    #[cfg(feature = "db")]
+    if !proxies::db::is_ready() {
+        return HttpResponse::ServiceUnavailable()
+            .content_type("application/json")
+            .body(r#"{"status":"database unavailable"}"#);
+    }
+    let status = if maintenance::is_enabled() {
+        r#"{"status":"maintenance"}"#
+    } else {
+        r#"{"status":"ok"}"#
+    };
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(status)
+}
+
+/// the max time we wait for the runtimes to handle the shutdown event
+pub(crate) const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// broadcast the shutdown event in every runtime in the pool
+/// because a runtime handles its jobs in order, the shutdown job completing also means all jobs which were queued
+/// before it are done
+async fn shutdown_scripts() {
+    for rt in script_pool().runtimes() {
+        shutdown_runtime(&rt).await;
+    }
+}
+
+/// broadcast the shutdown event in a runtime and wait for it for at most SHUTDOWN_TIMEOUT
+/// returns false when the runtime did not get to it in time
+async fn shutdown_runtime(rt: &QuickJsRuntimeFacade) -> bool {
+    let job = rt.js_loop_realm(None, |_rt, realm| {
+        let event_obj = dispatch::build_event(realm, &[])?;
+        // every proxy gets the chance to clean up
+        dispatch::broadcast(realm, "shutdown", &event_obj)
+    });
+    match actix_web::rt::time::timeout(SHUTDOWN_TIMEOUT, job).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(err)) => {
+            errors::log_script_error("could not dispatch shutdown event", &err);
+            true
+        }
+        Err(_) => {
+            log::error!("runtime did not finish its jobs before the shutdown timeout");
+            false
+        }
+    }
+}
+
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    // the body is read before the handler is called, actix responds with a 413 when it, or its Content-Length, is
+    // larger than the limit so the script is never invoked for it
+    cfg.app_data(web::PayloadConfig::new(config::get().max_body));
+    cfg.service(web::resource("/health").to(health));
+    cfg.service(web::resource("/metrics").to(metrics::metrics));
+    #[cfg(feature = "ws")]
+    cfg.service(web::resource("/ws").route(web::get().to(websocket::ws_index)));
+    cfg.service(web::resource("/events").route(web::get().to(sse::events)));
+    cfg.service(web::resource("/poll").route(web::get().to(sse::poll)));
+    cfg.service(web::resource("/rpc").route(web::post().to(rpc::rpc)));
+    debug_eval::configure(cfg);
+    admin::configure(cfg);
+    for route in routes::ROUTES {
+        cfg.service(web::resource(*route).to(index));
+    }
+    for route in routes::STREAMING_ROUTES {
+        cfg.service(web::resource(*route).to(body_stream::index));
+    }
+    // a resource per path with a route for every method registered for it, other methods get a 405
+    let registered = script_routes::all();
+    let mut paths: Vec<&str> = vec![];
+    for route in registered.iter() {
+        if !paths.contains(&route.path.as_str()) {
+            paths.push(route.path.as_str());
+        }
+    }
+    for path in paths {
+        let mut resource = web::resource(path);
+        for route in registered.iter().filter(|route| route.path == path) {
+            resource = resource.route(web::method(route.method.clone()).to(index));
+        }
+        cfg.service(resource);
+    }
+    static_files::configure(cfg);
+}
+
+/// the app every http worker runs
+pub(crate) fn app() -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    // compresses responses with gzip, deflate, br or zstd based on the Accept-Encoding of the request and
+    // adds Content-Encoding and Vary headers, see event::COMPRESS_MIN_SIZE for which script responses qualify
+    App::new()
+        .wrap(middleware::Compress::default())
+        .configure(configure_routes)
+        // requests which match none of the routes dispatch the notFound event
+        .default_service(web::to(index))
 }
 
-async fn index(_req: HttpRequest) -> HttpResponse {
-    do_dispatch().await;
-    HttpResponse::Ok().body("hello there")
+/// the keep alive of SCRIPT_KEEPALIVE_SECS, None for the actix default
+pub(crate) fn keep_alive() -> Option<KeepAlive> {
+    match config::get().keepalive_secs {
+        Some(0) => Some(KeepAlive::Disabled),
+        Some(secs) => Some(KeepAlive::Timeout(Duration::from_secs(secs))),
+        None => None,
+    }
+}
+
+const VALIDATE_VAR: &str = "SCRIPT_VALIDATE";
+
+/// run with --validate or SCRIPT_VALIDATE=1 to initialize the runtimes, evaluate the entry modules and dispatch the
+/// init event without starting the server, the exit code is 0 when that succeeded and 1 when a script failed
+/// meant for CI so broken scripts are caught before they are deployed
+fn validate_only() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--validate")
+        || std::env::var(VALIDATE_VAR)
+            .map(|val| val == "1")
+            .unwrap_or(false)
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    #[cfg(not(debug_assertions))]
-    {
-        simple_logging::log_to_file("myapp.log", LevelFilter::Info)?;
+async fn main() {
+    // startup errors are reported with a readable message instead of the debug output of the io::Error
+    if let Err(err) = run().await {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> std::io::Result<()> {
+    logging::init()?;
+
+    flags::init(&config::init()?.flags);
+    secrets::init()?;
+    auth::init()?;
+    maintenance::init(config::get().maintenance);
+    let pool = ScriptPool::new(config::get().pool_size, init_quickjs).map_err(|err| {
+        errors::log_script_error("could not initialize the script runtimes", &err);
+        std::io::Error::other(format!(
+            "could not initialize the script runtimes: {}",
+            errors::describe(&err)
+        ))
+    })?;
+    if SCRIPT_POOL.set(pool).is_err() {
+        unreachable!("the script pool is only created once");
+    }
+
+    entry::load()?;
+    for rt in script_pool().runtimes() {
+        for script in entry::startup_scripts() {
+            let name = entry::describe(script.get_path());
+            if let Err(err) = rt.js_eval_module(None, script).await {
+                let err = import_cycles::explain(err);
+                let msg = format!("{} failed", name);
+                errors::log_script_error(msg.as_str(), &err);
+                return Err(std::io::Error::other(format!(
+                    "{}: {}",
+                    msg,
+                    errors::describe(&err)
+                )));
+            }
+        }
+    }
+    for (pool_idx, rt) in script_pool().runtimes().iter().enumerate() {
+        rt.js_loop_realm(None, move |_rt, realm| {
+            entry::dispatch_init(realm, pool_idx)
+        })
+        .await
+        .map_err(|err| {
+            errors::log_script_error("the init event failed", &err);
+            std::io::Error::other(format!("the init event failed: {}", errors::describe(&err)))
+        })?;
     }
+    tenants::create_realms().await?;
+    // the init listeners registered their routes by now
+    script_routes::freeze();
+    if validate_only() {
+        // the init event may have started timers, those should not fire while we exit
+        tasks::TASKS.shutdown();
+        log::info!("the scripts are valid");
+        return Ok(());
+    }
+    warmup::run().await?;
     #[cfg(debug_assertions)]
     {
-        simple_logging::log_to_file("myapp.log", LevelFilter::Trace)?;
+        // note that reloading a module adds the listeners it registers again, modules which add listeners should
+        // be written so they can be evaluated more than once
+        if hot_reload::enabled() {
+            hot_reload::watch(config::get().module_dir.as_str());
+        }
+        // the proxies were installed in every runtime by now so the registry lists all of them
+        dts::write();
     }
+    debug_eval::log_status();
 
-    SCRIPT_RT
-        .js_eval_module(None, Script::new("file://main.ts", include_str!("main.ts")))
-        .await
-        .ok()
-        .expect("main.ts failed");
-    HttpServer::new(|| App::new().service(web::resource("/").to(index)))
-        .bind(("0.0.0.0", 8070))?
-        .run()
-        .await
+    // actix installs handlers for SIGINT, SIGTERM and SIGQUIT, on those it stops accepting connections and waits
+    // for the in-flight requests to complete after which run() returns and we let the scripts shut down
+    let server = HttpServer::new(app).shutdown_timeout(SHUTDOWN_TIMEOUT.as_secs());
+    // the workers only handle http, the scripts run on the runtimes of the pool so more workers than
+    // SCRIPT_POOL_SIZE runtimes does not make more scripts run in parallel
+    let server = match config::get().workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match keep_alive() {
+        Some(keep_alive) => server.keep_alive(keep_alive),
+        None => server,
+    };
+    let bind_address = config::bind_address();
+    let tls_config = tls::load_tls_config()?;
+    let protocols = tls::protocols(tls_config.is_some(), config::get().http2);
+    log::info!(
+        "listening on {}:{} with {}",
+        bind_address.0,
+        bind_address.1,
+        protocols
+    );
+    let server = match tls_config {
+        Some(tls_config) if !config::get().http2 => tls::bind_http1(bind_address, tls_config)?,
+        Some(tls_config) => server.bind_rustls(bind_address, tls_config)?.run(),
+        None => server.bind(bind_address)?.run(),
+    };
+    scheduler::start()?;
+    trace::start_exporter();
+    #[cfg(feature = "db")]
+    proxies::db::start_monitor();
+    server.await?;
+
+    // stop the scheduler first so no new jobs are dispatched while shutting down
+    scheduler::stop();
+    shutdown_scripts().await;
+    trace::flush().await;
+    // timers and async methods which are still pending would otherwise keep firing into runtimes which are done
+    tasks::TASKS.shutdown();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderMap;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use quickjs_runtime::quickjsrealmadapter::QuickJsRealmAdapter;
+
+    // the tests share a pool of a single runtime which is initialized like those of the server, the listeners
+    // they add stay in its main realm so every test only acts on the requests with its own x-test header
+    pub(crate) fn pool() -> &'static ScriptPool {
+        config::init_for_tests();
+        SCRIPT_POOL.get_or_init(|| {
+            ScriptPool::new(1, init_quickjs)
+                .unwrap_or_else(|err| panic!("could not create the pool: {}", err.get_message()))
+        })
+    }
+
+    /// evaluate a script in the main realm of the runtime of the tests, returns what it evaluated to as string
+    pub(crate) fn eval(script: &'static str) -> String {
+        pool()
+            .get(0)
+            .js_loop_realm_sync(None, move |_rt, realm| {
+                realm
+                    .js_eval(Script::new("file://test.js", script))?
+                    .js_to_string()
+            })
+            .unwrap_or_else(|err| panic!("the script failed: {}", err.get_message()))
+    }
+
+    /// run a job in the main realm of the runtime of the tests
+    pub(crate) fn with_realm<T, C>(job: C) -> T
+    where
+        T: Send + 'static,
+        C: FnOnce(&QuickJsRealmAdapter) -> T + Send + 'static,
+    {
+        pool()
+            .get(0)
+            .js_loop_realm_sync(None, move |_rt, realm| job(realm))
+    }
+
+    /// held for reading by call(), maintenance mode turns every request into a 503 so the tests which enable it
+    /// hold it for writing
+    pub(crate) fn maintenance_lock() -> &'static tokio::sync::RwLock<()> {
+        static LOCK: OnceCell<tokio::sync::RwLock<()>> = OnceCell::new();
+        LOCK.get_or_init(|| tokio::sync::RwLock::new(()))
+    }
+
+    /// the status, headers and body the app responds with to a request
+    pub(crate) async fn call(req: test::TestRequest) -> (StatusCode, HeaderMap, web::Bytes) {
+        let _maintenance = maintenance_lock().read().await;
+        respond(req).await
+    }
+
+    /// call() for the tests which hold the maintenance_lock
+    pub(crate) async fn respond(req: test::TestRequest) -> (StatusCode, HeaderMap, web::Bytes) {
+        pool();
+        let app = test::init_service(app()).await;
+        let res = test::call_service(&app, req.to_request()).await;
+        let (status, headers) = (res.status(), res.headers().clone());
+        (status, headers, test::read_body(res).await)
+    }
+
+    #[actix_web::test]
+    async fn every_runtime_responds_to_the_health_check() {
+        let (status, _, body) = call(test::TestRequest::get().uri("/health")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"status":"ok"}"#);
+    }
+
+    #[actix_web::test]
+    async fn a_vetoed_event_is_fully_handled_by_the_script() {
+        eval(
+            r#"com.mycompany.MyApp.addEventListener("request:/api", (evt) => {
+                if (evt.headers["x-test"] === "veto") {
+                    evt.responseStatus = 202;
+                    return false;
+                }
+            });
+            com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "veto") {
+                    evt.responseBody = "not vetoed";
+                }
+            });"#,
+        );
+        let req = test::TestRequest::get()
+            .uri("/api")
+            .insert_header(("x-test", "veto"));
+        let (status, _, body) = call(req).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(body.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn the_shutdown_event_is_dispatched_to_the_scripts() {
+        eval(
+            r#"com.mycompany.MyApp.addEventListener("shutdown", () => {
+                globalThis.shutdownDispatched = true;
+            });"#,
+        );
+        shutdown_scripts().await;
+        assert_eq!(eval("globalThis.shutdownDispatched"), "true");
+    }
+
+    #[actix_web::test]
+    async fn instances_keep_their_own_state() {
+        let names = eval(
+            r#"{
+                const first = new com.mycompany.MyApp("first");
+                const second = new com.mycompany.MyApp();
+                [first.getName(), second.getName().startsWith("instance-"), first.getId() !== second.getId()].join()
+            }"#,
+        );
+        assert_eq!(names, "first,true,true");
+    }
+
+    #[actix_web::test]
+    async fn the_request_id_is_on_the_event_and_the_response() {
+        eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "request-id") {
+                    evt.responseBody = evt.requestId;
+                }
+            });"#,
+        );
+        let req = test::TestRequest::get().insert_header(("x-test", "request-id"));
+        let (_, headers, body) = call(req).await;
+        assert_eq!(body.len(), 36);
+        assert_eq!(headers.get("x-request-id").unwrap().as_bytes(), body);
+    }
+
+    #[actix_web::test]
+    async fn unmatched_paths_dispatch_the_not_found_event() {
+        eval(
+            r#"com.mycompany.MyApp.addEventListener("notFound", (evt) => {
+                if (evt.headers["x-test"] === "not-found") {
+                    evt.responseStatus = 410;
+                    evt.responseBody = "gone from " + evt.path;
+                }
+            });"#,
+        );
+        let req = test::TestRequest::get()
+            .uri("/no-such-path")
+            .insert_header(("x-test", "not-found"));
+        let (status, _, body) = call(req).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body, "gone from /no-such-path");
+        // without a response from the script it is a plain 404
+        let (status, _, body) = call(test::TestRequest::get().uri("/no-such-path")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn only_the_proxies_of_the_enabled_features_are_installed() {
+        let installed = eval(
+            r#"[typeof com.mycompany.MyApp.sha256, typeof com.mycompany.MyApp.wsSend].join()"#,
+        );
+        let expected = |enabled: bool| if enabled { "function" } else { "undefined" };
+        assert_eq!(
+            installed,
+            format!(
+                "{},{}",
+                expected(cfg!(feature = "crypto")),
+                expected(cfg!(feature = "ws"))
+            )
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[actix_web::test]
+    async fn reinstalling_the_proxies_restores_them_and_keeps_the_listeners() {
+        // kvGet would be an example too but other tests use it in this realm at the same time
+        eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "reinstall") {
+                    evt.responseBody = typeof com.mycompany.MyApp.registerSchema;
+                }
+            });
+            // a plain assignment throws in the quickjs_runtime of the tests until the member was read once
+            Object.defineProperty(com.mycompany.MyApp, "registerSchema", {value: undefined, configurable: true});"#,
+        );
+        with_realm(|realm| reinstall_proxies(realm, 0))
+            .ok()
+            .unwrap();
+        let req = test::TestRequest::get().insert_header(("x-test", "reinstall"));
+        let (_, _, body) = call(req).await;
+        assert_eq!(body, "function");
+    }
+
+    #[actix_web::test]
+    async fn the_time_of_the_script_is_in_the_server_timing_header() {
+        let (_, headers, _) = call(test::TestRequest::get()).await;
+        let server_timing = headers.get("server-timing").unwrap().to_str().unwrap();
+        let dur = server_timing.strip_prefix("script;dur=").unwrap();
+        assert!(dur.parse::<f64>().unwrap() >= 0.0);
+    }
+
+    #[actix_web::test]
+    async fn the_error_event_can_respond_to_a_listener_which_threw() {
+        eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] && evt.headers["x-test"].startsWith("recover")) {
+                    evt.responseBody = "discarded";
+                    throw new TypeError("broken");
+                }
+            });
+            com.mycompany.MyApp.addEventListener("error", (evt) => {
+                if (evt.headers["x-test"] === "recover") {
+                    evt.responseStatus = 503;
+                    evt.responseBody = evt.error.name + ": " + evt.error.message;
+                }
+            });"#,
+        );
+        let req = test::TestRequest::get().insert_header(("x-test", "recover"));
+        let (status, _, body) = call(req).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "TypeError: broken");
+        // without a response from an error listener it is the 500
+        let req = test::TestRequest::get().insert_header(("x-test", "recover-not"));
+        let (status, _, _) = call(req).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn the_trace_context_of_the_traceparent_reaches_the_script() {
+        eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "trace") {
+                    const trace = evt.trace;
+                    evt.responseBody = [trace.traceId, trace.parentSpanId, trace.sampled, trace.traceparent].join(",");
+                }
+            });"#,
+        );
+        let req = test::TestRequest::get()
+            .insert_header(("x-test", "trace"))
+            .insert_header((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+            ));
+        let (_, _, body) = call(req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let parts: Vec<&str> = body.split(',').collect();
+        assert_eq!(
+            parts[..3],
+            [
+                "4bf92f3577b34da6a3ce929d0e0e4736",
+                "00f067aa0ba902b7",
+                "false"
+            ]
+        );
+        assert!(parts[3].starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(parts[3].ends_with("-00"));
+        assert!(!parts[3].contains("00f067aa0ba902b7"));
+    }
+
+    #[actix_web::test]
+    async fn a_handler_result_which_does_not_match_the_response_schema_fails() {
+        eval(
+            r#"com.mycompany.MyApp.registerResponseSchema("/response-schema-test", {
+                type: "object",
+                required: ["id", "name"],
+            });
+            globalThis.__routeHandlers = globalThis.__routeHandlers || {};
+            globalThis.__routeHandlers["/response-schema-test"] = (evt) => evt.returns;"#,
+        );
+        let invoke = |returns: &'static str| {
+            with_realm(move |realm| {
+                let handler = routes::RouteHandler {
+                    route: "/response-schema-test",
+                    module: "file://response_schema_test.js",
+                    export: routes::DEFAULT_EXPORT,
+                };
+                let event_obj = realm
+                    .js_eval(Script::new(
+                        "file://response_schema_test.js",
+                        format!("({{returns: {}}})", returns).as_str(),
+                    ))
+                    .ok()
+                    .unwrap();
+                invoke_handler(realm, &handler, &event_obj, 0).err()
+            })
+        };
+        assert!(invoke(r#"{id: 1, name: "jane"}"#).is_none());
+        let err = invoke("{id: 1}").unwrap();
+        assert_eq!(err.get_name(), "ResponseSchemaError");
+        assert!(
+            err.get_message()
+                .contains("\"name\" is a required property"),
+            "{}",
+            err.get_message()
+        );
+        // a 500 like other script errors
+        assert!(errors::http_error_status(&err).is_none());
+    }
 }
@@ -1,13 +1,377 @@
+/// <reference path="../types/my_app.d.ts" />
 import { calc } from 'ModuleA.ts';
 console.log("7*8=%s", calc(7, 8));
 
-type MyApp = EventTarget & {
-    printSomething: (thing: string) => void
+// the static members of com.mycompany.MyApp as generated from what is installed (see SCRIPT_DTS_PATH), the
+// members of a capability which is not granted are not installed and thus not declared
+type MyApp = typeof com.mycompany.MyApp;
+
+type TraceContext = {
+    traceId: string,
+    // the span of this request, the parent of the spans of outbound calls
+    spanId: string,
+    // the span of the caller from its traceparent header, null when the trace started here
+    parentSpanId: string | null,
+    sampled: boolean,
+    traceparent: string,
+    tracestate: string | null
+};
+
+type WsEvent = {
+    connectionId: string,
+    // only set for ws:message
+    data?: string
+};
+
+type AppState = {
+    // SCRIPT_APP_NAME
+    readonly name: string,
+    readonly version: string,
+    readonly debug: boolean,
+    readonly poolSize: number,
+    readonly isolateRequests: boolean,
+    readonly tenants: readonly string[],
+    readonly scriptTimeoutMs: number
+};
+
+type SetCookie = {
+    name: string,
+    value: string,
+    path?: string,
+    domain?: string,
+    maxAge?: number,
+    httpOnly?: boolean,
+    secure?: boolean,
+    sameSite?: "Strict" | "Lax" | "None"
+};
+
+type UploadedFile = {
+    id: string,
+    field: string,
+    filename: string,
+    contentType: string | null,
+    size: number
+};
+
+type RequestEvent = {
+    // a com.mycompany.RequestEvent, a typed view of the event: the setters validate their argument before they set
+    // responseStatus, responseBody, responseJson or responseHeaders and throw once the request was dispatched
+    request: com.mycompany.RequestEvent,
+    requestId: string,
+    method: string,
+    path: string,
+    remoteAddr: string,
+    // the X-Forwarded-For entry of the outermost of SCRIPT_TRUSTED_PROXIES, remoteAddr when that is 0
+    realIp: string,
+    route: string,
+    // the tenant from SCRIPT_TENANT_HEADER or the subdomain, null when SCRIPT_TENANTS is not set or none matched
+    tenant: string | null,
+    // the claims of the verified bearer token on a route in SCRIPT_AUTH_ROUTES, null on other routes
+    auth: Record<string, any> | null,
+    // the path parameters of the route like id for /users/{id}
+    params: Record<string, string>,
+    // the last value of repeated keys, see queryAll for all values
+    query: Record<string, string>,
+    queryAll: Record<string, string[]>,
+    // the raw query string without the ?
+    queryString: string,
+    headers: Record<string, string>,
+    cookies: Record<string, string>,
+    // read-only, the same for every request until the next restart
+    app: AppState,
+    // the W3C trace context, pass trace.traceparent on as header to continue the trace in the services we call
+    trace: TraceContext,
+    // the parsed body for application/json requests
+    body?: any,
+    // the body as string for non json requests or when the json could not be parsed, decoded with the charset of the
+    // Content-Type (utf-8 when it has none)
+    rawBody?: string,
+    // the body as bytes for requests which are not text, json or a form, like application/octet-stream or image/png
+    bodyBytes?: Uint8Array,
+    // the fields of application/x-www-form-urlencoded requests, repeated keys get an array of their values
+    form?: Record<string, string | string[]>,
+    bodyParseError?: boolean,
+    // the files and fields of multipart/form-data requests
+    files?: UploadedFile[],
+    fields?: Record<string, string>,
+    // set these to alter the response, defaults to 200 / "hello there"
+    responseStatus?: number,
+    responseBody?: string,
+    // sent as json, can't be combined with responseBody
+    responseJson?: any,
+    // binary content as Uint8Array or base64 string, can't be combined with responseBody or responseJson
+    responseBytes?: Uint8Array | string,
+    // a file from SCRIPT_FILES_DIR, needs the fs capability, a missing file is a 404
+    // sent with an ETag, Last-Modified and Cache-Control (see SCRIPT_STATIC_MAX_AGE), conditional requests get a 304
+    // the content type is guessed from the extension
+    responseFile?: string,
+    // a mime type like text/csv, defaults to text/plain for responseBody, application/json for responseJson and
+    // application/octet-stream for responseBytes
+    responseContentType?: string,
+    setCookies?: SetCookie[],
+    // extra response headers like Cache-Control, values can't contain newlines
+    responseHeaders?: Record<string, string>,
+    // set by download(filename)
+    responseDownload?: string,
+    // e.g. a sha256 of the body, when it matches If-None-Match the response is a 304 without body
+    etag?: string,
+    // only set for the error event, the error thrown by a listener or handler
+    error?: {name: string, message: string, stack: string},
+    // respond with a redirect, status defaults to 302 and should be a 3xx
+    redirect: (location: string, status?: number) => void,
+    // respond with Content-Disposition: attachment so browsers save the body as filename, path segments are dropped
+    download: (filename: string) => void,
+    // GET only, serve this response to the next requests for the same path and query without dispatching them
+    // seconds is 1 to 86400, key defaults to the path and is what cacheInvalidate(key) drops
+    cacheFor: (seconds: number, key?: string) => void,
+    // run the callback after the response was sent, also when a listener threw, for work the client should not
+    // wait for like audit logging, can only be called until the response was created
+    after: (callback: () => any) => void,
+    // write a chunk of a streaming response, end() must be called when done
+    // returns false when over 1MB was written which the client did not receive yet, wait before writing more
+    write: (chunk: string) => boolean,
+    // write the value as a line of json, the response defaults to application/x-ndjson when called from the listener
+    writeJson: (value: any) => boolean,
+    end: () => void
+};
+
+// the event of the routes in STREAMING_ROUTES, the same object is passed to every body:chunk and the body:end event
+// so listeners can keep their state on it
+type BodyStreamEvent = RequestEvent & {
+    // the current chunk, only set for body:chunk
+    chunk?: Uint8Array,
+    chunkIndex?: number,
+    // the size of the whole body, only set for body:end
+    size?: number,
+    received?: number
+};
+
+// installed as a global, thrown from a request listener the response gets the status and the message as body
+declare class HttpError extends Error {
+    constructor(status: number, message?: string);
+    status: number;
+}
+
+// a resource backed by rust like a FileHandle, the rust side is released by close() or dispose() (the same) or else
+// when the object is garbage collected, closing it again does nothing
+interface Disposable {
+    close(): void;
+    dispose(): void;
+    readonly closed: boolean;
+}
+
+// installed as a global, calls fn with the resource and closes it when fn returns, throws or its promise settles
+declare function using<T extends Disposable, U>(resource: T, fn: (resource: T) => U): U;
+
+// installed as a frozen global before any script runs, what was decided when compiling
+declare const __BUILD__: {
+    readonly profile: "debug" | "release",
+    readonly version: string,
+    readonly gitHash: string,
+    // the optional cargo features which were compiled in
+    readonly features: readonly string[],
+    readonly os: string,
+    readonly arch: string
+};
+
+// dispatched as rpc:<method> for JSON-RPC calls to POST /rpc
+type RpcEvent = {
+    method: string,
+    params: any,
+    // null or undefined for notifications
+    id: string | number | null | undefined,
+    // set one of these to respond, when neither is set the method is not found
+    result?: any,
+    error?: {code: number, message: string, data?: any}
+};
+
+type MyAppInstance = EventTarget & {
+    getId: () => number,
+    getName: () => string
 };
 
 const myApp: MyApp = com.mycompany.MyApp;
 
-com.mycompany.MyApp.addEventListener("request", (evt) => {
-    myApp.printSomething("Just letting you know javascript received your event loud and clear!");
+myApp.setRateLimit("/api", 60);
+myApp.registerSchema("/webhook", {type: "object", required: ["event"], properties: {event: {type: "string"}}});
+myApp.registerResponseSchema("/time", {type: "object", required: ["time"], properties: {time: {type: "string"}}});
+myApp.setCorsPolicy({origins: ["http://localhost:3000"], methods: ["GET", "POST"], headers: ["Content-Type"], maxAge: 600});
+
+const instanceA: MyAppInstance = new com.mycompany.MyApp("a");
+const instanceB: MyAppInstance = new com.mycompany.MyApp("b");
+console.log("created MyApp instances %s (%s) and %s (%s)", instanceA.getName(), instanceA.getId(), instanceB.getName(), instanceB.getId());
+
+// middleware runs before the request handlers, returning false vetoes the event so the handlers are skipped
+// when SCRIPT_API_TOKEN is set /api requires it as bearer token
+com.mycompany.MyApp.addEventListener("pre-request", (evt: RequestEvent) => {
+    // getEnv is only there with the env capability
+    const token = myApp.getEnv?.("SCRIPT_API_TOKEN");
+    if (token && evt.route === "/api" && evt.headers["authorization"] !== "Bearer " + token) {
+        evt.responseStatus = 401;
+        evt.responseBody = "unauthorized";
+        return false;
+    }
+    if (token) {
+        // code called by the handlers can read the user with myApp.getContext().user
+        myApp.getContext().user = "api-client";
+    }
+});
+
+com.mycompany.MyApp.addEventListener("request", (evt: RequestEvent) => {
+    myApp.printSomething("Just letting you know javascript received your " + evt.method + " " + evt.path + " event loud and clear!");
     console.log("logging from javascript");
+});
+
+// method events like get, post and delete are dispatched after the request:<route> event for every route
+com.mycompany.MyApp.addEventListener("post", (evt: RequestEvent) => {
+    console.debug("received a POST on %s", evt.route);
+});
+
+// streams the users as json lines, ?count= limits the number of users
+// writeJson returns false when the client is not keeping up, we then continue in a timer instead of queuing more
+com.mycompany.MyApp.addEventListener("request:/export", (evt: RequestEvent) => {
+    const count = Math.min(parseInt(evt.query.count || "3") || 3, 1000);
+    evt.download("users.ndjson");
+    let id = 1;
+    const writeUsers = () => {
+        while (id <= count) {
+            const keepWriting = evt.writeJson({id: id, name: "user " + id});
+            id++;
+            if (!keepWriting) {
+                setTimeout(writeUsers, 10);
+                return;
+            }
+        }
+        evt.end();
+    };
+    writeUsers();
+});
+
+com.mycompany.MyApp.addEventListener("request:/api", (evt: RequestEvent) => {
+    const count = parseInt(myApp.kvGet("apiCount") || "0") + 1;
+    // counted once the client got its response
+    evt.after(() => myApp.incrCounter("api_calls"));
+    myApp.kvSet("apiCount", "" + count);
+    evt.responseJson = {message: "hello from " + evt.app.name, count: count};
+    if (myApp.isEnabled("api-beta")) {
+        evt.responseJson.beta = true;
+    }
+    evt.responseHeaders = {"Cache-Control": "no-store"};
+});
+
+com.mycompany.MyApp.addEventListener("request:/users/{id}", (evt: RequestEvent) => {
+    if (!/^[0-9]+$/.test(evt.params.id)) {
+        throw new HttpError(404, "no such user");
+    }
+    evt.request.setJson({id: evt.params.id});
+    evt.request.setHeader("X-User-Id", evt.params.id);
+    evt.cacheFor(60, "users");
+    // sha256 is only there when compiled with the crypto feature
+    if (myApp.sha256) {
+        evt.etag = myApp.sha256(JSON.stringify(evt.responseJson));
+    }
+});
+
+// the events of the /dashboard aggregate are dispatched concurrently, the response has the result of each
+com.mycompany.MyApp.addEventListener("dashboard:greeting", (evt: RequestEvent & {result?: any}) => {
+    evt.result = "hello " + (evt.query.name || "there");
+});
+
+com.mycompany.MyApp.addEventListener("dashboard:apiCount", (evt: RequestEvent & {result?: any}) => {
+    evt.result = parseInt(myApp.kvGet("apiCount") || "0");
+});
+
+// the etag and Last-Modified of the file let browsers revalidate with a 304 instead of downloading it again
+com.mycompany.MyApp.addEventListener("request:/assets/{name}", (evt: RequestEvent) => {
+    evt.responseFile = evt.params.name;
+});
+
+com.mycompany.MyApp.addEventListener("request:/webhook", (evt: RequestEvent) => {
+    if (evt.body.event === "user.updated") {
+        myApp.cacheInvalidate("users");
+    }
+    evt.write("received ");
+    setTimeout(() => {
+        evt.write("your webhook");
+        evt.end();
+    }, 10);
+});
+
+// returning false from a body:chunk listener aborts the request without reading the rest of the body
+com.mycompany.MyApp.addEventListener("body:chunk", (evt: BodyStreamEvent) => {
+    evt.received = (evt.received || 0) + evt.chunk!.length;
+    if (evt.received > 1024 * 1024) {
+        evt.responseStatus = 413;
+        evt.responseBody = "at most 1MB can be ingested";
+        return false;
+    }
+});
+
+com.mycompany.MyApp.addEventListener("body:end", (evt: BodyStreamEvent) => {
+    evt.responseJson = {chunks: evt.chunkIndex === undefined ? 0 : evt.chunkIndex + 1, size: evt.size};
+});
+
+// dispatched when a listener throws, without a response set here the client gets a 500
+com.mycompany.MyApp.addEventListener("error", (evt: RequestEvent) => {
+    if (evt.route === "/api") {
+        evt.responseStatus = 503;
+        evt.responseJson = {error: "the api is temporarily unavailable"};
+    }
+});
+
+// dispatched for paths which match none of the routes, without a response set here it is a plain 404
+com.mycompany.MyApp.addEventListener("notFound", (evt: RequestEvent) => {
+    if (evt.path.startsWith("/api/")) {
+        evt.responseStatus = 404;
+        evt.responseJson = {error: "not found", path: evt.path};
+    }
+});
+
+com.mycompany.MyApp.addEventListener("rpc:add", (evt: RpcEvent) => {
+    const [a, b] = evt.params;
+    if (typeof a !== "number" || typeof b !== "number") {
+        evt.error = {code: -32602, message: "add expects two numbers"};
+        return;
+    }
+    evt.result = a + b;
+});
+
+// dispatched once in every runtime (and isolated realm) after the modules are evaluated, before requests are served
+// throwing here aborts the startup
+com.mycompany.MyApp.addEventListener("init", (evt: {runtime: number}) => {
+    const info = myApp.version();
+    console.debug("initializing runtime %s of my_app %s (%s, %s build)", evt.runtime, info.version, info.gitHash, __BUILD__.profile);
+    myApp.registerRoute("GET", "/status", "status");
+    myApp.registerRoute("GET", "/greeting", "greeting");
+});
+
+// the handler events of the routes registered in init
+com.mycompany.MyApp.addEventListener("status", (evt: RequestEvent) => {
+    evt.responseJson = {status: "ok", version: myApp.version().version};
+});
+
+com.mycompany.MyApp.addEventListener("greeting", (evt: RequestEvent) => {
+    evt.responseContentType = "text/html; charset=utf-8";
+    evt.responseBody = myApp.render("greeting", {app: evt.app.name, name: evt.query.name || "there", items: evt.queryAll.item});
+});
+
+// dispatched SCRIPT_WARMUP_ITERATIONS times at startup, before we serve requests
+com.mycompany.MyApp.addEventListener("warmup", (evt: {iteration: number}) => {
+    const started = performance.now();
+    calc(evt.iteration, 2);
+    console.debug("warmup %s took %sms", evt.iteration, performance.now() - started);
+});
+
+// dispatched every minute, see scheduler.rs
+com.mycompany.MyApp.addEventListener("cron:cleanup", () => {
+    console.log("running cleanup");
+});
+
+com.mycompany.MyApp.addEventListener("shutdown", () => {
+    console.log("shutting down");
+});
+
+com.mycompany.MyApp.addEventListener("ws:message", (evt: WsEvent) => {
+    myApp.wsSend!(evt.connectionId, "echo: " + evt.data);
+    myApp.sseBroadcast("ws", evt.data || "");
 });
\ No newline at end of file
@@ -0,0 +1,76 @@
+use crate::config;
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// the Retry-After of the 503 while in maintenance, a maintenance window usually takes a few minutes
+const RETRY_AFTER_SECS: u64 = 60;
+
+// shared by all workers, starts from SCRIPT_MAINTENANCE and can be changed with POST /admin/maintenance
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// set the state from the config, called once at startup
+pub fn init(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+    if enabled {
+        log::warn!("starting in maintenance mode");
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// enter or leave maintenance mode, returns the previous state
+pub fn set(enabled: bool) -> bool {
+    ENABLED.swap(enabled, Ordering::SeqCst)
+}
+
+/// the 503 with SCRIPT_MAINTENANCE_BODY to respond with while in maintenance, the request is then not dispatched
+/// /health, /metrics and the /admin endpoints don't check this so the server can be monitored and taken out of
+/// maintenance again
+pub fn check() -> Option<HttpResponse> {
+    if !is_enabled() {
+        return None;
+    }
+    Some(
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()))
+            .content_type("text/plain; charset=utf-8")
+            .body(config::get().maintenance_body.clone()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn in_maintenance_requests_get_a_503_and_health_stays_up() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "maintenance") {
+                    evt.responseBody = "dispatched";
+                }
+            });"#,
+        );
+        let request = || TestRequest::get().insert_header(("x-test", "maintenance"));
+        let _maintenance = crate::tests::maintenance_lock().write().await;
+        assert!(!set(true));
+        let (status, headers, body) = crate::tests::respond(request()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers.get("retry-after").unwrap(), "60");
+        assert_eq!(body, config::get().maintenance_body.as_str());
+        let (status, _, body) = crate::tests::respond(TestRequest::get().uri("/health")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"status":"maintenance"}"#);
+        assert!(set(false));
+        let (status, _, body) = crate::tests::respond(request()).await;
+        assert_eq!(
+            (status, body.as_ref()),
+            (StatusCode::OK, &b"dispatched"[..])
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::modules::ScriptModuleLoader;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+const MEMORY_PREFIX: &str = "mem://";
+
+lazy_static! {
+    // the modules compiled into the binary by name, these are preferred over modules with the same name on disk
+    static ref MODULES: HashMap<&'static str, &'static str> = {
+        let mut modules = HashMap::new();
+        modules.insert("ModuleA.ts", include_str!("../modules/ModuleA.ts"));
+        modules
+    };
+}
+
+/// a ScriptModuleLoader for the modules which are embedded in the binary
+/// modules are imported by name like import {calc} from 'ModuleA.ts' and get a mem:// path
+#[derive(Default)]
+pub struct MemoryModuleLoader {}
+
+impl MemoryModuleLoader {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl<R: JsRealmAdapter> ScriptModuleLoader<R> for MemoryModuleLoader {
+    fn normalize_path(&self, _realm: &R, _ref_path: &str, path: &str) -> Option<String> {
+        let name = path
+            .strip_prefix(MEMORY_PREFIX)
+            .unwrap_or(path)
+            .trim_start_matches("./");
+        if MODULES.contains_key(name) {
+            Some(format!("{}{}", MEMORY_PREFIX, name))
+        } else {
+            None
+        }
+    }
+
+    fn load_module(&self, _realm: &R, absolute_path: &str) -> String {
+        let name = absolute_path
+            .strip_prefix(MEMORY_PREFIX)
+            .unwrap_or(absolute_path);
+        // normalize_path only returns paths of modules which exist
+        MODULES
+            .get(name)
+            .map(|code| code.to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    #[test]
+    fn embedded_modules_are_found_by_name() {
+        let rt = QuickJsRuntimeBuilder::new().build();
+        rt.js_loop_realm_sync(None, |_rt, realm| {
+            let loader = MemoryModuleLoader::new();
+            for path in ["ModuleA.ts", "./ModuleA.ts", "mem://ModuleA.ts"] {
+                assert_eq!(
+                    loader.normalize_path(realm, "file://main.ts", path),
+                    Some("mem://ModuleA.ts".to_string())
+                );
+            }
+            assert_eq!(
+                loader.normalize_path(realm, "file://main.ts", "ModuleB.ts"),
+                None
+            );
+            assert!(loader
+                .load_module(realm, "mem://ModuleA.ts")
+                .contains("export function calc"));
+        });
+    }
+}
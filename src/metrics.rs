@@ -0,0 +1,630 @@
+use crate::trace::TraceContext;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{
+    Counter, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the max number of metrics scripts can create, every name is a new time series so this bounds the cardinality
+pub const MAX_SCRIPT_METRICS: usize = 100;
+
+/// the prefix of the metrics created by scripts so they can't clash with ours
+pub const SCRIPT_METRIC_PREFIX: &str = "script_app_";
+
+/// the content type of the OpenMetrics format, the only one with exemplars
+const OPENMETRICS_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// a metric created by script with incrCounter or observeHistogram
+enum ScriptMetric {
+    Counter(Counter),
+    Histogram(Histogram),
+}
+
+/// the last sampled request which fell in a bucket of DISPATCH_DURATION, links the bucket to a trace
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    // seconds since the epoch
+    timestamp: f64,
+}
+
+// method and route
+type RouteKey = (String, String);
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    // the exemplars of DISPATCH_DURATION by method and route, one per bucket with the +Inf bucket last
+    static ref DISPATCH_EXEMPLARS: Mutex<HashMap<RouteKey, Vec<Option<Exemplar>>>> =
+        Mutex::new(HashMap::new());
+    // the metrics created by scripts by name without SCRIPT_METRIC_PREFIX
+    static ref SCRIPT_METRICS: Mutex<HashMap<String, ScriptMetric>> = Mutex::new(HashMap::new());
+    // labels are kept to method and route (the route pattern, not the path) to keep cardinality low
+    pub static ref DISPATCHED: IntCounterVec = register_counter_vec(
+        "script_dispatched_events_total",
+        "the number of requests dispatched to the script",
+        &["method", "route"]
+    );
+    pub static ref SCRIPT_ERRORS: IntCounterVec = register_counter_vec(
+        "script_errors_total",
+        "the number of requests for which the script failed",
+        &["method", "route"]
+    );
+    // observed with observe_dispatch_duration so the buckets get exemplars
+    pub static ref DISPATCH_DURATION: HistogramVec = {
+        let histogram = HistogramVec::new(
+            HistogramOpts::new(
+                "script_dispatch_duration_seconds",
+                "the time it took to dispatch a request to the script",
+            )
+            .buckets(prometheus::DEFAULT_BUCKETS.to_vec()),
+            &["method", "route"],
+        )
+        .expect("could not create histogram");
+        REGISTRY
+            .register(Box::new(histogram.clone()))
+            .expect("could not register histogram");
+        histogram
+    };
+    // compressed responses are measured before compression
+    pub static ref REQUEST_BODY_BYTES: HistogramVec = register_size_histogram(
+        "script_request_body_bytes",
+        "the size of the request bodies passed to the script"
+    );
+    pub static ref RESPONSE_BODY_BYTES: HistogramVec = register_size_histogram(
+        "script_response_body_bytes",
+        "the size of the response bodies produced by the script, for streams the total when the stream is done"
+    );
+    // the number of dispatch jobs which were added to a runtime and did not complete yet
+    pub static ref PENDING_JOBS: IntGauge = {
+        let gauge = IntGauge::new(
+            "script_pending_jobs",
+            "the number of dispatch jobs queued or running in the runtimes",
+        )
+        .expect("could not create gauge");
+        REGISTRY
+            .register(Box::new(gauge.clone()))
+            .expect("could not register gauge");
+        gauge
+    };
+    // the requests which got a 503 because SCRIPT_MAX_PENDING requests were already pending, see backpressure.rs
+    pub static ref OVERLOADED: IntCounter = {
+        let counter = IntCounter::new(
+            "script_overloaded_requests_total",
+            "the number of requests rejected because too many requests were pending",
+        )
+        .expect("could not create counter");
+        REGISTRY
+            .register(Box::new(counter.clone()))
+            .expect("could not register counter");
+        counter
+    };
+    // the requests which were served from the response cache without dispatching them, see response_cache.rs
+    pub static ref RESPONSE_CACHE_HITS: IntCounter = {
+        let counter = IntCounter::new(
+            "script_response_cache_hits_total",
+            "the number of requests which were served from the response cache",
+        )
+        .expect("could not create counter");
+        REGISTRY
+            .register(Box::new(counter.clone()))
+            .expect("could not register counter");
+        counter
+    };
+    // the GETs which got the response of an identical request in flight without dispatching them, see coalesce.rs
+    pub static ref COALESCED_REQUESTS: IntCounter = {
+        let counter = IntCounter::new(
+            "script_coalesced_requests_total",
+            "the number of requests which got the response of an identical request in flight",
+        )
+        .expect("could not create counter");
+        REGISTRY
+            .register(Box::new(counter.clone()))
+            .expect("could not register counter");
+        counter
+    };
+    // by target, a host for fetch and database for query, see circuit_breaker.rs
+    pub static ref CIRCUIT_BREAKER_STATE: IntGaugeVec = {
+        let gauge = IntGaugeVec::new(
+            Opts::new(
+                "script_circuit_breaker_state",
+                "the state of the circuit breaker of a target, 0 closed, 1 open and 2 half open",
+            ),
+            &["target"],
+        )
+        .expect("could not create gauge");
+        REGISTRY
+            .register(Box::new(gauge.clone()))
+            .expect("could not register gauge");
+        gauge
+    };
+    pub static ref CIRCUIT_BREAKER_REJECTIONS: IntCounterVec = register_counter_vec(
+        "script_circuit_breaker_rejections_total",
+        "the number of calls which failed fast because the circuit breaker of their target was open",
+        &["target"]
+    );
+    // only used when SCRIPT_ISOLATE_REQUESTS is set, includes evaluating the entry modules in the new realm
+    pub static ref REALM_CREATE_DURATION: Histogram = {
+        let histogram = Histogram::with_opts(HistogramOpts::new(
+            "script_realm_create_duration_seconds",
+            "the time it took to create and initialize a realm for a request",
+        ))
+        .expect("could not create histogram");
+        REGISTRY
+            .register(Box::new(histogram.clone()))
+            .expect("could not register histogram");
+        histogram
+    };
+}
+
+fn register_counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter =
+        IntCounterVec::new(Opts::new(name, help), labels).expect("could not create counter");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("could not register counter");
+    counter
+}
+
+// 64 bytes up to 16MB
+fn register_size_histogram(name: &str, help: &str) -> HistogramVec {
+    let histogram = HistogramVec::new(
+        HistogramOpts::new(name, help)
+            .buckets(prometheus::exponential_buckets(64.0, 4.0, 10).expect("invalid buckets")),
+        &["method", "route"],
+    )
+    .expect("could not create histogram");
+    REGISTRY
+        .register(Box::new(histogram.clone()))
+        .expect("could not register histogram");
+    histogram
+}
+
+/// record the size of the body of a response in RESPONSE_BODY_BYTES, returns the size
+/// returns None for a streamed body, those record their size with a StreamedSize when the stream is done
+pub fn observe_response_size(labels: &[&str], response: &HttpResponse) -> Option<u64> {
+    let size = match response.body().size() {
+        BodySize::Sized(size) => size,
+        BodySize::None => 0,
+        BodySize::Stream => return None,
+    };
+    RESPONSE_BODY_BYTES
+        .with_label_values(labels)
+        .observe(size as f64);
+    Some(size)
+}
+
+/// counts the bytes of a streamed response body, the total is recorded in RESPONSE_BODY_BYTES when this is dropped
+/// together with the stream, which also happens when the client disconnects before the stream ended
+pub struct StreamedSize {
+    method: String,
+    route: String,
+    bytes: u64,
+}
+
+impl StreamedSize {
+    pub fn new(method: &str, route: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            route: route.to_string(),
+            bytes: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for StreamedSize {
+    fn drop(&mut self) {
+        RESPONSE_BODY_BYTES
+            .with_label_values(&[self.method.as_str(), self.route.as_str()])
+            .observe(self.bytes as f64);
+    }
+}
+
+/// record the dispatch duration of a request in DISPATCH_DURATION, labels are the method and route
+/// the request becomes the exemplar of the bucket its duration falls in so a slow sample links to its trace, only
+/// for sampled traces as the others are not exported
+pub fn observe_dispatch_duration(labels: &[&str], seconds: f64, trace: &TraceContext) {
+    DISPATCH_DURATION.with_label_values(labels).observe(seconds);
+    if !trace.sampled {
+        return;
+    }
+    let bounds = prometheus::DEFAULT_BUCKETS;
+    let bucket = bounds
+        .iter()
+        .position(|bound| seconds <= *bound)
+        .unwrap_or(bounds.len());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since| since.as_secs_f64());
+    let mut exemplars = DISPATCH_EXEMPLARS.lock().unwrap();
+    let buckets = exemplars
+        .entry((labels[0].to_string(), labels[1].to_string()))
+        .or_insert_with(|| (0..=bounds.len()).map(|_| None).collect());
+    buckets[bucket] = Some(Exemplar {
+        trace_id: trace.trace_id.clone(),
+        value: seconds,
+        timestamp,
+    });
+}
+
+/// add to the counter of a script, the counter is created the first time it is used as script_app_<name>
+pub fn incr_script_counter(name: &str, by: f64) -> Result<(), String> {
+    if !by.is_finite() || by < 0.0 {
+        return Err(format!("a counter can only be increased, got {}", by));
+    }
+    with_script_metric(name, "counter", |metric| match metric {
+        ScriptMetric::Counter(counter) => {
+            counter.inc_by(by);
+            Ok(())
+        }
+        ScriptMetric::Histogram(_) => Err(format!("{} is a histogram, not a counter", name)),
+    })
+}
+
+/// observe a value in the histogram of a script, created with the default buckets as script_app_<name>
+pub fn observe_script_histogram(name: &str, value: f64) -> Result<(), String> {
+    if !value.is_finite() {
+        return Err(format!("can not observe {}", value));
+    }
+    with_script_metric(name, "histogram", |metric| match metric {
+        ScriptMetric::Histogram(histogram) => {
+            histogram.observe(value);
+            Ok(())
+        }
+        ScriptMetric::Counter(_) => Err(format!("{} is a counter, not a histogram", name)),
+    })
+}
+
+fn with_script_metric<C: FnOnce(&ScriptMetric) -> Result<(), String>>(
+    name: &str,
+    kind: &str,
+    consumer: C,
+) -> Result<(), String> {
+    let mut metrics = SCRIPT_METRICS.lock().unwrap();
+    if let Some(metric) = metrics.get(name) {
+        return consumer(metric);
+    }
+    if metrics.len() >= MAX_SCRIPT_METRICS {
+        return Err(format!(
+            "can not create metric {}, scripts can create at most {} metrics",
+            name, MAX_SCRIPT_METRICS
+        ));
+    }
+    // the prefix is a valid start so only the rest needs checking
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "invalid metric name {}, only a-z, A-Z, 0-9 and _ are allowed",
+            name
+        ));
+    }
+    let full_name = format!("{}{}", SCRIPT_METRIC_PREFIX, name);
+    let help = format!("the {} {} of the script", name, kind);
+    let metric = if kind == "counter" {
+        let counter = Counter::new(full_name, help).map_err(|err| err.to_string())?;
+        REGISTRY
+            .register(Box::new(counter.clone()))
+            .map_err(|err| err.to_string())?;
+        ScriptMetric::Counter(counter)
+    } else {
+        let histogram = Histogram::with_opts(HistogramOpts::new(full_name, help))
+            .map_err(|err| err.to_string())?;
+        REGISTRY
+            .register(Box::new(histogram.clone()))
+            .map_err(|err| err.to_string())?;
+        ScriptMetric::Histogram(histogram)
+    };
+    let res = consumer(&metric);
+    metrics.insert(name.to_string(), metric);
+    res
+}
+
+// a label value or help text with backslashes, quotes and newlines escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+// the labels of a sample like {method="GET",route="/api"}, with le for the buckets of a histogram
+fn format_labels(labels: &[(&str, &str)], le: Option<f64>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        // OpenMetrics wants a float for le, 1.0 rather than 1
+        let le = if le.is_finite() && le.fract() == 0.0 {
+            format!("{:.1}", le)
+        } else {
+            format_value(le)
+        };
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// the metrics in the OpenMetrics text format, with the exemplars of DISPATCH_DURATION on its buckets
+/// counters are named without _total in their TYPE and HELP lines as OpenMetrics adds that to the sample
+fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let exemplars = DISPATCH_EXEMPLARS.lock().unwrap();
+    let dispatch_duration = "script_dispatch_duration_seconds";
+    let mut output = String::new();
+    for family in families {
+        let name = family.get_name();
+        let (family_name, type_name) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            // we don't register these
+            MetricType::SUMMARY | MetricType::UNTYPED => continue,
+        };
+        let _ = writeln!(
+            output,
+            "# HELP {} {}",
+            family_name,
+            escape(family.get_help())
+        );
+        let _ = writeln!(output, "# TYPE {} {}", family_name, type_name);
+        for metric in family.get_metric() {
+            let labels: Vec<(&str, &str)> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let _ = writeln!(
+                        output,
+                        "{}_total{} {}",
+                        family_name,
+                        format_labels(&labels, None),
+                        format_value(metric.get_counter().get_value())
+                    );
+                }
+                MetricType::GAUGE => {
+                    let _ = writeln!(
+                        output,
+                        "{}{} {}",
+                        name,
+                        format_labels(&labels, None),
+                        format_value(metric.get_gauge().get_value())
+                    );
+                }
+                _ => {
+                    let histogram = metric.get_histogram();
+                    let label = |name: &str| {
+                        labels
+                            .iter()
+                            .find(|(label, _)| *label == name)
+                            .map_or("", |(_, value)| *value)
+                    };
+                    let buckets = if name == dispatch_duration {
+                        exemplars.get(&(label("method").to_string(), label("route").to_string()))
+                    } else {
+                        None
+                    };
+                    // the +Inf bucket is not in the proto, its count is the sample count
+                    let bounds = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        .chain(std::iter::once((
+                            f64::INFINITY,
+                            histogram.get_sample_count(),
+                        )));
+                    for (idx, (upper_bound, count)) in bounds.enumerate() {
+                        let exemplar = match buckets.and_then(|buckets| buckets.get(idx)) {
+                            Some(Some(exemplar)) => format!(
+                                " # {{trace_id=\"{}\"}} {} {:.3}",
+                                exemplar.trace_id,
+                                format_value(exemplar.value),
+                                exemplar.timestamp
+                            ),
+                            _ => String::new(),
+                        };
+                        let _ = writeln!(
+                            output,
+                            "{}_bucket{} {}{}",
+                            name,
+                            format_labels(&labels, Some(upper_bound)),
+                            count,
+                            exemplar
+                        );
+                    }
+                    let _ = writeln!(
+                        output,
+                        "{}_count{} {}",
+                        name,
+                        format_labels(&labels, None),
+                        histogram.get_sample_count()
+                    );
+                    let _ = writeln!(
+                        output,
+                        "{}_sum{} {}",
+                        name,
+                        format_labels(&labels, None),
+                        format_value(histogram.get_sample_sum())
+                    );
+                }
+            }
+        }
+    }
+    output.push_str("# EOF\n");
+    output
+}
+
+/// the /metrics endpoint, outputs all metrics in the prometheus text format or, when the scraper accepts it, in the
+/// OpenMetrics format which also has the exemplars linking the buckets of script_dispatch_duration_seconds to traces
+pub async fn metrics(req: HttpRequest) -> HttpResponse {
+    let openmetrics = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    if openmetrics {
+        return HttpResponse::Ok()
+            .content_type(OPENMETRICS_TYPE)
+            .body(encode_openmetrics(&REGISTRY.gather()));
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(err) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        log::error!("could not encode metrics: {}", err);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App};
+
+    #[actix_web::test]
+    async fn dispatched_requests_are_counted_by_method_and_route() {
+        let dispatched = DISPATCHED.with_label_values(&["PATCH", "/webhook"]);
+        let before = dispatched.get();
+        let req = test::TestRequest::patch()
+            .uri("/webhook")
+            .insert_header(("x-test", "metrics"));
+        crate::tests::call(req).await;
+        assert_eq!(dispatched.get(), before + 1);
+        let (_, _, body) = crate::tests::call(test::TestRequest::get().uri("/metrics")).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(
+            body.contains(r#"script_dispatched_events_total{method="PATCH",route="/webhook"} "#)
+        );
+        assert!(body.contains(
+            r#"script_dispatch_duration_seconds_count{method="PATCH",route="/webhook"} "#
+        ));
+    }
+
+    #[actix_web::test]
+    async fn a_script_metric_keeps_the_kind_it_was_created_with() {
+        assert!(incr_script_counter("metrics_test_total", 2.0).is_ok());
+        assert!(incr_script_counter("metrics_test_total", 1.0).is_ok());
+        assert!(observe_script_histogram("metrics_test_total", 1.0).is_err());
+        assert!(incr_script_counter("metrics_test_total", -1.0).is_err());
+        assert!(incr_script_counter("metrics-test", 1.0).is_err());
+        let counter = REGISTRY
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "script_app_metrics_test_total")
+            .unwrap();
+        assert_eq!(counter.get_metric()[0].get_counter().get_value(), 3.0);
+    }
+
+    #[actix_web::test]
+    async fn the_size_of_a_streamed_body_is_recorded_when_it_is_done() {
+        let sizes = RESPONSE_BODY_BYTES.with_label_values(&["GET", "/size-test"]);
+        let response = HttpResponse::Ok().body("12345");
+        assert_eq!(
+            observe_response_size(&["GET", "/size-test"], &response),
+            Some(5)
+        );
+        assert_eq!(sizes.get_sample_sum(), 5.0);
+        let mut streamed = StreamedSize::new("GET", "/size-test");
+        streamed.add(10);
+        streamed.add(20);
+        assert_eq!(sizes.get_sample_count(), 1);
+        drop(streamed);
+        assert_eq!(sizes.get_sample_count(), 2);
+        assert_eq!(sizes.get_sample_sum(), 35.0);
+    }
+
+    #[actix_web::test]
+    async fn a_sampled_request_is_the_exemplar_of_its_bucket() {
+        let trace = |trace_id: &str, sampled| TraceContext {
+            trace_id: trace_id.to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            parent_span_id: None,
+            sampled,
+            tracestate: None,
+        };
+        let labels = ["GET", "/exemplar-test"];
+        observe_dispatch_duration(
+            &labels,
+            0.003,
+            &trace("4bf92f3577b34da6a3ce929d0e0e4736", true),
+        );
+        // not exported, so there would be nothing to link to
+        observe_dispatch_duration(
+            &labels,
+            0.004,
+            &trace("0af7651916cd43dd8448eb211c80319c", false),
+        );
+        let output = encode_openmetrics(&REGISTRY.gather());
+        let bucket = output
+            .lines()
+            .find(|line| {
+                line.starts_with(
+                    "script_dispatch_duration_seconds_bucket{method=\"GET\",route=\"/exemplar-test\",le=\"0.005\"}",
+                )
+            })
+            .unwrap();
+        assert!(
+            bucket.contains(" 2 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.003 "),
+            "{}",
+            bucket
+        );
+    }
+
+    #[actix_web::test]
+    async fn the_format_depends_on_the_accept_header() {
+        DISPATCHED
+            .with_label_values(&["GET", "/metrics-test"])
+            .inc();
+        let app = test::init_service(App::new().route("/metrics", web::get().to(metrics))).await;
+
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            TextEncoder::new().format_type()
+        );
+        let body = test::read_body(res).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("/metrics-test"));
+
+        let req = test::TestRequest::get()
+            .uri("/metrics")
+            .insert_header((header::ACCEPT, "application/openmetrics-text"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            OPENMETRICS_TYPE
+        );
+        let body = test::read_body(res).await;
+        assert!(std::str::from_utf8(&body).unwrap().ends_with("# EOF\n"));
+    }
+}
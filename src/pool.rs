@@ -0,0 +1,102 @@
+use quickjs_runtime::facades::QuickJsRuntimeFacade;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// a pool of identically initialized runtimes
+/// every runtime has its own worker thread so jobs in different runtimes run in parallel
+/// a runtime can be replaced while the server runs, see restart.rs, so the runtimes are handed out as Arc which
+/// keeps a replaced runtime alive for the jobs which still use it
+pub struct ScriptPool {
+    runtimes: Vec<RwLock<Arc<QuickJsRuntimeFacade>>>,
+    next: AtomicUsize,
+}
+
+impl ScriptPool {
+    /// create a new pool, init is called with the index of the runtime in the pool
+    /// fails with the first error returned by init
+    pub fn new<E, F: Fn(usize) -> Result<QuickJsRuntimeFacade, E>>(
+        size: usize,
+        init: F,
+    ) -> Result<Self, E> {
+        assert!(size > 0, "pool size should be at least 1");
+        Ok(Self {
+            runtimes: (0..size)
+                .map(|idx| init(idx).map(|rt| RwLock::new(Arc::new(rt))))
+                .collect::<Result<Vec<_>, E>>()?,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// get the next runtime, runtimes are picked round-robin
+    pub fn next(&self) -> Arc<QuickJsRuntimeFacade> {
+        self.get(self.next_index())
+    }
+
+    /// get the index of the next runtime, for when the index is needed to get back to the same runtime later
+    pub fn next_index(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.runtimes.len()
+    }
+
+    /// get a runtime by its index in the pool
+    /// a job which needs several steps in the same runtime should hold on to the returned runtime instead of getting
+    /// it again, the runtime at an index may have been replaced in between
+    pub fn get(&self, idx: usize) -> Arc<QuickJsRuntimeFacade> {
+        self.runtimes[idx].read().unwrap().clone()
+    }
+
+    /// all runtimes in the pool, used for things which need to happen in every runtime like loading the entry modules
+    pub fn runtimes(&self) -> Vec<Arc<QuickJsRuntimeFacade>> {
+        self.runtimes
+            .iter()
+            .map(|rt| rt.read().unwrap().clone())
+            .collect()
+    }
+
+    /// replace the runtime at an index, the next jobs for that index go to the new runtime
+    /// returns the old runtime so its jobs can be drained before it is dropped
+    pub fn replace(&self, idx: usize, rt: QuickJsRuntimeFacade) -> Arc<QuickJsRuntimeFacade> {
+        std::mem::replace(&mut *self.runtimes[idx].write().unwrap(), Arc::new(rt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    #[test]
+    fn runtimes_are_picked_round_robin() {
+        let pool = ScriptPool::new(2, |_idx| Ok::<_, ()>(QuickJsRuntimeBuilder::new().build()))
+            .ok()
+            .unwrap();
+        let first = pool.next();
+        let second = pool.next();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&pool.next(), &first));
+    }
+
+    #[test]
+    fn a_replaced_runtime_is_kept_for_the_jobs_which_hold_it() {
+        let pool = ScriptPool::new(1, |_idx| Ok::<_, ()>(QuickJsRuntimeBuilder::new().build()))
+            .ok()
+            .unwrap();
+        let held = pool.get(0);
+        let old = pool.replace(0, QuickJsRuntimeBuilder::new().build());
+        assert!(Arc::ptr_eq(&old, &held));
+        assert!(!Arc::ptr_eq(&pool.get(0), &held));
+        drop(old);
+        assert_eq!(Arc::strong_count(&held), 1);
+    }
+
+    #[test]
+    fn the_pool_fails_with_the_first_init_error() {
+        let pool = ScriptPool::new(3, |idx| {
+            if idx == 0 {
+                Ok(QuickJsRuntimeBuilder::new().build())
+            } else {
+                Err(idx)
+            }
+        });
+        assert_eq!(pool.err(), Some(1));
+    }
+}
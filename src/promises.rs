@@ -0,0 +1,99 @@
+use crate::proxies::SafeStaticMethods;
+use crate::tasks::TASKS;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsRuntimeAdapter};
+use hirofa_utils::js_utils::facades::{JsRuntimeFacade, JsRuntimeFacadeInner};
+use hirofa_utils::js_utils::JsError;
+use std::future::Future;
+
+/// the realm type the mapper of a promise gets, for the runtimes we use that is the same type as the realm which
+/// created the promise but the compiler can only see that for a concrete realm type
+pub type PromiseRealm<R> = <<<<<<R as JsRealmAdapter>::JsRuntimeAdapterType as JsRuntimeAdapter>::JsRuntimeFacadeType as JsRuntimeFacade>::JsRuntimeFacadeInnerType as JsRuntimeFacadeInner>::JsRuntimeFacadeType as JsRuntimeFacade>::JsRuntimeAdapterType as JsRuntimeAdapter>::JsRealmAdapterType;
+
+/// the script value type of a PromiseRealm
+pub type PromiseValue<R> = <PromiseRealm<R> as JsRealmAdapter>::JsValueAdapterType;
+
+/// create a promise which is resolved with the result of an async rust job
+///
+/// the producer runs on the task runtime so it can use tokio based libraries, when it completes the mapper converts
+/// its result to a script value in the realm which created the promise
+/// when either the producer or the mapper fail the promise is rejected with an Error carrying the message of the JsError
+pub fn create_promise<R, T, P, M>(
+    realm: &R,
+    producer: P,
+    mapper: M,
+) -> Result<R::JsValueAdapterType, JsError>
+where
+    R: JsRealmAdapter + 'static,
+    T: Send + 'static,
+    P: Future<Output = Result<T, JsError>> + Send + 'static,
+    M: FnOnce(&PromiseRealm<R>, T) -> Result<PromiseValue<R>, JsError> + Send + 'static,
+{
+    realm.js_promise_create_resolving_async(
+        async move {
+            TASKS
+                .spawn("async method", producer)
+                .await
+                .map_err(|err| JsError::new_string(format!("async task failed: {}", err)))?
+                .unwrap_or_else(|| Err(JsError::new_str("async task was stopped on shutdown")))
+        },
+        mapper,
+    )
+}
+
+/// add a static method which returns a promise, the signature is declared like for add_safe_static_method
+///
+/// script values can't be moved off the worker thread so the prepare fn first reads what the async job needs from
+/// the arguments, the job then runs on the task runtime and the mapper converts its result to a script value
+pub fn add_async_static_method<R, I, T, PR, J, F, M>(
+    proxy: JsProxy<R>,
+    name: &'static str,
+    signature: &'static str,
+    prepare: PR,
+    job: J,
+    mapper: M,
+) -> JsProxy<R>
+where
+    R: JsRealmAdapter + 'static,
+    I: Send + 'static,
+    T: Send + 'static,
+    PR: Fn(&R, &[R::JsValueAdapterType]) -> Result<I, JsError> + 'static,
+    J: Fn(I) -> F + 'static,
+    F: Future<Output = Result<T, JsError>> + Send + 'static,
+    M: Fn(&PromiseRealm<R>, T) -> Result<PromiseValue<R>, JsError> + Send + Sync + Copy + 'static,
+{
+    proxy.add_safe_static_method(name, signature, move |_rt, realm: &R, args| {
+        let input = prepare(realm, args)?;
+        create_promise(realm, job(input), mapper)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn the_promise_of_an_async_method_resolves_when_its_job_is_done() {
+        let err = crate::tests::eval(
+            r#"{
+                const app = com.mycompany.MyApp;
+                app.sleep(20).then((ms) => {
+                    globalThis.promiseTest = "slept " + ms;
+                });
+                try {
+                    app.sleep("long");
+                } catch (err) {
+                    err.message;
+                }
+            }"#,
+        );
+        assert!(err.ends_with("sleep expects a number of ms"));
+        let started = Instant::now();
+        while crate::tests::eval("globalThis.promiseTest") == "undefined" {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(started.elapsed() >= Duration::from_millis(10));
+        assert_eq!(crate::tests::eval("globalThis.promiseTest"), "slept 20");
+    }
+}
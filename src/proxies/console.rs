@@ -0,0 +1,124 @@
+use crate::context;
+use crate::proxies::SafeStaticMethods;
+use crate::proxy_registry;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use log::Level;
+
+fn arg_to_string<R: JsRealmAdapter>(
+    realm: &R,
+    arg: &R::JsValueAdapterType,
+) -> Result<String, JsError> {
+    if arg.js_is_object() {
+        realm.js_json_stringify(arg, None)
+    } else {
+        arg.js_to_string()
+    }
+}
+
+/// stringify the arguments of a console call, multiple arguments are joined with a space like a browser console does
+/// if the first argument is a string the %s, %d, %i, %f and %o placeholders in it are substituted by the following args
+fn args_to_string<R: JsRealmAdapter>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+) -> Result<String, JsError> {
+    let mut parts = vec![];
+    let mut remaining = args.iter();
+
+    if let Some(first) = args.first() {
+        if first.js_is_string() {
+            remaining.next();
+            let format = first.js_to_string()?;
+            let mut formatted = String::new();
+            let mut chars = format.chars().peekable();
+            while let Some(c) = chars.next() {
+                match chars.peek().copied() {
+                    Some(placeholder) if c == '%' && "sdifo".contains(placeholder) => {
+                        chars.next();
+                        match remaining.next() {
+                            Some(arg) => formatted.push_str(arg_to_string(realm, arg)?.as_str()),
+                            None => {
+                                formatted.push(c);
+                                formatted.push(placeholder);
+                            }
+                        }
+                    }
+                    _ => formatted.push(c),
+                }
+            }
+            parts.push(formatted);
+        }
+    }
+
+    for arg in remaining {
+        parts.push(arg_to_string(realm, arg)?);
+    }
+    Ok(parts.join(" "))
+}
+
+fn add_level_method<R: JsRealmAdapter + 'static>(
+    proxy: JsProxy<R>,
+    name: &'static str,
+    level: Level,
+) -> JsProxy<R> {
+    proxy.add_safe_static_method(
+        name,
+        "(...data: any[]): void",
+        move |_rt, realm: &R, args| {
+            let message = args_to_string(realm, args)?;
+            // the log crate has no MDC so we prefix the message with the id of the current request
+            match context::request_id() {
+                Some(request_id) => log::log!(level, "[{}] {}", request_id, message),
+                None => log::log!(level, "{}", message),
+            }
+            realm.js_undefined_create()
+        },
+    )
+}
+
+/// install a global console object which forwards to the log crate
+/// this replaces the console installed by green_copper_runtime so make sure to call this after init_greco_rt
+pub fn init_console_proxy<R: JsRealmAdapter + 'static>(realm: &R) -> Result<(), JsError> {
+    let mut proxy = JsProxy::new(&[], "console");
+    proxy = add_level_method(proxy, "log", Level::Info);
+    proxy = add_level_method(proxy, "info", Level::Info);
+    proxy = add_level_method(proxy, "warn", Level::Warn);
+    proxy = add_level_method(proxy, "error", Level::Error);
+    proxy = add_level_method(proxy, "debug", Level::Debug);
+    proxy_registry::install(realm, proxy)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use hirofa_utils::js_utils::Script;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    // the message a console method logs for the array of arguments the script evaluates to
+    fn message(args: &'static str) -> String {
+        let rt = QuickJsRuntimeBuilder::new().build();
+        rt.js_loop_realm_sync(None, move |_rt, realm| {
+            let array = realm
+                .js_eval(Script::new("file://args.js", args))
+                .ok()
+                .unwrap();
+            let args: Vec<_> = (0..realm.js_array_get_length(&array).ok().unwrap())
+                .map(|idx| realm.js_array_get_element(&array, idx).ok().unwrap())
+                .collect();
+            args_to_string(realm, &args).ok().unwrap()
+        })
+    }
+
+    #[test]
+    fn placeholders_are_substituted_and_the_other_args_appended() {
+        assert_eq!(
+            message(r#"["%s has %d items", "cart", 3]"#),
+            "cart has 3 items"
+        );
+        assert_eq!(message(r#"["%s and %s", "one"]"#), "one and %s");
+        assert_eq!(message(r#"["state", {a: 1}, 2]"#), r#"state {"a":1} 2"#);
+    }
+}
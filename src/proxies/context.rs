@@ -0,0 +1,13 @@
+use crate::context;
+use crate::proxies::SafeStaticMethods;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+
+/// add the getContext() static method to a proxy
+/// it returns the context of the request which is being dispatched (see context::with_script_context), undefined
+/// when called outside of a dispatch like from a timer
+pub fn init_context_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy.add_safe_static_method("getContext", "(): {id: string, method: string, path: string, route: string, tenant: string | null, user: any} | undefined", |_rt, realm: &R, _args| {
+        context::script_context(realm)
+    })
+}
@@ -0,0 +1,73 @@
+use crate::cors::{self, CorsPolicy};
+use crate::proxies::SafeStaticMethods;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+
+fn read_string_array<R: JsRealmAdapter>(
+    realm: &R,
+    obj: &R::JsValueAdapterType,
+    name: &str,
+) -> Result<Vec<String>, JsError> {
+    let val = realm.js_object_get_property(obj, name)?;
+    if val.js_is_null_or_undefined() {
+        return Ok(vec![]);
+    }
+    if !val.js_is_array() {
+        return Err(JsError::new_string(format!(
+            "{} should be an array of strings",
+            name
+        )));
+    }
+    let mut items = vec![];
+    for idx in 0..realm.js_array_get_length(&val)? {
+        let item = realm.js_array_get_element(&val, idx)?;
+        if !item.js_is_string() {
+            return Err(JsError::new_string(format!(
+                "{} should be an array of strings",
+                name
+            )));
+        }
+        items.push(item.js_to_string()?);
+    }
+    Ok(items)
+}
+
+/// read a {origins, methods, headers, maxAge, credentials} object
+fn read_policy<R: JsRealmAdapter>(
+    realm: &R,
+    obj: &R::JsValueAdapterType,
+) -> Result<CorsPolicy, JsError> {
+    let mut policy = CorsPolicy {
+        origins: read_string_array(realm, obj, "origins")?,
+        methods: read_string_array(realm, obj, "methods")?,
+        headers: read_string_array(realm, obj, "headers")?,
+        ..Default::default()
+    };
+    if policy.methods.is_empty() {
+        policy.methods = vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()];
+    }
+    let max_age = realm.js_object_get_property(obj, "maxAge")?;
+    if max_age.js_is_i32() && max_age.js_to_i32() >= 0 {
+        policy.max_age = Some(max_age.js_to_i32() as u32);
+    }
+    let credentials = realm.js_object_get_property(obj, "credentials")?;
+    policy.credentials = credentials.js_is_bool() && credentials.js_to_bool();
+    Ok(policy)
+}
+
+/// add the setCorsPolicy(policy) static method to a proxy
+/// the policy applies to all requests, until it is set no CORS headers are sent
+/// throws for a policy which allows credentials from any origin
+pub fn init_cors_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    // methods defaults to GET, HEAD and POST
+    proxy.add_safe_static_method("setCorsPolicy", "(policy: {origins: string[], methods?: string[], headers?: string[], maxAge?: number, credentials?: boolean}): void", |_rt, realm: &R, args| {
+        match args.first() {
+            Some(obj) if obj.js_is_object() => {
+                cors::set_policy(read_policy(realm, obj)?).map_err(JsError::new_string)?
+            }
+            _ => return Err(JsError::new_str("setCorsPolicy expects an object")),
+        }
+        realm.js_undefined_create()
+    })
+}
@@ -0,0 +1,276 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::{config, secrets};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// the max number of bytes randomBytes(n) returns
+const MAX_RANDOM_BYTES: i32 = 1024;
+
+lazy_static! {
+    // the state of the seeded generator, None when SCRIPT_RNG_SEED is not set and the os rng is used
+    static ref SEEDED_RNG: Option<Mutex<u64>> = config::get().rng_seed.map(Mutex::new);
+}
+
+/// fill bytes from the os rng or, with SCRIPT_RNG_SEED, from a splitmix64 generator which gives the same sequence
+/// for the same seed, the sequence is shared by all runtimes so it is only repeatable with SCRIPT_POOL_SIZE=1
+fn fill_random(bytes: &mut [u8]) -> Result<(), JsError> {
+    match SEEDED_RNG.as_ref() {
+        Some(state) => {
+            fill_seeded(&mut state.lock().unwrap(), bytes);
+            Ok(())
+        }
+        None => getrandom::getrandom(bytes)
+            .map_err(|err| JsError::new_string(format!("could not get random bytes: {}", err))),
+    }
+}
+
+// the next bytes of the splitmix64 generator with the state
+fn fill_seeded(state: &mut u64, bytes: &mut [u8]) {
+    for chunk in bytes.chunks_mut(8) {
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Result<HmacSha256, JsError> {
+    let mut mac = HmacSha256::new_from_slice(key)
+        .map_err(|err| JsError::new_string(format!("invalid hmac key: {}", err)))?;
+    mac.update(message);
+    Ok(mac)
+}
+
+// the key argument of hmacSha256 and hmacVerify, a string or {secret: name} for a secret from secrets.rs so a key
+// the script must not know can still be used, the value of the secret never reaches script
+fn get_key_arg<R: JsRealmAdapter>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+    method: &str,
+) -> Result<String, JsError> {
+    match args.first() {
+        Some(key) if key.js_is_object() => {
+            let name = realm.js_object_get_property(key, "secret")?;
+            if !name.js_is_string() {
+                return Err(JsError::new_string(format!(
+                    "{} expects a string or {{secret: name}} as key",
+                    method
+                )));
+            }
+            let name = name.js_to_string()?;
+            secrets::get(name.as_str())
+                .map(str::to_string)
+                .ok_or_else(|| JsError::new_string(format!("{}: unknown secret {}", method, name)))
+        }
+        _ => get_string_arg(args, 0, method),
+    }
+}
+
+/// add the sha256(str), sha1(str), hmacSha256(key, message), hmacVerify(key, message, expectedHex), uuidV4() and
+/// randomBytes(n) static methods to a proxy, strings are hashed as UTF-8 and hashes and bytes are returned as hex
+/// strings, randomness comes from the os rng unless SCRIPT_RNG_SEED is set, see fill_random
+pub fn init_crypto_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        // the crypto methods are only there when compiled with the crypto feature (on by default)
+        .add_safe_static_method(
+            "sha256",
+            "(input: string): string",
+            |_rt, realm: &R, args| {
+                let input = get_string_arg(args, 0, "sha256")?;
+                realm.js_string_create(hex::encode(Sha256::digest(input.as_bytes())).as_str())
+            },
+        )
+        .add_safe_static_method("sha1", "(input: string): string", |_rt, realm: &R, args| {
+            let input = get_string_arg(args, 0, "sha1")?;
+            realm.js_string_create(hex::encode(Sha1::digest(input.as_bytes())).as_str())
+        })
+        // the key can be {secret: "NAME"} to use SCRIPT_SECRET_NAME or NAME from SCRIPT_SECRETS_FILE, secrets can't be
+        // read by script, also not with getEnv
+        .add_safe_static_method(
+            "hmacSha256",
+            "(key: string | {secret: string}, message: string): string",
+            |_rt, realm: &R, args| {
+                let key = get_key_arg(realm, args, "hmacSha256")?;
+                let message = get_string_arg(args, 1, "hmacSha256")?;
+                let mac = hmac_sha256(key.as_bytes(), message.as_bytes())?;
+                realm.js_string_create(hex::encode(mac.finalize().into_bytes()).as_str())
+            },
+        )
+        // use this instead of comparing hex strings in script, the comparison is done in constant time
+        .add_safe_static_method(
+            "hmacVerify",
+            "(key: string | {secret: string}, message: string, expectedHex: string): boolean",
+            |_rt, realm: &R, args| {
+                let key = get_key_arg(realm, args, "hmacVerify")?;
+                let message = get_string_arg(args, 1, "hmacVerify")?;
+                let expected = get_string_arg(args, 2, "hmacVerify")?;
+                let valid = match hex::decode(expected.trim()) {
+                    Ok(expected) => hmac_sha256(key.as_bytes(), message.as_bytes())?
+                        .verify_slice(&expected)
+                        .is_ok(),
+                    Err(_) => false,
+                };
+                realm.js_boolean_create(valid)
+            },
+        )
+        .add_safe_static_method("uuidV4", "(): string", |_rt, realm: &R, _args| {
+            let mut bytes = [0u8; 16];
+            fill_random(&mut bytes)?;
+            // sets the version and variant bits like new_v4 does
+            let uuid = uuid::Builder::from_random_bytes(bytes).into_uuid();
+            realm.js_string_create(uuid.to_string().as_str())
+        })
+        // n random bytes as hex, n can be at most 1024
+        .add_safe_static_method(
+            "randomBytes",
+            "(n: number): string",
+            |_rt, realm: &R, args| {
+                let len = match args.first() {
+                    Some(len) if len.js_is_i32() => len.js_to_i32(),
+                    _ => return Err(JsError::new_str("randomBytes expects a number")),
+                };
+                if !(0..=MAX_RANDOM_BYTES).contains(&len) {
+                    return Err(JsError::new_string(format!(
+                        "randomBytes expects a number between 0 and {}",
+                        MAX_RANDOM_BYTES
+                    )));
+                }
+                let mut bytes = vec![0u8; len as usize];
+                fill_random(&mut bytes)?;
+                realm.js_string_create(hex::encode(bytes).as_str())
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256_gives_the_macs_of_rfc_4231() {
+        let long_key = [0xaa; 131];
+        let key_4: Vec<u8> = (1..=25).collect();
+        let cases: [(&[u8], &[u8], &str); 7] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &key_4,
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            // the rfc only gives the first 128 bits of this one
+            (
+                &[0x0c; 20],
+                b"Test With Truncation",
+                "a3b6167473100ee06e0c796c2955552b",
+            ),
+            (
+                &long_key,
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                &long_key,
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs \
+                to be hashed before being used by the HMAC algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            let mac = hmac_sha256(key, message).ok().unwrap();
+            assert!(hex::encode(mac.finalize().into_bytes()).starts_with(expected));
+        }
+    }
+
+    #[test]
+    fn strings_are_hashed_as_utf8_and_macs_verified() {
+        let results = crate::tests::eval(
+            r#"{
+                const app = com.mycompany.MyApp;
+                const message = "what do ya want for nothing?";
+                const mac = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+                [
+                    app.sha256("abc"),
+                    app.sha1("abc"),
+                    app.hmacSha256("Jefe", message),
+                    app.hmacVerify("Jefe", message, mac),
+                    app.hmacVerify("Jefe", message + "!", mac),
+                    app.hmacVerify("Jefe", message, "not hex"),
+                ].join()
+            }"#,
+        );
+        assert_eq!(
+            results,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad,\
+             a9993e364706816aba3e25717850c26c9cd0d89d,\
+             5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843,true,false,false"
+        );
+    }
+
+    #[test]
+    fn random_values_have_the_requested_size() {
+        let results = crate::tests::eval(
+            r#"{
+                const app = com.mycompany.MyApp;
+                const fails = (n) => {
+                    try {
+                        app.randomBytes(n);
+                        return false;
+                    } catch (err) {
+                        return true;
+                    }
+                };
+                [
+                    /^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$/.test(app.uuidV4()),
+                    app.uuidV4() !== app.uuidV4(),
+                    /^[0-9a-f]{32}$/.test(app.randomBytes(16)),
+                    app.randomBytes(0),
+                    fails(1025),
+                    fails(-1),
+                    fails("16"),
+                ].join()
+            }"#,
+        );
+        assert_eq!(results, "true,true,true,,true,true,true");
+    }
+
+    #[test]
+    fn a_seed_gives_the_same_sequence_every_time() {
+        let sequence = |seed: u64| {
+            let mut state = seed;
+            let (mut first, mut second) = ([0u8; 12], [0u8; 8]);
+            fill_seeded(&mut state, &mut first);
+            fill_seeded(&mut state, &mut second);
+            (first, second)
+        };
+        assert_eq!(sequence(42), sequence(42));
+        assert_ne!(sequence(42), sequence(43));
+        // the first value of splitmix64 for seed 0 is 0xe220a8397b1dcdaf
+        let (first, second) = sequence(0);
+        assert_eq!(first[..8], 0xe220_a839_7b1d_cdaf_u64.to_le_bytes());
+        assert_ne!(second, [0u8; 8]);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::proxies::{get_number_arg, get_string_arg, SafeStaticMethods};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use std::fmt::Write;
+
+// the tz argument, an IANA name like Europe/Amsterdam, UTC when it is omitted
+fn get_tz_arg<V: JsValueAdapter>(args: &[V], idx: usize, method: &str) -> Result<Tz, JsError> {
+    match args.get(idx) {
+        Some(arg) if !arg.js_is_null_or_undefined() => {
+            let name = get_string_arg(args, idx, method)?;
+            name.parse::<Tz>()
+                .map_err(|_| JsError::new_string(format!("{}: unknown timezone {}", method, name)))
+        }
+        _ => Ok(Tz::UTC),
+    }
+}
+
+// the parsed items of a strftime format, chrono only fails on an invalid specifier while formatting
+fn format_items<'a>(format: &'a str, method: &str) -> Result<Vec<Item<'a>>, JsError> {
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(JsError::new_string(format!(
+            "{}: invalid format {}",
+            method, format
+        )));
+    }
+    Ok(items)
+}
+
+fn format_date(epoch_ms: f64, format: &str, tz: Tz) -> Result<String, JsError> {
+    let items = format_items(format, "formatDate")?;
+    if !epoch_ms.is_finite() {
+        return Err(JsError::new_str("formatDate: invalid date"));
+    }
+    let date = match Utc.timestamp_millis_opt(epoch_ms.floor() as i64) {
+        LocalResult::Single(date) => date.with_timezone(&tz),
+        _ => return Err(JsError::new_str("formatDate: invalid date")),
+    };
+    let mut output = String::new();
+    // a format which needs something the value does not have fails here instead of panicking in to_string
+    write!(output, "{}", date.format_with_items(items.into_iter())).map_err(|_| {
+        JsError::new_string(format!("formatDate: could not format with {}", format))
+    })?;
+    Ok(output)
+}
+
+/// parse input with a strftime format into epoch ms
+/// a format with an offset (%z) uses that, otherwise the date and time are local to tz, a format with only a date
+/// is the start of that day, a local time which is skipped by a dst change is invalid and one which occurs twice is
+/// the first of the two
+fn parse_date(input: &str, format: &str, tz: Tz) -> Result<f64, JsError> {
+    format_items(format, "parseDate")?;
+    if let Ok(date) = DateTime::parse_from_str(input, format) {
+        return Ok(date.timestamp_millis() as f64);
+    }
+    let local = match NaiveDateTime::parse_from_str(input, format) {
+        Ok(local) => local,
+        Err(err) => NaiveDate::parse_from_str(input, format)
+            .map(|date| date.and_time(NaiveTime::MIN))
+            .map_err(|_| {
+                JsError::new_string(format!(
+                    "parseDate: {} does not match {}: {}",
+                    input, format, err
+                ))
+            })?,
+    };
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(date) | LocalResult::Ambiguous(date, _) => {
+            Ok(date.timestamp_millis() as f64)
+        }
+        LocalResult::None => Err(JsError::new_string(format!(
+            "parseDate: {} does not exist in {}",
+            input,
+            tz.name()
+        ))),
+    }
+}
+
+/// add the formatDate(epochMs, format, tz) and parseDate(input, format, tz) static methods to a proxy
+/// format uses the strftime specifiers of chrono like %Y-%m-%d %H:%M:%S, tz is an IANA name like Europe/Amsterdam
+/// and defaults to UTC, an invalid format, timezone or date throws
+pub fn init_dates_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        .add_safe_static_method(
+            "formatDate",
+            "(epochMs: number, format: string, tz?: string): string",
+            |_rt, realm: &R, args| {
+                let epoch_ms = get_number_arg(args, 0, "formatDate")?
+                    .ok_or_else(|| JsError::new_str("formatDate expects a number as argument 1"))?;
+                let format = get_string_arg(args, 1, "formatDate")?;
+                let tz = get_tz_arg(args, 2, "formatDate")?;
+                let output = format_date(epoch_ms, format.as_str(), tz)?;
+                realm.js_string_create(output.as_str())
+            },
+        )
+        .add_safe_static_method(
+            "parseDate",
+            "(input: string, format: string, tz?: string): number",
+            |_rt, realm: &R, args| {
+                let input = get_string_arg(args, 0, "parseDate")?;
+                let format = get_string_arg(args, 1, "parseDate")?;
+                let tz = get_tz_arg(args, 2, "parseDate")?;
+                let epoch_ms = parse_date(input.as_str(), format.as_str(), tz)?;
+                realm.js_f64_create(epoch_ms)
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_formatted_in_the_timezone() {
+        let format = "%Y-%m-%d %H:%M";
+        assert_eq!(
+            format_date(0.0, format, Tz::UTC).ok().unwrap(),
+            "1970-01-01 00:00"
+        );
+        assert_eq!(
+            format_date(0.0, format, Tz::Europe__Amsterdam)
+                .ok()
+                .unwrap(),
+            "1970-01-01 01:00"
+        );
+        assert!(format_date(0.0, "%Y %Q", Tz::UTC).is_err());
+        assert!(format_date(f64::NAN, format, Tz::UTC).is_err());
+    }
+
+    #[test]
+    fn dates_are_parsed_in_the_timezone() {
+        let amsterdam = Tz::Europe__Amsterdam;
+        assert_eq!(
+            parse_date("2021-06-01", "%Y-%m-%d", Tz::UTC).ok(),
+            Some(1_622_505_600_000.0)
+        );
+        // the offset in the input wins over tz
+        assert_eq!(
+            parse_date("2021-06-01 12:00 +0200", "%Y-%m-%d %H:%M %z", amsterdam).ok(),
+            Some(1_622_541_600_000.0)
+        );
+        // skipped by the start of dst
+        assert!(parse_date("2021-03-28 02:30", "%Y-%m-%d %H:%M", amsterdam).is_err());
+        // the end of dst, the first 02:30 is still in CEST
+        assert_eq!(
+            parse_date("2021-10-31 02:30", "%Y-%m-%d %H:%M", amsterdam).ok(),
+            Some(1_635_640_200_000.0)
+        );
+        assert!(parse_date("yesterday", "%Y-%m-%d", Tz::UTC).is_err());
+    }
+}
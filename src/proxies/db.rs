@@ -0,0 +1,642 @@
+use crate::circuit_breaker::{self, Permit};
+use crate::db_tls::MakeRustlsConnect;
+use crate::proxies::{get_string_arg, safe_function_create};
+use crate::tasks::TASKS;
+use crate::{config, context, promises, secrets};
+use deadpool_postgres::{Client, Manager, ManagerConfig, Pool, RecyclingMethod};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::Row;
+
+pub const DATABASE_URL_VAR: &str = "DATABASE_URL";
+/// a pem file with the CA of the database server, its certificate is checked against this and the mozilla roots
+pub const DATABASE_CA_FILE_VAR: &str = "DATABASE_CA_FILE";
+/// the target of the circuit breaker of the queries, see circuit_breaker.rs
+const BREAKER_TARGET: &str = "database";
+/// the max number of connections the pool opens
+const MAX_CONNECTIONS: usize = 16;
+/// the delay before the first retry when the database can't be reached, doubled after every failed attempt
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// how often the connection is checked once the database was reached
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// the max time a connection attempt or check may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    // the pool is shared by all runtimes, connections are only opened when the first query runs
+    // None when DATABASE_URL is not set or invalid, queries then reject
+    static ref POOL: Option<Pool> = create_pool();
+    // the open transactions by request id and transaction id, a request only has an entry while its
+    // TransactionGuard exists
+    static ref TRANSACTIONS: Mutex<HashMap<String, HashMap<u64, Transaction>>> = Mutex::new(HashMap::new());
+}
+
+// the connection a transaction is pinned to, None once it was committed or rolled back
+// the lock also keeps the queries of a transaction from running at the same time on its connection
+type Transaction = Arc<tokio::sync::Mutex<Option<Client>>>;
+
+static NEXT_TRANSACTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// whether the last connection attempt or check succeeded, see start_monitor
+static READY: AtomicBool = AtomicBool::new(false);
+
+fn create_pool() -> Option<Pool> {
+    // the url usually has the password, it may also be the secret DATABASE_URL so it can come from the secrets file
+    let url = std::env::var(DATABASE_URL_VAR)
+        .ok()
+        .or_else(|| secrets::get(DATABASE_URL_VAR).map(str::to_string))?;
+    let pg_config = match url.parse::<tokio_postgres::Config>() {
+        Ok(pg_config) => pg_config,
+        Err(err) => {
+            log::error!("invalid {}: {}", DATABASE_URL_VAR, err);
+            return None;
+        }
+    };
+    let ca_file = std::env::var(DATABASE_CA_FILE_VAR)
+        .ok()
+        .filter(|ca_file| !ca_file.is_empty());
+    let tls = match MakeRustlsConnect::new(ca_file.as_deref()) {
+        Ok(tls) => tls,
+        Err(err) => {
+            log::error!("invalid {}: {}", DATABASE_CA_FILE_VAR, err);
+            return None;
+        }
+    };
+    let manager = Manager::from_config(
+        pg_config,
+        tls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    match Pool::builder(manager).max_size(MAX_CONNECTIONS).build() {
+        Ok(pool) => Some(pool),
+        Err(err) => {
+            log::error!("could not create the database pool: {}", err);
+            None
+        }
+    }
+}
+
+/// whether queries can be run, false while the database can't be reached
+/// always true when there is no database to wait for because the db capability is not granted or DATABASE_URL is
+/// not set or invalid, queries then reject but there is no point in marking the server as not ready for that
+pub fn is_ready() -> bool {
+    POOL.is_none() || !config::get().capabilities.db || READY.load(Ordering::SeqCst)
+}
+
+/// connect to the database in the background, called once at startup
+///
+/// the server starts without waiting for the database, until it is reached /health is a 503 and queries reject
+/// attempts are retried with a backoff from RETRY_DELAY up to MAX_RETRY_DELAY, once connected the connection is
+/// checked every CHECK_INTERVAL so an outage later on is noticed and retried the same way
+pub fn start_monitor() {
+    if !config::get().capabilities.db {
+        return;
+    }
+    let pool = match POOL.as_ref() {
+        Some(pool) => pool,
+        None => return,
+    };
+    TASKS.spawn("db monitor", async move {
+        let mut delay = RETRY_DELAY;
+        loop {
+            match tokio::time::timeout(CONNECT_TIMEOUT, check(pool)).await {
+                Ok(Ok(())) => {
+                    if !READY.swap(true, Ordering::SeqCst) {
+                        log::info!("connected to the database");
+                    }
+                    delay = RETRY_DELAY;
+                    tokio::time::sleep(CHECK_INTERVAL).await;
+                }
+                res => {
+                    let err = match res {
+                        Ok(Err(err)) => err,
+                        _ => "timed out".to_string(),
+                    };
+                    if READY.swap(false, Ordering::SeqCst) {
+                        log::error!("lost the database connection: {}", err);
+                    }
+                    log::warn!(
+                        "could not connect to the database, retrying in {}ms: {}",
+                        delay.as_millis(),
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = next_delay(delay);
+                }
+            }
+        }
+    });
+}
+
+// the delay before the next attempt when one more attempt failed
+fn next_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_RETRY_DELAY)
+}
+
+// get a connection and run a trivial query on it, a broken idle connection is only noticed by using it
+async fn check(pool: &Pool) -> Result<(), String> {
+    let client = pool.get().await.map_err(|err| err.to_string())?;
+    client
+        .simple_query("SELECT 1")
+        .await
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+// the (sql, params) arguments of query
+fn read_query_args<R: JsRealmAdapter>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+) -> Result<(String, Vec<Value>), JsError> {
+    let sql = get_string_arg(args, 0, "query")?;
+    let params = match args.get(1) {
+        Some(params) if params.js_is_array() => {
+            // script values can't leave the worker thread, json can
+            let json = realm.js_json_stringify(params, None)?;
+            match serde_json::from_str(json.as_str()) {
+                Ok(Value::Array(params)) => params,
+                _ => return Err(JsError::new_str("query params could not be read")),
+            }
+        }
+        Some(params) if !params.js_is_null_or_undefined() => {
+            return Err(JsError::new_str(
+                "query expects an array of params as argument 2",
+            ));
+        }
+        _ => vec![],
+    };
+    Ok((sql, params))
+}
+
+/// add the query(sql, params) and beginTransaction() static methods to a proxy
+///
+/// query returns a promise which resolves to the rows as an array of objects, the params are bound as $1, $2 etc.
+/// so values are never interpolated into the sql
+/// beginTransaction returns a promise which resolves to a transaction, see create_transaction_obj
+pub fn init_db_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    // params are bound to $1, $2 etc., resolves to the rows as objects by column name
+    // only there when compiled with the db feature and with the db capability
+    let proxy = promises::add_async_static_method(
+        proxy,
+        "query",
+        "(sql: string, params?: any[]): Promise<Record<string, any>[]>",
+        read_query_args,
+        |(sql, params)| async move { query(sql.as_str(), params).await },
+        |realm, rows: String| realm.js_json_parse(rows.as_str()),
+    );
+    // only from a listener of a request, a transaction which is not committed is rolled back when the request ended
+    promises::add_async_static_method(
+        proxy,
+        "beginTransaction",
+        "(): Promise<Transaction>",
+        |_realm: &R, _args| {
+            // read here as the request id is only known while the listener runs, not once the promise resolves
+            context::request_id().ok_or_else(|| {
+                JsError::new_str(
+                    "beginTransaction can only be called while a listener of a request runs, not after an await",
+                )
+            })
+        },
+        |request_id| async move { begin_transaction(request_id).await },
+        create_transaction_obj,
+    )
+}
+
+/// the transactions a request began with beginTransaction(), the ones which were not committed are rolled back when
+/// this is dropped
+///
+/// the ScriptResponse holds it so that is after the response was created, for streamed responses after the stream
+/// ended, and when dispatching the request failed (e.g. the listener threw) it is dropped with the error
+pub struct TransactionGuard {
+    request_id: String,
+}
+
+impl TransactionGuard {
+    pub fn new(request_id: &str) -> Self {
+        TRANSACTIONS
+            .lock()
+            .unwrap()
+            .insert(request_id.to_string(), HashMap::new());
+        Self {
+            request_id: request_id.to_string(),
+        }
+    }
+}
+
+impl Drop for TransactionGuard {
+    fn drop(&mut self) {
+        let transactions = TRANSACTIONS
+            .lock()
+            .unwrap()
+            .remove(&self.request_id)
+            .unwrap_or_default();
+        for transaction in transactions.into_values() {
+            log::warn!(
+                "request {} did not commit its transaction, rolling it back",
+                self.request_id
+            );
+            spawn_rollback(transaction);
+        }
+    }
+}
+
+fn spawn_rollback(transaction: Transaction) {
+    TASKS.spawn("db rollback", async move {
+        if let Some(client) = transaction.lock().await.take() {
+            end(client, "ROLLBACK").await.ok();
+        }
+    });
+}
+
+// run COMMIT or ROLLBACK, a connection which fails that is closed instead of going back to the pool in the
+// middle of a transaction
+async fn end(client: Client, statement: &'static str) -> Result<(), JsError> {
+    client.batch_execute(statement).await.map_err(|err| {
+        log::error!("{} failed: {}", statement, err);
+        drop(Client::take(client));
+        db_error(err)
+    })
+}
+
+// begin a transaction on a connection of its own, returns the request id and the id of the transaction
+async fn begin_transaction(request_id: String) -> Result<(String, u64), JsError> {
+    let (client, permit) = connect().await?;
+    let result = client.batch_execute("BEGIN").await.map_err(QueryError::Db);
+    permit.record(matches!(&result, Err(err) if err.is_connection_error()));
+    result.map_err(QueryError::into_js_error)?;
+    let id = NEXT_TRANSACTION_ID.fetch_add(1, Ordering::SeqCst);
+    let transaction = Arc::new(tokio::sync::Mutex::new(Some(client)));
+    let added = match TRANSACTIONS.lock().unwrap().get_mut(&request_id) {
+        Some(transactions) => {
+            transactions.insert(id, transaction.clone());
+            true
+        }
+        None => false,
+    };
+    if !added {
+        spawn_rollback(transaction);
+        return Err(JsError::new_str(
+            "the request ended before its transaction began",
+        ));
+    }
+    Ok((request_id, id))
+}
+
+fn get_transaction(request_id: &str, id: u64) -> Result<Transaction, JsError> {
+    TRANSACTIONS
+        .lock()
+        .unwrap()
+        .get(request_id)
+        .and_then(|transactions| transactions.get(&id))
+        .cloned()
+        .ok_or_else(|| JsError::new_str("the transaction was already committed or rolled back"))
+}
+
+// run a query in a transaction, like query
+async fn transaction_query(
+    request_id: String,
+    id: u64,
+    sql: String,
+    params: Vec<Value>,
+) -> Result<String, JsError> {
+    let transaction = get_transaction(request_id.as_str(), id)?;
+    let client = transaction.lock().await;
+    let client = client
+        .as_ref()
+        .ok_or_else(|| JsError::new_str("the transaction was already committed or rolled back"))?;
+    run_query(client, sql.as_str(), params)
+        .await
+        .map_err(QueryError::into_js_error)
+}
+
+// commit or roll back a transaction, its connection goes back to the pool
+async fn end_transaction(
+    request_id: String,
+    id: u64,
+    statement: &'static str,
+) -> Result<(), JsError> {
+    let transaction = get_transaction(request_id.as_str(), id)?;
+    if let Some(transactions) = TRANSACTIONS.lock().unwrap().get_mut(&request_id) {
+        transactions.remove(&id);
+    }
+    // waits for the queries of the transaction which are still running
+    let client = transaction.lock().await.take();
+    match client {
+        Some(client) => end(client, statement).await,
+        None => Err(JsError::new_str(
+            "the transaction was already committed or rolled back",
+        )),
+    }
+}
+
+/// the object beginTransaction() resolves to, with query(sql, params) like myApp.query but on the connection of the
+/// transaction, commit() and rollback(), they all return a promise
+fn create_transaction_obj<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    (request_id, id): (String, u64),
+) -> Result<R::JsValueAdapterType, JsError> {
+    let query_request_id = request_id.clone();
+    let query = safe_function_create(
+        realm,
+        "query",
+        move |realm: &R, _this, args| {
+            let (sql, params) = read_query_args(realm, args)?;
+            promises::create_promise(
+                realm,
+                transaction_query(query_request_id.clone(), id, sql, params),
+                |realm, rows: String| realm.js_json_parse(rows.as_str()),
+            )
+        },
+        2,
+    )?;
+    let commit_request_id = request_id.clone();
+    let commit = safe_function_create(
+        realm,
+        "commit",
+        move |realm: &R, _this, _args| {
+            promises::create_promise(
+                realm,
+                end_transaction(commit_request_id.clone(), id, "COMMIT"),
+                |realm, _| realm.js_undefined_create(),
+            )
+        },
+        0,
+    )?;
+    let rollback = safe_function_create(
+        realm,
+        "rollback",
+        move |realm: &R, _this, _args| {
+            promises::create_promise(
+                realm,
+                end_transaction(request_id.clone(), id, "ROLLBACK"),
+                |realm, _| realm.js_undefined_create(),
+            )
+        },
+        0,
+    )?;
+    let transaction_obj = realm.js_object_create()?;
+    realm.js_object_set_property(&transaction_obj, "query", &query)?;
+    realm.js_object_set_property(&transaction_obj, "commit", &commit)?;
+    realm.js_object_set_property(&transaction_obj, "rollback", &rollback)?;
+    Ok(transaction_obj)
+}
+
+// why running a query failed, only errors of the connection count for the circuit breaker
+enum QueryError {
+    Db(tokio_postgres::Error),
+    Invalid(JsError),
+}
+
+impl From<JsError> for QueryError {
+    fn from(err: JsError) -> Self {
+        QueryError::Invalid(err)
+    }
+}
+
+impl QueryError {
+    // an error the server answered with (like a syntax error or a constraint) is one of the query, not the database
+    fn is_connection_error(&self) -> bool {
+        matches!(self, QueryError::Db(err) if err.code().is_none())
+    }
+
+    fn into_js_error(self) -> JsError {
+        match self {
+            QueryError::Db(err) => db_error(err),
+            QueryError::Invalid(err) => err,
+        }
+    }
+}
+
+// a connection of the pool and the permit of the circuit breaker to record the outcome of its first statement with
+async fn connect() -> Result<(Client, Permit), JsError> {
+    let pool = POOL.as_ref().ok_or_else(|| {
+        JsError::new_string(format!("{} is not set or invalid", DATABASE_URL_VAR))
+    })?;
+    // fail right away instead of every query waiting for a connection attempt of its own
+    if !READY.load(Ordering::SeqCst) {
+        return Err(JsError::new_str(
+            "the database is unavailable, reconnecting in the background",
+        ));
+    }
+    let permit = circuit_breaker::acquire(BREAKER_TARGET)?;
+    match pool.get().await {
+        Ok(client) => Ok((client, permit)),
+        Err(err) => {
+            permit.record(true);
+            Err(JsError::new_string(format!(
+                "could not get a connection: {}",
+                err
+            )))
+        }
+    }
+}
+
+/// run a query and return the rows as a json array
+async fn query(sql: &str, params: Vec<Value>) -> Result<String, JsError> {
+    let (client, permit) = connect().await?;
+    let result = run_query(&client, sql, params).await;
+    permit.record(matches!(&result, Err(err) if err.is_connection_error()));
+    result.map_err(QueryError::into_js_error)
+}
+
+async fn run_query(client: &Client, sql: &str, params: Vec<Value>) -> Result<String, QueryError> {
+    let statement = client.prepare_cached(sql).await.map_err(QueryError::Db)?;
+    if statement.params().len() != params.len() {
+        return Err(JsError::new_string(format!(
+            "query expects {} params but got {}",
+            statement.params().len(),
+            params.len()
+        ))
+        .into());
+    }
+    // postgres is strict about parameter types so the json values are converted to the types of the statement
+    let params = params
+        .iter()
+        .zip(statement.params())
+        .enumerate()
+        .map(|(idx, (value, ty))| to_sql_param(value, ty, idx))
+        .collect::<Result<Vec<_>, _>>()?;
+    let param_refs: Vec<&(dyn ToSql + Sync)> = params
+        .iter()
+        .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+        .collect();
+    let rows = client
+        .query(&statement, param_refs.as_slice())
+        .await
+        .map_err(QueryError::Db)?;
+    let rows = rows
+        .iter()
+        .map(row_to_json)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::Array(rows).to_string())
+}
+
+fn db_error(err: tokio_postgres::Error) -> JsError {
+    JsError::new_string(format!("query failed: {}", err))
+}
+
+fn to_sql_param(
+    value: &Value,
+    ty: &Type,
+    idx: usize,
+) -> Result<Box<dyn ToSql + Sync + Send>, JsError> {
+    let invalid = || {
+        JsError::new_string(format!(
+            "query param {} can not be converted to {}",
+            idx + 1,
+            ty
+        ))
+    };
+    let param: Box<dyn ToSql + Sync + Send> = match *ty {
+        Type::BOOL => Box::new(nullable(value, |v| v.as_bool()).ok_or_else(invalid)?),
+        Type::INT2 => Box::new(
+            nullable(value, |v| v.as_i64().and_then(|n| i16::try_from(n).ok()))
+                .ok_or_else(invalid)?,
+        ),
+        Type::INT4 => Box::new(
+            nullable(value, |v| v.as_i64().and_then(|n| i32::try_from(n).ok()))
+                .ok_or_else(invalid)?,
+        ),
+        Type::INT8 => Box::new(nullable(value, |v| v.as_i64()).ok_or_else(invalid)?),
+        Type::FLOAT4 => {
+            Box::new(nullable(value, |v| v.as_f64().map(|n| n as f32)).ok_or_else(invalid)?)
+        }
+        Type::FLOAT8 => Box::new(nullable(value, |v| v.as_f64()).ok_or_else(invalid)?),
+        Type::JSON | Type::JSONB => Box::new(value.clone()),
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => {
+            Box::new(nullable(value, |v| v.as_str().map(str::to_string)).ok_or_else(invalid)?)
+        }
+        _ => {
+            return Err(JsError::new_string(format!(
+                "query param {} has unsupported type {}",
+                idx + 1,
+                ty
+            )))
+        }
+    };
+    Ok(param)
+}
+
+// Some(None) for null, None when the value can't be converted
+fn nullable<T, F: FnOnce(&Value) -> Option<T>>(value: &Value, convert: F) -> Option<Option<T>> {
+    if value.is_null() {
+        Some(None)
+    } else {
+        convert(value).map(Some)
+    }
+}
+
+fn row_to_json(row: &Row) -> Result<Value, JsError> {
+    let mut obj = Map::new();
+    for (idx, column) in row.columns().iter().enumerate() {
+        let value = match *column.type_() {
+            Type::BOOL => row
+                .try_get::<_, Option<bool>>(idx)
+                .map(|v| v.map(Value::from)),
+            Type::INT2 => row
+                .try_get::<_, Option<i16>>(idx)
+                .map(|v| v.map(Value::from)),
+            Type::INT4 => row
+                .try_get::<_, Option<i32>>(idx)
+                .map(|v| v.map(Value::from)),
+            Type::INT8 => row
+                .try_get::<_, Option<i64>>(idx)
+                .map(|v| v.map(Value::from)),
+            Type::FLOAT4 => row
+                .try_get::<_, Option<f32>>(idx)
+                .map(|v| v.map(Value::from)),
+            Type::FLOAT8 => row
+                .try_get::<_, Option<f64>>(idx)
+                .map(|v| v.map(Value::from)),
+            Type::JSON | Type::JSONB => row.try_get::<_, Option<Value>>(idx),
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => row
+                .try_get::<_, Option<String>>(idx)
+                .map(|v| v.map(Value::from)),
+            _ => {
+                return Err(JsError::new_string(format!(
+                    "column {} has unsupported type {}, cast it to text in the query",
+                    column.name(),
+                    column.type_()
+                )))
+            }
+        }
+        .map_err(db_error)?;
+        obj.insert(column.name().to_string(), value.unwrap_or(Value::Null));
+    }
+    Ok(Value::Object(obj))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn params_are_converted_to_the_type_of_the_placeholder() {
+        assert!(to_sql_param(&json!(42), &Type::INT4, 0).is_ok());
+        assert!(to_sql_param(&Value::Null, &Type::INT4, 0).is_ok());
+        assert!(to_sql_param(&json!("text"), &Type::TEXT, 0).is_ok());
+        assert!(to_sql_param(&json!({"a": 1}), &Type::JSONB, 0).is_ok());
+        assert_eq!(
+            to_sql_param(&json!(70_000), &Type::INT2, 1)
+                .err()
+                .unwrap()
+                .get_message(),
+            "query param 2 can not be converted to int2"
+        );
+        assert!(to_sql_param(&json!("42"), &Type::INT4, 0).is_err());
+        assert_eq!(
+            to_sql_param(&json!("x"), &Type::BYTEA, 0)
+                .err()
+                .unwrap()
+                .get_message(),
+            "query param 1 has unsupported type bytea"
+        );
+    }
+
+    #[test]
+    fn the_retries_back_off_up_to_the_max_delay() {
+        let mut delays = vec![RETRY_DELAY];
+        for _ in 0..8 {
+            delays.push(next_delay(*delays.last().unwrap()));
+        }
+        assert_eq!(delays[1], Duration::from_secs(1));
+        assert_eq!(delays[6], Duration::from_secs(30));
+        assert_eq!(delays[8], MAX_RETRY_DELAY);
+        // without DATABASE_URL there is nothing to wait for
+        assert!(is_ready());
+    }
+
+    #[test]
+    fn the_transactions_of_a_request_are_dropped_when_it_ended() {
+        let guard = TransactionGuard::new("transaction-test");
+        assert!(TRANSACTIONS
+            .lock()
+            .unwrap()
+            .contains_key("transaction-test"));
+        assert_eq!(
+            get_transaction("transaction-test", u64::MAX)
+                .err()
+                .unwrap()
+                .get_message(),
+            "the transaction was already committed or rolled back"
+        );
+        drop(guard);
+        assert!(!TRANSACTIONS
+            .lock()
+            .unwrap()
+            .contains_key("transaction-test"));
+    }
+}
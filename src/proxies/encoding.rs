@@ -0,0 +1,86 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::JsError;
+
+fn decode(input: &str, config: base64::Config) -> Result<String, JsError> {
+    let bytes = base64::decode_config(input, config)
+        .map_err(|err| JsError::new_string(format!("invalid base64: {}", err)))?;
+    // scripts work with strings so the decoded bytes must be valid UTF-8
+    String::from_utf8(bytes)
+        .map_err(|_| JsError::new_str("decoded base64 is not a valid UTF-8 string"))
+}
+
+/// add the base64Encode(str), base64Decode(str), base64UrlEncode(str) and base64UrlDecode(str) static methods to
+/// a proxy, strings are encoded as UTF-8
+/// the url-safe variants are unpadded (like in a JWT), base64UrlDecode also accepts padded input
+pub fn init_encoding_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        // the decode methods throw on invalid input
+        .add_safe_static_method(
+            "base64Encode",
+            "(input: string): string",
+            |_rt, realm: &R, args| {
+                let input = get_string_arg(args, 0, "base64Encode")?;
+                realm.js_string_create(base64::encode_config(input, base64::STANDARD).as_str())
+            },
+        )
+        .add_safe_static_method(
+            "base64Decode",
+            "(input: string): string",
+            |_rt, realm: &R, args| {
+                let input = get_string_arg(args, 0, "base64Decode")?;
+                realm.js_string_create(decode(input.as_str(), base64::STANDARD)?.as_str())
+            },
+        )
+        .add_safe_static_method(
+            "base64UrlEncode",
+            "(input: string): string",
+            |_rt, realm: &R, args| {
+                let input = get_string_arg(args, 0, "base64UrlEncode")?;
+                realm.js_string_create(
+                    base64::encode_config(input, base64::URL_SAFE_NO_PAD).as_str(),
+                )
+            },
+        )
+        .add_safe_static_method(
+            "base64UrlDecode",
+            "(input: string): string",
+            |_rt, realm: &R, args| {
+                let input = get_string_arg(args, 0, "base64UrlDecode")?;
+                let unpadded = input.trim_end_matches('=');
+                realm.js_string_create(decode(unpadded, base64::URL_SAFE_NO_PAD)?.as_str())
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn strings_are_encoded_as_utf8_and_url_safe_without_padding() {
+        let results = crate::tests::eval(
+            r#"{
+                const app = com.mycompany.MyApp;
+                const fails = (decode) => {
+                    try {
+                        decode();
+                        return false;
+                    } catch (err) {
+                        return true;
+                    }
+                };
+                [
+                    app.base64Encode("fo"),
+                    app.base64Encode("é"),
+                    app.base64Decode("Zm9vYmFy"),
+                    app.base64UrlEncode("??>"),
+                    app.base64UrlDecode("Pz8-"),
+                    app.base64UrlDecode("Zm8="),
+                    fails(() => app.base64Decode("not base64!")),
+                    fails(() => app.base64Decode("/w==")),
+                ].join()
+            }"#,
+        );
+        assert_eq!(results, "Zm8=,w6k=,foobar,Pz8-,??>,fo,true,true");
+    }
+}
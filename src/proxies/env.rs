@@ -0,0 +1,79 @@
+use crate::proxies::SafeStaticMethods;
+use crate::{config, secrets};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+
+/// scripts may only read env vars with this prefix so they can't read things like AWS_SECRET
+pub const ENV_PREFIX: &str = "SCRIPT_";
+
+/// the tokens of the /admin and /debug/eval endpoints, a script which could read them could call those endpoints
+const DENIED: &[&str] = &[config::ADMIN_TOKEN_VAR, config::DEBUG_EVAL_TOKEN_VAR];
+
+/// the secrets (SCRIPT_SECRET_*) are not readable, those are only used by the proxies, see secrets.rs
+pub fn is_readable(name: &str) -> bool {
+    name.starts_with(ENV_PREFIX)
+        && !name.starts_with(secrets::ENV_PREFIX)
+        && !DENIED.contains(&name)
+}
+
+/// add the getEnv(name) static method to a proxy
+/// getEnv returns undefined for vars which are not set or may not be read by script
+pub fn init_env_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    // only env vars prefixed with SCRIPT_ can be read, only there with the env capability
+    proxy.add_safe_static_method(
+        "getEnv",
+        "(name: string): string | undefined",
+        |_rt, realm: &R, args| {
+            if let Some(name) = args.first() {
+                if name.js_is_string() {
+                    let name = name.js_to_string()?;
+                    if is_readable(name.as_str()) {
+                        if let Ok(value) = std::env::var(name.as_str()) {
+                            return realm.js_string_create(value.as_str());
+                        }
+                    }
+                }
+            }
+            realm.js_undefined_create()
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_registry;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use hirofa_utils::js_utils::Script;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    #[test]
+    fn only_the_script_vars_which_are_not_secrets_or_tokens_are_readable() {
+        assert!(is_readable("SCRIPT_API_URL"));
+        assert!(!is_readable("PATH"));
+        assert!(!is_readable("SCRIPT_SECRET_WEBHOOK_KEY"));
+        assert!(!is_readable(config::ADMIN_TOKEN_VAR));
+        assert!(!is_readable(config::DEBUG_EVAL_TOKEN_VAR));
+    }
+
+    #[test]
+    fn get_env_returns_undefined_for_vars_which_are_not_readable() {
+        std::env::set_var("SCRIPT_ENV_TEST", "readable");
+        std::env::set_var("ENV_TEST_NOT_PREFIXED", "hidden");
+        let rt = QuickJsRuntimeBuilder::new().build();
+        let results = rt.js_loop_realm_sync(None, |_rt, realm| {
+            let proxy = init_env_proxy(JsProxy::new(&["envTest"], "Env"));
+            proxy_registry::install(realm, proxy).ok().unwrap();
+            let eval = |name: &str| {
+                let script = format!("String(envTest.Env.getEnv('{}'))", name);
+                realm
+                    .js_eval(Script::new("file://env_test.js", script.as_str()))
+                    .and_then(|value| value.js_to_string())
+                    .ok()
+                    .unwrap()
+            };
+            (eval("SCRIPT_ENV_TEST"), eval("ENV_TEST_NOT_PREFIXED"))
+        });
+        assert_eq!(results, ("readable".to_string(), "undefined".to_string()));
+    }
+}
@@ -0,0 +1,425 @@
+use crate::abort;
+use crate::circuit_breaker::{self, Permit};
+use crate::config;
+use crate::context;
+use crate::promises;
+use crate::proxies::{safe_function_create, SafeMembers};
+use crate::proxy_registry;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .redirect(config::allowed_redirects())
+        .timeout(config::get().fetch_timeout)
+        .connect_timeout(config::get().fetch_connect_timeout)
+        .build()
+        .expect("could not create the fetch client");
+}
+
+thread_local! {
+    // the responses of the Response instances in this runtime by realm id and instance id
+    static RESPONSES: RefCell<HashMap<(String, usize), FetchResponse>> = RefCell::new(HashMap::new());
+}
+
+struct FetchRequest {
+    url: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+}
+
+struct FetchResponse {
+    status: u16,
+    body: String,
+}
+
+/// read the fetch(url, options) arguments into a FetchRequest
+fn read_request<R: JsRealmAdapter>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+) -> Result<FetchRequest, JsError> {
+    if args.is_empty() || !args[0].js_is_string() {
+        return Err(JsError::new_str("fetch expects an url as first argument"));
+    }
+    let mut request = FetchRequest {
+        url: args[0].js_to_string()?,
+        method: "GET".to_string(),
+        headers: vec![],
+        body: None,
+    };
+
+    if let Some(options) = args.get(1) {
+        if options.js_is_object() {
+            let method = realm.js_object_get_property(options, "method")?;
+            if method.js_is_string() {
+                request.method = method.js_to_string()?.to_uppercase();
+            }
+            let headers = realm.js_object_get_property(options, "headers")?;
+            if headers.js_is_object() {
+                for name in realm.js_object_get_properties(&headers)? {
+                    let value = realm.js_object_get_property(&headers, name.as_str())?;
+                    request.headers.push((name, value.js_to_string()?));
+                }
+            }
+            let body = realm.js_object_get_property(options, "body")?;
+            if body.js_is_string() {
+                request.body = Some(body.js_to_string()?);
+            }
+        }
+    }
+
+    Ok(request)
+}
+
+// the circuit breakers are per host, with the port when it is not the default of the scheme
+fn breaker_target(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => url.to_string(),
+        },
+        Err(_) => url.to_string(),
+    }
+}
+
+/// the permit is recorded as failed for a network error and a 5xx, other statuses are answers of a working host
+async fn do_fetch(request: FetchRequest, permit: Permit) -> Result<FetchResponse, JsError> {
+    let method = reqwest::Method::from_bytes(request.method.as_bytes())
+        .map_err(|_| JsError::new_string(format!("invalid method: {}", request.method)))?;
+    let mut builder = CLIENT.request(method, request.url.as_str());
+    for (name, value) in request.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
+    let response = match builder.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            permit.record(true);
+            return Err(JsError::new_string(format!("fetch failed: {}", err)));
+        }
+    };
+    let status = response.status().as_u16();
+    let max_body = config::get().fetch_max_body;
+    let body = read_body(response, max_body).await;
+    permit.record(status >= 500 || body.is_err());
+    match body {
+        Ok(Some(body)) => Ok(FetchResponse { status, body }),
+        Ok(None) => Err(JsError::new_string(format!(
+            "the response body of {} is larger than {} bytes",
+            request.url, max_body
+        ))),
+        Err(err) => Err(JsError::new_string(format!(
+            "could not read response body: {}",
+            err
+        ))),
+    }
+}
+
+/// read the body in chunks so a response which is too large is refused without buffering all of it, None when the
+/// body is larger than max_body
+async fn read_body(
+    mut response: reqwest::Response,
+    max_body: usize,
+) -> Result<Option<String>, reqwest::Error> {
+    if matches!(response.content_length(), Some(length) if length > max_body as u64) {
+        return Ok(None);
+    }
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_body {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// make a fetch abortable by the signal in options.signal
+///
+/// the signal gets an abort listener which aborts the rust side of the request, a signal which was already aborted
+/// aborts it right away, the fetch is also aborted when the request it was started for is dropped
+fn watch_signal<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+) -> Result<(u64, oneshot::Receiver<()>), JsError> {
+    let (abort_id, aborted) = abort::register(context::request_id());
+    let signal = match args.get(1) {
+        Some(options) if options.js_is_object() => {
+            realm.js_object_get_property(options, "signal")?
+        }
+        _ => return Ok((abort_id, aborted)),
+    };
+    if !signal.js_is_object() {
+        return Ok((abort_id, aborted));
+    }
+    let add_listener = || -> Result<(), JsError> {
+        let already_aborted = realm.js_object_get_property(&signal, "aborted")?;
+        if already_aborted.js_is_bool() && already_aborted.js_to_bool() {
+            abort::abort(abort_id);
+            return Ok(());
+        }
+        let add_event_listener = realm.js_object_get_property(&signal, "addEventListener")?;
+        if !add_event_listener.js_is_function() {
+            return Err(JsError::new_str(
+                "fetch expects an AbortSignal as options.signal",
+            ));
+        }
+        let on_abort = safe_function_create(
+            realm,
+            "onAbort",
+            move |realm: &R, _this, _args| {
+                abort::abort(abort_id);
+                realm.js_undefined_create()
+            },
+            1,
+        )?;
+        realm.js_function_invoke(
+            Some(&signal),
+            &add_event_listener,
+            &[&realm.js_string_create("abort")?, &on_abort],
+        )?;
+        Ok(())
+    };
+    match add_listener() {
+        Ok(()) => Ok((abort_id, aborted)),
+        Err(err) => {
+            abort::done(abort_id);
+            Err(err)
+        }
+    }
+}
+
+fn fetch<R: JsRealmAdapter + 'static>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+) -> Result<R::JsValueAdapterType, JsError> {
+    let request = read_request(realm, args)?;
+    let (abort_id, aborted) = watch_signal(realm, args)?;
+    // reqwest needs a tokio runtime, create_promise runs the request on the task runtime
+    promises::create_promise(
+        realm,
+        async move {
+            // scripts may only fetch from the same domains as we allow modules to be loaded from
+            if !config::is_allowed_url(request.url.as_str()) {
+                abort::done(abort_id);
+                return Err(JsError::new_string(format!(
+                    "fetch of {} is not allowed",
+                    request.url
+                )));
+            }
+            let url = request.url.clone();
+            let permit = match circuit_breaker::acquire(breaker_target(url.as_str()).as_str()) {
+                Ok(permit) => permit,
+                Err(err) => {
+                    abort::done(abort_id);
+                    return Err(err);
+                }
+            };
+            // dropping the fetch future cancels the request, the abort branch goes first so an already aborted
+            // signal never sends the request, an aborted fetch does not count for the circuit breaker
+            let result = tokio::select! {
+                biased;
+                _ = aborted => Err(JsError::new_string(format!("fetch of {} was aborted", url))),
+                result = do_fetch(request, permit) => result,
+            };
+            abort::done(abort_id);
+            result
+        },
+        |realm, response: FetchResponse| {
+            let (instance_id, response_obj) = realm.js_proxy_instantiate(&[], "Response", &[])?;
+            RESPONSES.with(|responses| {
+                responses
+                    .borrow_mut()
+                    .insert((realm.js_get_realm_id().to_string(), instance_id), response)
+            });
+            Ok(response_obj)
+        },
+    )
+}
+
+fn with_response<R: JsRealmAdapter, T, C: FnOnce(&FetchResponse) -> Result<T, JsError>>(
+    realm: &R,
+    instance_id: usize,
+    consumer: C,
+) -> Result<T, JsError> {
+    RESPONSES.with(|responses| {
+        match responses
+            .borrow()
+            .get(&(realm.js_get_realm_id().to_string(), instance_id))
+        {
+            Some(response) => consumer(response),
+            None => Err(JsError::new_str("no such response")),
+        }
+    })
+}
+
+/// create a promise which is already resolved with a value or rejected with an error, text() and json() return
+/// promises like they do in a browser even though we already read the whole body
+fn settled_promise<R: JsRealmAdapter>(
+    realm: &R,
+    result: Result<R::JsValueAdapterType, JsError>,
+) -> Result<R::JsValueAdapterType, JsError> {
+    let promise = realm.js_promise_create()?;
+    match result {
+        Ok(value) => promise.js_promise_resolve(realm, &value)?,
+        Err(err) => {
+            let error =
+                realm.js_error_create(err.get_name(), err.get_message(), err.get_stack())?;
+            promise.js_promise_reject(realm, &error)?
+        }
+    }
+    Ok(promise.js_promise_get_value(realm))
+}
+
+/// install the global fetch function and the Response class it resolves to
+pub fn init_fetch<R: JsRealmAdapter + 'static>(realm: &R) -> Result<(), JsError> {
+    let proxy = JsProxy::new(&[], "Response")
+        .add_safe_getter("status", "number", |_rt, realm: &R, instance_id| {
+            with_response(realm, instance_id, |response| {
+                realm.js_i32_create(response.status as i32)
+            })
+        })
+        .add_safe_getter("ok", "boolean", |_rt, realm: &R, instance_id| {
+            with_response(realm, instance_id, |response| {
+                realm.js_boolean_create((200..300).contains(&response.status))
+            })
+        })
+        .add_safe_method(
+            "text",
+            "(): Promise<string>",
+            |_rt, realm: &R, instance_id, _args| {
+                let text = with_response(realm, instance_id, |response| {
+                    realm.js_string_create(response.body.as_str())
+                })?;
+                settled_promise(realm, Ok(text))
+            },
+        )
+        .add_safe_method(
+            "json",
+            "(): Promise<any>",
+            |_rt, realm: &R, instance_id, _args| {
+                // a body which is not json rejects the promise like it does in a browser
+                let json = with_response(realm, instance_id, |response| {
+                    Ok(realm.js_json_parse(response.body.as_str()))
+                })?;
+                settled_promise(realm, json)
+            },
+        )
+        .set_safe_finalizer(|_rt, realm: &R, instance_id| {
+            RESPONSES.with(|responses| {
+                responses
+                    .borrow_mut()
+                    .remove(&(realm.js_get_realm_id().to_string(), instance_id));
+            });
+        });
+    proxy_registry::install(realm, proxy)?;
+
+    realm.js_install_closure(
+        &[],
+        "fetch",
+        |_rt, realm: &R, _this, args| fetch(realm, args),
+        2,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use hirofa_utils::js_utils::Script;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // serve a single response on a local port and return its url
+    fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(response.as_bytes());
+        });
+        url
+    }
+
+    async fn body_of(response: &'static str, max_body: usize) -> Option<String> {
+        let response = reqwest::get(serve(response).as_str()).await.unwrap();
+        read_body(response, max_body).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn bodies_larger_than_the_max_are_refused() {
+        let sized = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello";
+        assert_eq!(body_of(sized, 5).await.as_deref(), Some("hello"));
+        assert_eq!(body_of(sized, 4).await, None);
+
+        // without a Content-Length the body is only refused once more than max_body bytes were read
+        let streamed = "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello";
+        assert_eq!(body_of(streamed, 5).await.as_deref(), Some("hello"));
+        assert_eq!(body_of(streamed, 4).await, None);
+    }
+
+    #[test]
+    fn json_rejects_for_a_body_which_is_not_json() {
+        let rt = QuickJsRuntimeBuilder::new().build();
+        let evaluated = rt.js_loop_realm_sync(None, |_rt, realm| {
+            init_fetch(realm).ok().unwrap();
+            let (instance_id, response) = realm
+                .js_proxy_instantiate(&[], "Response", &[])
+                .ok()
+                .unwrap();
+            let body = "not json".to_string();
+            RESPONSES.with(|responses| {
+                responses.borrow_mut().insert(
+                    (realm.js_get_realm_id().to_string(), instance_id),
+                    FetchResponse { status: 200, body },
+                )
+            });
+            let global = realm.js_get_global().ok().unwrap();
+            realm
+                .js_object_set_property(&global, "response", &response)
+                .ok()
+                .unwrap();
+            let script = "response.json().then(() => { globalThis.result = 'resolved'; }, \
+                (err) => { globalThis.result = 'rejected'; });";
+            realm
+                .js_eval(Script::new("file://fetch_test.js", script))
+                .is_ok()
+        });
+        assert!(evaluated);
+        let result = rt.js_loop_realm_sync(None, |_rt, realm| {
+            realm
+                .js_eval(Script::new(
+                    "file://fetch_test.js",
+                    "String(globalThis.result)",
+                ))
+                .and_then(|value| value.js_to_string())
+                .ok()
+                .unwrap()
+        });
+        assert_eq!(result, "rejected");
+    }
+
+    #[test]
+    fn the_breakers_are_per_host_and_port() {
+        assert_eq!(breaker_target("https://example.com/a?b=c"), "example.com");
+        assert_eq!(breaker_target("https://example.com:443/a"), "example.com");
+        assert_eq!(
+            breaker_target("http://example.com:8080/a"),
+            "example.com:8080"
+        );
+        assert_eq!(breaker_target("not a url"), "not a url");
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::{self, FILES_DIR_VAR, WRITE_DIR_VAR};
+use crate::proxies::{get_string_arg, SafeMembers, SafeStaticMethods};
+use crate::proxy_registry;
+use crate::resources;
+use crate::sandbox::sandboxed_path;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// what a dir counts toward the quota, the size of a block on most filesystems, otherwise creating empty dirs would be
+// free
+const DIR_SIZE: u64 = 4096;
+/// the class of the instances openFile() returns
+const FILE_HANDLE_CLASS: &str = "FileHandle";
+
+lazy_static! {
+    // the bytes used in SCRIPT_WRITE_DIR, counted on the first write and kept up to date by writeFile
+    // the lock is held while writing so concurrent writes from other runtimes can't both fit in what is left
+    static ref WRITTEN: Mutex<Option<u64>> = Mutex::new(None);
+}
+
+/// add the readFile(path), readFileBase64(path), openFile(path) and writeFile(path, contents) static methods to a proxy
+/// path is relative to SCRIPT_FILES_DIR, readFile fails for files which are not valid utf-8, binary files can be
+/// read with readFileBase64
+/// writeFile writes to SCRIPT_WRITE_DIR instead, see write_file
+/// files are read on the worker thread of the runtime so these are meant for small assets, larger files can be read
+/// line by line with the FileHandle openFile returns
+pub fn init_files_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        // path is relative to SCRIPT_FILES_DIR, readFile throws for files which are not utf-8
+        // only there with the fs capability
+        .add_safe_static_method(
+            "readFile",
+            "(path: string): string",
+            |_rt, realm: &R, args| {
+                let path = get_string_arg(args, 0, "readFile")?;
+                let data = read_file(path.as_str())?;
+                let text = String::from_utf8(data).map_err(|_| {
+                    JsError::new_string(format!(
+                        "{} is not valid utf-8, use readFileBase64 for binary files",
+                        path
+                    ))
+                })?;
+                realm.js_string_create(text.as_str())
+            },
+        )
+        .add_safe_static_method(
+            "readFileBase64",
+            "(path: string): string",
+            |_rt, realm: &R, args| {
+                let path = get_string_arg(args, 0, "readFileBase64")?;
+                let data = read_file(path.as_str())?;
+                realm.js_string_create(base64::encode(data).as_str())
+            },
+        )
+        // for files which are too large to read at once, close the handle when done or use using(handle, fn)
+        .add_safe_static_method(
+            "openFile",
+            "(path: string): FileHandle",
+            |_rt, realm: &R, args| {
+                let path = get_string_arg(args, 0, "openFile")?;
+                let file = File::open(resolve(path.as_str())?).map_err(|err| {
+                    JsError::new_string(format!("could not open {}: {}", path, err))
+                })?;
+                let (instance_id, handle) =
+                    realm.js_proxy_instantiate(&[], FILE_HANDLE_CLASS, &[])?;
+                resources::insert(realm, FILE_HANDLE_CLASS, instance_id, BufReader::new(file));
+                Ok(handle)
+            },
+        )
+        // path is relative to SCRIPT_WRITE_DIR, throws when the dir would grow over SCRIPT_WRITE_QUOTA
+        // only there with the fs capability
+        .add_safe_static_method(
+            "writeFile",
+            "(path: string, contents: string): void",
+            |_rt, realm: &R, args| {
+                let path = get_string_arg(args, 0, "writeFile")?;
+                let contents = get_string_arg(args, 1, "writeFile")?;
+                write_file(path.as_str(), contents.as_bytes())?;
+                realm.js_undefined_create()
+            },
+        )
+}
+
+/// install the FileHandle class, an open file from openFile(path)
+/// readLine() returns the next line without the line ending or null at the end of the file, close() closes the
+/// file, which also happens when the handle is garbage collected, see resources::disposable
+pub fn init_file_handle<R: JsRealmAdapter + 'static>(realm: &R) -> Result<(), JsError> {
+    let proxy = JsProxy::new(&[], FILE_HANDLE_CLASS).add_safe_method(
+        "readLine",
+        "(): string | null",
+        |_rt, realm: &R, instance_id, _args| {
+            let line = resources::with_resource(
+                realm,
+                FILE_HANDLE_CLASS,
+                instance_id,
+                |reader: &mut BufReader<File>| {
+                    let mut line = String::new();
+                    match reader.read_line(&mut line) {
+                        Ok(0) => Ok(None),
+                        Ok(_) => Ok(Some(line)),
+                        Err(err) => Err(JsError::new_string(format!(
+                            "could not read the file: {}",
+                            err
+                        ))),
+                    }
+                },
+            )?;
+            match line {
+                Some(line) => realm.js_string_create(line.trim_end_matches(&['\r', '\n'][..])),
+                None => realm.js_null_create(),
+            }
+        },
+    );
+    proxy_registry::install(realm, resources::disposable(proxy, FILE_HANDLE_CLASS))?;
+    Ok(())
+}
+
+/// a file from SCRIPT_FILES_DIR which is sent as response, see event.responseFile
+pub struct StaticFile {
+    pub data: Vec<u8>,
+    pub modified: SystemTime,
+    pub content_type: String,
+}
+
+/// read a file to respond with, the content type is guessed from the extension
+/// a file which does not exist fails with the same error as a thrown HttpError(404) so the client gets a 404
+pub fn read_static_file(rel_path: &str) -> Result<StaticFile, JsError> {
+    if !sandboxed_path(config::get().files_dir.as_str(), rel_path)?.is_file() {
+        return Err(JsError::new(
+            "HttpError(404)".to_string(),
+            format!("{} not found", rel_path),
+            String::new(),
+        ));
+    }
+    let path = resolve(rel_path)?;
+    let modified = std::fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|err| JsError::new_string(format!("could not read {}: {}", rel_path, err)))?;
+    let data = std::fs::read(&path)
+        .map_err(|err| JsError::new_string(format!("could not read {}: {}", rel_path, err)))?;
+    Ok(StaticFile {
+        data,
+        modified,
+        content_type: mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .to_string(),
+    })
+}
+
+fn read_file(rel_path: &str) -> Result<Vec<u8>, JsError> {
+    std::fs::read(resolve(rel_path)?)
+        .map_err(|err| JsError::new_string(format!("could not read {}: {}", rel_path, err)))
+}
+
+// the canonical path of a file in SCRIPT_FILES_DIR
+fn resolve(rel_path: &str) -> Result<PathBuf, JsError> {
+    let path = sandboxed_path(config::get().files_dir.as_str(), rel_path)?;
+    // a symlink in the dir could still point outside of it
+    let root = std::fs::canonicalize(config::get().files_dir.as_str())
+        .map_err(|err| JsError::new_string(format!("could not read {}: {}", FILES_DIR_VAR, err)))?;
+    let path = std::fs::canonicalize(&path)
+        .map_err(|err| JsError::new_string(format!("could not read {}: {}", rel_path, err)))?;
+    if !path.starts_with(&root) {
+        return Err(JsError::new_string(format!(
+            "path {} is not allowed",
+            rel_path
+        )));
+    }
+    Ok(path)
+}
+
+// the total size of the files in a dir and its subdirs, symlinks are not followed, every subdir counts as DIR_SIZE
+fn dir_size(dir: &Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => DIR_SIZE + dir_size(entry.path().as_path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+// the number of dirs which have to be created for dir, None when its deepest existing ancestor is not in root (the
+// canonical write dir) e.g. because it is a symlink to somewhere else
+fn missing_dirs(root: &Path, dir: &Path) -> Option<u64> {
+    let mut missing = 0;
+    let mut ancestor = dir;
+    // a dangling symlink exists but can't be canonicalized
+    while std::fs::symlink_metadata(ancestor).is_err() {
+        ancestor = ancestor.parent()?;
+        missing += 1;
+    }
+    let ancestor = std::fs::canonicalize(ancestor).ok()?;
+    if ancestor.starts_with(root) {
+        Some(missing)
+    } else {
+        None
+    }
+}
+
+/// write a file in SCRIPT_WRITE_DIR, replacing it if it exists, missing parent dirs are created
+///
+/// fails if the files and dirs in the dir would take more than SCRIPT_WRITE_QUOTA bytes after the write, the size of
+/// a file which is replaced does not count and every dir counts as DIR_SIZE bytes
+/// the contents are written to a temp file next to it which is then renamed, so readers never see half a file and a
+/// failed write leaves the old file
+pub fn write_file(rel_path: &str, contents: &[u8]) -> Result<(), JsError> {
+    let write_dir = config::get().write_dir.as_str();
+    let path = sandboxed_path(write_dir, rel_path)?;
+    let failed =
+        |err: std::io::Error| JsError::new_string(format!("could not write {}: {}", rel_path, err));
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(write_dir));
+    std::fs::create_dir_all(write_dir).map_err(failed)?;
+    let root = std::fs::canonicalize(write_dir).map_err(|err| {
+        JsError::new_string(format!("could not write {}: {}", WRITE_DIR_VAR, err))
+    })?;
+    // checked before the missing dirs are created, those would otherwise be created wherever a symlinked dir points
+    let missing_dirs = match missing_dirs(&root, dir) {
+        Some(missing_dirs) => missing_dirs,
+        None => {
+            return Err(JsError::new_string(format!(
+                "path {} is not allowed",
+                rel_path
+            )))
+        }
+    };
+
+    let mut written = WRITTEN.lock().unwrap();
+    let used = *written.get_or_insert_with(|| dir_size(Path::new(write_dir)));
+    let replaced = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_dir() => {
+            return Err(JsError::new_string(format!(
+                "could not write {}: it is a dir",
+                rel_path
+            )))
+        }
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    let total = used.saturating_sub(replaced) + contents.len() as u64 + missing_dirs * DIR_SIZE;
+    let quota = config::get().write_quota;
+    if total > quota {
+        return Err(JsError::new_string(format!(
+            "could not write {}: {} bytes would exceed the quota of {} bytes, {} bytes are used",
+            rel_path,
+            contents.len(),
+            quota,
+            used
+        )));
+    }
+
+    std::fs::create_dir_all(dir).map_err(failed)?;
+    // a symlinked dir could have been created in the meantime, a symlinked file is replaced by the rename, not
+    // followed
+    if !std::fs::canonicalize(dir)
+        .map_err(failed)?
+        .starts_with(&root)
+    {
+        return Err(JsError::new_string(format!(
+            "path {} is not allowed",
+            rel_path
+        )));
+    }
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = dir.join(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+    if let Err(err) = std::fs::write(&temp, contents).and_then(|_| std::fs::rename(&temp, &path)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(failed(err));
+    }
+    *written = Some(total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_outside_the_files_dir_are_not_allowed() {
+        config::init_for_tests();
+        for path in ["../Cargo.toml", "/etc/passwd", "a/../../Cargo.toml"] {
+            let err = read_file(path).err().unwrap();
+            assert_eq!(err.get_message(), format!("path {} is not allowed", path));
+        }
+        assert!(read_file("").is_err());
+    }
+
+    #[test]
+    fn a_file_is_replaced_in_the_write_dir() {
+        config::init_for_tests();
+        let dir = format!("files-test-{}", uuid::Uuid::new_v4());
+        let path = format!("{}/a/b.txt", dir);
+        write_file(path.as_str(), b"first").ok().unwrap();
+        write_file(path.as_str(), b"second").ok().unwrap();
+        let written = Path::new(config::get().write_dir.as_str()).join(&dir);
+        assert_eq!(
+            std::fs::read_to_string(written.join("a/b.txt")).unwrap(),
+            "second"
+        );
+        // no temp files are left behind
+        assert_eq!(std::fs::read_dir(written.join("a")).unwrap().count(), 1);
+        assert_eq!(
+            write_file(format!("{}/a", dir).as_str(), b"x")
+                .err()
+                .unwrap()
+                .get_message(),
+            format!("could not write {}/a: it is a dir", dir)
+        );
+        assert!(write_file("../outside.txt", b"x").is_err());
+        std::fs::remove_dir_all(written).unwrap();
+    }
+
+    #[test]
+    fn dirs_are_checked_before_they_are_created() {
+        let base = std::env::temp_dir().join(format!("files-test-{}", uuid::Uuid::new_v4()));
+        let root = base.join("data");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(root.join("existing")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        let root = std::fs::canonicalize(&root).unwrap();
+
+        assert_eq!(missing_dirs(&root, &root.join("existing")), Some(0));
+        assert_eq!(missing_dirs(&root, &root.join("existing/a/b")), Some(2));
+        assert_eq!(missing_dirs(&root, &root.join("link/a/b")), None);
+        assert!(!outside.join("a").exists());
+        // the dirs count toward the quota
+        std::fs::create_dir_all(root.join("existing/a/b")).unwrap();
+        assert_eq!(dir_size(&root.join("existing")), 2 * DIR_SIZE);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
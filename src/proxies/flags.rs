@@ -0,0 +1,18 @@
+use crate::flags;
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+
+/// add the isEnabled(flag) static method to a proxy
+/// the flags come from SCRIPT_FLAGS or the [flags] of the config file and can be changed with POST /admin/flags/{name},
+/// unknown flags are disabled
+pub fn init_flags_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy.add_safe_static_method(
+        "isEnabled",
+        "(flag: string): boolean",
+        |_rt, realm: &R, args| {
+            let name = get_string_arg(args, 0, "isEnabled")?;
+            realm.js_boolean_create(flags::is_enabled(name.as_str()))
+        },
+    )
+}
@@ -0,0 +1,78 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::tenants;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    // shared by all runtimes in the pool and kept for the lifetime of the process
+    // keyed by tenant and key, every tenant has its own values, the tenant is empty outside of tenant realms
+    static ref STORE: Mutex<HashMap<(String, String), String>> = Mutex::new(HashMap::new());
+}
+
+/// add the kvSet(key, value), kvGet(key) and kvDelete(key) static methods to a proxy
+/// values are strings, scripts can JSON.stringify richer data themselves
+pub fn init_kv_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        // every tenant has its own values
+        .add_safe_static_method(
+            "kvSet",
+            "(key: string, value: string): void",
+            |_rt, realm: &R, args| {
+                let key = store_key(realm, get_string_arg(args, 0, "kvSet")?);
+                let value = get_string_arg(args, 1, "kvSet")?;
+                STORE.lock().unwrap().insert(key, value);
+                realm.js_undefined_create()
+            },
+        )
+        .add_safe_static_method(
+            "kvGet",
+            "(key: string): string | undefined",
+            |_rt, realm: &R, args| {
+                let key = store_key(realm, get_string_arg(args, 0, "kvGet")?);
+                let value = STORE.lock().unwrap().get(&key).cloned();
+                match value {
+                    Some(value) => realm.js_string_create(value.as_str()),
+                    None => realm.js_undefined_create(),
+                }
+            },
+        )
+        // returns true if the key existed
+        .add_safe_static_method(
+            "kvDelete",
+            "(key: string): boolean",
+            |_rt, realm: &R, args| {
+                let key = store_key(realm, get_string_arg(args, 0, "kvDelete")?);
+                let existed = STORE.lock().unwrap().remove(&key).is_some();
+                realm.js_boolean_create(existed)
+            },
+        )
+}
+
+fn store_key<R: JsRealmAdapter>(realm: &R, key: String) -> (String, String) {
+    let tenant = tenants::tenant_of(realm.js_get_realm_id()).unwrap_or_default();
+    (tenant.to_string(), key)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn values_are_kept_until_they_are_deleted() {
+        let results = crate::tests::eval(
+            r#"{
+                const app = com.mycompany.MyApp;
+                app.kvSet("kv-test", "first");
+                app.kvSet("kv-test", "second");
+                [
+                    app.kvGet("kv-test"),
+                    app.kvDelete("kv-test"),
+                    app.kvGet("kv-test"),
+                    app.kvDelete("kv-test"),
+                ].join()
+            }"#,
+        );
+        assert_eq!(results, "second,true,,false");
+    }
+}
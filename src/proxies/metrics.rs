@@ -0,0 +1,35 @@
+use crate::metrics;
+use crate::proxies::{get_number_arg, get_string_arg, SafeStaticMethods};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::JsError;
+
+/// add the incrCounter(name, by) and observeHistogram(name, value) static methods to a proxy
+/// the metrics are on /metrics as script_app_<name>, a name is a counter or a histogram depending on the method which
+/// used it first and scripts can create at most metrics::MAX_SCRIPT_METRICS of them, by defaults to 1
+pub fn init_metrics_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        .add_safe_static_method(
+            "incrCounter",
+            "(name: string, by?: number): void",
+            |_rt, realm: &R, args| {
+                let name = get_string_arg(args, 0, "incrCounter")?;
+                let by = get_number_arg(args, 1, "incrCounter")?.unwrap_or(1.0);
+                metrics::incr_script_counter(name.as_str(), by).map_err(JsError::new_string)?;
+                realm.js_undefined_create()
+            },
+        )
+        .add_safe_static_method(
+            "observeHistogram",
+            "(name: string, value: number): void",
+            |_rt, realm: &R, args| {
+                let name = get_string_arg(args, 0, "observeHistogram")?;
+                let value = get_number_arg(args, 1, "observeHistogram")?.ok_or_else(|| {
+                    JsError::new_str("observeHistogram expects a number as argument 2")
+                })?;
+                metrics::observe_script_histogram(name.as_str(), value)
+                    .map_err(JsError::new_string)?;
+                realm.js_undefined_create()
+            },
+        )
+}
@@ -0,0 +1,302 @@
+use crate::proxy_registry;
+use hirofa_utils::js_utils::adapters::proxies::{JsProxy, JsProxyInstanceId};
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+pub mod console;
+pub mod context;
+pub mod cors;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+pub mod dates;
+#[cfg(feature = "db")]
+pub mod db;
+pub mod encoding;
+pub mod env;
+#[cfg(feature = "fetch")]
+pub mod fetch;
+pub mod files;
+pub mod flags;
+pub mod kv;
+pub mod metrics;
+pub mod performance;
+pub mod rate_limit;
+pub mod response_cache;
+pub mod routes;
+pub mod schema;
+pub mod sse;
+pub mod templates;
+pub mod uploads;
+pub mod url;
+pub mod version;
+#[cfg(feature = "ws")]
+pub mod websocket;
+
+/// get a string argument of a proxy method or fail with a readable error
+pub fn get_string_arg<V: JsValueAdapter>(
+    args: &[V],
+    idx: usize,
+    method: &str,
+) -> Result<String, JsError> {
+    match args.get(idx) {
+        Some(arg) if arg.js_is_string() => arg.js_to_string(),
+        _ => Err(JsError::new_string(format!(
+            "{} expects a string as argument {}",
+            method,
+            idx + 1
+        ))),
+    }
+}
+
+/// get an optional number argument of a proxy method, None when it is null or undefined
+/// js numbers are either an i32 or an f64 in the adapter
+pub fn get_number_arg<V: JsValueAdapter>(
+    args: &[V],
+    idx: usize,
+    method: &str,
+) -> Result<Option<f64>, JsError> {
+    match args.get(idx) {
+        Some(arg) if arg.js_is_i32() => Ok(Some(arg.js_to_i32() as f64)),
+        Some(arg) if arg.js_is_f64() => Ok(Some(arg.js_to_f64())),
+        Some(arg) if !arg.js_is_null_or_undefined() => Err(JsError::new_string(format!(
+            "{} expects a number as argument {}",
+            method,
+            idx + 1
+        ))),
+        _ => Ok(None),
+    }
+}
+
+/// JsProxy::add_static_method for methods which may panic, all our static methods are added with this
+///
+/// a panic like a failed unwrap would unwind into QuickJS and abort the process, a safe static method catches it and
+/// throws an Error instead so the script can catch it and only the current request fails
+///
+/// the signature is the typescript parameters and return type like `(name: string): string`, it is what
+/// the generated declaration (see dts.rs) says about the method
+pub trait SafeStaticMethods<R: JsRealmAdapter> {
+    fn add_safe_static_method<M>(
+        self,
+        name: &'static str,
+        signature: &'static str,
+        method: M,
+    ) -> Self
+    where
+        M: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                &[R::JsValueAdapterType],
+            ) -> Result<R::JsValueAdapterType, JsError>
+            + 'static;
+}
+
+impl<R: JsRealmAdapter + 'static> SafeStaticMethods<R> for JsProxy<R> {
+    fn add_safe_static_method<M>(
+        self,
+        name: &'static str,
+        signature: &'static str,
+        method: M,
+    ) -> Self
+    where
+        M: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                &[R::JsValueAdapterType],
+            ) -> Result<R::JsValueAdapterType, JsError>
+            + 'static,
+    {
+        proxy_registry::declare(proxy_registry::full_name(&self).as_str(), name, signature);
+        self.add_static_method(name, move |rt, realm: &R, args| {
+            catch_panic(name, || method(rt, realm, args))
+        })
+    }
+}
+
+/// like SafeStaticMethods for the members of instances, all our instance methods, getters, constructors and
+/// finalizers are added with these, the signature of a getter is its type like `boolean`
+pub trait SafeMembers<R: JsRealmAdapter> {
+    fn add_safe_method<M>(self, name: &'static str, signature: &'static str, method: M) -> Self
+    where
+        M: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                JsProxyInstanceId,
+                &[R::JsValueAdapterType],
+            ) -> Result<R::JsValueAdapterType, JsError>
+            + 'static;
+
+    fn add_safe_getter<G>(self, name: &'static str, signature: &'static str, getter: G) -> Self
+    where
+        G: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                JsProxyInstanceId,
+            ) -> Result<R::JsValueAdapterType, JsError>
+            + 'static;
+
+    fn set_safe_constructor<C>(self, constructor: C) -> Self
+    where
+        C: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                JsProxyInstanceId,
+                &[R::JsValueAdapterType],
+            ) -> Result<(), JsError>
+            + 'static;
+
+    /// a finalizer can't throw, a panic is only logged
+    fn set_safe_finalizer<F>(self, finalizer: F) -> Self
+    where
+        F: Fn(&R::JsRuntimeAdapterType, &R, JsProxyInstanceId) + 'static;
+}
+
+impl<R: JsRealmAdapter + 'static> SafeMembers<R> for JsProxy<R> {
+    fn add_safe_method<M>(self, name: &'static str, signature: &'static str, method: M) -> Self
+    where
+        M: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                JsProxyInstanceId,
+                &[R::JsValueAdapterType],
+            ) -> Result<R::JsValueAdapterType, JsError>
+            + 'static,
+    {
+        proxy_registry::declare(proxy_registry::full_name(&self).as_str(), name, signature);
+        self.add_method(name, move |rt, realm: &R, instance_id, args| {
+            catch_panic(name, || method(rt, realm, instance_id, args))
+        })
+    }
+
+    fn add_safe_getter<G>(self, name: &'static str, signature: &'static str, getter: G) -> Self
+    where
+        G: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                JsProxyInstanceId,
+            ) -> Result<R::JsValueAdapterType, JsError>
+            + 'static,
+    {
+        proxy_registry::declare(proxy_registry::full_name(&self).as_str(), name, signature);
+        self.add_getter(name, move |rt, realm: &R, instance_id| {
+            catch_panic(name, || getter(rt, realm, instance_id))
+        })
+    }
+
+    fn set_safe_constructor<C>(self, constructor: C) -> Self
+    where
+        C: Fn(
+                &R::JsRuntimeAdapterType,
+                &R,
+                JsProxyInstanceId,
+                &[R::JsValueAdapterType],
+            ) -> Result<(), JsError>
+            + 'static,
+    {
+        let name = self.name;
+        self.set_constructor(move |rt, realm: &R, instance_id, args| {
+            catch_panic(name, || constructor(rt, realm, instance_id, args))
+        })
+    }
+
+    fn set_safe_finalizer<F>(self, finalizer: F) -> Self
+    where
+        F: Fn(&R::JsRuntimeAdapterType, &R, JsProxyInstanceId) + 'static,
+    {
+        let name = self.name;
+        self.set_finalizer(move |rt, realm: &R, instance_id| {
+            if let Err(panic) = catch_unwind(AssertUnwindSafe(|| finalizer(rt, realm, instance_id)))
+            {
+                log::error!(
+                    "the finalizer of {} panicked: {}",
+                    name,
+                    panic_message(panic.as_ref())
+                );
+            }
+        })
+    }
+}
+
+/// js_function_create for the functions we add to objects like the event, a panic throws an Error like in a safe
+/// static method
+pub fn safe_function_create<R, F>(
+    realm: &R,
+    name: &'static str,
+    function: F,
+    arg_count: u32,
+) -> Result<R::JsValueAdapterType, JsError>
+where
+    R: JsRealmAdapter,
+    F: Fn(
+            &R,
+            &R::JsValueAdapterType,
+            &[R::JsValueAdapterType],
+        ) -> Result<R::JsValueAdapterType, JsError>
+        + 'static,
+{
+    realm.js_function_create(
+        name,
+        move |realm: &R, this, args| catch_panic(name, || function(realm, this, args)),
+        arg_count,
+    )
+}
+
+// run a method, a panic becomes an Error the script can catch
+// the borrows and locks the method held are released while unwinding, a poisoned Mutex makes the next call panic as
+// well which then also ends up here
+fn catch_panic<T, F: FnOnce() -> Result<T, JsError>>(name: &str, method: F) -> Result<T, JsError> {
+    catch_unwind(AssertUnwindSafe(method)).unwrap_or_else(|panic| {
+        let message = panic_message(panic.as_ref());
+        log::error!("{} panicked: {}", name, message);
+        Err(JsError::new_string(format!(
+            "{} failed unexpectedly: {}",
+            name, message
+        )))
+    })
+}
+
+// the message of panic!() and unwrap() is a &str or a String
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_static_method_which_panics_throws_an_error() {
+        crate::tests::with_realm(|realm| {
+            let proxy = JsProxy::new(&[], "PanicTest").add_safe_static_method(
+                "explode",
+                "(): void",
+                |_rt, _realm: &_, _args| -> Result<_, JsError> { panic!("boom") },
+            );
+            realm.js_proxy_install(proxy, true).ok().unwrap();
+        });
+        let message = crate::tests::eval(
+            r#"(() => {
+                try {
+                    PanicTest.explode();
+                    return "no error";
+                } catch (err) {
+                    return err.message;
+                }
+            })()"#,
+        );
+        assert!(
+            message.ends_with("explode failed unexpectedly: boom"),
+            "{}",
+            message
+        );
+        // the runtime keeps working
+        assert_eq!(crate::tests::eval("1 + 1"), "2");
+    }
+}
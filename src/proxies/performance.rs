@@ -0,0 +1,43 @@
+use crate::proxies::SafeStaticMethods;
+use crate::proxy_registry;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::time::Instant;
+
+lazy_static! {
+    // the zero point of performance.now(), the same for every runtime and realm in the process
+    static ref TIME_ORIGIN: Instant = Instant::now();
+}
+
+/// install the performance global with now(), which returns the ms since the first realm was initialized as a
+/// fractional number, it is monotonic so it is meant for measuring durations, not for telling the time
+pub fn init_performance_proxy<R: JsRealmAdapter + 'static>(realm: &R) -> Result<(), JsError> {
+    lazy_static::initialize(&TIME_ORIGIN);
+    let proxy = JsProxy::new(&[], "performance").add_safe_static_method(
+        "now",
+        "(): number",
+        |_rt, realm: &R, _args| realm.js_f64_create(TIME_ORIGIN.elapsed().as_secs_f64() * 1000.0),
+    );
+    proxy_registry::install(realm, proxy)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn now_only_goes_forward() {
+        let result = crate::tests::eval(
+            r#"{
+                const start = performance.now();
+                let end = start;
+                while (end === start) {
+                    end = performance.now();
+                }
+                [start >= 0, end > start, end - start < 1000].join()
+            }"#,
+        );
+        assert_eq!(result, "true,true,true");
+    }
+}
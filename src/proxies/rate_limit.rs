@@ -0,0 +1,30 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::rate_limit;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+
+/// add the setRateLimit(route, perMinute) static method to a proxy
+/// route is a route pattern like "/api", requests over the limit get a 429 without being dispatched to the script
+pub fn init_rate_limit_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    // a limit of 0 removes the limit for the route
+    proxy.add_safe_static_method(
+        "setRateLimit",
+        "(route: string, perMinute: number): void",
+        |_rt, realm: &R, args| {
+            let route = get_string_arg(args, 0, "setRateLimit")?;
+            let per_minute = match args.get(1) {
+                Some(limit) if limit.js_is_i32() && limit.js_to_i32() >= 0 => {
+                    limit.js_to_i32() as u32
+                }
+                _ => {
+                    return Err(JsError::new_str(
+                        "setRateLimit expects a number of requests per minute as argument 2",
+                    ))
+                }
+            };
+            rate_limit::set_limit(route, per_minute);
+            realm.js_undefined_create()
+        },
+    )
+}
@@ -0,0 +1,20 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::{response_cache, tenants};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+
+/// add the cacheInvalidate(key) static method to a proxy
+/// it removes the responses which were cached with event.cacheFor(seconds, key) for the tenant of the realm and
+/// returns the number of responses it removed
+pub fn init_response_cache_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy.add_safe_static_method(
+        "cacheInvalidate",
+        "(key: string): number",
+        |_rt, realm: &R, args| {
+            let key = get_string_arg(args, 0, "cacheInvalidate")?;
+            let tenant = tenants::tenant_of(realm.js_get_realm_id()).unwrap_or_default();
+            let removed = response_cache::invalidate(tenant, key.as_str());
+            realm.js_i32_create(removed as i32)
+        },
+    )
+}
@@ -0,0 +1,37 @@
+use crate::proxies::{get_number_arg, get_string_arg, SafeStaticMethods};
+use crate::script_routes;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::JsError;
+use std::time::Duration;
+
+/// add the registerRoute(method, path, handlerName, timeoutMs) static method to a proxy
+/// requests for the route dispatch the handlerName event instead of request:<path>, the server is built with the
+/// routes after the init event so this throws for new routes once the server started, see script_routes::register
+/// without a timeoutMs the events of the route get SCRIPT_TIMEOUT_MS
+pub fn init_routes_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy.add_safe_static_method(
+        "registerRoute",
+        "(method: string, path: string, handlerName: string, timeoutMs?: number): void",
+        |_rt, realm: &R, args| {
+            let method = get_string_arg(args, 0, "registerRoute")?;
+            let path = get_string_arg(args, 1, "registerRoute")?;
+            let handler = get_string_arg(args, 2, "registerRoute")?;
+            let timeout = match get_number_arg(args, 3, "registerRoute")? {
+                Some(timeout_ms) if timeout_ms.is_finite() && timeout_ms >= 1.0 => {
+                    Some(Duration::from_millis(timeout_ms as u64))
+                }
+                Some(timeout_ms) => {
+                    return Err(JsError::new_string(format!(
+                        "registerRoute expects a timeout of at least 1 ms, got {}",
+                        timeout_ms
+                    )))
+                }
+                None => None,
+            };
+            script_routes::register(method.as_str(), path.as_str(), handler.as_str(), timeout)
+                .map_err(JsError::new_string)?;
+            realm.js_undefined_create()
+        },
+    )
+}
@@ -0,0 +1,52 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::schema;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+
+/// add the registerSchema(route, schema) and registerResponseSchema(route, schema) static methods to a proxy
+/// route is a route pattern like "/api", requests with a body which does not match the json schema get a 400
+/// without being dispatched to the script
+/// the value a route handler (see routes::HANDLERS) returns for a route with a response schema is validated before
+/// it is sent, a value which does not match fails the request, validating is opt-in per route
+pub fn init_schema_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        .add_safe_static_method(
+            "registerSchema",
+            "(route: string, schema: object): void",
+            |_rt, realm: &R, args| {
+                let route = get_string_arg(args, 0, "registerSchema")?;
+                let schema = get_schema_arg(realm, args, "registerSchema")?;
+                schema::register(route, &schema).map_err(JsError::new_string)?;
+                realm.js_undefined_create()
+            },
+        )
+        .add_safe_static_method(
+            "registerResponseSchema",
+            "(route: string, schema: object): void",
+            |_rt, realm: &R, args| {
+                let route = get_string_arg(args, 0, "registerResponseSchema")?;
+                let schema = get_schema_arg(realm, args, "registerResponseSchema")?;
+                schema::register_response(route, &schema).map_err(JsError::new_string)?;
+                realm.js_undefined_create()
+            },
+        )
+}
+
+fn get_schema_arg<R: JsRealmAdapter>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+    method: &str,
+) -> Result<serde_json::Value, JsError> {
+    match args.get(1) {
+        Some(schema) if schema.js_is_object() => {
+            let json = realm.js_json_stringify(schema, None)?;
+            serde_json::from_str(json.as_str())
+                .map_err(|err| JsError::new_string(format!("invalid schema: {}", err)))
+        }
+        _ => Err(JsError::new_string(format!(
+            "{} expects a schema object as argument 2",
+            method
+        ))),
+    }
+}
@@ -0,0 +1,19 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::sse;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+
+/// add the sseBroadcast(channel, data) static method to a proxy
+/// returns the number of /events subscribers and waiting /poll requests the message was sent to
+pub fn init_sse_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy.add_safe_static_method(
+        "sseBroadcast",
+        "(channel: string, data: string): number",
+        |_rt, realm: &R, args| {
+            let channel = get_string_arg(args, 0, "sseBroadcast")?;
+            let data = get_string_arg(args, 1, "sseBroadcast")?;
+            let sent = sse::broadcast(channel.as_str(), data.as_str());
+            realm.js_i32_create(sent as i32)
+        },
+    )
+}
@@ -0,0 +1,108 @@
+use crate::config;
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::sandbox::sandboxed_path;
+use handlebars::Handlebars;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+
+/// the extension of the template files, render("page", data) renders page.hbs
+const TEMPLATE_EXTENSION: &str = "hbs";
+
+lazy_static! {
+    // shared by all runtimes, a template is loaded the first time it is rendered
+    static ref TEMPLATES: RwLock<Handlebars<'static>> = {
+        let mut handlebars = Handlebars::new();
+        // debug builds read the template again for every render so changes show up without a restart
+        handlebars.set_dev_mode(cfg!(debug_assertions));
+        RwLock::new(handlebars)
+    };
+}
+
+/// add the render(templateName, data) static method to a proxy
+/// it renders the handlebars template SCRIPT_TEMPLATES_DIR/<templateName>.hbs with data (anything JSON.stringify
+/// accepts) and returns the result, values are html escaped unless the template uses {{{triple braces}}}
+/// a missing template or one which fails to parse or render throws
+pub fn init_templates_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy.add_safe_static_method(
+        "render",
+        "(templateName: string, data?: any): string",
+        |_rt, realm: &R, args| {
+            let name = get_string_arg(args, 0, "render")?;
+            let data = match args.get(1) {
+                Some(data) if !data.js_is_null_or_undefined() => {
+                    let json = realm.js_json_stringify(data, None)?;
+                    serde_json::from_str(json.as_str()).map_err(|err| {
+                        JsError::new_string(format!("render could not convert the data: {}", err))
+                    })?
+                }
+                _ => serde_json::Value::Null,
+            };
+            let output = render(name.as_str(), &data)?;
+            realm.js_string_create(output.as_str())
+        },
+    )
+}
+
+fn render(name: &str, data: &serde_json::Value) -> Result<String, JsError> {
+    let registered = TEMPLATES.read().unwrap().has_template(name);
+    if !registered {
+        load(name)?;
+    }
+    TEMPLATES
+        .read()
+        .unwrap()
+        .render(name, data)
+        .map_err(|err| JsError::new_string(format!("could not render template {}: {}", name, err)))
+}
+
+// register the file of a template, another runtime may have done so in the meantime which is harmless
+fn load(name: &str) -> Result<(), JsError> {
+    let templates_dir = config::get().templates_dir.as_str();
+    let path = sandboxed_path(
+        templates_dir,
+        format!("{}.{}", name, TEMPLATE_EXTENSION).as_str(),
+    )?;
+    if !path.is_file() {
+        return Err(JsError::new_string(format!(
+            "template {} not found in {}",
+            name, templates_dir
+        )));
+    }
+    TEMPLATES
+        .write()
+        .unwrap()
+        .register_template_file(name, &path)
+        .map_err(|err| JsError::new_string(format!("invalid template {}: {}", name, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::eval;
+
+    #[test]
+    fn a_template_is_rendered_with_the_data() {
+        let output =
+            eval(r#"com.mycompany.MyApp.render("greeting", {app: "Test", name: "<world>"})"#);
+        assert!(output.contains("<title>Test</title>"));
+        // values are html escaped
+        assert!(output.contains("<h1>Hello &lt;world&gt;</h1>"));
+        assert!(!output.contains("<ul>"));
+        let error = eval(
+            r#"(() => {
+                try {
+                    return com.mycompany.MyApp.render("does-not-exist", {});
+                } catch (err) {
+                    return err.message;
+                }
+            })()"#,
+        );
+        assert!(
+            error.contains("template does-not-exist not found"),
+            "{}",
+            error
+        );
+    }
+}
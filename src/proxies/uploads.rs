@@ -0,0 +1,20 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::uploads;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+
+/// add the saveUploadedFile(id, path) static method to a proxy
+/// path is relative to SCRIPT_UPLOAD_DIR, uploads can only be saved while the request is being handled
+pub fn init_uploads_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    // only there with the fs capability
+    proxy.add_safe_static_method(
+        "saveUploadedFile",
+        "(id: string, path: string): void",
+        |_rt, realm: &R, args| {
+            let id = get_string_arg(args, 0, "saveUploadedFile")?;
+            let path = get_string_arg(args, 1, "saveUploadedFile")?;
+            uploads::save(id.as_str(), path.as_str())?;
+            realm.js_undefined_create()
+        },
+    )
+}
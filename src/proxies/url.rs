@@ -0,0 +1,123 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::JsError;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// the parts of a url as passed to and from script, the query and hash are without their ? and #
+/// optional parts are null when the url does not have them, port is null for the default port of the protocol
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UrlParts {
+    protocol: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    query: Option<String>,
+    #[serde(default)]
+    hash: Option<String>,
+}
+
+fn parse(input: &str) -> Result<UrlParts, JsError> {
+    let url = Url::parse(input)
+        .map_err(|err| JsError::new_string(format!("invalid url {}: {}", input, err)))?;
+    Ok(UrlParts {
+        protocol: url.scheme().to_string(),
+        username: url.username().to_string(),
+        password: url.password().map(str::to_string),
+        host: url.host_str().map(str::to_string),
+        port: url.port(),
+        path: url.path().to_string(),
+        query: url.query().map(str::to_string),
+        hash: url.fragment().map(str::to_string),
+    })
+}
+
+fn build(parts: UrlParts) -> Result<String, JsError> {
+    let invalid = |what: &str| JsError::new_string(format!("invalid {} for url", what));
+    let base = match &parts.host {
+        Some(host) => format!("{}://{}", parts.protocol, host),
+        None => format!("{}:", parts.protocol),
+    };
+    let mut url = Url::parse(base.as_str())
+        .map_err(|err| JsError::new_string(format!("invalid url {}: {}", base, err)))?;
+    if !parts.username.is_empty() {
+        url.set_username(parts.username.as_str())
+            .map_err(|_| invalid("username"))?;
+    }
+    if parts.password.is_some() {
+        url.set_password(parts.password.as_deref())
+            .map_err(|_| invalid("password"))?;
+    }
+    if parts.port.is_some() {
+        url.set_port(parts.port).map_err(|_| invalid("port"))?;
+    }
+    url.set_path(parts.path.as_str());
+    url.set_query(parts.query.as_deref());
+    url.set_fragment(parts.hash.as_deref());
+    Ok(url.to_string())
+}
+
+/// add the parseUrl(str) and buildUrl(parts) static methods to a proxy
+/// parseUrl returns {protocol, username, password, host, port, path, query, hash} and buildUrl is its inverse, so
+/// buildUrl(parseUrl(url)) gives back the url (normalized, e.g. with a lowercase host), both throw for invalid urls
+pub fn init_url_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    proxy
+        .add_safe_static_method(
+            "parseUrl",
+            "(url: string): UrlParts",
+            |_rt, realm: &R, args| {
+                let input = get_string_arg(args, 0, "parseUrl")?;
+                let json = serde_json::to_string(&parse(input.as_str())?).map_err(|err| {
+                    JsError::new_string(format!("could not serialize url: {}", err))
+                })?;
+                realm.js_json_parse(json.as_str())
+            },
+        )
+        .add_safe_static_method(
+            "buildUrl",
+            "(parts: UrlParts): string",
+            |_rt, realm: &R, args| {
+                let parts = match args.first() {
+                    Some(parts) if parts.js_is_object() => realm.js_json_stringify(parts, None)?,
+                    _ => return Err(JsError::new_str("buildUrl expects an object as argument 1")),
+                };
+                let parts: UrlParts = serde_json::from_str(parts.as_str())
+                    .map_err(|err| JsError::new_string(format!("invalid url parts: {}", err)))?;
+                realm.js_string_create(build(parts)?.as_str())
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_is_the_inverse_of_parse() {
+        let parts = parse("HTTPS://user:pw@Example.com:8443/a%20b?q=1#top")
+            .ok()
+            .unwrap();
+        assert_eq!(parts.host.as_deref(), Some("example.com"));
+        assert_eq!(parts.port, Some(8443));
+        assert_eq!(parts.path, "/a%20b");
+        assert_eq!(parts.query.as_deref(), Some("q=1"));
+        assert_eq!(parts.hash.as_deref(), Some("top"));
+        assert_eq!(
+            build(parts).ok().unwrap(),
+            "https://user:pw@example.com:8443/a%20b?q=1#top"
+        );
+        // the default port of the protocol is null
+        assert_eq!(parse("https://example.com:443/").ok().unwrap().port, None);
+        assert!(parse("not a url").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::proxies::SafeStaticMethods;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::{JsError, Script};
+
+/// the version from Cargo.toml
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// the short hash of the commit we were built from, set by build.rs
+pub const GIT_HASH: &str = env!("GIT_HASH");
+
+// the optional proxies which were compiled in, see the features in Cargo.toml
+fn features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "crypto") {
+        features.push("crypto");
+    }
+    if cfg!(feature = "db") {
+        features.push("db");
+    }
+    if cfg!(feature = "fetch") {
+        features.push("fetch");
+    }
+    if cfg!(feature = "ws") {
+        features.push("ws");
+    }
+    features
+}
+
+fn profile() -> &'static str {
+    if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    }
+}
+
+/// add the version() static method to a proxy, it returns
+/// {version, gitHash, runtime: {engine, poolSize, profile, os, arch, features}} for diagnostics
+pub fn init_version_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    // build info for diagnostics, gitHash is "unknown" when built without git
+    proxy.add_safe_static_method(
+        "version",
+        "(): {version: string, gitHash: string, runtime: any}",
+        |_rt, realm: &R, _args| {
+            let compiled = realm.js_array_create()?;
+            for (idx, feature) in features().into_iter().enumerate() {
+                realm.js_array_set_element(
+                    &compiled,
+                    idx as u32,
+                    &realm.js_string_create(feature)?,
+                )?;
+            }
+            let runtime = realm.js_object_create()?;
+            realm.js_object_set_property(
+                &runtime,
+                "engine",
+                &realm.js_string_create("quickjs")?,
+            )?;
+            realm.js_object_set_property(
+                &runtime,
+                "poolSize",
+                &realm.js_i32_create(crate::script_pool().runtimes().len() as i32)?,
+            )?;
+            realm.js_object_set_property(
+                &runtime,
+                "profile",
+                &realm.js_string_create(profile())?,
+            )?;
+            realm.js_object_set_property(
+                &runtime,
+                "os",
+                &realm.js_string_create(std::env::consts::OS)?,
+            )?;
+            realm.js_object_set_property(
+                &runtime,
+                "arch",
+                &realm.js_string_create(std::env::consts::ARCH)?,
+            )?;
+            realm.js_object_set_property(&runtime, "features", &compiled)?;
+
+            let info = realm.js_object_create()?;
+            realm.js_object_set_property(&info, "version", &realm.js_string_create(VERSION)?)?;
+            realm.js_object_set_property(&info, "gitHash", &realm.js_string_create(GIT_HASH)?)?;
+            realm.js_object_set_property(&info, "runtime", &runtime)?;
+            Ok(info)
+        },
+    )
+}
+
+// defines __BUILD__ with the json BUILD_JSON is replaced with, unless it already is
+const BUILD_GLOBAL: &str = r#"
+if (!Object.getOwnPropertyDescriptor(globalThis, "__BUILD__")) {
+    const build = BUILD_JSON;
+    Object.freeze(build.features);
+    Object.defineProperty(globalThis, "__BUILD__", {value: Object.freeze(build)});
+}
+"#;
+
+/// define globalThis.__BUILD__ with what was decided when compiling:
+/// {profile, version, gitHash, features, os, arch}, like version() but without a call to the native side
+/// the object (and its features array) is frozen and the global can't be reassigned or deleted, so scripts can rely
+/// on it, e.g. if (__BUILD__.profile === "debug")
+/// it is defined before any script runs and left alone when the proxies are installed again, see reinstall_proxies
+pub fn init_build_global<R: JsRealmAdapter>(realm: &R) -> Result<(), JsError> {
+    let build = serde_json::json!({
+        "profile": profile(),
+        "version": VERSION,
+        "gitHash": GIT_HASH,
+        "features": features(),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+    });
+    realm.js_eval(Script::new(
+        "file://build.js",
+        BUILD_GLOBAL
+            .replace("BUILD_JSON", build.to_string().as_str())
+            .as_str(),
+    ))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_version_is_that_of_the_crate() {
+        let info = crate::tests::eval(
+            r#"{
+                const info = com.mycompany.MyApp.version();
+                [info.version, info.gitHash.length > 0, info.runtime.engine, info.runtime.poolSize].join()
+            }"#,
+        );
+        assert_eq!(info, format!("{},true,quickjs,1", VERSION));
+    }
+
+    #[test]
+    fn the_build_global_is_frozen() {
+        let build = crate::tests::eval(
+            r#"{
+                __BUILD__ = 1;
+                delete globalThis.__BUILD__;
+                __BUILD__.profile = "other";
+                let pushed = true;
+                try {
+                    __BUILD__.features.push("other");
+                } catch (err) {
+                    pushed = false;
+                }
+                [Object.isFrozen(__BUILD__), pushed, __BUILD__.profile, __BUILD__.version, __BUILD__.os].join()
+            }"#,
+        );
+        assert_eq!(
+            build,
+            format!(
+                "true,false,{},{},{}",
+                profile(),
+                VERSION,
+                std::env::consts::OS
+            )
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use crate::proxies::{get_string_arg, SafeStaticMethods};
+use crate::websocket;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+
+/// add the wsSend(connectionId, text) static method to a proxy
+/// returns false when the connection was already closed
+pub fn init_websocket_proxy<R: JsRealmAdapter + 'static>(proxy: JsProxy<R>) -> JsProxy<R> {
+    // only there with the ws feature (on by default)
+    proxy.add_safe_static_method(
+        "wsSend",
+        "(connectionId: string, text: string): boolean",
+        |_rt, realm: &R, args| {
+            let connection_id = get_string_arg(args, 0, "wsSend")?;
+            let text = get_string_arg(args, 1, "wsSend")?;
+            realm.js_boolean_create(websocket::send(connection_id.as_str(), text))
+        },
+    )
+}
@@ -0,0 +1,198 @@
+use crate::isolation;
+use hirofa_utils::js_utils::adapters::proxies::{JsProxy, JsProxyMember, JsProxyStaticMember};
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// what a proxy offers to script, as listed by GET /admin/proxies
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyInfo {
+    /// the full name like com.mycompany.MyApp
+    pub name: String,
+    pub static_methods: Vec<String>,
+    pub static_getters: Vec<String>,
+    /// the methods and getters of instances, empty for proxies without a constructor
+    pub methods: Vec<String>,
+    pub getters: Vec<String>,
+    /// true when script can addEventListener on the class itself, like the events we dispatch to
+    /// com.mycompany.MyApp
+    pub static_event_target: bool,
+    pub event_target: bool,
+}
+
+/// the id of the main realm of a runtime
+pub const MAIN_REALM_ID: &str = "__main__";
+
+lazy_static! {
+    // by realm id and full name, every runtime installs the same proxies in a realm so the last install simply
+    // replaces the info
+    static ref PROXIES: Mutex<BTreeMap<String, BTreeMap<String, ProxyInfo>>> =
+        Mutex::new(BTreeMap::new());
+    // the typescript signatures by full proxy name and member name, declared where the member is added
+    static ref SIGNATURES: Mutex<BTreeMap<(String, String), &'static str>> = Mutex::new(BTreeMap::new());
+}
+
+fn sorted<'a, I: Iterator<Item = &'a &'static str>>(names: I) -> Vec<String> {
+    let mut names: Vec<String> = names.map(|name| name.to_string()).collect();
+    names.sort();
+    names
+}
+
+/// install a proxy in a realm and record what it offers, all our proxies are installed with this
+///
+/// the methods which are left out because a capability is not granted are not installed and thus not listed, which
+/// makes the listing a way to check what a deployment actually gives scripts
+pub fn install<R: JsRealmAdapter + 'static>(realm: &R, proxy: JsProxy<R>) -> Result<(), JsError> {
+    let name = full_name(&proxy);
+    let mut static_methods = vec![];
+    let mut static_getters = vec![];
+    for (member_name, member) in &proxy.static_members {
+        match member {
+            JsProxyStaticMember::StaticMethod { .. } => static_methods.push(member_name),
+            JsProxyStaticMember::StaticGetterSetter { .. } => static_getters.push(member_name),
+        }
+    }
+    let mut methods = vec![];
+    let mut getters = vec![];
+    for (member_name, member) in &proxy.members {
+        match member {
+            JsProxyMember::Method { .. } => methods.push(member_name),
+            JsProxyMember::GetterSetter { .. } => getters.push(member_name),
+        }
+    }
+    let info = ProxyInfo {
+        name,
+        static_methods: sorted(static_methods.into_iter()),
+        static_getters: sorted(static_getters.into_iter()),
+        methods: sorted(methods.into_iter()),
+        getters: sorted(getters.into_iter()),
+        static_event_target: proxy.static_event_target,
+        event_target: proxy.event_target,
+    };
+    realm.js_proxy_install(proxy, true)?;
+    record(realm.js_get_realm_id(), info);
+    Ok(())
+}
+
+/// the name of a proxy including its namespace like com.mycompany.MyApp
+pub fn full_name<R: JsRealmAdapter>(proxy: &JsProxy<R>) -> String {
+    let mut name = proxy.namespace.join(".");
+    if !name.is_empty() {
+        name.push('.');
+    }
+    name.push_str(proxy.name);
+    name
+}
+
+/// record the typescript signature of a member, called by the safe methods of proxies/mod.rs when the member is added
+pub fn declare(proxy: &str, member: &str, signature: &'static str) {
+    SIGNATURES
+        .lock()
+        .unwrap()
+        .insert((proxy.to_string(), member.to_string()), signature);
+}
+
+/// the signature a member was added with
+pub fn signature(proxy: &str, member: &str) -> Option<&'static str> {
+    SIGNATURES
+        .lock()
+        .unwrap()
+        .get(&(proxy.to_string(), member.to_string()))
+        .copied()
+}
+
+fn record(realm_id: &str, info: ProxyInfo) {
+    // the realms of isolated requests get the same proxies as the main realm, recording them would add an entry
+    // for every request
+    if !isolation::is_isolated(realm_id) {
+        PROXIES
+            .lock()
+            .unwrap()
+            .entry(realm_id.to_string())
+            .or_default()
+            .insert(info.name.clone(), info);
+    }
+}
+
+/// the proxies installed in a realm sorted by name
+pub fn installed(realm_id: &str) -> Vec<ProxyInfo> {
+    PROXIES
+        .lock()
+        .unwrap()
+        .get(realm_id)
+        .map(|proxies| proxies.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// the installed proxies by realm id, the main realm and the realms of the tenants
+pub fn by_realm() -> BTreeMap<String, Vec<ProxyInfo>> {
+    PROXIES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(realm_id, proxies)| (realm_id.clone(), proxies.values().cloned().collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(static_method: &str) -> ProxyInfo {
+        ProxyInfo {
+            name: "registryTest.Proxy".to_string(),
+            static_methods: vec![static_method.to_string()],
+            static_getters: vec![],
+            methods: vec![],
+            getters: vec![],
+            static_event_target: false,
+            event_target: false,
+        }
+    }
+
+    #[test]
+    fn the_installed_proxies_are_listed_with_their_members() {
+        let proxies = crate::tests::with_realm(|realm| installed(realm.js_get_realm_id()));
+        let console = proxies
+            .iter()
+            .find(|proxy| proxy.name == "console")
+            .unwrap();
+        assert!(console.static_methods.contains(&"log".to_string()));
+        assert!(!console.static_event_target);
+        let my_app = proxies
+            .iter()
+            .find(|proxy| proxy.name == "com.mycompany.MyApp")
+            .unwrap();
+        assert!(my_app.static_event_target);
+        let names: Vec<&str> = proxies.iter().map(|proxy| proxy.name.as_str()).collect();
+        let mut sorted_names = names.clone();
+        sorted_names.sort_unstable();
+        assert_eq!(names, sorted_names);
+    }
+
+    #[test]
+    fn the_proxies_are_listed_by_realm() {
+        record("tenant-registry-a", info("onlyInA"));
+        record("tenant-registry-b", info("onlyInB"));
+        record("request-registry", info("onlyInRequest"));
+        let static_methods = |realm_id: &str| -> Vec<Vec<String>> {
+            installed(realm_id)
+                .into_iter()
+                .map(|proxy| proxy.static_methods)
+                .collect()
+        };
+        assert_eq!(
+            static_methods("tenant-registry-a"),
+            vec![vec!["onlyInA".to_string()]]
+        );
+        assert_eq!(
+            static_methods("tenant-registry-b"),
+            vec![vec!["onlyInB".to_string()]]
+        );
+        assert!(!by_realm().contains_key("request-registry"));
+    }
+}
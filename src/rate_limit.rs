@@ -0,0 +1,121 @@
+use crate::client_addr::client_ip;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// when there are more buckets than this, buckets which have not been used for a minute are removed
+const PRUNE_THRESHOLD: usize = 10_000;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // the requests per minute by route pattern, set by script with MyApp.setRateLimit()
+    static ref LIMITS: Mutex<HashMap<String, u32>> = Mutex::new(HashMap::new());
+    // the buckets by client ip and route pattern
+    static ref BUCKETS: Mutex<HashMap<(String, String), Bucket>> = Mutex::new(HashMap::new());
+}
+
+/// a token bucket which holds at most per_minute tokens and refills at per_minute tokens per minute
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, per_minute: u32, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// set the limit for a route pattern like "/api", a limit of 0 removes the limit
+pub fn set_limit(route: String, per_minute: u32) {
+    let mut limits = LIMITS.lock().unwrap();
+    if per_minute == 0 {
+        limits.remove(&route);
+    } else {
+        limits.insert(route, per_minute);
+    }
+}
+
+/// check the limit for a request, returns the 429 response when the client exceeded the limit for the route
+pub fn check(req: &HttpRequest) -> Option<HttpResponse> {
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| req.path().to_string());
+    let per_minute = *LIMITS.lock().unwrap().get(&route)?;
+    let now = Instant::now();
+
+    let mut buckets = BUCKETS.lock().unwrap();
+    if buckets.len() > PRUNE_THRESHOLD {
+        buckets.retain(|_key, bucket| now.duration_since(bucket.last_refill) < IDLE_TIMEOUT);
+    }
+    let bucket = buckets
+        .entry((client_ip(req), route))
+        .or_insert_with(|| Bucket {
+            tokens: per_minute as f64,
+            last_refill: now,
+        });
+    if bucket.take(per_minute, now) {
+        None
+    } else {
+        // the time until the next token is available
+        let retry_after = ((1.0 - bucket.tokens) * 60.0 / per_minute as f64).ceil() as u64;
+        Some(
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.max(1).to_string()))
+                .body("too many requests"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn a_bucket_refills_at_the_limit_per_minute() {
+        let now = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 2.0,
+            last_refill: now,
+        };
+        assert!(bucket.take(2, now));
+        assert!(bucket.take(2, now));
+        assert!(!bucket.take(2, now));
+        assert!(bucket.take(2, now + Duration::from_secs(30)));
+        // it never holds more than the limit
+        assert!(bucket.take(2, now + Duration::from_secs(3600)));
+        assert!(bucket.take(2, now + Duration::from_secs(3600)));
+        assert!(!bucket.take(2, now + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn every_client_has_a_bucket_of_its_own() {
+        crate::config::init_for_tests();
+        set_limit("/rate-limit-test".to_string(), 1);
+        let req = |ip: &str| {
+            TestRequest::with_uri("/rate-limit-test")
+                .peer_addr(format!("{}:1234", ip).parse().unwrap())
+                .to_http_request()
+        };
+        assert!(check(&req("10.0.0.1")).is_none());
+        let res = check(&req("10.0.0.1")).expect("the second request is over the limit");
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "60");
+        assert!(check(&req("10.0.0.2")).is_none());
+        set_limit("/rate-limit-test".to_string(), 0);
+        assert!(check(&req("10.0.0.1")).is_none());
+    }
+}
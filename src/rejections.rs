@@ -0,0 +1,161 @@
+use crate::context;
+use hirofa_utils::eventloop::EventLoop;
+use libquickjs_sys as q;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_int, c_void};
+
+thread_local! {
+    // the promises of this runtime which were rejected without a handler by address, with their reason and the
+    // request which was being handled when they were rejected
+    static UNHANDLED: RefCell<HashMap<usize, (String, Option<String>)>> = RefCell::new(HashMap::new());
+}
+
+/// log promises which are rejected without a handler at error level
+///
+/// QuickJS calls the rejection tracker when a promise is rejected without a handler and again when a handler is
+/// added later, like Promise.reject(err).catch(...) does, so the rejections are only logged from a job which runs
+/// after the current one and its pending promise jobs, unless a handler was added by then
+/// this must be called from the worker thread of the runtime
+pub fn install(context: *mut q::JSContext) {
+    unsafe {
+        q::JS_SetHostPromiseRejectionTracker(
+            q::JS_GetRuntime(context),
+            Some(track_rejection),
+            std::ptr::null_mut(),
+        );
+    }
+}
+
+unsafe extern "C" fn track_rejection(
+    ctx: *mut q::JSContext,
+    promise: q::JSValue,
+    reason: q::JSValue,
+    is_handled: c_int,
+    _opaque: *mut c_void,
+) {
+    let key = promise.u.ptr as usize;
+    if is_handled != 0 {
+        UNHANDLED.with(|unhandled| unhandled.borrow_mut().remove(&key));
+        return;
+    }
+    let reason = reason_to_string(ctx, reason);
+    let first = UNHANDLED.with(|unhandled| {
+        let mut unhandled = unhandled.borrow_mut();
+        unhandled.insert(key, (reason, context::request_id()));
+        unhandled.len() == 1
+    });
+    if first {
+        // the tracker is called on the worker thread of the runtime, so the job goes to the event loop of that
+        // runtime and not to the one in the pool at its index, which is still the old runtime during a restart
+        if EventLoop::is_a_pool_thread() {
+            EventLoop::add_local_void(log_unhandled);
+        } else {
+            log_unhandled();
+        }
+    }
+}
+
+// the reason as toString() would give it, e.g. "Error: something failed"
+unsafe fn reason_to_string(ctx: *mut q::JSContext, reason: q::JSValue) -> String {
+    let mut len = 0;
+    let ptr = q::JS_ToCStringLen2(ctx, &mut len, reason, 0);
+    if ptr.is_null() {
+        return "(reason could not be converted to a string)".to_string();
+    }
+    let reason = CStr::from_ptr(ptr).to_string_lossy().to_string();
+    q::JS_FreeCString(ctx, ptr);
+    reason
+}
+
+fn log_unhandled() {
+    let unhandled: Vec<(String, Option<String>)> = UNHANDLED.with(|unhandled| {
+        unhandled
+            .borrow_mut()
+            .drain()
+            .map(|(_, value)| value)
+            .collect()
+    });
+    for (reason, request_id) in unhandled {
+        match request_id {
+            Some(request_id) => log::error!(
+                "unhandled promise rejection in request {}: {}",
+                request_id,
+                reason
+            ),
+            None => log::error!("unhandled promise rejection: {}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use hirofa_utils::js_utils::Script;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+    use quickjs_runtime::facades::QuickJsRuntimeFacade;
+    use quickjs_runtime::quickjsrealmadapter::QuickJsRealmAdapter;
+
+    fn runtime() -> QuickJsRuntimeFacade {
+        let rt = QuickJsRuntimeBuilder::new().build();
+        rt.js_loop_realm_sync(None, |_rt, realm: &QuickJsRealmAdapter| {
+            install(realm.context)
+        });
+        rt
+    }
+
+    // reject a promise without a handler and return the number of rejections which were not logged yet once the
+    // jobs after it ran
+    fn reject(rt: &QuickJsRuntimeFacade, reason: &'static str) -> usize {
+        let evaluated = rt.js_loop_realm_sync(None, move |_rt, realm| {
+            let script = format!("Promise.reject(new Error('{}'));", reason);
+            realm
+                .js_eval(Script::new("file://reject.js", script.as_str()))
+                .is_ok()
+        });
+        assert!(evaluated);
+        rt.js_loop_sync(|_rt| ());
+        rt.js_loop_sync(|_rt| UNHANDLED.with(|unhandled| unhandled.borrow().len()))
+    }
+
+    #[test]
+    fn every_rejection_is_logged_after_a_restart() {
+        // a pool of one runtime which is replaced like a restart does, the rejections in both the replaced and the
+        // new runtime are logged on the thread of the runtime they happened in, not in a runtime of the pool of the
+        // server which the tests share
+        crate::tests::pool();
+        let pool = crate::pool::ScriptPool::new(1, |_| Ok::<_, ()>(runtime()))
+            .ok()
+            .unwrap();
+        let old = pool.replace(0, runtime());
+        let new = pool.get(0);
+        assert_eq!(reject(&old, "first in old"), 0);
+        assert_eq!(reject(&old, "second in old"), 0);
+        assert_eq!(reject(&new, "first in new"), 0);
+        assert_eq!(reject(&new, "second in new"), 0);
+    }
+
+    #[test]
+    fn a_rejection_which_gets_a_handler_in_the_same_job_is_not_unhandled() {
+        crate::tests::pool();
+        let rt = QuickJsRuntimeBuilder::new().build();
+        let counts = rt.js_loop_realm_sync(None, |_rt, realm: &QuickJsRealmAdapter| {
+            install(realm.context);
+            let unhandled = |script: &'static str| {
+                realm
+                    .js_eval(Script::new("file://reject.js", script))
+                    .ok()
+                    .unwrap();
+                UNHANDLED.with(|unhandled| unhandled.borrow().len())
+            };
+            (
+                unhandled("Promise.reject(new Error('handled')).catch(() => {});"),
+                unhandled("Promise.reject(new Error('unhandled'));"),
+            )
+        });
+        assert_eq!(counts, (0, 1));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::event::{self, RequestInfo};
+use crate::proxies::SafeMembers;
+use crate::proxy_registry;
+use crate::MY_APP_NAMESPACE;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::facades::JsValueType;
+use hirofa_utils::js_utils::JsError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+pub const REQUEST_EVENT_CLASS: &str = "RequestEvent";
+
+/// the rust side state of a RequestEvent instance
+struct RequestEventState {
+    request_id: String,
+    method: String,
+    path: String,
+    route: String,
+    headers: Vec<(String, String)>,
+    // the cache id of the event object the setters write to, None once the request was dispatched
+    event_cache_id: Option<i32>,
+}
+
+thread_local! {
+    // the RequestEvent instances in this runtime by realm id and instance id
+    static REQUEST_EVENTS: RefCell<HashMap<(String, usize), RequestEventState>> = RefCell::new(HashMap::new());
+}
+
+fn with_state<R: JsRealmAdapter, T, C: FnOnce(&RequestEventState) -> Result<T, JsError>>(
+    realm: &R,
+    instance_id: usize,
+    consumer: C,
+) -> Result<T, JsError> {
+    REQUEST_EVENTS.with(|events| {
+        match events
+            .borrow()
+            .get(&(realm.js_get_realm_id().to_string(), instance_id))
+        {
+            Some(state) => consumer(state),
+            None => Err(JsError::new_str("no such RequestEvent")),
+        }
+    })
+}
+
+/// run a setter against the event object of the instance
+/// setters can only be used while the request is being dispatched, after that the response was already read
+fn with_event_obj<R: JsRealmAdapter, C: FnOnce(&R::JsValueAdapterType) -> Result<(), JsError>>(
+    realm: &R,
+    instance_id: usize,
+    method: &str,
+    consumer: C,
+) -> Result<R::JsValueAdapterType, JsError> {
+    let cache_id =
+        with_state(realm, instance_id, |state| Ok(state.event_cache_id))?.ok_or_else(|| {
+            JsError::new_string(format!(
+                "{} can only be called while the request is being dispatched",
+                method
+            ))
+        })?;
+    realm.js_cache_with(cache_id, consumer)?;
+    realm.js_undefined_create()
+}
+
+/// install the com.mycompany.RequestEvent class, its instances are created for every request, see with_request_event
+///
+/// it is a typed view of the event object: getters for the request and setters which validate their argument before
+/// setting responseStatus, responseBody, responseJson or responseHeaders on the event
+pub fn init_request_event_proxy<R: JsRealmAdapter + 'static>(realm: &R) -> Result<(), JsError> {
+    let proxy = JsProxy::new(MY_APP_NAMESPACE, REQUEST_EVENT_CLASS)
+        .set_safe_finalizer(|_rt, realm: &R, instance_id| {
+            REQUEST_EVENTS.with(|events| {
+                events
+                    .borrow_mut()
+                    .remove(&(realm.js_get_realm_id().to_string(), instance_id));
+            });
+        })
+        .add_safe_getter("requestId", "string", |_rt, realm: &R, instance_id| {
+            with_state(realm, instance_id, |state| {
+                realm.js_string_create(state.request_id.as_str())
+            })
+        })
+        .add_safe_getter("method", "string", |_rt, realm: &R, instance_id| {
+            with_state(realm, instance_id, |state| {
+                realm.js_string_create(state.method.as_str())
+            })
+        })
+        .add_safe_getter("path", "string", |_rt, realm: &R, instance_id| {
+            with_state(realm, instance_id, |state| {
+                realm.js_string_create(state.path.as_str())
+            })
+        })
+        .add_safe_getter("route", "string", |_rt, realm: &R, instance_id| {
+            with_state(realm, instance_id, |state| {
+                realm.js_string_create(state.route.as_str())
+            })
+        })
+        .add_safe_getter(
+            "headers",
+            "Record<string, string>",
+            |_rt, realm: &R, instance_id| {
+                with_state(realm, instance_id, |state| {
+                    let headers_obj = realm.js_object_create()?;
+                    for (name, value) in state.headers.iter() {
+                        realm.js_object_set_property(
+                            &headers_obj,
+                            name.as_str(),
+                            &realm.js_string_create(value.as_str())?,
+                        )?;
+                    }
+                    Ok(headers_obj)
+                })
+            },
+        )
+        // header names are case insensitive, returns null for a header which is not in the request
+        .add_safe_method(
+            "getHeader",
+            "(name: string): string | null",
+            |_rt, realm: &R, instance_id, args| {
+                let name = crate::proxies::get_string_arg(args, 0, "getHeader")?.to_lowercase();
+                with_state(realm, instance_id, |state| {
+                    match state.headers.iter().find(|(header, _)| *header == name) {
+                        Some((_, value)) => realm.js_string_create(value.as_str()),
+                        None => realm.js_null_create(),
+                    }
+                })
+            },
+        )
+        .add_safe_method(
+            "setStatus",
+            "(status: number): void",
+            |_rt, realm: &R, instance_id, args| {
+                let status = match args.first() {
+                    Some(status) if status.js_is_i32() => status.js_to_i32(),
+                    _ => return Err(JsError::new_str("setStatus expects an integer status")),
+                };
+                if !(100..=599).contains(&status) {
+                    return Err(JsError::new_string(format!(
+                        "setStatus expects a status from 100 to 599, got {}",
+                        status
+                    )));
+                }
+                with_event_obj(realm, instance_id, "setStatus", |event_obj| {
+                    realm.js_object_set_property(
+                        event_obj,
+                        "responseStatus",
+                        &realm.js_i32_create(status)?,
+                    )
+                })
+            },
+        )
+        .add_safe_method(
+            "setBody",
+            "(body: string): void",
+            |_rt, realm: &R, instance_id, args| {
+                let body = crate::proxies::get_string_arg(args, 0, "setBody")?;
+                with_event_obj(realm, instance_id, "setBody", |event_obj| {
+                    realm.js_object_set_property(
+                        event_obj,
+                        "responseBody",
+                        &realm.js_string_create(body.as_str())?,
+                    )
+                })
+            },
+        )
+        .add_safe_method(
+            "setJson",
+            "(value: any): void",
+            |_rt, realm: &R, instance_id, args| {
+                let value = match args.first() {
+                    Some(value) if value.js_get_type() != JsValueType::Undefined => value.clone(),
+                    _ => return Err(JsError::new_str("setJson expects a value")),
+                };
+                with_event_obj(realm, instance_id, "setJson", |event_obj| {
+                    realm.js_object_set_property(event_obj, "responseJson", &value)
+                })
+            },
+        )
+        // adds to event.responseHeaders, a header which can't be sent fails here instead of when responding
+        .add_safe_method(
+            "setHeader",
+            "(name: string, value: string): void",
+            |_rt, realm: &R, instance_id, args| {
+                let name = crate::proxies::get_string_arg(args, 0, "setHeader")?;
+                let value = crate::proxies::get_string_arg(args, 1, "setHeader")?;
+                event::validate_header(name.as_str(), value.as_str())?;
+                with_event_obj(realm, instance_id, "setHeader", |event_obj| {
+                    let mut headers = realm.js_object_get_property(event_obj, "responseHeaders")?;
+                    if !headers.js_is_object() {
+                        headers = realm.js_object_create()?;
+                        realm.js_object_set_property(event_obj, "responseHeaders", &headers)?;
+                    }
+                    realm.js_object_set_property(
+                        &headers,
+                        name.as_str(),
+                        &realm.js_string_create(value.as_str())?,
+                    )
+                })
+            },
+        );
+    proxy_registry::install(realm, proxy)?;
+    Ok(())
+}
+
+/// dispatch a request with a RequestEvent instance for its event object, the instance is set as event.request
+/// the setters of the instance stop working when the job is done
+pub fn with_request_event<R, T, F>(
+    realm: &R,
+    info: &RequestInfo,
+    event_obj: &R::JsValueAdapterType,
+    job: F,
+) -> Result<T, JsError>
+where
+    R: JsRealmAdapter + 'static,
+    F: FnOnce() -> Result<T, JsError>,
+{
+    let (instance_id, instance) =
+        realm.js_proxy_instantiate(MY_APP_NAMESPACE, REQUEST_EVENT_CLASS, &[])?;
+    let cache_id = realm.js_cache_add(event_obj);
+    let key = (realm.js_get_realm_id().to_string(), instance_id);
+    REQUEST_EVENTS.with(|events| {
+        events.borrow_mut().insert(
+            key.clone(),
+            RequestEventState {
+                request_id: info.request_id.clone(),
+                method: info.method.clone(),
+                path: info.path.clone(),
+                route: info.route.clone(),
+                headers: info.headers.clone(),
+                event_cache_id: Some(cache_id),
+            },
+        )
+    });
+    let res = realm
+        .js_object_set_property(event_obj, "request", &instance)
+        .and_then(|_| job());
+    REQUEST_EVENTS.with(|events| {
+        if let Some(state) = events.borrow_mut().get_mut(&key) {
+            state.event_cache_id = None;
+        }
+    });
+    realm.js_cache_dispose(cache_id);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn the_setters_validate_before_they_set_the_response() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.request.getHeader("X-Test") === "request-event") {
+                    const request = evt.request;
+                    const failed = [];
+                    request.setStatus(201);
+                    request.setHeader("x-custom", "1");
+                    try { request.setStatus(1000); } catch (err) { failed.push("status"); }
+                    try { request.setHeader("connection", "close"); } catch (err) { failed.push("header"); }
+                    request.setBody([request.method, request.route, failed.join("+")].join(" "));
+                }
+            });"#,
+        );
+        let req = test::TestRequest::get()
+            .uri("/users/7")
+            .insert_header(("x-test", "request-event"));
+        let (status, headers, body) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(headers.get("x-custom").unwrap(), "1");
+        assert_eq!(body, "GET /users/{id} status+header");
+    }
+}
@@ -0,0 +1,194 @@
+use crate::proxies::SafeMembers;
+use hirofa_utils::js_utils::adapters::proxies::JsProxy;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::{JsError, Script};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// realm id, class and instance id
+type ResourceKey = (String, &'static str, usize);
+
+thread_local! {
+    // the rust side of the open resources in this runtime by realm id, class and instance id
+    // a resource is released by dropping it, removing it from the map makes sure that only happens once
+    static RESOURCES: RefCell<HashMap<ResourceKey, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+// calls fn with the resource and closes it afterwards, also when fn throws or its promise rejects
+const USING_FUNCTION: &str = r#"
+globalThis.using = function using(resource, fn) {
+    if (!resource || typeof resource.close !== "function") {
+        throw new TypeError("using expects a resource with a close() method");
+    }
+    let result;
+    try {
+        result = fn(resource);
+    } catch (err) {
+        resource.close();
+        throw err;
+    }
+    if (result && typeof result.then === "function") {
+        return Promise.resolve(result).finally(() => resource.close());
+    }
+    resource.close();
+    return result;
+};
+"#;
+
+/// install the global using(resource, fn) function which closes the resource once fn is done with it
+pub fn init_using<R: JsRealmAdapter>(realm: &R) -> Result<(), JsError> {
+    realm.js_eval(Script::new("file://using.js", USING_FUNCTION))?;
+    Ok(())
+}
+
+/// add close(), dispose() (the same as close) and a closed getter to the proxy of a class whose instances hold a
+/// native resource, and a finalizer which releases the resource when the instance is garbage collected without
+/// close(), the resource of an instance is added with insert
+/// closing an instance again does nothing, its other methods fail once it is closed, see with_resource
+pub fn disposable<R: JsRealmAdapter + 'static>(
+    proxy: JsProxy<R>,
+    class: &'static str,
+) -> JsProxy<R> {
+    proxy
+        .add_safe_method(
+            "close",
+            "(): void",
+            move |_rt, realm: &R, instance_id, _args| {
+                release(realm, class, instance_id);
+                realm.js_undefined_create()
+            },
+        )
+        .add_safe_method(
+            "dispose",
+            "(): void",
+            move |_rt, realm: &R, instance_id, _args| {
+                release(realm, class, instance_id);
+                realm.js_undefined_create()
+            },
+        )
+        .add_safe_getter("closed", "boolean", move |_rt, realm: &R, instance_id| {
+            let open = RESOURCES.with(|resources| {
+                resources.borrow().contains_key(&(
+                    realm.js_get_realm_id().to_string(),
+                    class,
+                    instance_id,
+                ))
+            });
+            realm.js_boolean_create(!open)
+        })
+        .set_safe_finalizer(move |_rt, realm: &R, instance_id| {
+            if release(realm, class, instance_id) {
+                log::debug!("released a {} which was not closed", class);
+            }
+        })
+}
+
+/// add the resource of an instance of a disposable class
+pub fn insert<R: JsRealmAdapter, T: 'static>(
+    realm: &R,
+    class: &'static str,
+    instance_id: usize,
+    resource: T,
+) {
+    RESOURCES.with(|resources| {
+        resources.borrow_mut().insert(
+            (realm.js_get_realm_id().to_string(), class, instance_id),
+            Box::new(resource),
+        );
+    });
+}
+
+/// use the resource of an instance, fails when it was closed
+pub fn with_resource<R: JsRealmAdapter, T: 'static, U, C: FnOnce(&mut T) -> Result<U, JsError>>(
+    realm: &R,
+    class: &'static str,
+    instance_id: usize,
+    consumer: C,
+) -> Result<U, JsError> {
+    RESOURCES.with(|resources| {
+        match resources
+            .borrow_mut()
+            .get_mut(&(realm.js_get_realm_id().to_string(), class, instance_id))
+            .and_then(|resource| resource.downcast_mut::<T>())
+        {
+            Some(resource) => consumer(resource),
+            None => Err(JsError::new_string(format!("the {} is closed", class))),
+        }
+    })
+}
+
+// drop the resource of an instance, returns false when it was already released
+fn release<R: JsRealmAdapter>(realm: &R, class: &'static str, instance_id: usize) -> bool {
+    // removed before it is dropped so a resource which uses script in its drop can't end up here again
+    let resource = RESOURCES.with(|resources| {
+        resources
+            .borrow_mut()
+            .remove(&(realm.js_get_realm_id().to_string(), class, instance_id))
+    });
+    resource.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hirofa_utils::js_utils::adapters::JsValueAdapter;
+    use std::rc::Rc;
+
+    const CLASS: &str = "DisposableTest";
+
+    // counts how often it was dropped
+    struct Resource(Rc<RefCell<u32>>);
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            *self.0.borrow_mut() += 1;
+        }
+    }
+
+    #[test]
+    fn a_resource_is_released_once_when_it_is_closed() {
+        let result = crate::tests::with_realm(|realm| {
+            realm
+                .js_proxy_install(disposable(JsProxy::new(&[], CLASS), CLASS), true)
+                .ok()
+                .unwrap();
+            let test = realm
+                .js_eval(Script::new(
+                    "file://disposable_test.js",
+                    r#"(closing, using_it) => {
+                        const open = closing.closed;
+                        closing.close();
+                        closing.dispose();
+                        try {
+                            using(using_it, () => { throw new Error("failed"); });
+                        } catch (err) {}
+                        return [open, closing.closed, using_it.closed].join();
+                    }"#,
+                ))
+                .ok()
+                .unwrap();
+            let drops = Rc::new(RefCell::new(0));
+            let (mut ids, mut handles) = (vec![], vec![]);
+            for _ in 0..2 {
+                let (instance_id, handle) =
+                    realm.js_proxy_instantiate(&[], CLASS, &[]).ok().unwrap();
+                insert(realm, CLASS, instance_id, Resource(drops.clone()));
+                ids.push(instance_id);
+                handles.push(handle);
+            }
+            let closed = realm
+                .js_function_invoke(None, &test, &[&handles[0], &handles[1]])
+                .ok()
+                .unwrap()
+                .js_to_string()
+                .ok()
+                .unwrap();
+            // the methods of a closed instance fail
+            let usable = with_resource(realm, CLASS, ids[0], |_: &mut Resource| Ok(())).is_ok();
+            let drops = *drops.borrow();
+            (closed, drops, usable)
+        });
+        assert_eq!(result, ("false,true,true".to_string(), 2, false));
+    }
+}
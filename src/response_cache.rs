@@ -0,0 +1,299 @@
+use crate::event::ScriptResponse;
+use crate::{auth, metrics, tenants};
+use actix_web::http::header::{self, EntityTag, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+/// the max number of cached responses, responses are not cached when it is full until entries expire
+pub const MAX_CACHED_RESPONSES: usize = 1000;
+/// the max seconds event.cacheFor accepts
+pub const MAX_TTL_SECS: i32 = 24 * 60 * 60;
+
+lazy_static! {
+    // shared by all runtimes in the pool, keyed by tenant and path with query, the tenant is empty without tenants
+    static ref CACHE: Mutex<HashMap<(String, String), CachedResponse>> = Mutex::new(HashMap::new());
+}
+
+/// the parts of a ScriptResponse we need to respond again
+struct CachedResponse {
+    // the key the script passed to event.cacheFor, cacheInvalidate(key) removes all responses with the key
+    key: String,
+    stored: Instant,
+    expires: Instant,
+    handled: bool,
+    status: Option<u16>,
+    body: Option<Bytes>,
+    content_type: Option<String>,
+    location: Option<String>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    etag: Option<EntityTag>,
+    last_modified: Option<SystemTime>,
+    cache_control: Option<String>,
+    // the request headers the response varies on with their value in the request it was cached for, the response is
+    // only served to requests with the same values
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+// requests for an unknown tenant are rejected before we get here
+fn cache_key(req: &HttpRequest) -> (String, String) {
+    let tenant = tenants::resolve(req).ok().flatten().unwrap_or_default();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| req.path(), |path| path.as_str());
+    (tenant, path.to_string())
+}
+
+// the names in the Vary headers of a response, None for Vary: * which matches no other request
+fn vary_names(response: &ScriptResponse) -> Option<Vec<HeaderName>> {
+    let mut names = vec![];
+    for (_, value) in response
+        .headers
+        .iter()
+        .filter(|(name, _)| name == header::VARY)
+    {
+        for name in value.to_str().unwrap_or("*").split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                names.push(name);
+            }
+        }
+    }
+    Some(names)
+}
+
+/// the cached response for a GET request, these are served without dispatching the request to the script
+/// the response gets an Age header with the seconds since it was cached
+///
+/// like the request itself the pre-request middleware is skipped for a cached response, requests with credentials
+/// (see auth::has_credentials) are never served from the cache
+pub fn lookup(req: &HttpRequest) -> Option<HttpResponse> {
+    if req.method() != Method::GET || auth::has_credentials(req) {
+        return None;
+    }
+    let key = cache_key(req);
+    let mut cache = CACHE.lock().unwrap();
+    let cached = cache.get(&key)?;
+    if cached.expires <= Instant::now() {
+        cache.remove(&key);
+        return None;
+    }
+    if !cached
+        .vary
+        .iter()
+        .all(|(name, value)| req.headers().get(name) == value.as_ref())
+    {
+        return None;
+    }
+    let age = cached.stored.elapsed().as_secs();
+    let response = ScriptResponse {
+        handled: cached.handled,
+        status: cached.status,
+        body: cached.body.clone(),
+        content_type: cached.content_type.clone(),
+        location: cached.location.clone(),
+        headers: cached.headers.clone(),
+        etag: cached.etag.clone(),
+        last_modified: cached.last_modified,
+        cache_control: cached.cache_control.clone(),
+        ..ScriptResponse::default()
+    };
+    drop(cache);
+    log::debug!("serving {} {} from the response cache", key.0, key.1);
+    metrics::RESPONSE_CACHE_HITS.inc();
+    let mut response = response.into_http_response(req);
+    response
+        .headers_mut()
+        .insert(header::AGE, HeaderValue::from(age));
+    Some(response)
+}
+
+/// cache the response of a GET request when the script called event.cacheFor(seconds, key)
+/// responses which set cookies and responses to requests with credentials are not cached, those are for a single
+/// client (RFC 9111 section 3.5), nor are responses with Vary: *
+pub fn store(req: &HttpRequest, response: &ScriptResponse) {
+    let (ttl, key) = match &response.cache {
+        Some(cache) => cache.clone(),
+        None => return,
+    };
+    // scripts could set responseCacheSeconds themselves, cacheFor only works for GET requests
+    if req.method() != Method::GET {
+        return;
+    }
+    if !response.set_cookies.is_empty() {
+        log::debug!(
+            "not caching the response for {} as it sets cookies",
+            req.path()
+        );
+        return;
+    }
+    if auth::has_credentials(req) {
+        log::debug!(
+            "not caching the response for {} as the request has credentials",
+            req.path()
+        );
+        return;
+    }
+    let vary = match vary_names(response) {
+        Some(names) => names
+            .into_iter()
+            .map(|name| {
+                let value = req.headers().get(&name).cloned();
+                (name, value)
+            })
+            .collect(),
+        None => {
+            log::debug!(
+                "not caching the response for {} as it varies on *",
+                req.path()
+            );
+            return;
+        }
+    };
+    let now = Instant::now();
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHED_RESPONSES {
+        cache.retain(|_, cached| cached.expires > now);
+        if cache.len() >= MAX_CACHED_RESPONSES {
+            log::warn!(
+                "not caching the response for {}, the cache is full with {} responses",
+                req.path(),
+                MAX_CACHED_RESPONSES
+            );
+            return;
+        }
+    }
+    cache.insert(
+        cache_key(req),
+        CachedResponse {
+            key,
+            stored: now,
+            expires: now + ttl,
+            handled: response.handled,
+            status: response.status,
+            body: response.body.clone(),
+            content_type: response.content_type.clone(),
+            location: response.location.clone(),
+            headers: response.headers.clone(),
+            etag: response.etag.clone(),
+            last_modified: response.last_modified,
+            cache_control: response.cache_control.clone(),
+            vary,
+        },
+    );
+}
+
+/// remove the cached responses of a tenant (empty for none) which were cached with key, returns the number removed
+pub fn invalidate(tenant: &str, key: &str) -> usize {
+    let mut cache = CACHE.lock().unwrap();
+    let before = cache.len();
+    cache.retain(|(cached_tenant, _), cached| cached_tenant != tenant || cached.key != key);
+    before - cache.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    fn cached_response(headers: Vec<(HeaderName, HeaderValue)>) -> ScriptResponse {
+        ScriptResponse {
+            handled: true,
+            body: Some(Bytes::from_static(b"cached")),
+            headers,
+            cache: Some((Duration::from_secs(60), "test".to_string())),
+            ..ScriptResponse::default()
+        }
+    }
+
+    #[test]
+    fn requests_with_credentials_are_not_served_from_the_cache() {
+        config::init_for_tests();
+        let req = TestRequest::get().uri("/credentials").to_http_request();
+        store(&req, &cached_response(vec![]));
+        assert!(lookup(&req).is_some());
+        let req = TestRequest::get()
+            .uri("/credentials")
+            .insert_header((header::AUTHORIZATION, "Bearer token"))
+            .to_http_request();
+        assert!(lookup(&req).is_none());
+        let req = TestRequest::get()
+            .uri("/credentials")
+            .insert_header((header::COOKIE, "session=1"))
+            .to_http_request();
+        assert!(lookup(&req).is_none());
+    }
+
+    #[test]
+    fn responses_to_requests_with_credentials_are_not_stored() {
+        config::init_for_tests();
+        let req = TestRequest::get()
+            .uri("/not-stored")
+            .insert_header((header::COOKIE, "session=1"))
+            .to_http_request();
+        store(&req, &cached_response(vec![]));
+        let req = TestRequest::get().uri("/not-stored").to_http_request();
+        assert!(lookup(&req).is_none());
+    }
+
+    #[test]
+    fn cached_responses_are_only_served_to_requests_with_the_same_varied_headers() {
+        config::init_for_tests();
+        let vary = vec![(header::VARY, HeaderValue::from_static("Accept-Language"))];
+        let req = TestRequest::get()
+            .uri("/vary")
+            .insert_header((header::ACCEPT_LANGUAGE, "nl"))
+            .to_http_request();
+        store(&req, &cached_response(vary));
+        assert!(lookup(&req).is_some());
+        let req = TestRequest::get()
+            .uri("/vary")
+            .insert_header((header::ACCEPT_LANGUAGE, "en"))
+            .to_http_request();
+        assert!(lookup(&req).is_none());
+        let req = TestRequest::get().uri("/vary").to_http_request();
+        assert!(lookup(&req).is_none());
+
+        let vary_all = vec![(header::VARY, HeaderValue::from_static("*"))];
+        let req = TestRequest::get().uri("/vary-all").to_http_request();
+        store(&req, &cached_response(vary_all));
+        assert!(lookup(&req).is_none());
+    }
+
+    #[actix_web::test]
+    async fn a_cached_response_is_served_until_it_is_invalidated() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("request", (evt) => {
+                if (evt.headers["x-test"] === "cache") {
+                    globalThis.cacheTestCount = (globalThis.cacheTestCount || 0) + 1;
+                    evt.cacheFor(60, "cache-test");
+                    evt.responseBody = "response " + globalThis.cacheTestCount;
+                }
+            });"#,
+        );
+        let req = || {
+            TestRequest::get()
+                .uri("/?cache-test")
+                .insert_header(("x-test", "cache"))
+        };
+        let (_, headers, body) = crate::tests::call(req()).await;
+        assert_eq!(body, "response 1");
+        assert!(headers.get(header::AGE).is_none());
+        let (_, headers, body) = crate::tests::call(req()).await;
+        assert_eq!(body, "response 1");
+        assert_eq!(headers.get(header::AGE).unwrap(), "0");
+        assert_eq!(invalidate("", "cache-test"), 1);
+        let (_, _, body) = crate::tests::call(req()).await;
+        assert_eq!(body, "response 2");
+    }
+}
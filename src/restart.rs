@@ -0,0 +1,88 @@
+use crate::{entry, errors, script_pool, tenants};
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use lazy_static::lazy_static;
+use quickjs_runtime::facades::QuickJsRuntimeFacade;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+lazy_static! {
+    // held while the runtimes are being restarted, a second restart is refused instead of queued
+    static ref RESTARTING: Mutex<()> = Mutex::new(());
+}
+
+/// why a restart did not happen or stopped halfway
+pub enum RestartError {
+    /// another restart is still going on
+    InProgress,
+    /// the new runtime at this index could not be initialized, it and the runtimes after it keep running as they were
+    Failed(usize, String),
+}
+
+/// replace every runtime of the pool with a freshly initialized one without stopping the server
+///
+/// the runtimes are replaced one at a time so the others keep handling requests meanwhile, a new runtime gets the
+/// proxies, the entry modules, the init event and the realms of the tenants like at startup before it is swapped in
+/// (the warmup is skipped) and the modules it imports are loaded from the module dir again
+/// the old runtime then gets the shutdown event, as a runtime handles its jobs in order that is only after the
+/// requests which were dispatched to it are done, the requests which still hold it (streamed and isolated requests)
+/// keep it alive until they are done
+/// its timers are dropped, websocket connections stay open and get their next events in the new runtime
+/// returns the number of runtimes which were replaced
+pub async fn restart() -> Result<usize, RestartError> {
+    let _restarting = RESTARTING
+        .try_lock()
+        .map_err(|_| RestartError::InProgress)?;
+    let size = script_pool().runtimes().len();
+    log::warn!("restarting {} script runtimes", size);
+    for pool_idx in 0..size {
+        let rt = init_runtime(pool_idx).await.map_err(|err| {
+            log::error!("could not restart runtime {}: {}", pool_idx, err);
+            RestartError::Failed(pool_idx, err)
+        })?;
+        let old = script_pool().replace(pool_idx, rt);
+        drain(old).await;
+        log::info!("restarted runtime {}", pool_idx);
+    }
+    Ok(size)
+}
+
+// a new runtime for pool_idx which is ready to handle requests, the new runtime is dropped again on an error
+async fn init_runtime(pool_idx: usize) -> Result<QuickJsRuntimeFacade, String> {
+    // initializing waits for the worker thread of the new runtime, which should not block the http worker
+    let rt = actix_web::rt::task::spawn_blocking(move || crate::init_quickjs(pool_idx))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| {
+            errors::log_script_error("could not initialize the script runtime", &err);
+            errors::describe(&err)
+        })?;
+    for script in entry::startup_scripts() {
+        let name = entry::describe(script.get_path());
+        if let Err(err) = rt.js_eval_module(None, script).await {
+            let err = crate::import_cycles::explain(err);
+            let msg = format!("{} failed", name);
+            errors::log_script_error(msg.as_str(), &err);
+            return Err(format!("{}: {}", msg, errors::describe(&err)));
+        }
+    }
+    rt.js_loop_realm(None, move |_rt, realm| {
+        entry::dispatch_init(realm, pool_idx)
+    })
+    .await
+    .map_err(|err| {
+        errors::log_script_error("the init event failed", &err);
+        format!("the init event failed: {}", errors::describe(&err))
+    })?;
+    tenants::create_runtime_realms(&rt, pool_idx)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(rt)
+}
+
+// let a replaced runtime finish its jobs and shut down
+async fn drain(old: Arc<QuickJsRuntimeFacade>) {
+    if !crate::shutdown_runtime(&old).await {
+        // a runtime which is stuck could also block dropping it, so that happens on a thread of its own
+        std::thread::spawn(move || drop(old));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::script_routes;
+use hirofa_utils::js_utils::Script;
+use std::time::Duration;
+
+/// the routes we register with actix, every route dispatches a `request:<route>` event followed by an event for the
+/// method and the generic `request` event so a script can either handle specific routes or all of them
+/// routes can have path parameters like /users/{id}, scripts get those as event.params
+pub const ROUTES: &[&str] = &[
+    "/",
+    "/api",
+    "/webhook",
+    "/users/{id}",
+    "/hello",
+    "/dashboard",
+    "/export",
+    "/assets/{name}",
+    "/time",
+];
+
+/// the routes which pass the body to the script in chunks as it is read instead of dispatching the request events
+/// with the whole body, see body_stream.rs, these should not be in ROUTES
+pub const STREAMING_ROUTES: &[&str] = &["/ingest"];
+
+/// a route which is handled by a function exported from a module instead of by the `request:<route>` listeners
+pub struct RouteHandler {
+    pub route: &'static str,
+    // loaded by the module loaders like an import from main.ts
+    pub module: &'static str,
+    // the name of the exported function, DEFAULT_EXPORT for the default export of the module
+    pub export: &'static str,
+}
+
+/// the export of a RouteHandler for a module which has the handler as `export default function(evt) {...}`
+pub const DEFAULT_EXPORT: &str = "default";
+
+/// the routes (which should also be in ROUTES) handled by a module function
+/// the function is called with the event, its return value is the response body (see event::set_handler_result)
+pub const HANDLERS: &[RouteHandler] = &[
+    RouteHandler {
+        route: "/hello",
+        module: "handlers/hello.ts",
+        export: "helloHandler",
+    },
+    RouteHandler {
+        route: "/time",
+        module: "handlers/time.ts",
+        export: DEFAULT_EXPORT,
+    },
+];
+
+/// a route (which should also be in ROUTES) which responds with the combined results of several events
+/// the middleware events are dispatched as usual, after those the events are dispatched concurrently instead of the
+/// request events, see aggregate.rs
+pub struct Aggregate {
+    pub route: &'static str,
+    pub events: &'static [&'static str],
+}
+
+pub const AGGREGATES: &[Aggregate] = &[Aggregate {
+    route: "/dashboard",
+    events: &["dashboard:greeting", "dashboard:apiCount"],
+}];
+
+/// the aggregate for a route, if any
+pub fn aggregate(route: &str) -> Option<&'static Aggregate> {
+    AGGREGATES.iter().find(|aggregate| aggregate.route == route)
+}
+
+/// a route (which should also be in ROUTES or STREAMING_ROUTES) whose request events may run longer than
+/// SCRIPT_TIMEOUT_MS, or should be cut off sooner, routes which are not listed use SCRIPT_TIMEOUT_MS
+/// scripts set the timeout of their routes with registerRoute
+pub struct RouteTimeout {
+    pub route: &'static str,
+    pub timeout: Duration,
+}
+
+pub const ROUTE_TIMEOUTS: &[RouteTimeout] = &[RouteTimeout {
+    // builds the whole export before it is streamed
+    route: "/export",
+    timeout: Duration::from_secs(30),
+}];
+
+/// the timeout of a route, if it has its own
+pub fn timeout(route: &str) -> Option<Duration> {
+    ROUTE_TIMEOUTS
+        .iter()
+        .find(|timeout| timeout.route == route)
+        .map(|timeout| timeout.timeout)
+}
+
+/// the global the handlers are stored in by route, see handlers_script
+pub const HANDLERS_GLOBAL: &str = "__routeHandlers";
+
+/// the middleware events, these are dispatched in order before the request events
+/// a middleware can add things to the event (like the authenticated user) for the handlers after it or veto the
+/// event to respond without invoking the handlers, e.g. with a 401
+pub const MIDDLEWARE: &[&str] = &["pre-request"];
+
+/// the handler for a route, if any
+pub fn handler(route: &str) -> Option<&'static RouteHandler> {
+    HANDLERS.iter().find(|handler| handler.route == route)
+}
+
+/// a module which imports the handler functions and stores them in HANDLERS_GLOBAL so they can be invoked by route
+/// an export which is not a function fails the module so a wrong HANDLERS entry is reported at startup
+pub fn handlers_script() -> Script {
+    let mut code = String::new();
+    let mut checks = String::new();
+    let mut entries = vec![];
+    for (idx, handler) in HANDLERS.iter().enumerate() {
+        let module = serde_json::Value::from(handler.module);
+        let import = if handler.export == DEFAULT_EXPORT {
+            format!("import handler{} from {};\n", idx, module)
+        } else {
+            format!(
+                "import {{ {} as handler{} }} from {};\n",
+                handler.export, idx, module
+            )
+        };
+        code.push_str(import.as_str());
+        checks.push_str(
+            format!(
+                "if (typeof handler{} !== \"function\") {{ throw new TypeError({}); }}\n",
+                idx,
+                serde_json::Value::from(format!(
+                    "the {} export of {} is not a function",
+                    handler.export, handler.module
+                ))
+            )
+            .as_str(),
+        );
+        entries.push(format!(
+            "{}: handler{}",
+            serde_json::Value::from(handler.route),
+            idx
+        ));
+    }
+    code.push_str(checks.as_str());
+    code.push_str(
+        format!(
+            "globalThis.{} = {{{}}};\n",
+            HANDLERS_GLOBAL,
+            entries.join(", ")
+        )
+        .as_str(),
+    );
+    Script::new("file://route_handlers.js", code.as_str())
+}
+
+/// the event dispatched instead of the middleware and request events for requests which match none of the routes
+/// when the script does not set a response a plain 404 is sent
+pub const NOT_FOUND_EVENT: &str = "notFound";
+
+/// the event dispatched when a listener or handler of a request throws an error other than a HttpError, the event
+/// has the error as evt.error {name, message, stack} and a listener can set a response on it which is sent instead
+/// of the 500, the response the failed listener set is discarded
+pub const ERROR_EVENT: &str = "error";
+
+/// the methods which dispatch an event of their own, see event_names
+pub const METHOD_EVENTS: &[&str] = &["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"];
+
+/// the names of the events dispatched for a request on the given route, in order
+/// these are the middleware events, `request:<route>`, the lowercase method (like `get` or `post`) and `request`
+/// only the METHOD_EVENTS get an event of their own so a request with a made up method like INIT can't dispatch one
+/// of the other events of the script
+/// for routes with a handler the handler is invoked instead of dispatching the `request:<route>` event, for routes
+/// registered by script the handler event is dispatched instead, see script_routes.rs
+pub fn event_names(route: &str, method: &str) -> Vec<String> {
+    let mut names: Vec<String> = MIDDLEWARE.iter().map(|name| name.to_string()).collect();
+    names.push(
+        script_routes::handler(route, method).unwrap_or_else(|| format!("request:{}", route)),
+    );
+    if METHOD_EVENTS.contains(&method) {
+        names.push(method.to_lowercase());
+    }
+    names.push("request".to_string());
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_route_event_is_dispatched_before_the_generic_event() {
+        let names = event_names("/api", "GET");
+        let route = names.iter().position(|name| name == "request:/api");
+        assert!(route.is_some());
+        assert!(route < names.iter().position(|name| name == "request"));
+    }
+
+    #[test]
+    fn the_middleware_events_are_dispatched_first() {
+        let names = event_names("/api", "GET");
+        assert_eq!(names[..MIDDLEWARE.len()], *MIDDLEWARE);
+    }
+
+    #[test]
+    fn the_method_event_is_dispatched_between_the_route_and_the_generic_event() {
+        let names = event_names("/users/{id}", "DELETE");
+        assert_eq!(
+            names[names.len() - 3..],
+            ["request:/users/{id}", "delete", "request"]
+        );
+    }
+
+    #[test]
+    fn only_known_methods_dispatch_an_event() {
+        assert_eq!(
+            event_names("/api", "POST"),
+            vec!["pre-request", "request:/api", "post", "request"]
+        );
+        assert_eq!(
+            event_names("/api", "SHUTDOWN"),
+            vec!["pre-request", "request:/api", "request"]
+        );
+    }
+
+    #[test]
+    fn every_handler_is_a_route_and_imported() {
+        let script = handlers_script();
+        for route_handler in HANDLERS {
+            assert!(ROUTES.contains(&route_handler.route));
+            assert!(handler(route_handler.route).is_some());
+            let module = serde_json::Value::from(route_handler.module).to_string();
+            assert!(script.get_code().contains(module.as_str()));
+        }
+        assert!(handler("/api").is_none());
+    }
+
+    #[test]
+    fn a_default_export_is_imported_as_default() {
+        let code = handlers_script().get_code().to_string();
+        for (idx, route_handler) in HANDLERS.iter().enumerate() {
+            let module = serde_json::Value::from(route_handler.module);
+            let import = match route_handler.export {
+                DEFAULT_EXPORT => format!("import handler{} from {};", idx, module),
+                export => format!("import {{ {} as handler{} }} from {};", export, idx, module),
+            };
+            assert!(code.contains(import.as_str()), "{} is not imported", import);
+            assert!(code.contains(format!("typeof handler{} !== \"function\"", idx).as_str()));
+        }
+        assert!(HANDLERS
+            .iter()
+            .any(|handler| handler.export == DEFAULT_EXPORT));
+    }
+
+    #[test]
+    fn the_route_timeouts_are_routes() {
+        for timeout in ROUTE_TIMEOUTS {
+            assert!(
+                ROUTES.contains(&timeout.route) || STREAMING_ROUTES.contains(&timeout.route),
+                "{} has a timeout but is not a route",
+                timeout.route
+            );
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::timeout::{script_timeout, with_deadline};
+use crate::{
+    auth, backpressure, content_encoding, dispatch, errors, maintenance, rate_limit, script_pool,
+    tenants, MY_APP_CLASS, MY_APP_NAMESPACE,
+};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::facades::{JsRuntimeFacade, JsValueType};
+use hirofa_utils::js_utils::JsError;
+use serde_json::{json, Value};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+// the listener set a result or error we can't send
+const INTERNAL_ERROR: i64 = -32603;
+// the first of the codes reserved for implementation defined server errors, used for errors thrown by the script
+const SERVER_ERROR: i64 = -32000;
+
+/// a valid call from the body, notifications have no id and get no response
+struct Call {
+    id: Option<Value>,
+    method: String,
+    params: Value,
+}
+
+/// the POST /rpc endpoint, handles a JSON-RPC 2.0 call or batch of calls
+///
+/// every call dispatches a `rpc:<method>` event with method, params and id, listener return values are not passed
+/// back by the EventTarget dispatch so the listener sets evt.result, or evt.error to an {code, message, data} object
+/// a thrown error results in a server error, when neither is set the method was not found
+/// all calls of a batch are handled in one job in the main realm (or the realm of the tenant) of one of the runtimes
+/// the requests are rejected like those for the routes, e.g. by the rate limit or SCRIPT_AUTH
+pub async fn rpc(req: HttpRequest, body: Bytes) -> HttpResponse {
+    let rejected = maintenance::check()
+        .or_else(|| rate_limit::check(&req))
+        .or_else(|| tenants::check(&req))
+        .or_else(|| auth::check(&req))
+        .or_else(|| content_encoding::check(&req));
+    if let Some(response) = rejected {
+        return response;
+    }
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => {
+            return HttpResponse::Ok().json(error_response(
+                Value::Null,
+                PARSE_ERROR,
+                format!("parse error: {}", err).as_str(),
+            ))
+        }
+    };
+    let (batch, requests) = match request {
+        Value::Array(requests) if !requests.is_empty() => (true, requests),
+        Value::Array(_) => {
+            return HttpResponse::Ok().json(error_response(
+                Value::Null,
+                INVALID_REQUEST,
+                "invalid request: empty batch",
+            ))
+        }
+        request => (false, vec![request]),
+    };
+
+    // invalid requests are answered right away, the valid calls are dispatched
+    let mut responses = vec![];
+    let mut calls = vec![];
+    for request in requests {
+        match read_call(request) {
+            Ok(call) => calls.push(call),
+            Err(response) => responses.push(response),
+        }
+    }
+    if !calls.is_empty() {
+        // the permit is held until the calls were dispatched
+        let _permit = match backpressure::try_acquire() {
+            Ok(permit) => permit,
+            Err(response) => return response,
+        };
+        let realm_id = tenants::resolve(&req)
+            .ok()
+            .flatten()
+            .map(|tenant| tenants::realm_id(tenant.as_str()));
+        let res = script_pool()
+            .next()
+            .js_loop_realm(realm_id.as_deref(), move |_rt, realm| {
+                with_deadline(script_timeout(), || {
+                    let responses: Vec<Value> = calls
+                        .into_iter()
+                        .filter_map(|call| handle_call(realm, call))
+                        .collect();
+                    Ok::<_, JsError>(responses)
+                })
+            })
+            .await;
+        match res {
+            Ok(call_responses) => responses.extend(call_responses),
+            // the calls themselves get error responses, this is e.g. a realm which could not be found
+            Err(err) => {
+                errors::log_script_error("could not dispatch rpc calls", &err);
+                return errors::script_error_response(&err);
+            }
+        }
+    }
+
+    if responses.is_empty() {
+        // only notifications
+        HttpResponse::NoContent().finish()
+    } else if batch {
+        HttpResponse::Ok().json(Value::Array(responses))
+    } else {
+        HttpResponse::Ok().json(responses.remove(0))
+    }
+}
+
+fn read_call(request: Value) -> Result<Call, Value> {
+    let id = request.get("id").cloned();
+    // the id of an invalid request can only be used if it is valid itself
+    let error_id = match &id {
+        Some(id) if id.is_string() || id.is_number() => id.clone(),
+        _ => Value::Null,
+    };
+    let invalid = |msg: &str| error_response(error_id.clone(), INVALID_REQUEST, msg);
+    if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err(invalid("invalid request: jsonrpc should be \"2.0\""));
+    }
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method.to_string(),
+        None => return Err(invalid("invalid request: method should be a string")),
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    if !(params.is_null() || params.is_array() || params.is_object()) {
+        return Err(invalid(
+            "invalid request: params should be an array or object",
+        ));
+    }
+    match &id {
+        None | Some(Value::Null) | Some(Value::String(_)) | Some(Value::Number(_)) => {}
+        Some(_) => return Err(invalid("invalid request: id should be a string or number")),
+    }
+    Ok(Call { id, method, params })
+}
+
+/// dispatch the event for a call, returns the response or None for notifications
+/// a failed call gets an error response, the other calls of the batch are still handled
+fn handle_call<R: JsRealmAdapter>(realm: &R, call: Call) -> Option<Value> {
+    let response = match dispatch_call(realm, &call) {
+        Ok(response) => response,
+        Err(err) => {
+            errors::log_script_error(format!("rpc method {} failed", call.method).as_str(), &err);
+            error_response(
+                call.id.clone().unwrap_or(Value::Null),
+                INTERNAL_ERROR,
+                err.get_message(),
+            )
+        }
+    };
+    call.id.map(|_| response)
+}
+
+// the response for a call, a listener which throws results in a server error
+fn dispatch_call<R: JsRealmAdapter>(realm: &R, call: &Call) -> Result<Value, JsError> {
+    let id_json = call.id.clone().unwrap_or(Value::Null).to_string();
+    let event_obj = dispatch::build_event(
+        realm,
+        &[
+            ("method", realm.js_string_create(call.method.as_str())?),
+            (
+                "params",
+                realm.js_json_parse(call.params.to_string().as_str())?,
+            ),
+            ("id", realm.js_json_parse(id_json.as_str())?),
+        ],
+    )?;
+    let event_name = format!("rpc:{}", call.method);
+    match dispatch::dispatch_to(
+        realm,
+        MY_APP_NAMESPACE,
+        MY_APP_CLASS,
+        event_name.as_str(),
+        &event_obj,
+    ) {
+        Ok(_) => read_outcome(realm, &event_obj, call),
+        Err(err) => {
+            errors::log_script_error(format!("rpc method {} failed", call.method).as_str(), &err);
+            Ok(error_response(
+                call.id.clone().unwrap_or(Value::Null),
+                SERVER_ERROR,
+                err.get_message(),
+            ))
+        }
+    }
+}
+
+// the result or error the listener set on the event
+fn read_outcome<R: JsRealmAdapter>(
+    realm: &R,
+    event_obj: &R::JsValueAdapterType,
+    call: &Call,
+) -> Result<Value, JsError> {
+    let id = call.id.clone().unwrap_or(Value::Null);
+    let error = realm.js_object_get_property(event_obj, "error")?;
+    if !error.js_is_null_or_undefined() {
+        let mut error = to_json(realm, &error)?;
+        if !error.get("code").map(Value::is_i64).unwrap_or(false) {
+            return Err(JsError::new_string(format!(
+                "the error of rpc method {} should have a numeric code",
+                call.method
+            )));
+        }
+        if !error.get("message").map(Value::is_string).unwrap_or(false) {
+            error["message"] = Value::from("error");
+        }
+        return Ok(json!({"jsonrpc": "2.0", "error": error, "id": id}));
+    }
+    let result = realm.js_object_get_property(event_obj, "result")?;
+    if result.js_get_type() == JsValueType::Undefined {
+        return Ok(error_response(
+            id,
+            METHOD_NOT_FOUND,
+            format!("method not found: {}", call.method).as_str(),
+        ));
+    }
+    Ok(json!({"jsonrpc": "2.0", "result": to_json(realm, &result)?, "id": id}))
+}
+
+fn to_json<R: JsRealmAdapter>(realm: &R, value: &R::JsValueAdapterType) -> Result<Value, JsError> {
+    let json = realm.js_json_stringify(value, None)?;
+    serde_json::from_str(json.as_str())
+        .map_err(|err| JsError::new_string(format!("rpc result is not valid json: {}", err)))
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "error": {"code": code, "message": message}, "id": id})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App};
+
+    async fn call(body: &'static str) -> Value {
+        crate::config::init_for_tests();
+        // in maintenance mode every call is a 503
+        let _maintenance = crate::tests::maintenance_lock().read().await;
+        let app = test::init_service(App::new().route("/rpc", web::post().to(rpc))).await;
+        let req = test::TestRequest::post()
+            .uri("/rpc")
+            .set_payload(body)
+            .to_request();
+        test::call_and_read_body_json(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn invalid_calls_are_answered_without_dispatching() {
+        let response = call("{not json").await;
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        assert_eq!(response["id"], Value::Null);
+
+        let response = call("[]").await;
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+
+        let response = call(r#"{"jsonrpc": "2.0", "id": 7}"#).await;
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        assert_eq!(response["id"], 7);
+    }
+
+    #[actix_web::test]
+    async fn a_batch_gets_a_response_per_call() {
+        let response = call(r#"[{"jsonrpc": "1.0", "method": "a", "id": 1}, {"jsonrpc": "2.0", "method": "b", "params": 3, "id": "two"}]"#).await;
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[1]["id"], "two");
+        assert!(responses
+            .iter()
+            .all(|response| response["error"]["code"] == INVALID_REQUEST));
+    }
+}
@@ -0,0 +1,39 @@
+use hirofa_utils::js_utils::JsError;
+use std::path::{Component, Path, PathBuf};
+
+/// resolve a path provided by script relative to a root dir
+/// absolute paths and paths containing .. are rejected so scripts can't escape the root
+pub fn sandboxed_path(root: &str, rel_path: &str) -> Result<PathBuf, JsError> {
+    let rel = Path::new(rel_path);
+    if rel_path.is_empty() {
+        return Err(JsError::new_str("path should not be empty"));
+    }
+    for component in rel.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => {
+                return Err(JsError::new_string(format!(
+                    "path {} is not allowed",
+                    rel_path
+                )))
+            }
+        }
+    }
+    Ok(Path::new(root).join(rel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_paths_inside_the_root_are_allowed() {
+        assert_eq!(
+            sandboxed_path("./root", "./a/b.txt").ok().unwrap(),
+            Path::new("./root/a/b.txt")
+        );
+        for path in ["", "/etc/passwd", "../a.txt", "a/../../b.txt"] {
+            assert!(sandboxed_path("./root", path).is_err(), "{}", path);
+        }
+    }
+}
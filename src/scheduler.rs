@@ -0,0 +1,157 @@
+use crate::tasks::TASKS;
+use crate::timeout::{script_timeout, with_deadline};
+use crate::{dispatch, errors, maintenance, script_pool, MY_APP_CLASS, MY_APP_NAMESPACE};
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use lazy_static::lazy_static;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+pub enum Schedule {
+    Every(Duration),
+    // a cron expression with seconds, e.g. "0 0 3 * * *" for every day at 3:00 UTC
+    // none of the JOBS uses one yet
+    #[allow(dead_code)]
+    Cron(&'static str),
+}
+
+pub struct ScheduledJob {
+    pub name: &'static str,
+    pub schedule: Schedule,
+}
+
+/// the scheduled jobs, every job dispatches a `cron:<name>` event to one of the runtimes in the pool
+pub const JOBS: &[ScheduledJob] = &[ScheduledJob {
+    name: "cleanup",
+    schedule: Schedule::Every(Duration::from_secs(60)),
+}];
+
+lazy_static! {
+    static ref HANDLES: Mutex<Vec<JoinHandle<Option<()>>>> = Mutex::new(vec![]);
+}
+
+/// start running the JOBS, fails when one of the cron expressions is invalid
+pub fn start() -> std::io::Result<()> {
+    let mut handles = HANDLES.lock().unwrap();
+    for job in JOBS {
+        let name = job.name;
+        let running = Arc::new(AtomicBool::new(false));
+        let handle = match &job.schedule {
+            Schedule::Every(period) => {
+                let period = *period;
+                TASKS.spawn(name, async move {
+                    let mut interval = tokio::time::interval(period);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                    // the first tick completes immediately
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        run(name, &running);
+                    }
+                })
+            }
+            Schedule::Cron(expr) => {
+                let schedule = cron::Schedule::from_str(expr).map_err(|err| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid cron expression for job {}: {}", name, err),
+                    )
+                })?;
+                TASKS.spawn(name, async move {
+                    while let Some(next) = schedule.upcoming(chrono::Utc).next() {
+                        let delay = (next - chrono::Utc::now()).to_std().unwrap_or_default();
+                        tokio::time::sleep(delay).await;
+                        run(name, &running);
+                    }
+                })
+            }
+        };
+        handles.push(handle);
+    }
+    Ok(())
+}
+
+/// stop running the JOBS, runs which already started are not aborted
+pub fn stop() {
+    for handle in HANDLES.lock().unwrap().drain(..) {
+        handle.abort();
+    }
+}
+
+/// dispatch the event for a job unless the previous run is still busy
+/// jobs don't run in maintenance mode, like requests
+fn run(name: &'static str, running: &Arc<AtomicBool>) {
+    if maintenance::is_enabled() {
+        log::debug!("skipping job {}, in maintenance mode", name);
+        return;
+    }
+    if running.swap(true, Ordering::SeqCst) {
+        log::debug!(
+            "skipping job {}, the previous run did not complete yet",
+            name
+        );
+        return;
+    }
+    let running = running.clone();
+    // the job only completes once the listeners ran, so the result is handled in the job itself
+    script_pool()
+        .next()
+        .js_loop_realm_void(None, move |_rt, realm| {
+            let res = with_deadline(script_timeout(), || {
+                let event_obj =
+                    dispatch::build_event(realm, &[("name", realm.js_string_create(name)?)])?;
+                dispatch::dispatch_to(
+                    realm,
+                    MY_APP_NAMESPACE,
+                    MY_APP_CLASS,
+                    format!("cron:{}", name).as_str(),
+                    &event_obj,
+                )
+            });
+            if let Err(err) = res {
+                errors::log_script_error(format!("job {} failed", name).as_str(), &err);
+            }
+            running.store(false, Ordering::SeqCst);
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn a_job_is_skipped_while_its_previous_run_is_busy() {
+        crate::tests::eval(
+            r#"{
+                const app = com.mycompany.MyApp;
+                app.addEventListener("cron:test", (evt) => {
+                    const runs = app.kvGet("cron-test");
+                    app.kvSet("cron-test", runs === undefined ? evt.name : runs + "," + evt.name);
+                });
+            }"#,
+        );
+        // jobs are skipped in maintenance mode
+        let _maintenance = crate::tests::maintenance_lock().blocking_read();
+        let running = Arc::new(AtomicBool::new(true));
+        run("test", &running);
+        running.store(false, Ordering::SeqCst);
+        run("test", &running);
+        let started = Instant::now();
+        while running.load(Ordering::SeqCst) {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "the job did not complete"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            crate::tests::eval(r#"com.mycompany.MyApp.kvGet("cron-test")"#),
+            "test"
+        );
+    }
+}
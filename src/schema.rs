@@ -0,0 +1,120 @@
+use actix_web::http::Method;
+use actix_web::{HttpRequest, HttpResponse};
+use jsonschema::JSONSchema;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    // the compiled schemas by route pattern, shared by all runtimes
+    static ref SCHEMAS: Mutex<HashMap<String, Arc<JSONSchema>>> = Mutex::new(HashMap::new());
+    // the compiled schemas of the values returned by route handlers by route pattern
+    static ref RESPONSE_SCHEMAS: Mutex<HashMap<String, Arc<JSONSchema>>> = Mutex::new(HashMap::new());
+}
+
+/// set the json schema the body of requests on a route must match, replaces an earlier schema for the route
+pub fn register(route: String, schema: &Value) -> Result<(), String> {
+    let compiled = JSONSchema::compile(schema).map_err(|err| format!("invalid schema: {}", err))?;
+    SCHEMAS.lock().unwrap().insert(route, Arc::new(compiled));
+    Ok(())
+}
+
+/// set the json schema the value returned by the handler of a route must match, replaces an earlier schema
+pub fn register_response(route: String, schema: &Value) -> Result<(), String> {
+    let compiled = JSONSchema::compile(schema).map_err(|err| format!("invalid schema: {}", err))?;
+    RESPONSE_SCHEMAS
+        .lock()
+        .unwrap()
+        .insert(route, Arc::new(compiled));
+    Ok(())
+}
+
+/// true when a response schema was registered for the route, the value only needs to be converted to json then
+pub fn has_response_schema(route: &str) -> bool {
+    RESPONSE_SCHEMAS.lock().unwrap().contains_key(route)
+}
+
+/// validate the value a route handler returned against the response schema of the route, returns the errors when it
+/// does not match, values of routes without a response schema are not checked
+pub fn check_response(route: &str, value: &Value) -> Result<(), Vec<String>> {
+    let schema = match RESPONSE_SCHEMAS.lock().unwrap().get(route).cloned() {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+    schema.validate(value).map_err(|errors| {
+        errors
+            .map(|err| format!("{}: {}", err.instance_path, err))
+            .collect()
+    })
+}
+
+/// validate the body of a request against the schema of its route, returns the 400 response when it does not match
+/// only POST, PUT and PATCH requests are checked, requests on routes without a schema are not checked
+pub fn check(req: &HttpRequest, body: &[u8]) -> Option<HttpResponse> {
+    if !matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH) {
+        return None;
+    }
+    let route = req.match_pattern()?;
+    // cloned so the lock is not held while validating
+    let schema = SCHEMAS.lock().unwrap().get(&route).cloned()?;
+    let value: Value = match serde_json::from_slice(body) {
+        Ok(value) => value,
+        Err(err) => {
+            return Some(bad_request(vec![format!(
+                "body is not valid json: {}",
+                err
+            )]))
+        }
+    };
+    let errors: Vec<String> = match schema.validate(&value) {
+        Ok(()) => return None,
+        Err(errors) => errors
+            .map(|err| format!("{}: {}", err.instance_path, err))
+            .collect(),
+    };
+    Some(bad_request(errors))
+}
+
+fn bad_request(errors: Vec<String>) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": "invalid body",
+        "errors": errors,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+
+    // responds with the 400 of check or a 200 when the body is valid
+    async fn checked(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+        check(&req, &body).unwrap_or_else(|| HttpResponse::Ok().finish())
+    }
+
+    async fn status_of(req: test::TestRequest) -> StatusCode {
+        let app = test::init_service(App::new().route("/schema-test/{id}", web::to(checked))).await;
+        test::call_service(&app, req.uri("/schema-test/1").to_request())
+            .await
+            .status()
+    }
+
+    #[actix_web::test]
+    async fn bodies_which_do_not_match_the_schema_are_a_bad_request() {
+        let schema = serde_json::json!({"type": "object", "required": ["name"]});
+        register("/schema-test/{id}".to_string(), &schema).unwrap();
+        assert!(register("/schema-test".to_string(), &serde_json::json!({"type": 1})).is_err());
+        let post = |body: &'static str| test::TestRequest::post().set_payload(body);
+        assert_eq!(status_of(post(r#"{"name":"a"}"#)).await, StatusCode::OK);
+        assert_eq!(
+            status_of(post(r#"{"id":1}"#)).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status_of(post("not json")).await, StatusCode::BAD_REQUEST);
+        // only bodies of POST, PUT and PATCH requests are checked
+        let get = test::TestRequest::get().set_payload("not json");
+        assert_eq!(status_of(get).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::{config, routes, scheduler};
+use actix_web::http::Method;
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// a route registered by script with registerRoute(method, path, handlerName, timeoutMs)
+/// requests for it dispatch the handler event instead of `request:<route>`, see routes::event_names
+#[derive(Clone, PartialEq)]
+pub struct ScriptRoute {
+    pub method: Method,
+    pub path: String,
+    // the event which is dispatched for the route, listeners add themselves with addEventListener(handler, ...)
+    pub handler: String,
+    // like routes::ROUTE_TIMEOUTS, SCRIPT_TIMEOUT_MS when None
+    pub timeout: Option<Duration>,
+}
+
+lazy_static! {
+    // the init event is dispatched in every runtime so every route is registered once per runtime and realm
+    static ref SCRIPT_ROUTES: Mutex<Vec<ScriptRoute>> = Mutex::new(vec![]);
+}
+
+// set when the server is built from the routes, after that no new routes can be added
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+// the endpoints of the server itself, a route of the script can't be (or overlap) one of these
+const RESERVED_PATHS: &[&str] = &[
+    "/health",
+    "/metrics",
+    "/ws",
+    "/events",
+    "/poll",
+    "/rpc",
+    "/debug/eval",
+];
+// like RESERVED_PATHS but for everything under the path
+const RESERVED_PREFIXES: &[&str] = &["/admin"];
+
+// the events the server dispatches itself, a handler with one of these names would also be invoked for those
+const RESERVED_HANDLERS: &[&str] = &["init", "shutdown", "warmup", "request"];
+// the prefixes of the events the server dispatches like rpc:<method>
+const RESERVED_HANDLER_PREFIXES: &[&str] = &["request:", "rpc:", "ws:", "body:", "cron:"];
+
+/// register a route, a route which was already registered with the same handler is ignored
+/// actix needs the routes before the server starts so new routes can only be registered from the init event, isolated
+/// realms dispatch init for every request and may only register the routes the runtimes registered at startup
+pub fn register(
+    method: &str,
+    path: &str,
+    handler: &str,
+    timeout: Option<Duration>,
+) -> Result<(), String> {
+    let method = match method.trim().to_uppercase().as_str() {
+        "GET" => Method::GET,
+        "POST" => Method::POST,
+        "PUT" => Method::PUT,
+        "PATCH" => Method::PATCH,
+        "DELETE" => Method::DELETE,
+        "HEAD" => Method::HEAD,
+        "OPTIONS" => Method::OPTIONS,
+        _ => {
+            return Err(format!(
+                "invalid method {}, expected GET, POST, PUT, PATCH, DELETE, HEAD or OPTIONS",
+                method
+            ))
+        }
+    };
+    check_path(path)?;
+    check_handler(handler)?;
+    let route = ScriptRoute {
+        method,
+        path: path.to_string(),
+        handler: handler.to_string(),
+        timeout,
+    };
+    let mut script_routes = SCRIPT_ROUTES.lock().unwrap();
+    // another method on the same path is fine, that gets a route on the same actix resource
+    if let Some(registered) = script_routes
+        .iter()
+        .find(|registered| registered.path != path && overlaps(registered.path.as_str(), path))
+    {
+        return Err(format!(
+            "{} overlaps {} {} which is already registered",
+            path, registered.method, registered.path
+        ));
+    }
+    match script_routes
+        .iter()
+        .find(|registered| registered.method == route.method && registered.path == route.path)
+    {
+        Some(registered) if registered.handler == route.handler && registered.timeout == route.timeout => {
+            Ok(())
+        }
+        Some(registered) if registered.handler == route.handler => Err(format!(
+            "{} {} is already registered with another timeout",
+            registered.method, registered.path
+        )),
+        Some(registered) => Err(format!(
+            "{} {} is already registered for handler {}",
+            registered.method, registered.path, registered.handler
+        )),
+        None if FROZEN.load(Ordering::SeqCst) => Err(format!(
+            "can not register {} {} after the server started, routes can only be registered from the init event",
+            route.method, route.path
+        )),
+        None => {
+            script_routes.push(route);
+            Ok(())
+        }
+    }
+}
+
+// the paths of the server itself and those under the static prefix can't be registered, nor paths which would
+// also match requests for one of those
+fn check_path(path: &str) -> Result<(), String> {
+    if !path.starts_with('/') {
+        return Err(format!("the path should start with /, got {}", path));
+    }
+    let server_routes = RESERVED_PATHS
+        .iter()
+        .chain(routes::ROUTES)
+        .chain(routes::STREAMING_ROUTES);
+    for route in server_routes {
+        if overlaps(route, path) {
+            return Err(format!(
+                "{} overlaps {}, a route of the server",
+                path, route
+            ));
+        }
+    }
+    let static_prefix = config::get().static_prefix.as_deref();
+    for prefix in RESERVED_PREFIXES.iter().copied().chain(static_prefix) {
+        if is_under(prefix, path) {
+            return Err(format!("{} is under {} which is reserved", path, prefix));
+        }
+    }
+    Ok(())
+}
+
+fn check_handler(handler: &str) -> Result<(), String> {
+    if handler.is_empty() {
+        return Err("the handler name can not be empty".to_string());
+    }
+    let reserved = RESERVED_HANDLERS.contains(&handler)
+        || [routes::NOT_FOUND_EVENT, routes::ERROR_EVENT].contains(&handler)
+        || routes::MIDDLEWARE.contains(&handler)
+        || routes::METHOD_EVENTS
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(handler))
+        || RESERVED_HANDLER_PREFIXES
+            .iter()
+            .any(|prefix| handler.starts_with(prefix))
+        || routes::AGGREGATES
+            .iter()
+            .any(|aggregate| aggregate.events.contains(&handler))
+        || scheduler::JOBS
+            .iter()
+            .any(|job| handler == format!("cron:{}", job.name));
+    if reserved {
+        return Err(format!(
+            "{} is an event of the server, it can't be a handler name",
+            handler
+        ));
+    }
+    Ok(())
+}
+
+// true when a request path could match both patterns, a {param} matches any segment
+fn overlaps(a: &str, b: &str) -> bool {
+    let a: Vec<&str> = a.split('/').collect();
+    let b: Vec<&str> = b.split('/').collect();
+    let is_param = |segment: &str| segment.starts_with('{');
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|(a, b)| a == b || is_param(a) || is_param(b))
+}
+
+// true for the prefix itself and the paths under it, a path under it with a {param} could match too
+fn is_under(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    let segments = prefix.split('/').count();
+    let head: Vec<&str> = path.split('/').take(segments).collect();
+    head.len() == segments && overlaps(prefix, head.join("/").as_str())
+}
+
+/// stop accepting new routes and log the registered routes, called before the server is built
+pub fn freeze() {
+    FROZEN.store(true, Ordering::SeqCst);
+    for route in SCRIPT_ROUTES.lock().unwrap().iter() {
+        log::info!(
+            "script route {} {} dispatches {}",
+            route.method,
+            route.path,
+            route.handler
+        );
+    }
+}
+
+/// the registered routes
+pub fn all() -> Vec<ScriptRoute> {
+    SCRIPT_ROUTES.lock().unwrap().clone()
+}
+
+/// the handler event for a request on a route registered by script, if any
+pub fn handler(route: &str, method: &str) -> Option<String> {
+    SCRIPT_ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|registered| registered.path == route && registered.method.as_str() == method)
+        .map(|registered| registered.handler.clone())
+}
+
+/// the timeout of a route registered by script, if it has its own
+pub fn timeout(route: &str, method: &str) -> Option<Duration> {
+    SCRIPT_ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|registered| registered.path == route && registered.method.as_str() == method)
+        .and_then(|registered| registered.timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register_get(path: &str, handler: &str) -> Result<(), String> {
+        register("GET", path, handler, None)
+    }
+
+    #[test]
+    fn a_route_is_registered_once_per_method() {
+        config::init_for_tests();
+        register("get", "/register-test", "listItems", None).unwrap();
+        register("GET", "/register-test", "listItems", None).unwrap();
+        assert!(register("GET", "/register-test", "otherItems", None).is_err());
+        assert!(register("FETCH", "/register-test/fetch", "fetch", None).is_err());
+        assert!(register("GET", "register-test/relative", "relative", None).is_err());
+        assert!(register("GET", "/register-test/empty", "", None).is_err());
+        assert_eq!(
+            handler("/register-test", "GET").as_deref(),
+            Some("listItems")
+        );
+        assert_eq!(handler("/register-test", "POST"), None);
+    }
+
+    #[test]
+    fn routes_of_the_server_are_rejected() {
+        config::init_for_tests();
+        assert!(register_get("/health", "health").is_err());
+        assert!(register_get("/{name}", "name").is_err());
+        assert!(register_get("/admin", "admin").is_err());
+        assert!(register_get("/admin/users", "admin").is_err());
+        assert!(register_get("/{scope}/restart", "restart").is_err());
+        assert!(register_get("/users/me", "me").is_err());
+        assert!(register_get("/administrators", "administrators").is_ok());
+    }
+
+    #[test]
+    fn overlapping_routes_are_rejected() {
+        config::init_for_tests();
+        register_get("/overlap-test/{id}", "item").unwrap();
+        // the same route again and another method on it are fine
+        register_get("/overlap-test/{id}", "item").unwrap();
+        register("POST", "/overlap-test/{id}", "updateItem", None).unwrap();
+        assert!(register_get("/overlap-test/new", "newItem").is_err());
+        assert!(register_get("/overlap-test/{name}", "named").is_err());
+        register_get("/overlap-test/{id}/parts", "parts").unwrap();
+    }
+
+    #[test]
+    fn events_of_the_server_are_not_handlers() {
+        config::init_for_tests();
+        for handler in [
+            "init",
+            "error",
+            "pre-request",
+            "request",
+            "get",
+            "rpc:add",
+            "cron:cleanup",
+        ] {
+            assert!(
+                register_get("/handler-test", handler).is_err(),
+                "{} was accepted",
+                handler
+            );
+        }
+    }
+}
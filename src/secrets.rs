@@ -0,0 +1,97 @@
+use once_cell::sync::OnceCell;
+use std::collections::BTreeMap;
+
+/// env vars with this prefix are secrets, SCRIPT_SECRET_WEBHOOK_KEY is the secret WEBHOOK_KEY
+/// getEnv refuses them even though they also start with SCRIPT_
+pub const ENV_PREFIX: &str = "SCRIPT_SECRET_";
+/// a toml file of name = "value" pairs, e.g. mounted from a secret manager, the env vars override these
+pub const SECRETS_FILE_VAR: &str = "SCRIPT_SECRETS_FILE";
+
+// by name, never passed to script, only the proxies which take a secret name use them on the rust side
+static SECRETS: OnceCell<BTreeMap<String, String>> = OnceCell::new();
+
+/// load the secrets from SCRIPT_SECRETS_FILE and the SCRIPT_SECRET_ env vars, called once at the start of main
+/// only the names are logged
+pub fn init() -> std::io::Result<()> {
+    let mut secrets = match std::env::var(SECRETS_FILE_VAR) {
+        Ok(path) => read_file(path.as_str())?,
+        Err(_) => BTreeMap::new(),
+    };
+    for (var, value) in std::env::vars() {
+        if let Some(name) = var.strip_prefix(ENV_PREFIX) {
+            if !name.is_empty() {
+                secrets.insert(name.to_string(), value);
+            }
+        }
+    }
+    let names: Vec<&str> = secrets.keys().map(String::as_str).collect();
+    log::info!("secrets: {}", names.join(","));
+    let _ = SECRETS.set(secrets);
+    Ok(())
+}
+
+fn read_file(path: &str) -> std::io::Result<BTreeMap<String, String>> {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+        std::io::Error::new(
+            err.kind(),
+            format!("could not read {} {}: {}", SECRETS_FILE_VAR, path, err),
+        )
+    })?;
+    // the error of toml may quote the line, which could be part of a value, so only the position is reported
+    toml::from_str(text.as_str()).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "invalid {} {}, expected name = \"value\" pairs{}",
+                SECRETS_FILE_VAR,
+                path,
+                err.line_col()
+                    .map(|(line, _)| format!(" (line {})", line + 1))
+                    .unwrap_or_default()
+            ),
+        )
+    })
+}
+
+/// the value of a secret, for the rust side of a proxy only, the value must never be returned to script
+pub fn get(name: &str) -> Option<&'static str> {
+    SECRETS.get()?.get(name).map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_secrets_are_only_used_on_the_rust_side() {
+        let path = std::env::temp_dir().join(format!("secrets-test-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "WEBHOOK_KEY = \"from the file\"\nAPI_KEY = \"from the file\"\n",
+        )
+        .unwrap();
+        std::env::set_var(SECRETS_FILE_VAR, &path);
+        std::env::set_var("SCRIPT_SECRET_API_KEY", "from the env");
+        init().unwrap();
+        assert_eq!(get("WEBHOOK_KEY"), Some("from the file"));
+        assert_eq!(get("API_KEY"), Some("from the env"));
+        assert_eq!(get("MISSING"), None);
+        assert_eq!(
+            crate::tests::eval(
+                "const app = com.mycompany.MyApp; \
+                 app.hmacSha256({secret: 'WEBHOOK_KEY'}, 'message') === app.hmacSha256('from the file', 'message')"
+            ),
+            "true"
+        );
+        // the line of an invalid file could be part of a value, only its number is in the error
+        std::fs::write(&path, "WEBHOOK_KEY = not quoted\n").unwrap();
+        let err = read_file(path.to_str().unwrap()).err().unwrap().to_string();
+        assert!(
+            err.ends_with("expected name = \"value\" pairs (line 1)"),
+            "{}",
+            err
+        );
+        assert!(!err.contains("not quoted"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use crate::maintenance;
+use actix_web::http::header::{self, ContentEncoding};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// the number of messages a slow client may fall behind before it misses messages
+const CHANNEL_CAPACITY: usize = 64;
+/// how long /poll waits for a message when the request has no timeout, and the max timeout it may ask for
+const DEFAULT_POLL_TIMEOUT_SECS: u64 = 30;
+const MAX_POLL_TIMEOUT_SECS: u64 = 60;
+
+lazy_static! {
+    // the channels which have subscribers by name, the messages are the data as broadcast, /events formats them as
+    // server-sent events, a channel is removed when its last Subscription is dropped
+    static ref CHANNELS: Mutex<HashMap<String, broadcast::Sender<Bytes>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeQuery {
+    channel: String,
+}
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    channel: String,
+    // in seconds, DEFAULT_POLL_TIMEOUT_SECS when missing
+    timeout: Option<u64>,
+}
+
+/// the messages of a channel for one client
+struct Subscription {
+    channel: String,
+    // only None while dropping
+    stream: Option<BroadcastStream<Bytes>>,
+}
+
+impl Stream for Subscription {
+    type Item = Result<Bytes, BroadcastStreamRecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // the receiver is dropped while we hold the lock so nobody subscribes to a sender we then remove
+        let mut channels = CHANNELS.lock().unwrap();
+        self.stream.take();
+        if channels
+            .get(&self.channel)
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            channels.remove(&self.channel);
+        }
+    }
+}
+
+fn subscribe(channel: &str) -> Subscription {
+    let receiver = CHANNELS
+        .lock()
+        .unwrap()
+        .entry(channel.to_string())
+        .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+        .subscribe();
+    Subscription {
+        channel: channel.to_string(),
+        stream: Some(BroadcastStream::new(receiver)),
+    }
+}
+
+/// the /events?channel=name endpoint, streams the messages the script broadcasts to the channel as server-sent events
+pub async fn events(query: web::Query<SubscribeQuery>) -> HttpResponse {
+    if let Some(response) = maintenance::check() {
+        return response;
+    }
+    // when the client disconnects the stream and with it the subscription is dropped
+    let stream = subscribe(query.channel.as_str()).filter_map(|msg| match msg {
+        Ok(msg) => Some(Ok::<_, std::io::Error>(format_event(&msg))),
+        Err(err) => {
+            log::debug!("sse client missed messages: {}", err);
+            None
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // the encoder would buffer the messages
+        .insert_header(ContentEncoding::Identity)
+        .streaming(stream)
+}
+
+/// the /poll?channel=name&timeout=secs endpoint, a long poll for clients which can't use /events
+///
+/// the request is held open until the script broadcasts a message to the channel, which is the body of the 200, or
+/// until the timeout (at most MAX_POLL_TIMEOUT_SECS) passes which is a 204, only messages broadcast while the request
+/// waits are received so a client should poll again right away
+pub async fn poll(query: web::Query<PollQuery>) -> HttpResponse {
+    if let Some(response) = maintenance::check() {
+        return response;
+    }
+    let secs = query.timeout.unwrap_or(DEFAULT_POLL_TIMEOUT_SECS);
+    if secs == 0 || secs > MAX_POLL_TIMEOUT_SECS {
+        return HttpResponse::BadRequest().body(format!(
+            "timeout should be 1 to {} seconds",
+            MAX_POLL_TIMEOUT_SECS
+        ));
+    }
+    let mut subscription = subscribe(query.channel.as_str());
+    let received = actix_web::rt::time::timeout(Duration::from_secs(secs), async {
+        loop {
+            match subscription.next().await {
+                Some(Ok(msg)) => return Some(msg),
+                // more than CHANNEL_CAPACITY messages since we subscribed, the next one is still new to the client
+                Some(Err(BroadcastStreamRecvError::Lagged(_))) => continue,
+                None => return None,
+            }
+        }
+    })
+    .await;
+    match received {
+        Ok(Some(msg)) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .body(msg),
+        _ => HttpResponse::NoContent()
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .finish(),
+    }
+}
+
+/// send a message to the subscribers of a channel, returns the number of subscribers it was sent to
+pub fn broadcast(channel: &str, data: &str) -> usize {
+    let mut channels = CHANNELS.lock().unwrap();
+    let sent = match channels.get(channel) {
+        Some(sender) => sender
+            .send(Bytes::copy_from_slice(data.as_bytes()))
+            .unwrap_or(0),
+        None => 0,
+    };
+    if sent == 0 {
+        // all subscribers disconnected
+        channels.remove(channel);
+    }
+    sent
+}
+
+// every line of the data gets its own data: field so messages can contain newlines, like the client we take \r\n, \r
+// and \n as line ends, a lone \r in a field would end it for the client and start a field the script did not send
+fn format_event(data: &[u8]) -> Bytes {
+    let data = String::from_utf8_lossy(data).replace("\r\n", "\n");
+    let mut event = String::new();
+    for line in data.split(['\r', '\n']) {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    Bytes::from(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::http::StatusCode;
+
+    #[actix_web::test]
+    async fn the_broadcasts_to_a_channel_are_streamed_as_events() {
+        crate::config::init_for_tests();
+        let _maintenance = crate::tests::maintenance_lock().read().await;
+        let channel = "sse-stream-test";
+        let query = web::Query(SubscribeQuery {
+            channel: channel.to_string(),
+        });
+        let res = events(query).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(broadcast(channel, "hello"), 1);
+        let mut body = res.into_body();
+        let chunk =
+            std::future::poll_fn(|cx| MessageBody::poll_next(std::pin::Pin::new(&mut body), cx))
+                .await;
+        assert_eq!(chunk.unwrap().ok().unwrap(), Bytes::from("data: hello\n\n"));
+    }
+
+    #[actix_web::test]
+    async fn a_poll_gets_the_next_broadcast_or_a_204() {
+        crate::config::init_for_tests();
+        let _maintenance = crate::tests::maintenance_lock().read().await;
+        let channel = "sse-poll-test";
+        let query = |timeout| {
+            web::Query(PollQuery {
+                channel: channel.to_string(),
+                timeout: Some(timeout),
+            })
+        };
+        let (res, ()) = tokio::join!(poll(query(5)), async {
+            // once the poll is waiting
+            while broadcast(channel, "hello") == 0 {
+                actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = actix_web::body::to_bytes(res.into_body())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(body, Bytes::from("hello"));
+        assert_eq!(poll(query(1)).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(poll(query(61)).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn every_line_gets_a_data_field() {
+        assert_eq!(format_event(b"hello"), Bytes::from("data: hello\n\n"));
+        assert_eq!(
+            format_event(b"a\r\nb\rc\nd"),
+            Bytes::from("data: a\ndata: b\ndata: c\ndata: d\n\n")
+        );
+        // an injected field stays data
+        assert_eq!(
+            format_event(b"x\revent: admin"),
+            Bytes::from("data: x\ndata: event: admin\n\n")
+        );
+    }
+
+    #[test]
+    fn a_channel_is_removed_with_its_last_subscription() {
+        let channel = "sse-test";
+        let first = subscribe(channel);
+        let second = subscribe(channel);
+        drop(first);
+        assert_eq!(broadcast(channel, "still there"), 1);
+        drop(second);
+        assert!(!CHANNELS.lock().unwrap().contains_key(channel));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::{self, FILES_DIR_VAR, STATIC_DIR_VAR, UPLOAD_DIR_VAR, WRITE_DIR_VAR};
+use actix_files::Files;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::web;
+use lazy_static::lazy_static;
+use std::path::{Component, Path, PathBuf};
+
+lazy_static! {
+    // SCRIPT_STATIC_DIR resolved once so the symlinks in it can be checked against it, None when it can't be read
+    static ref ROOT: Option<PathBuf> = match std::fs::canonicalize(&config::get().static_dir) {
+        Ok(root) => shared_dir(&root).map_or(Some(root), |shared_with| {
+            log::error!(
+                "{} {} is also (in) {}, no static files are served",
+                STATIC_DIR_VAR,
+                config::get().static_dir,
+                shared_with
+            );
+            None
+        }),
+        Err(err) => {
+            log::error!(
+                "could not read {} {}, no static files are served: {}",
+                STATIC_DIR_VAR,
+                config::get().static_dir,
+                err
+            );
+            None
+        }
+    };
+}
+
+// the static dir serves everything in it to anyone, so it can't share files with SCRIPT_FILES_DIR which may have
+// files only the script should send, with SCRIPT_UPLOAD_DIR or with SCRIPT_WRITE_DIR or what the users upload and the
+// scripts write would be served too
+fn shared_dir(root: &Path) -> Option<&'static str> {
+    let config = config::get();
+    [
+        (FILES_DIR_VAR, config.files_dir.as_str()),
+        (UPLOAD_DIR_VAR, config.upload_dir.as_str()),
+        (WRITE_DIR_VAR, config.write_dir.as_str()),
+    ]
+    .iter()
+    .find(|(_, dir)| overlaps(root, dir))
+    .map(|(var, _)| *var)
+}
+
+// a dir which does not exist does not share any files
+fn overlaps(root: &Path, dir: &str) -> bool {
+    match std::fs::canonicalize(dir) {
+        Ok(dir) => dir.starts_with(root) || root.starts_with(&dir),
+        Err(_) => false,
+    }
+}
+
+/// the Cache-Control of the files at SCRIPT_STATIC_PREFIX and of event.responseFile responses
+pub fn cache_control() -> String {
+    match config::get().static_max_age {
+        0 => "no-cache".to_string(),
+        max_age => format!("public, max-age={}", max_age),
+    }
+}
+
+/// serve the files in SCRIPT_STATIC_DIR at SCRIPT_STATIC_PREFIX without dispatching to the scripts, should be
+/// registered after the other routes so the routes of the scripts under the prefix take precedence
+///
+/// the responses get a Content-Type guessed from the extension, an ETag and Last-Modified to revalidate with and the
+/// Cache-Control of SCRIPT_STATIC_MAX_AGE, range requests are supported and dirs are not listed
+/// actix-files refuses paths with .. and hidden files (like .env) with a 400, we also refuse those and files a
+/// symlink leads to outside of the dir, which are a 404 like a missing file
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let prefix = match &config::get().static_prefix {
+        Some(prefix) => prefix.as_str(),
+        None => return,
+    };
+    let root = match ROOT.as_ref() {
+        Some(root) => root,
+        None => return,
+    };
+    log::debug!("serving {} at {}", root.display(), prefix);
+    serve_dir(cfg, prefix, root.clone());
+}
+
+fn serve_dir(cfg: &mut web::ServiceConfig, prefix: &str, root: PathBuf) {
+    let filter_root = root.clone();
+    cfg.service(
+        web::scope(prefix)
+            .wrap_fn(|req, srv| {
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await?;
+                    set_cache_control(&mut res);
+                    Ok(res)
+                }
+            })
+            .service(
+                Files::new("", root)
+                    .use_etag(true)
+                    .use_last_modified(true)
+                    .path_filter(move |path, _head| is_allowed(&filter_root, path)),
+            ),
+    );
+}
+
+// only the files get the Cache-Control, a 404 should not be cached for SCRIPT_STATIC_MAX_AGE
+fn set_cache_control<B>(res: &mut ServiceResponse<B>) {
+    let status = res.status();
+    if !(status.is_success() || status == StatusCode::NOT_MODIFIED) {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(cache_control().as_str()) {
+        res.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+}
+
+// path is relative to the dir and has no .. components, actix-files already refused those
+fn is_allowed(root: &Path, path: &Path) -> bool {
+    let hidden = path.components().any(|component| {
+        matches!(component, Component::Normal(name) if name.to_string_lossy().starts_with('.'))
+    });
+    if hidden {
+        return false;
+    }
+    // a path which does not exist is a 404 anyway
+    match std::fs::canonicalize(root.join(path)) {
+        Ok(path) => path.starts_with(root),
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn files_get_a_content_type_and_only_files_are_cached() {
+        config::init_for_tests();
+        let root = std::env::temp_dir().join(format!("static-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("site.css"), "body {}").unwrap();
+        std::fs::write(root.join(".env"), "SECRET=1").unwrap();
+        let root = std::fs::canonicalize(root).unwrap();
+        let app =
+            test::init_service(App::new().configure(|cfg| serve_dir(cfg, "/static", root.clone())))
+                .await;
+
+        let res = test::call_service(
+            &app,
+            TestRequest::get().uri("/static/site.css").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(
+            res.headers()
+                .get(header::CACHE_CONTROL)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            cache_control()
+        );
+
+        for uri in ["/static/missing.css", "/static/.env"] {
+            let res = test::call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            // actix-files refuses hidden files itself with a 400
+            assert!(res.status().is_client_error());
+            assert!(!res.headers().contains_key(header::CACHE_CONTROL));
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn dirs_in_or_around_the_static_dir_are_shared() {
+        let root = std::env::temp_dir().join(format!("static-{}", uuid::Uuid::new_v4()));
+        let child = root.join("files");
+        let sibling = root.with_extension("files");
+        std::fs::create_dir_all(&child).unwrap();
+        std::fs::create_dir_all(&sibling).unwrap();
+        let root = std::fs::canonicalize(root).unwrap();
+        let dir = |path: &Path| path.to_str().unwrap().to_string();
+
+        assert!(overlaps(&root, &dir(&root)));
+        assert!(overlaps(&root, &dir(&child)));
+        assert!(overlaps(&child, &dir(&root)));
+        assert!(!overlaps(&root, &dir(&sibling)));
+        assert!(!overlaps(&root, &dir(&root.join("missing"))));
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(sibling).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hidden_files_and_files_outside_the_dir_are_refused() {
+        let root = std::env::temp_dir().join(format!("static-{}", uuid::Uuid::new_v4()));
+        let outside = root.with_extension("outside");
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("css/site.css"), "body {}").unwrap();
+        std::fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("secret.txt")).unwrap();
+        let root = std::fs::canonicalize(root).unwrap();
+
+        assert!(is_allowed(&root, Path::new("css/site.css")));
+        assert!(is_allowed(&root, Path::new("missing.css")));
+        assert!(!is_allowed(&root, Path::new(".env")));
+        assert!(!is_allowed(&root, Path::new(".git/config")));
+        assert!(!is_allowed(&root, Path::new("secret.txt")));
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+}
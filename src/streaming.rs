@@ -0,0 +1,231 @@
+use actix_web::web::Bytes;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+pub type Chunk = Result<Bytes, std::io::Error>;
+
+/// when more than this many bytes are written but not yet sent to the client write() and writeJson() return false,
+/// the chunk is still queued but the script should wait before it writes more
+pub const HIGH_WATER_MARK: usize = 1024 * 1024;
+/// a script which keeps writing when write() returned false fails its stream once this many bytes are queued, the
+/// client sees the response being aborted and write() throws
+pub const MAX_BUFFERED: usize = 16 * HIGH_WATER_MARK;
+
+/// the state shared by the sending and receiving side of a stream
+#[derive(Default)]
+struct StreamState {
+    // the bytes written by the script which the client did not receive yet
+    buffered: AtomicUsize,
+    // set by writeJson(), the response then defaults to application/x-ndjson
+    ndjson: AtomicBool,
+}
+
+/// the receiving side of a stream, used as the body of a streaming response
+/// the stream is removed when this (or the stream it turned into) drops, nothing can receive its chunks after that
+pub struct ResponseBody {
+    receiver: UnboundedReceiver<Chunk>,
+    state: Arc<StreamState>,
+    closer: Closer,
+}
+
+// removes the stream on drop, e.g. when the request was dropped because the client disconnected or when the client
+// disconnected from a streaming response the script never ends
+struct Closer(u64);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        STREAMS.lock().unwrap().remove(&self.0);
+    }
+}
+
+impl ResponseBody {
+    /// true when the script wrote json lines with writeJson()
+    pub fn is_ndjson(&self) -> bool {
+        self.state.ndjson.load(Ordering::Relaxed)
+    }
+
+    /// the chunks as the script writes them, a chunk no longer counts as buffered once it is taken from the stream
+    pub fn into_stream(self) -> impl Stream<Item = Chunk> {
+        let (state, closer) = (self.state, self.closer);
+        UnboundedReceiverStream::new(self.receiver).map(move |chunk| {
+            let _closer = &closer;
+            if let Ok(bytes) = &chunk {
+                state.buffered.fetch_sub(bytes.len(), Ordering::Relaxed);
+            }
+            chunk
+        })
+    }
+}
+
+/// the sending side of a streaming response body
+///
+/// the script writes chunks from the worker thread of its runtime, the receiving side is the body of the
+/// HttpResponse so chunks are forwarded to the client as soon as they are written
+struct ResponseStream {
+    // None once the script ended the stream
+    sender: Option<UnboundedSender<Chunk>>,
+    state: Arc<StreamState>,
+    started: bool,
+    // true once the handler returned, from then on the stream is removed as soon as it ends
+    detached: bool,
+}
+
+lazy_static! {
+    static ref STREAMS: Mutex<HashMap<u64, ResponseStream>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(1);
+
+/// open a stream for a request, the stream is only used as response body if the script writes to it
+pub fn open() -> (u64, ResponseBody) {
+    let id = NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = unbounded_channel();
+    let state = Arc::new(StreamState::default());
+    STREAMS.lock().unwrap().insert(
+        id,
+        ResponseStream {
+            sender: Some(sender),
+            state: state.clone(),
+            started: false,
+            detached: false,
+        },
+    );
+    (
+        id,
+        ResponseBody {
+            receiver,
+            state,
+            closer: Closer(id),
+        },
+    )
+}
+
+/// write a chunk to a stream, called by event.write()
+/// returns false when the client is not keeping up, see HIGH_WATER_MARK and MAX_BUFFERED
+pub fn write(id: u64, chunk: Bytes) -> Result<bool, JsError> {
+    let mut streams = STREAMS.lock().unwrap();
+    let stream = streams
+        .get_mut(&id)
+        .ok_or_else(|| JsError::new_str("stream is closed"))?;
+    stream.started = true;
+    let len = chunk.len();
+    if stream.state.buffered.load(Ordering::Relaxed) + len > MAX_BUFFERED {
+        let err = JsError::new_string(format!(
+            "more than {} bytes are queued, the script should wait when write() returns false",
+            MAX_BUFFERED
+        ));
+        if let Some(stream) = streams.remove(&id) {
+            if let Some(sender) = stream.sender {
+                let _ = sender.send(Err(std::io::Error::other(err.get_message())));
+            }
+        }
+        return Err(err);
+    }
+    let sender = match &stream.sender {
+        Some(sender) => sender,
+        None => return Err(JsError::new_str("stream already ended")),
+    };
+    // counted before it is sent, the client may take the chunk (and subtract it) before send returns
+    let buffered = stream.state.buffered.fetch_add(len, Ordering::Relaxed) + len;
+    if sender.send(Ok(chunk)).is_err() {
+        // the receiver is dropped when the client disconnects
+        streams.remove(&id);
+        return Err(JsError::new_str("client disconnected"));
+    }
+    Ok(buffered <= HIGH_WATER_MARK)
+}
+
+/// write a json value followed by a newline to a stream, called by event.writeJson()
+/// the response defaults to application/x-ndjson when this is called before the listener returned
+pub fn write_json(id: u64, json: String) -> Result<bool, JsError> {
+    if let Some(stream) = STREAMS.lock().unwrap().get(&id) {
+        stream.state.ndjson.store(true, Ordering::Relaxed);
+    }
+    let mut line = json.into_bytes();
+    line.push(b'\n');
+    write(id, Bytes::from(line))
+}
+
+/// end a stream, called by event.end()
+pub fn end(id: u64) {
+    let mut streams = STREAMS.lock().unwrap();
+    let remove = match streams.get_mut(&id) {
+        Some(stream) => {
+            stream.sender = None;
+            stream.detached
+        }
+        None => false,
+    };
+    if remove {
+        streams.remove(&id);
+    }
+}
+
+/// terminate a stream with an error, the client will see the response being aborted
+pub fn fail(id: u64, err: &JsError) {
+    if let Some(stream) = STREAMS.lock().unwrap().remove(&id) {
+        if let Some(sender) = stream.sender {
+            let _ = sender.send(Err(std::io::Error::other(err.get_message())));
+        }
+    }
+}
+
+/// true once the script wrote to the stream, the stream is then the response body
+pub fn is_started(id: u64) -> bool {
+    STREAMS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .is_some_and(|stream| stream.started)
+}
+
+/// called by the handler after dispatching, returns true if the script started writing to the stream in which
+/// case the stream should be used as the response body
+/// when the script did not start writing the stream is closed
+pub fn detach(id: u64) -> bool {
+    let mut streams = STREAMS.lock().unwrap();
+    let (started, remove) = match streams.get_mut(&id) {
+        Some(stream) => {
+            stream.detached = true;
+            (stream.started, !stream.started || stream.sender.is_none())
+        }
+        None => (false, false),
+    };
+    if remove {
+        streams.remove(&id);
+    }
+    started
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_stream_is_removed_with_its_receiver() {
+        let (id, receiver) = open();
+        assert!(matches!(write(id, Bytes::from_static(b"chunk")), Ok(true)));
+        drop(receiver);
+        assert!(!STREAMS.lock().unwrap().contains_key(&id));
+        assert!(write(id, Bytes::from_static(b"chunk")).is_err());
+    }
+
+    #[test]
+    fn a_stream_which_is_not_read_fails_at_the_max() {
+        let (id, _receiver) = open();
+        let chunk = Bytes::from(vec![b'x'; HIGH_WATER_MARK]);
+        assert!(matches!(write(id, chunk.clone()), Ok(true)));
+        assert!(matches!(write(id, chunk.clone()), Ok(false)));
+        for _ in 2..MAX_BUFFERED / HIGH_WATER_MARK {
+            assert!(matches!(write(id, chunk.clone()), Ok(false)));
+        }
+        assert!(write(id, chunk).is_err());
+        assert!(!STREAMS.lock().unwrap().contains_key(&id));
+    }
+}
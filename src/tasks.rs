@@ -0,0 +1,107 @@
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+lazy_static! {
+    /// the runtimes in the pool don't run on a tokio runtime so we keep one here to drive our background tasks
+    /// like timers and outbound http requests
+    /// the tasks on it don't keep the process alive, when main returns pending tasks are simply dropped
+    pub static ref TASK_RT: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("script-tasks")
+        .enable_all()
+        .build()
+        .expect("could not create task runtime");
+    /// the background tasks of timers, scheduled jobs and async methods, stopped when we shut down
+    pub static ref TASKS: TaskRegistry = TaskRegistry::new();
+}
+
+/// keeps track of the background tasks spawned on TASK_RT so they can all be stopped on shutdown
+///
+/// a task is stopped by dropping its future at the next await, the same as aborting its JoinHandle
+/// tasks spawned after shutdown() are stopped right away so a script can't start new timers while we exit
+pub struct TaskRegistry {
+    stopped_tx: watch::Sender<bool>,
+    stopped_rx: watch::Receiver<bool>,
+    running: AtomicUsize,
+}
+
+// decrements the running count when a task completes, is aborted or is stopped
+struct RunningGuard<'a>(&'a AtomicUsize);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl TaskRegistry {
+    fn new() -> Self {
+        let (stopped_tx, stopped_rx) = watch::channel(false);
+        Self {
+            stopped_tx,
+            stopped_rx,
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// spawn a task on TASK_RT which is stopped on shutdown, name is only used for logging
+    /// the handle resolves to None when the task was stopped, it can still be used to abort the task earlier like
+    /// clearTimeout does
+    pub fn spawn<F>(&'static self, name: &'static str, task: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        // a clone has not seen the value sent by shutdown() yet so changed() also completes for tasks spawned after
+        let mut stopped = self.stopped_rx.clone();
+        self.running.fetch_add(1, Ordering::Relaxed);
+        TASK_RT.spawn(async move {
+            let _guard = RunningGuard(&self.running);
+            tokio::select! {
+                output = task => Some(output),
+                _ = stopped.changed() => {
+                    log::debug!("stopped background task {} on shutdown", name);
+                    None
+                }
+            }
+        })
+    }
+
+    /// stop all tasks, those which are still running are dropped at their next await
+    pub fn shutdown(&self) {
+        let running = self.running.load(Ordering::Relaxed);
+        if running > 0 {
+            log::info!("stopping {} background tasks", running);
+        }
+        // send only fails when there are no receivers and we always keep one
+        let _ = self.stopped_tx.send(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // sleep only creates its timer when it is polled on TASK_RT
+    async fn sleep() {
+        tokio::time::sleep(Duration::from_secs(60)).await
+    }
+
+    #[test]
+    fn the_tasks_are_stopped_on_shutdown() {
+        // a registry of its own, shutting down TASKS would stop the tasks of the other tests
+        let tasks: &'static TaskRegistry = Box::leak(Box::new(TaskRegistry::new()));
+        let done = tasks.spawn("done", async { 1 });
+        assert_eq!(TASK_RT.block_on(done).unwrap(), Some(1));
+        let sleeping = tasks.spawn("sleeping", sleep());
+        tasks.shutdown();
+        assert_eq!(TASK_RT.block_on(sleeping).unwrap(), None);
+        let late = tasks.spawn("late", sleep());
+        assert_eq!(TASK_RT.block_on(late).unwrap(), None);
+        assert_eq!(tasks.running.load(Ordering::Relaxed), 0);
+    }
+}
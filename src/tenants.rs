@@ -0,0 +1,118 @@
+use crate::config;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use quickjs_runtime::facades::QuickJsRuntimeFacade;
+
+const REALM_PREFIX: &str = "tenant-";
+
+/// the id of the realm of a tenant
+pub fn realm_id(tenant: &str) -> String {
+    format!("{}{}", REALM_PREFIX, tenant)
+}
+
+/// the tenant a realm belongs to, None for the main realm and the realms of isolated requests
+pub fn tenant_of(realm_id: &str) -> Option<&str> {
+    realm_id.strip_prefix(REALM_PREFIX)
+}
+
+/// the tenant of a request, from the SCRIPT_TENANT_HEADER header or else the subdomain like tenant-a.example.com
+/// Err with the name when the header names a tenant which does not exist
+pub fn resolve(req: &HttpRequest) -> Result<Option<String>, String> {
+    let config = config::get();
+    if config.tenants.is_empty() {
+        return Ok(None);
+    }
+    if let Some(tenant) = req
+        .headers()
+        .get(config.tenant_header.as_str())
+        .and_then(|val| val.to_str().ok())
+    {
+        return if config.tenants.iter().any(|t| t == tenant) {
+            Ok(Some(tenant.to_string()))
+        } else {
+            Err(tenant.to_string())
+        };
+    }
+    let subdomain = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|host| host.split('.').next());
+    Ok(subdomain
+        .filter(|subdomain| config.tenants.iter().any(|t| t == subdomain))
+        .map(|subdomain| subdomain.to_string()))
+}
+
+/// the 404 for requests for an unknown tenant, these are not dispatched
+pub fn check(req: &HttpRequest) -> Option<HttpResponse> {
+    match resolve(req) {
+        Ok(_) => None,
+        Err(tenant) => Some(HttpResponse::NotFound().body(format!("unknown tenant {}", tenant))),
+    }
+}
+
+/// create the realms of the tenants in every runtime, these get the same proxies and modules as the main realm but
+/// their own globals, state and listeners
+pub async fn create_realms() -> std::io::Result<()> {
+    for (pool_idx, rt) in crate::script_pool().runtimes().iter().enumerate() {
+        create_runtime_realms(rt, pool_idx).await?;
+    }
+    Ok(())
+}
+
+/// create the realms of the tenants in one runtime, also used for a runtime which replaces the one at pool_idx
+pub async fn create_runtime_realms(
+    rt: &QuickJsRuntimeFacade,
+    pool_idx: usize,
+) -> std::io::Result<()> {
+    for tenant in config::get().tenants.iter() {
+        let id = realm_id(tenant);
+        let res = async {
+            crate::isolation::create_realm(rt, id.as_str(), pool_idx).await?;
+            for script in crate::entry::startup_scripts() {
+                rt.js_eval_module(Some(id.as_str()), script).await?;
+            }
+            rt.js_loop_realm(Some(id.as_str()), move |_rt, realm| {
+                crate::entry::dispatch_init(realm, pool_idx)
+            })
+            .await
+        };
+        res.await.map_err(|err| {
+            crate::errors::log_script_error(
+                format!("could not create the realm of tenant {}", tenant).as_str(),
+                &err,
+            );
+            std::io::Error::other(format!(
+                "could not create the realm of tenant {}: {}",
+                tenant,
+                err.get_message()
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn only_the_realms_of_tenants_have_a_tenant() {
+        assert_eq!(tenant_of(realm_id("a").as_str()), Some("a"));
+        assert_eq!(tenant_of("__main__"), None);
+        assert_eq!(tenant_of("isolated-1"), None);
+    }
+
+    #[test]
+    fn without_tenants_every_request_is_for_the_main_realm() {
+        config::init_for_tests();
+        let req = TestRequest::get()
+            .insert_header(("x-tenant", "a"))
+            .insert_header((header::HOST, "a.example.com"))
+            .to_http_request();
+        assert_eq!(resolve(&req), Ok(None));
+        assert!(check(&req).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// the max time a single script job (e.g. dispatching a request event) may run, see SCRIPT_TIMEOUT_MS
+pub fn script_timeout() -> Duration {
+    crate::config::get().script_timeout
+}
+
+/// the max time the request events of a route may run, the timeout in routes::ROUTE_TIMEOUTS, the timeout the script
+/// registered the route with or script_timeout
+pub fn route_timeout(route: &str, method: &str) -> Duration {
+    crate::routes::timeout(route)
+        .or_else(|| crate::script_routes::timeout(route, method))
+        .unwrap_or_else(script_timeout)
+}
+
+thread_local! {
+    // the deadline of the job currently running on this (the runtime's worker) thread
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// run a job with a deadline, when the deadline passes while script is running the interrupt handler
+/// will abort the script with an (uncatchable) InternalError
+/// this must be called from the worker thread of the runtime e.g. inside js_loop_realm
+pub fn with_deadline<T, F: FnOnce() -> T>(timeout: Duration, job: F) -> T {
+    let previous = DEADLINE.with(|deadline| deadline.replace(Some(Instant::now() + timeout)));
+    let res = job();
+    DEADLINE.with(|deadline| deadline.set(previous));
+    res
+}
+
+/// used as interrupt handler for the runtime, returns true when the current job should be aborted
+pub fn deadline_passed() -> bool {
+    DEADLINE.with(|deadline| match deadline.get() {
+        Some(deadline) => Instant::now() > deadline,
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script_routes;
+    use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+    use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+    use hirofa_utils::js_utils::Script;
+    use quickjs_runtime::builder::QuickJsRuntimeBuilder;
+
+    const SPIN: &str = "{ const end = Date.now() + 300; while (Date.now() < end) {} }";
+
+    #[test]
+    fn a_job_is_aborted_when_it_passes_its_deadline() {
+        let rt = QuickJsRuntimeBuilder::new()
+            .set_interrupt_handler(|_rt| deadline_passed())
+            .build();
+        let (slow, fast) = rt.js_loop_realm_sync(None, |_rt, realm| {
+            let slow = with_deadline(Duration::from_millis(50), || {
+                realm.js_eval(Script::new("file://slow.js", SPIN))
+            });
+            let fast = with_deadline(Duration::from_secs(5), || {
+                realm.js_eval(Script::new("file://fast.js", "1 + 1"))
+            });
+            // without a deadline the job may run as long as it takes
+            let unlimited = realm.js_eval(Script::new("file://slow.js", SPIN));
+            (slow.is_ok(), fast.is_ok() && unlimited.is_ok())
+        });
+        assert!(!slow);
+        assert!(fast);
+    }
+
+    // a job which keeps the runtime busy for 300 ms on the given route
+    fn run_slow_job(route: &str) -> bool {
+        let rt = QuickJsRuntimeBuilder::new()
+            .set_interrupt_handler(|_rt| deadline_passed())
+            .build();
+        let timeout = route_timeout(route, "GET");
+        rt.js_loop_realm_sync(None, move |_rt, realm| {
+            with_deadline(timeout, || {
+                realm.js_eval(Script::new(
+                    "file://slow.js",
+                    "const end = Date.now() + 300; while (Date.now() < end) {}",
+                ))
+            })
+            .is_ok()
+        })
+    }
+
+    #[test]
+    fn a_slow_route_runs_for_the_timeout_of_the_route() {
+        crate::config::init_for_tests();
+        assert_eq!(route_timeout("/export", "GET"), Duration::from_secs(30));
+        script_routes::register(
+            "GET",
+            "/timeout-test/fast",
+            "fast",
+            Some(Duration::from_millis(50)),
+        )
+        .unwrap();
+        script_routes::register(
+            "GET",
+            "/timeout-test/slow",
+            "slow",
+            Some(Duration::from_secs(5)),
+        )
+        .unwrap();
+        assert!(!run_slow_job("/timeout-test/fast"));
+        assert!(run_slow_job("/timeout-test/slow"));
+    }
+}
@@ -0,0 +1,250 @@
+use crate::tasks::TASKS;
+use crate::timeout::{script_timeout, with_deadline};
+use hirofa_utils::js_utils::adapters::{JsRealmAdapter, JsValueAdapter};
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::thread::ThreadId;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+lazy_static! {
+    // the pending timers by id, a timer is only fired if it is still in this map
+    static ref TIMERS: Mutex<HashMap<i32, PendingTimer>> = Mutex::new(HashMap::new());
+}
+
+struct PendingTimer {
+    handle: JoinHandle<Option<()>>,
+    callback_id: i32,
+    realm_id: String,
+}
+
+static NEXT_TIMER_ID: AtomicI32 = AtomicI32::new(1);
+
+/// the state we need to fire a timer in the realm which created it
+struct Timer {
+    id: i32,
+    pool_idx: usize,
+    // the worker thread of the runtime which created the timer, when the runtime at pool_idx was restarted the job
+    // runs on the thread of the new runtime which does not have the callback
+    thread: ThreadId,
+    realm_id: String,
+    // the id of the callback function in the realm's object cache
+    callback_id: i32,
+    repeat: bool,
+}
+
+fn fire(timer: &Timer) {
+    let id = timer.id;
+    let callback_id = timer.callback_id;
+    let repeat = timer.repeat;
+    let thread = timer.thread;
+    crate::script_pool().get(timer.pool_idx).js_loop_realm_void(
+        Some(timer.realm_id.as_str()),
+        move |_rt, realm| {
+            // the timers of a restarted runtime went away with it
+            if std::thread::current().id() != thread {
+                if let Some(timer) = TIMERS.lock().unwrap().remove(&id) {
+                    timer.handle.abort();
+                }
+                return;
+            }
+            // the timer may have been cleared after this job was queued
+            let pending = if repeat {
+                TIMERS.lock().unwrap().contains_key(&id)
+            } else {
+                TIMERS.lock().unwrap().remove(&id).is_some()
+            };
+            if !pending {
+                return;
+            }
+            // timer callbacks are subject to the same timeout as request jobs
+            let res = with_deadline(script_timeout(), || {
+                if repeat {
+                    realm.js_cache_with(callback_id, |callback| {
+                        realm.js_function_invoke(None, callback, &[])
+                    })
+                } else {
+                    let callback = realm.js_cache_consume(callback_id);
+                    realm.js_function_invoke(None, &callback, &[])
+                }
+            });
+            if let Err(err) = res {
+                crate::errors::log_script_error("timer callback failed", &err);
+            }
+        },
+    );
+}
+
+fn add_timer<R: JsRealmAdapter>(
+    realm: &R,
+    pool_idx: usize,
+    args: &[R::JsValueAdapterType],
+    repeat: bool,
+) -> Result<R::JsValueAdapterType, JsError> {
+    if args.is_empty() || !args[0].js_is_function() {
+        return Err(JsError::new_str("first argument should be a function"));
+    }
+    let delay_ms = match args.get(1) {
+        Some(delay) if delay.js_is_i32() => delay.js_to_i32().max(0) as u64,
+        Some(delay) if delay.js_is_f64() => delay.js_to_f64().max(0.0) as u64,
+        _ => 0,
+    };
+    let delay = Duration::from_millis(delay_ms);
+
+    let timer = Timer {
+        id: NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed),
+        pool_idx,
+        thread: std::thread::current().id(),
+        realm_id: realm.js_get_realm_id().to_string(),
+        callback_id: realm.js_cache_add(&args[0]),
+        repeat,
+    };
+    let id = timer.id;
+    let callback_id = timer.callback_id;
+    let realm_id = timer.realm_id.clone();
+
+    // hold the lock while spawning so the task can't fire before it is registered
+    let mut timers = TIMERS.lock().unwrap();
+    let handle = TASKS.spawn("timer", async move {
+        if timer.repeat {
+            // an interval of 0 would make tokio panic
+            let mut interval = tokio::time::interval(delay.max(Duration::from_millis(1)));
+            // the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                fire(&timer);
+            }
+        } else {
+            tokio::time::sleep(delay).await;
+            fire(&timer);
+        }
+    });
+    timers.insert(
+        id,
+        PendingTimer {
+            handle,
+            callback_id,
+            realm_id,
+        },
+    );
+
+    realm.js_i32_create(id)
+}
+
+fn clear_timer<R: JsRealmAdapter>(
+    realm: &R,
+    args: &[R::JsValueAdapterType],
+) -> Result<R::JsValueAdapterType, JsError> {
+    if let Some(id) = args.first() {
+        if id.js_is_i32() {
+            // the ids are shared by all realms, a realm can only clear its own timers
+            let mut timers = TIMERS.lock().unwrap();
+            let removed = match timers.get(&id.js_to_i32()) {
+                Some(timer) if timer.realm_id == realm.js_get_realm_id() => {
+                    timers.remove(&id.js_to_i32())
+                }
+                _ => None,
+            };
+            drop(timers);
+            if let Some(timer) = removed {
+                timer.handle.abort();
+                realm.js_cache_dispose(timer.callback_id);
+            }
+        }
+    }
+    realm.js_undefined_create()
+}
+
+/// abort all pending timers of a realm, called before a realm is removed
+/// the callbacks are not disposed from the object cache as that goes away with the realm
+pub fn clear_realm_timers(realm_id: &str) {
+    let mut timers = TIMERS.lock().unwrap();
+    timers.retain(|_id, timer| {
+        if timer.realm_id == realm_id {
+            timer.handle.abort();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// install the setTimeout, setInterval, clearTimeout and clearInterval functions
+/// callbacks run in the realm which created the timer of the runtime at pool_idx in the script pool
+pub fn init_timers<R: JsRealmAdapter + 'static>(realm: &R, pool_idx: usize) -> Result<(), JsError> {
+    realm.js_install_closure(
+        &[],
+        "setTimeout",
+        move |_rt, realm: &R, _this, args| add_timer(realm, pool_idx, args, false),
+        2,
+    )?;
+    realm.js_install_closure(
+        &[],
+        "setInterval",
+        move |_rt, realm: &R, _this, args| add_timer(realm, pool_idx, args, true),
+        2,
+    )?;
+    realm.js_install_closure(
+        &[],
+        "clearTimeout",
+        |_rt, realm: &R, _this, args| clear_timer(realm, args),
+        1,
+    )?;
+    realm.js_install_closure(
+        &[],
+        "clearInterval",
+        |_rt, realm: &R, _this, args| clear_timer(realm, args),
+        1,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::eval;
+
+    #[test]
+    fn timers_fire_unless_they_are_cleared() {
+        eval(
+            r#"{
+                globalThis.timersFired = [];
+                setTimeout(() => timersFired.push("timeout"), 10);
+                const cleared = setTimeout(() => timersFired.push("cleared"), 10);
+                clearTimeout(cleared);
+                const interval = setInterval(() => {
+                    timersFired.push("interval");
+                    clearInterval(interval);
+                }, 10);
+            }"#,
+        );
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert_eq!(eval("timersFired.sort().join()"), "interval,timeout");
+    }
+
+    #[actix_web::test]
+    async fn only_the_timers_of_the_realm_are_cleared() {
+        let timer = |realm_id: &str| PendingTimer {
+            handle: tokio::spawn(std::future::pending()),
+            callback_id: 0,
+            realm_id: realm_id.to_string(),
+        };
+        let cleared = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+        let kept = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+        TIMERS
+            .lock()
+            .unwrap()
+            .insert(cleared, timer("request-cleared"));
+        TIMERS.lock().unwrap().insert(kept, timer("request-kept"));
+
+        clear_realm_timers("request-cleared");
+        let mut timers = TIMERS.lock().unwrap();
+        assert!(!timers.contains_key(&cleared));
+        timers.remove(&kept).unwrap().handle.abort();
+    }
+}
@@ -0,0 +1,140 @@
+use crate::config;
+use actix_http::HttpService;
+use actix_server::Server;
+use actix_service::map_config;
+use actix_web::dev::AppConfig;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+
+pub const TLS_CERT_VAR: &str = "SCRIPT_TLS_CERT";
+pub const TLS_KEY_VAR: &str = "SCRIPT_TLS_KEY";
+
+/// load the rustls config from the PEM files in SCRIPT_TLS_CERT and SCRIPT_TLS_KEY
+/// returns None when neither var is set, in which case we serve plaintext
+pub fn load_tls_config() -> std::io::Result<Option<ServerConfig>> {
+    match (std::env::var(TLS_CERT_VAR), std::env::var(TLS_KEY_VAR)) {
+        (Ok(cert_path), Ok(key_path)) => {
+            let certs = load_certs(cert_path.as_str())?;
+            let key = load_key(key_path.as_str())?;
+            let config = ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(|err| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid tls certificate or key: {}", err),
+                    )
+                })?;
+            Ok(Some(config))
+        }
+        (Err(_), Err(_)) => Ok(None),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("both {} and {} should be set", TLS_CERT_VAR, TLS_KEY_VAR),
+        )),
+    }
+}
+
+/// the protocols we serve, for logging
+///
+/// a TLS listener offers h2 and http/1.1 through ALPN so clients which support it use HTTP/2, with SCRIPT_HTTP2=false
+/// it only offers http/1.1 (see bind_http1), plaintext listeners only serve HTTP/1.1 (we don't do h2c)
+pub fn protocols(tls: bool, http2: bool) -> &'static str {
+    if tls && http2 {
+        "h2, http/1.1"
+    } else {
+        "http/1.1"
+    }
+}
+
+/// serve the app over TLS with only http/1.1 in the ALPN protocols, for SCRIPT_HTTP2=false
+///
+/// the TLS listeners of actix-web's HttpServer always offer h2 so this builds the server from the http/1.1 service
+/// of actix-http instead, with the same workers, keep alive and shutdown timeout as the HttpServer in main
+pub fn bind_http1(addr: (String, u16), mut tls_config: ServerConfig) -> std::io::Result<Server> {
+    // the http/1.1 service does not add any ALPN protocols, clients which ask for h2 then see it is not offered
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let mut builder = Server::build().shutdown_timeout(crate::SHUTDOWN_TIMEOUT.as_secs());
+    if let Some(workers) = config::get().workers {
+        builder = builder.workers(workers);
+    }
+    let keep_alive = crate::keep_alive();
+    let server = builder.bind("script-http1", addr, move || {
+        let service = HttpService::build();
+        let service = match keep_alive {
+            Some(keep_alive) => service.keep_alive(keep_alive),
+            None => service,
+        };
+        // the app does not use the host and scheme of the AppConfig, those are only defaults for url_for and
+        // connection_info
+        service
+            .h1(map_config(crate::app(), |_| AppConfig::default()))
+            .rustls(tls_config.clone())
+    })?;
+    Ok(server.run())
+}
+
+fn open(path: &str) -> std::io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| Error::new(err.kind(), format!("could not read {}: {}", path, err)))
+}
+
+pub fn load_certs(path: &str) -> std::io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no certificates found in {}", path),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> std::io::Result<PrivateKey> {
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut open(path)?)?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut open(path)?)?;
+    }
+    match keys.into_iter().next() {
+        Some(key) => Ok(PrivateKey(key)),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no private key found in {}", path),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http2_is_only_offered_over_tls() {
+        assert_eq!(protocols(true, true), "h2, http/1.1");
+        assert_eq!(protocols(true, false), "http/1.1");
+        assert_eq!(protocols(false, true), "http/1.1");
+        assert_eq!(protocols(false, false), "http/1.1");
+    }
+
+    #[test]
+    fn files_without_a_certificate_or_key_are_refused() {
+        let path = std::env::temp_dir().join(format!("tls-test-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a pem file").unwrap();
+        let path = path.to_str().unwrap();
+        let err = load_certs(path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("no certificates found"));
+        assert!(load_key(path)
+            .unwrap_err()
+            .to_string()
+            .starts_with("no private key found"));
+        std::fs::remove_file(path).unwrap();
+        assert!(load_certs(path)
+            .unwrap_err()
+            .to_string()
+            .starts_with("could not read"));
+    }
+}
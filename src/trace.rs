@@ -0,0 +1,353 @@
+use crate::config;
+use crate::tasks::{TASKS, TASK_RT};
+use actix_web::HttpRequest;
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::MissedTickBehavior;
+
+/// the spans are exported in batches with this interval
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// the max number of spans waiting to be exported, spans are dropped while the collector does not keep up
+const MAX_BUFFERED_SPANS: usize = 2048;
+
+// the span kinds of OTLP
+const SPAN_KIND_INTERNAL: i32 = 1;
+const SPAN_KIND_SERVER: i32 = 2;
+const STATUS_CODE_ERROR: i32 = 2;
+
+lazy_static! {
+    static ref SPANS: Mutex<Vec<Span>> = Mutex::new(vec![]);
+    // the spans only go to SCRIPT_OTLP_ENDPOINT, a redirect is reported as a failed export
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("could not create the trace client");
+}
+
+/// the W3C trace context of a request, from its traceparent header or a new trace when it has none
+///
+/// span_id is the span of our handling of the request, it is the parent of the spans of outbound calls which pass
+/// traceparent() along
+#[derive(Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    // the span of the caller, None when the trace started here
+    pub parent_span_id: Option<String>,
+    pub sampled: bool,
+    // passed on as is, we don't add to it
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .map(|val| val.trim().to_string())
+        };
+        match header("traceparent").as_deref().and_then(parse_traceparent) {
+            Some((trace_id, parent_span_id, sampled)) => Self {
+                trace_id,
+                span_id: new_span_id(),
+                parent_span_id: Some(parent_span_id),
+                sampled,
+                tracestate: header("tracestate").filter(|state| !state.is_empty()),
+            },
+            // without a valid traceparent the tracestate is meaningless
+            None => Self {
+                trace_id: hex(&uuid::Uuid::new_v4().as_bytes()[..]),
+                span_id: new_span_id(),
+                parent_span_id: None,
+                sampled: true,
+                tracestate: None,
+            },
+        }
+    }
+
+    /// the traceparent header for an outbound call made while handling the request
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled { "01" } else { "00" }
+        )
+    }
+}
+
+/// parse a traceparent header like 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01 into the trace id, the
+/// parent span id and the sampled flag, None when it is invalid
+/// versions after 00 may add fields, those are ignored
+pub fn parse_traceparent(value: &str) -> Option<(String, String, bool)> {
+    let parts: Vec<&str> = value.split('-').collect();
+    if parts.len() < 4 {
+        return None;
+    }
+    let (version, trace_id, parent_id, flags) = (parts[0], parts[1], parts[2], parts[3]);
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.len() != 4) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    // all zeros is an invalid id
+    if trace_id.chars().all(|c| c == '0') || parent_id.chars().all(|c| c == '0') {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), parent_id.to_string(), flags & 1 == 1))
+}
+
+// lowercase hex of the given length, uppercase is invalid in a traceparent
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// a new random span id
+pub fn new_span_id() -> String {
+    // the second half of a v4 uuid is random except for the variant bits
+    hex(&uuid::Uuid::new_v4().as_bytes()[8..])
+}
+
+/// a span to export, see record
+pub struct Span {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub server: bool,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+    pub error: bool,
+}
+
+impl Span {
+    /// the span of our handling of the request, from start until now
+    pub fn server(context: &TraceContext, name: &str, start: SystemTime) -> Self {
+        Self {
+            trace_id: context.trace_id.clone(),
+            span_id: context.span_id.clone(),
+            parent_span_id: context.parent_span_id.clone(),
+            name: name.to_string(),
+            server: true,
+            start,
+            end: SystemTime::now(),
+            attributes: vec![],
+            error: false,
+        }
+    }
+
+    /// a span within the server span like the dispatch to the script, from start until now
+    pub fn internal(context: &TraceContext, name: &str, start: SystemTime) -> Self {
+        Self {
+            span_id: new_span_id(),
+            parent_span_id: Some(context.span_id.clone()),
+            server: false,
+            ..Self::server(context, name, start)
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let attributes: Vec<serde_json::Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+            .collect();
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": self.name,
+            "kind": if self.server { SPAN_KIND_SERVER } else { SPAN_KIND_INTERNAL },
+            // uint64 is a string in the json mapping of protobuf
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end).to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = json!(parent_span_id);
+        }
+        if self.error {
+            span["status"] = json!({ "code": STATUS_CODE_ERROR });
+        }
+        span
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or(0)
+}
+
+/// queue a span for export, spans are only kept when SCRIPT_OTLP_ENDPOINT is set and the trace is sampled
+pub fn record(context: &TraceContext, span: Span) {
+    if config::get().otlp_endpoint.is_none() || !context.sampled {
+        return;
+    }
+    let mut spans = SPANS.lock().unwrap();
+    if spans.len() >= MAX_BUFFERED_SPANS {
+        log::debug!("dropping span {}, the export buffer is full", span.name);
+        return;
+    }
+    spans.push(span);
+}
+
+/// export the recorded spans every EXPORT_INTERVAL, does nothing when SCRIPT_OTLP_ENDPOINT is not set
+pub fn start_exporter() {
+    if config::get().otlp_endpoint.is_none() {
+        return;
+    }
+    TASKS.spawn("otlp exporter", async {
+        let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            export().await;
+        }
+    });
+}
+
+/// export the spans which were recorded since the last export, called once more on shutdown
+pub async fn flush() {
+    if config::get().otlp_endpoint.is_none() {
+        return;
+    }
+    // the client belongs to TASK_RT where the exporter runs, the server may already be shutting down
+    if let Err(err) = TASK_RT.spawn(export()).await {
+        log::error!("could not export the last spans: {}", err);
+    }
+}
+
+// POST the spans as OTLP json to <SCRIPT_OTLP_ENDPOINT>/v1/traces, the spans are dropped when that fails
+async fn export() {
+    let spans: Vec<Span> = SPANS.lock().unwrap().drain(..).collect();
+    if spans.is_empty() {
+        return;
+    }
+    let endpoint = match &config::get().otlp_endpoint {
+        Some(endpoint) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        None => return,
+    };
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    {"key": "service.name", "value": {"stringValue": config::get().app_name}},
+                    {"key": "service.version", "value": {"stringValue": env!("CARGO_PKG_VERSION")}},
+                ]
+            },
+            "scopeSpans": [{
+                "scope": {"name": env!("CARGO_PKG_NAME")},
+                "spans": spans.iter().map(Span::to_json).collect::<Vec<_>>(),
+            }]
+        }]
+    });
+    let res = CLIENT
+        .post(endpoint.as_str())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .timeout(EXPORT_INTERVAL)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    match res {
+        Ok(response) if response.status().is_success() => {
+            log::trace!("exported {} spans", spans.len())
+        }
+        Ok(response) => log::warn!(
+            "could not export {} spans to {}: status {}",
+            spans.len(),
+            endpoint,
+            response.status()
+        ),
+        Err(err) => log::warn!(
+            "could not export {} spans to {}: {}",
+            spans.len(),
+            endpoint,
+            err
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn only_valid_traceparents_are_continued() {
+        let (trace_id, parent_id) = ("4bf92f3577b34da6a3ce929d0e0e4736", "00f067aa0ba902b7");
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some((trace_id.to_string(), parent_id.to_string(), true))
+        );
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"),
+            Some((trace_id.to_string(), parent_id.to_string(), false))
+        );
+        // later versions may add fields, 00 may not
+        assert!(
+            parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x")
+                .is_some()
+        );
+        assert!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x")
+                .is_none()
+        );
+        assert!(
+            parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01").is_none()
+        );
+        assert!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-01").is_none());
+    }
+
+    #[test]
+    fn a_request_gets_a_span_of_its_own_in_the_trace_of_the_caller() {
+        let req = TestRequest::default()
+            .insert_header((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .insert_header(("tracestate", "vendor=value"))
+            .to_http_request();
+        let trace = TraceContext::from_request(&req);
+        assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_eq!(trace.tracestate.as_deref(), Some("vendor=value"));
+        assert_eq!(trace.span_id.len(), 16);
+        assert_ne!(trace.span_id, "00f067aa0ba902b7");
+        assert_eq!(
+            trace.traceparent(),
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", trace.span_id)
+        );
+        // an invalid traceparent starts a new trace and drops the tracestate
+        let req = TestRequest::default()
+            .insert_header(("traceparent", "invalid"))
+            .insert_header(("tracestate", "vendor=value"))
+            .to_http_request();
+        let trace = TraceContext::from_request(&req);
+        assert_eq!(trace.trace_id.len(), 32);
+        assert_eq!((trace.parent_span_id, trace.tracestate), (None, None));
+        assert!(trace.sampled);
+    }
+}
@@ -0,0 +1,192 @@
+use hirofa_utils::js_utils::{JsError, Script, ScriptPreProcessor};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use typescript_utils::TypeScriptPreProcessor;
+
+/// a ScriptPreProcessor which caches the output of the TypeScriptPreProcessor on disk
+///
+/// cache entries are keyed by a hash of the path, the source and the transpile options so changing any of those
+/// simply results in a new entry, old entries are never read again
+pub struct CachingTypeScriptPreProcessor {
+    inner: TypeScriptPreProcessor,
+    // identifies the options the inner preprocessor was created with, see TsOptions::cache_key
+    options: String,
+    dir: PathBuf,
+}
+
+impl CachingTypeScriptPreProcessor {
+    pub fn new<P: Into<PathBuf>>(inner: TypeScriptPreProcessor, options: &str, dir: P) -> Self {
+        Self {
+            inner,
+            options: options.to_string(),
+            dir: dir.into(),
+        }
+    }
+
+    fn cache_file(&self, script: &Script) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        script.get_path().hash(&mut hasher);
+        script.get_code().hash(&mut hasher);
+        self.options.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.js", hasher.finish()))
+    }
+}
+
+impl ScriptPreProcessor for CachingTypeScriptPreProcessor {
+    fn process(&self, script: &mut Script) -> Result<(), JsError> {
+        // only typescript is transpiled so there is nothing to cache for other scripts
+        if !script.get_path().ends_with(".ts") {
+            return self.inner.process(script);
+        }
+
+        let cache_file = self.cache_file(script);
+        if let Ok(code) = std::fs::read_to_string(&cache_file) {
+            log::trace!("using cached transpiled code for {}", script.get_path());
+            script.set_code(code);
+            return Ok(());
+        }
+
+        self.inner.process(script)?;
+
+        // failing to write the cache is not fatal, we'll just transpile again next time
+        if let Err(err) = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&cache_file, script.get_code()))
+        {
+            log::warn!(
+                "could not write {} to ts cache: {}",
+                cache_file.display(),
+                err
+            );
+        }
+        Ok(())
+    }
+}
+
+/// a ScriptPreProcessor which transpiles a module with the options of the SCRIPT_TS_MODULES prefix it is under
+///
+/// a prefix with a scheme like https://github.com/org/legacy/ matches the modules loaded from there, others are a
+/// dir relative to the module dir (or absolute) and match the file:// modules in it, the first matching prefix wins
+/// so they should be added longest first, modules under none of them use the default
+pub struct ModulePreProcessor {
+    module_dir: PathBuf,
+    // the forms a prefix can take in a module path and the preprocessor of the modules under it
+    by_prefix: Vec<(Vec<String>, CachingTypeScriptPreProcessor)>,
+    default: CachingTypeScriptPreProcessor,
+}
+
+impl ModulePreProcessor {
+    pub fn new<P: Into<PathBuf>>(module_dir: P, default: CachingTypeScriptPreProcessor) -> Self {
+        Self {
+            module_dir: module_dir.into(),
+            by_prefix: vec![],
+            default,
+        }
+    }
+
+    pub fn add(mut self, prefix: &str, preprocessor: CachingTypeScriptPreProcessor) -> Self {
+        let forms = if prefix.contains("://") {
+            vec![prefix.to_string()]
+        } else {
+            // the module loader may use the dir as configured or its absolute path
+            let dir = self.module_dir.join(prefix);
+            let mut forms = vec![without_cur_dir(dir.to_string_lossy().as_ref()).to_string()];
+            if let Ok(absolute) = self.module_dir.canonicalize() {
+                forms.push(absolute.join(prefix).to_string_lossy().to_string());
+            }
+            forms
+        };
+        self.by_prefix.push((forms, preprocessor));
+        self
+    }
+
+    fn preprocessor_for(&self, path: &str) -> &CachingTypeScriptPreProcessor {
+        let file = path.strip_prefix("file://").map(without_cur_dir);
+        self.by_prefix
+            .iter()
+            .find(|(forms, _)| {
+                forms.iter().any(|form| match file {
+                    // by path component so legacy/ does not match legacy2/
+                    Some(file) => Path::new(file).starts_with(form),
+                    None => path.starts_with(form.as_str()),
+                })
+            })
+            .map(|(_, preprocessor)| preprocessor)
+            .unwrap_or(&self.default)
+    }
+}
+
+fn without_cur_dir(path: &str) -> &str {
+    path.strip_prefix("./").unwrap_or(path)
+}
+
+impl ScriptPreProcessor for ModulePreProcessor {
+    fn process(&self, script: &mut Script) -> Result<(), JsError> {
+        self.preprocessor_for(script.get_path()).process(script)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use typescript_utils::TargetVersion;
+
+    const SOURCE: &str = "export const answer: number = 42;";
+
+    #[test]
+    fn only_typescript_is_cached_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("ts-cache-test-{}", std::process::id()));
+        let tspp = CachingTypeScriptPreProcessor::new(
+            TypeScriptPreProcessor::new(TargetVersion::Es2020, false, false),
+            "es2020",
+            &dir,
+        );
+        let mut js = Script::new("file://test.js", SOURCE);
+        assert!(tspp.process(&mut js).is_ok());
+        assert!(std::fs::read_dir(&dir).is_err());
+
+        let mut ts = Script::new("file://test.ts", SOURCE);
+        assert!(tspp.process(&mut ts).is_ok());
+        let cache_file = tspp.cache_file(&Script::new("file://test.ts", SOURCE));
+        assert_eq!(std::fs::read_to_string(&cache_file).unwrap(), ts.get_code());
+
+        // a second process of the same source is answered from the cache
+        std::fs::write(&cache_file, "export const answer = 43;").unwrap();
+        let mut again = Script::new("file://test.ts", SOURCE);
+        assert!(tspp.process(&mut again).is_ok());
+        assert_eq!(again.get_code(), "export const answer = 43;");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_module_is_transpiled_with_the_options_of_its_prefix() {
+        let dir = std::env::temp_dir().join(format!("ts-modules-test-{}", std::process::id()));
+        let tspp = |target: TargetVersion, options: &str| {
+            CachingTypeScriptPreProcessor::new(
+                TypeScriptPreProcessor::new(target, false, false),
+                options,
+                &dir,
+            )
+        };
+        let modules = ModulePreProcessor::new("./modules", tspp(TargetVersion::Es2020, "es2020"))
+            .add("legacy/", tspp(TargetVersion::Es5, "es5"))
+            .add(
+                "https://example.com/lib/",
+                tspp(TargetVersion::Es2015, "es2015"),
+            );
+        for (path, expected) in [
+            ("file://./modules/legacy/a.ts", "es5"),
+            ("file://modules/legacy/sub/b.ts", "es5"),
+            ("https://example.com/lib/c.ts", "es2015"),
+            // by path component, legacy/ is not a prefix of legacy2/
+            ("file://modules/legacy2/d.ts", "es2020"),
+            ("file://modules/main.ts", "es2020"),
+            ("https://example.com/other/e.ts", "es2020"),
+        ] {
+            assert_eq!(modules.preprocessor_for(path).options, expected, "{}", path);
+        }
+        for path in ["file://modules/legacy/a.js", "file://modules/main.js"] {
+            assert!(modules.process(&mut Script::new(path, SOURCE)).is_ok());
+        }
+    }
+}
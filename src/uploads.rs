@@ -0,0 +1,169 @@
+use crate::sandbox::sandboxed_path;
+use actix_web::web::Bytes;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use multer::{Constraints, Multipart, SizeLimit};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    // the contents of uploaded files by id, we keep these out of the script heap
+    static ref UPLOADS: Mutex<HashMap<String, Bytes>> = Mutex::new(HashMap::new());
+}
+
+/// the metadata of an uploaded file as passed to the script in event.files
+#[derive(Clone)]
+pub struct UploadedFile {
+    pub id: String,
+    pub field: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: usize,
+}
+
+#[derive(Default)]
+pub struct MultipartBody {
+    pub files: Vec<UploadedFile>,
+    pub fields: Vec<(String, String)>,
+}
+
+/// parse a multipart/form-data body, the contents of files are stored until discard() is called
+/// when the body turns out to be invalid the files before the error are discarded right away
+pub async fn parse_multipart(content_type: &str, body: Bytes) -> Result<MultipartBody, JsError> {
+    let boundary = multer::parse_boundary(content_type)
+        .map_err(|err| JsError::new_string(format!("invalid multipart content type: {}", err)))?;
+    // the body was already limited to SCRIPT_MAX_BODY when it was read
+    let max_body = crate::config::get().max_body as u64;
+    let constraints = Constraints::new().size_limit(SizeLimit::new().whole_stream(max_body));
+    let stream = tokio_stream::once(Ok::<Bytes, std::io::Error>(body));
+    let multipart = Multipart::with_constraints(stream, boundary, constraints);
+
+    let mut parsed = MultipartBody::default();
+    match read_fields(multipart, &mut parsed).await {
+        Ok(()) => Ok(parsed),
+        Err(err) => {
+            let ids: Vec<String> = parsed.files.iter().map(|file| file.id.clone()).collect();
+            discard(&ids);
+            Err(err)
+        }
+    }
+}
+
+async fn read_fields(
+    mut multipart: Multipart<'_>,
+    parsed: &mut MultipartBody,
+) -> Result<(), JsError> {
+    let map_err =
+        |err: multer::Error| JsError::new_string(format!("invalid multipart body: {}", err));
+    while let Some(field) = multipart.next_field().await.map_err(map_err)? {
+        let name = field.name().unwrap_or_default().to_string();
+        match field.file_name().map(|file_name| file_name.to_string()) {
+            Some(filename) => {
+                let content_type = field.content_type().map(|mime| mime.to_string());
+                let data = field.bytes().await.map_err(map_err)?;
+                let id = uuid::Uuid::new_v4().to_string();
+                parsed.files.push(UploadedFile {
+                    id: id.clone(),
+                    field: name,
+                    filename,
+                    content_type,
+                    size: data.len(),
+                });
+                UPLOADS.lock().unwrap().insert(id, data);
+            }
+            None => {
+                let text = field.text().await.map_err(map_err)?;
+                parsed.fields.push((name, text));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// save an uploaded file to a path relative to SCRIPT_UPLOAD_DIR
+pub fn save(id: &str, rel_path: &str) -> Result<(), JsError> {
+    let path = sandboxed_path(crate::config::get().upload_dir.as_str(), rel_path)?;
+    let data = UPLOADS
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| JsError::new_string(format!("no such upload: {}", id)))?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| JsError::new_string(format!("could not create dir: {}", err)))?;
+    }
+    std::fs::write(&path, &data)
+        .map_err(|err| JsError::new_string(format!("could not save upload: {}", err)))
+}
+
+/// forget the uploaded files of a request, called when the request was handled
+pub fn discard(ids: &[String]) {
+    let mut uploads = UPLOADS.lock().unwrap();
+    for id in ids {
+        uploads.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn files_and_fields_are_parsed_and_files_kept_until_discarded() {
+        crate::config::init_for_tests();
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            holiday\r\n\
+            --b\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"beach.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            not really a png\r\n\
+            --b--\r\n";
+        let parsed = parse_multipart("multipart/form-data; boundary=b", Bytes::from(body))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(
+            parsed.fields,
+            vec![("title".to_string(), "holiday".to_string())]
+        );
+        let file = &parsed.files[0];
+        assert_eq!(parsed.files.len(), 1);
+        assert_eq!(
+            (file.field.as_str(), file.filename.as_str()),
+            ("photo", "beach.png")
+        );
+        assert_eq!(file.content_type.as_deref(), Some("image/png"));
+        assert_eq!(file.size, "not really a png".len());
+        assert!(UPLOADS.lock().unwrap().contains_key(&file.id));
+        assert!(save(&file.id, "../beach.png").is_err());
+
+        let ids: Vec<String> = parsed.files.iter().map(|file| file.id.clone()).collect();
+        discard(&ids);
+        assert!(!UPLOADS.lock().unwrap().contains_key(&file.id));
+        assert!(save(&file.id, "beach.png")
+            .err()
+            .unwrap()
+            .get_message()
+            .starts_with("no such upload"));
+    }
+
+    #[actix_web::test]
+    async fn the_files_of_an_invalid_body_are_discarded() {
+        crate::config::init_for_tests();
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\n\r\n\
+            first\r\n\
+            --b\r\n\
+            Content-Disposition: form-data; name=\"b\"; filename=\"b.txt\"\r\n\r\n\
+            truncated";
+        let uploads_before = UPLOADS.lock().unwrap().len();
+        assert!(
+            parse_multipart("multipart/form-data; boundary=b", Bytes::from(body))
+                .await
+                .is_err()
+        );
+        assert_eq!(UPLOADS.lock().unwrap().len(), uploads_before);
+    }
+}
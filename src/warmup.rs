@@ -0,0 +1,74 @@
+use crate::timeout::{script_timeout, with_deadline};
+use crate::{config, dispatch, errors, script_pool, MY_APP_CLASS, MY_APP_NAMESPACE};
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use std::time::Instant;
+
+/// dispatch the warmup event SCRIPT_WARMUP_ITERATIONS times in every runtime before we start serving
+///
+/// QuickJS does not JIT but the first runs of a listener are still slower, e.g. because of lazily compiled
+/// functions and inline caches, listeners can also use the event to fill caches of their own
+/// every iteration is a separate job so the script timeout applies per iteration
+pub async fn run() -> std::io::Result<()> {
+    dispatch_warmup(config::get().warmup_iterations).await
+}
+
+async fn dispatch_warmup(iterations: u32) -> std::io::Result<()> {
+    if iterations == 0 {
+        return Ok(());
+    }
+    let started = Instant::now();
+    for rt in script_pool().runtimes() {
+        for iteration in 0..iterations {
+            rt.js_loop_realm(None, move |_rt, realm| {
+                with_deadline(script_timeout(), || {
+                    let event_obj = dispatch::build_event(
+                        realm,
+                        &[("iteration", realm.js_i32_create(iteration as i32)?)],
+                    )?;
+                    dispatch::dispatch_to(
+                        realm,
+                        MY_APP_NAMESPACE,
+                        MY_APP_CLASS,
+                        "warmup",
+                        &event_obj,
+                    )
+                })
+            })
+            .await
+            .map_err(|err| {
+                errors::log_script_error("warmup failed", &err);
+                std::io::Error::other(format!("warmup failed: {}", err.get_message()))
+            })?;
+        }
+    }
+    log::info!(
+        "dispatched {} warmup events in {} runtimes in {:?}",
+        iterations,
+        script_pool().runtimes().len(),
+        started.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn the_warmup_event_is_dispatched_for_every_iteration() {
+        crate::tests::eval(
+            r#"com.mycompany.MyApp.addEventListener("warmup", (evt) => {
+                const app = com.mycompany.MyApp;
+                const iterations = app.kvGet("warmup-test");
+                app.kvSet("warmup-test", iterations === undefined ? "" + evt.iteration : iterations + "," + evt.iteration);
+            });"#,
+        );
+        dispatch_warmup(0).await.unwrap();
+        dispatch_warmup(3).await.unwrap();
+        assert_eq!(
+            crate::tests::eval(r#"com.mycompany.MyApp.kvGet("warmup-test")"#),
+            "0,1,2"
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use crate::timeout::{script_timeout, with_deadline};
+use crate::{
+    auth, backpressure, dispatch, errors, maintenance, rate_limit, script_pool, tenants,
+    MY_APP_CLASS, MY_APP_NAMESPACE,
+};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, StreamHandler};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use hirofa_utils::js_utils::adapters::JsRealmAdapter;
+use hirofa_utils::js_utils::facades::JsRuntimeFacade;
+use hirofa_utils::js_utils::JsError;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::SemaphorePermit;
+
+lazy_static! {
+    // the open connections by id, used by wsSend() to find the connection to send to
+    static ref CONNECTIONS: Mutex<HashMap<String, Addr<WsSession>>> = Mutex::new(HashMap::new());
+}
+
+/// a text message sent to the client by script
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Outgoing(String);
+
+/// the actor for a single websocket connection
+///
+/// the ws:open, ws:message and ws:close events of a connection are dispatched to the same runtime so they are
+/// handled in order, events are dispatched in the main realm or the realm of the tenant of the upgrade request, even
+/// when requests are isolated
+struct WsSession {
+    id: String,
+    pool_idx: usize,
+    // None for the main realm
+    realm_id: Option<String>,
+    // taken by the upgrade for the ws:open event
+    open_permit: Option<SemaphorePermit<'static>>,
+}
+
+impl WsSession {
+    /// dispatch an event with the connection id and optionally the message as data
+    /// the permit (see backpressure.rs) is held until the event was dispatched
+    fn dispatch(
+        &self,
+        event: &'static str,
+        data: Option<String>,
+        permit: Option<SemaphorePermit<'static>>,
+    ) {
+        let id = self.id.clone();
+        script_pool().get(self.pool_idx).js_loop_realm_void(
+            self.realm_id.as_deref(),
+            move |_rt, realm| {
+                let _permit = permit;
+                let res = with_deadline(script_timeout(), || {
+                    let event_obj = create_event_obj(realm, id.as_str(), data.as_deref())?;
+                    dispatch::dispatch_to(realm, MY_APP_NAMESPACE, MY_APP_CLASS, event, &event_obj)
+                });
+                if let Err(err) = res {
+                    errors::log_script_error(
+                        format!("could not dispatch {} for connection {}", event, id).as_str(),
+                        &err,
+                    );
+                }
+            },
+        );
+    }
+}
+
+fn create_event_obj<R: JsRealmAdapter>(
+    realm: &R,
+    connection_id: &str,
+    data: Option<&str>,
+) -> Result<R::JsValueAdapterType, JsError> {
+    let mut fields = vec![("connectionId", realm.js_string_create(connection_id)?)];
+    if let Some(data) = data {
+        fields.push(("data", realm.js_string_create(data)?));
+    }
+    dispatch::build_event(realm, &fields)
+}
+
+impl Actor for WsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        CONNECTIONS
+            .lock()
+            .unwrap()
+            .insert(self.id.clone(), ctx.address());
+        let permit = self.open_permit.take();
+        self.dispatch("ws:open", None, permit);
+    }
+
+    // the close of a connection which was opened is always dispatched so the script can clean up after it
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        CONNECTIONS.lock().unwrap().remove(&self.id);
+        self.dispatch("ws:close", None, None);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            // when SCRIPT_MAX_PENDING jobs are pending the client should retry later, like a request gets a 503
+            Ok(ws::Message::Text(text)) => match backpressure::try_acquire() {
+                Ok(permit) => self.dispatch("ws:message", Some(text.to_string()), permit),
+                Err(_) => {
+                    log::debug!("closing connection {}, too many pending requests", self.id);
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Again,
+                        description: Some("too many pending requests".to_string()),
+                    }));
+                    ctx.stop();
+                }
+            },
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            // binary messages are not supported by the script api
+            Ok(ws::Message::Binary(_)) => {
+                log::debug!("ignoring binary message on connection {}", self.id);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                log::debug!("websocket error on connection {}: {}", self.id, err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Handler<Outgoing> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Outgoing, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+/// the /ws endpoint, upgrades the request to a websocket connection
+/// the upgrade is rejected like other requests e.g. by the rate limit or SCRIPT_AUTH, the connection stays with the
+/// tenant of the upgrade request
+pub async fn ws_index(
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let rejected = maintenance::check()
+        .or_else(|| rate_limit::check(&req))
+        .or_else(|| tenants::check(&req))
+        .or_else(|| auth::check(&req));
+    if let Some(response) = rejected {
+        return Ok(response);
+    }
+    let open_permit = match backpressure::try_acquire() {
+        Ok(permit) => permit,
+        Err(response) => return Ok(response),
+    };
+    let session = WsSession {
+        id: uuid::Uuid::new_v4().to_string(),
+        pool_idx: script_pool().next_index(),
+        realm_id: tenants::resolve(&req)
+            .ok()
+            .flatten()
+            .map(|tenant| tenants::realm_id(tenant.as_str())),
+        open_permit,
+    };
+    ws::start(session, &req, stream)
+}
+
+/// send a text message to a connection, returns false if the connection is not open (anymore)
+pub fn send(connection_id: &str, text: String) -> bool {
+    match CONNECTIONS.lock().unwrap().get(connection_id) {
+        Some(addr) => {
+            addr.do_send(Outgoing(text));
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::TestRequest;
+    use std::time::{Duration, Instant};
+
+    // a text frame as a client sends it, masked
+    fn text_frame(text: &str) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x81, 0x80 | text.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            text.bytes()
+                .zip(mask.iter().cycle())
+                .map(|(byte, mask)| byte ^ mask),
+        );
+        frame
+    }
+
+    #[actix_web::test]
+    async fn the_messages_of_a_connection_are_dispatched_between_open_and_close() {
+        crate::tests::eval(
+            r#"{
+                globalThis.wsEvents = [];
+                for (const name of ["ws:open", "ws:message", "ws:close"]) {
+                    com.mycompany.MyApp.addEventListener(name, (evt) => {
+                        wsEvents.push(evt.data === undefined ? name : name + " " + evt.data);
+                    });
+                }
+            }"#,
+        );
+        // the connection is closed when the payload ends
+        let req = TestRequest::get()
+            .uri("/ws")
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .set_payload(text_frame("hello"));
+        let (status, _, _) = crate::tests::call(req).await;
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+
+        let started = Instant::now();
+        while crate::tests::eval("wsEvents.length") != "3" {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            crate::tests::eval("wsEvents.join()"),
+            "ws:open,ws:message hello,ws:close"
+        );
+    }
+}